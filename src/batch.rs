@@ -0,0 +1,197 @@
+use crate::client::totals::Summary;
+use crate::error::Error;
+use crate::language;
+use log::{error, info};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A book of a batch and where it is translated to.
+pub struct Book {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+/// The books of `inputs` translated into the directory `output`, each under
+/// its own file name. An input whose file name holds `*` or `?` is matched
+/// against the files of its directory, for shells that do not expand it.
+pub fn books(inputs: &[PathBuf], output: &Path) -> Result<Vec<Book>, Error> {
+    let mut books = Vec::new();
+    let mut names: HashMap<std::ffi::OsString, PathBuf> = HashMap::new();
+    for input in inputs {
+        for input in expand(input)? {
+            let name = input.file_name().unwrap_or_default().to_owned();
+            if let Some(other) = names.insert(name.clone(), input.clone()) {
+                return Err(Error::Input(format!(
+                    "{} and {} would both be written to {}",
+                    other.display(),
+                    input.display(),
+                    output.join(&name).display()
+                )));
+            }
+            books.push(Book {
+                output: output.join(name),
+                input,
+            });
+        }
+    }
+    Ok(books)
+}
+
+/// Whether `path` is a pattern, its file name holding `*` or `?`.
+pub fn is_pattern(path: &Path) -> bool {
+    !path.exists()
+        && path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().contains(['*', '?']))
+}
+
+/// The files matching the pattern `path`, in name order, or `path` itself
+/// when it is not a pattern.
+fn expand(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !is_pattern(path) {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let pattern = regex::escape(&name)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    let pattern = Regex::new(&format!("^{}$", pattern)).expect("escaped pattern");
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut matched = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if pattern.is_match(&entry.file_name().to_string_lossy()) {
+            matched.push(entry.path());
+        }
+    }
+    if matched.is_empty() {
+        return Err(Error::Input(format!("no file matches {}", path.display())));
+    }
+    matched.sort();
+    Ok(matched)
+}
+
+/// Each of `books` paired with each of `languages`; with several languages
+/// the outputs are named after the language, like `book.vi.epub`.
+pub fn fan_out(books: Vec<Book>, languages: &[String]) -> Vec<(Book, String)> {
+    let mut fanned = Vec::new();
+    for book in books {
+        for language in languages {
+            let output = match languages {
+                [_] => book.output.clone(),
+                _ => language_path(&book.output, language),
+            };
+            let book = Book {
+                input: book.input.clone(),
+                output,
+            };
+            fanned.push((book, language.clone()));
+        }
+    }
+    fanned
+}
+
+/// `path` with the code of `language` before its extension, `book.epub`
+/// becoming `book.vi.epub`.
+pub fn language_path(path: &Path, language: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(language::code(language));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// `path` with the name of the `book` before its extension, `stats.json`
+/// becoming `stats.job-1.json` for `job-1.epub`.
+pub fn book_path(path: &Path, book: &Path) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(book.file_stem().unwrap_or_default());
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// How a book of a batch went, as written to `--stats-out`.
+#[derive(Serialize)]
+pub struct Outcome {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub language: String,
+    /// why the book failed or is incomplete, `None` when it is translated
+    pub error: Option<String>,
+    pub stats: Option<Summary>,
+}
+
+/// Log a line per book of the batch and write the outcomes to `stats_out`;
+/// fails when any book did.
+pub fn conclude(outcomes: &[Outcome], stats_out: Option<&Path>) -> Result<(), Error> {
+    info!("batch: {} books", outcomes.len());
+    for outcome in outcomes {
+        let stats = match &outcome.stats {
+            Some(stats) => format!(
+                " ({} requests, {} tokens, {:.1}sec)",
+                stats.requests, stats.total_tokens, stats.seconds
+            ),
+            None => String::new(),
+        };
+        let input = format!("{} into {}", outcome.input.display(), outcome.language);
+        match &outcome.error {
+            None => info!("  done {}{}", input, stats),
+            Some(e) => error!("  failed {}{}: {}", input, stats, e),
+        }
+    }
+    let mut languages: Vec<&str> = Vec::new();
+    for outcome in outcomes {
+        if !languages.contains(&outcome.language.as_str()) {
+            languages.push(&outcome.language);
+        }
+    }
+    if languages.len() > 1 {
+        for language in languages {
+            let stats: Vec<&Summary> = outcomes
+                .iter()
+                .filter(|outcome| outcome.language == language)
+                .filter_map(|outcome| outcome.stats.as_ref())
+                .collect();
+            let costs: Vec<f64> = stats.iter().filter_map(|stats| stats.cost).collect();
+            let cost = if costs.is_empty() {
+                String::new()
+            } else {
+                format!(", estimated cost: ${:.4}", costs.iter().sum::<f64>())
+            };
+            info!(
+                "  {}: {} requests, {} tokens, {:.1}sec{}",
+                language,
+                stats.iter().map(|stats| stats.requests).sum::<u64>(),
+                stats.iter().map(|stats| stats.total_tokens).sum::<u64>(),
+                stats.iter().map(|stats| stats.seconds).sum::<f64>(),
+                cost
+            );
+        }
+    }
+    if let Some(path) = stats_out {
+        std::fs::write(path, serde_json::to_string_pretty(outcomes)? + "\n")?;
+    }
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| outcome.error.is_some())
+        .count();
+    if failed > 0 {
+        return Err(Error::Batch {
+            failed,
+            books: outcomes.len(),
+        });
+    }
+    Ok(())
+}
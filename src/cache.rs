@@ -0,0 +1,234 @@
+use crate::error::Error;
+use crate::memory::{Memory, Segment};
+use log::{error, info};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const FILE_NAME: &str = "translations.jsonl";
+/// locked by the runs appending to the cache directory, and by the one
+/// compacting it alone
+const LOCK_NAME: &str = "translations.lock";
+
+/// The caches opened by [`Cache::shared`], by directory.
+static SHARED: Mutex<Vec<(PathBuf, Arc<Cache>)>> = Mutex::new(Vec::new());
+
+/// (model, language, normalized source paragraph)
+type Key = (String, String, String);
+
+/// Translations of earlier runs, so paragraphs already paid for are not
+/// requested again, neither on a re-run nor in a revised edition of a book.
+///
+/// Kept as a translation memory file in the cache directory, appended to as
+/// chunks are translated and loaded whole into memory, rather than in a
+/// key-value store: the cache of a book or a batch is some megabytes, and a
+/// store would be one more dependency for what a file does. A translation
+/// recorded again under the same key supersedes the earlier line, and the
+/// file is compacted to the latest of each once they are a quarter of it.
+/// Appending takes a shared lock of the directory and compacting an
+/// exclusive one, so runs of other processes sharing the directory lose
+/// nothing to a compaction.
+pub struct Cache {
+    dir: PathBuf,
+    memory: Memory,
+    translations: Mutex<HashMap<Key, String>>,
+}
+
+impl Cache {
+    /// The cache in `dir`, opened once in the process and shared by the
+    /// books of a batch and the jobs of a server.
+    pub fn shared(dir: &Path) -> Result<Arc<Self>, Error> {
+        let mut shared = SHARED.lock().unwrap();
+        if let Some((_, cache)) = shared.iter().find(|(opened, _)| opened == dir) {
+            return Ok(cache.clone());
+        }
+        let cache = Arc::new(Self::open(dir)?);
+        shared.push((dir.to_path_buf(), cache.clone()));
+        Ok(cache)
+    }
+
+    /// Open the cache in `dir`, loading the translations recorded so far.
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        let memory = Memory::new(dir.join(FILE_NAME));
+        let mut translations = HashMap::new();
+        if dir.join(FILE_NAME).exists() {
+            let lock = lock_file(dir)?;
+            lock.lock()?;
+            let segments = memory.load()?;
+            // the line of the latest translation of each key
+            let mut latest = HashMap::new();
+            for (i, segment) in segments.iter().enumerate() {
+                latest.insert(key(&segment.model, &segment.language, &segment.source), i);
+            }
+            let superseded = segments.len() - latest.len();
+            if superseded > 0 && superseded * 4 >= segments.len() {
+                let mut kept: Vec<usize> = latest.values().copied().collect();
+                kept.sort_unstable();
+                memory.rewrite(kept.iter().map(|i| &segments[*i]))?;
+                info!("cache: compacted {} superseded translations", superseded);
+            }
+            for (key, i) in latest {
+                translations.insert(key, segments[i].target.clone());
+            }
+            info!(
+                "cache: {} translations in {}",
+                translations.len(),
+                dir.display()
+            );
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            memory,
+            translations: Mutex::new(translations),
+        })
+    }
+
+    pub fn get(&self, model: &str, language: &str, source: &str) -> Option<String> {
+        let translations = self.translations.lock().unwrap();
+        translations.get(&key(model, language, source)).cloned()
+    }
+
+    pub fn insert(&self, segments: &[Segment]) {
+        let mut translations = self.translations.lock().unwrap();
+        for segment in segments {
+            translations.insert(
+                key(&segment.model, &segment.language, &segment.source),
+                segment.target.clone(),
+            );
+        }
+        let appended = lock_file(&self.dir).and_then(|lock| {
+            lock.lock_shared()?;
+            self.memory.append(segments)
+        });
+        if let Err(e) = appended {
+            error!("cache write error: {}", e);
+        }
+    }
+}
+
+/// The lock file of the cache in `dir`, unlocked when it is dropped.
+fn lock_file(dir: &Path) -> Result<File, Error> {
+    create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_NAME))?;
+    Ok(file)
+}
+
+/// `$XDG_CACHE_HOME/trans-epub`, or `~/.cache/trans-epub`.
+pub fn default_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(base.join("trans-epub"))
+}
+
+/// Paragraphs differing only in whitespace share a translation.
+fn key(model: &str, language: &str, source: &str) -> Key {
+    let source = source.split_whitespace().collect::<Vec<_>>().join(" ");
+    (model.to_string(), language.to_string(), source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(source: &str, target: &str) -> Segment {
+        Segment {
+            source: source.to_string(),
+            target: target.to_string(),
+            language: "German".to_string(),
+            model: "model".to_string(),
+        }
+    }
+
+    #[test]
+    fn superseded_translations_are_compacted() {
+        let dir = std::env::temp_dir().join(format!("trans-epub-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = Cache::open(&dir).unwrap();
+        cache.insert(&[segment("one", "eins"), segment("two", "zwei")]);
+        cache.insert(&[segment("one", "Eins")]);
+        drop(cache);
+        let lines = || {
+            std::fs::read_to_string(dir.join(FILE_NAME))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        // one of three lines superseded
+        assert_eq!(lines(), 3);
+        let cache = Cache::open(&dir).unwrap();
+        assert_eq!(lines(), 2);
+        assert_eq!(cache.get("model", "German", "one").as_deref(), Some("Eins"));
+        assert_eq!(cache.get("model", "German", "two").as_deref(), Some("zwei"));
+
+        // under a quarter, the file is left as it is
+        for source in ["three", "four", "five", "six", "seven"] {
+            cache.insert(&[segment(source, source)]);
+        }
+        cache.insert(&[segment("two", "Zwei")]);
+        drop(cache);
+        let cache = Cache::open(&dir).unwrap();
+        assert_eq!(lines(), 8);
+        assert_eq!(cache.get("model", "German", "two").as_deref(), Some("Zwei"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn caches_are_shared_by_directory() {
+        let dir = std::env::temp_dir().join(format!("trans-epub-shared-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = Cache::shared(&dir).unwrap();
+        cache.insert(&[segment("one", "eins")]);
+        let again = Cache::shared(&dir).unwrap();
+        assert!(Arc::ptr_eq(&cache, &again));
+        assert_eq!(again.get("model", "German", "one").as_deref(), Some("eins"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn compacting_keeps_the_translations_of_concurrent_writers() {
+        let dir = std::env::temp_dir().join(format!("trans-epub-writers-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let rounds = 200;
+        std::thread::scope(|scope| {
+            // two runs of their own, each translating its paragraphs twice so
+            // that every other line is superseded
+            for writer in ["a", "b"] {
+                let dir = &dir;
+                scope.spawn(move || {
+                    let cache = Cache::open(dir).unwrap();
+                    for i in 0..rounds {
+                        let source = format!("{}{}", writer, i);
+                        cache.insert(&[segment(&source, "draft")]);
+                        cache.insert(&[segment(&source, &source.to_uppercase())]);
+                    }
+                });
+            }
+            // and runs starting meanwhile, compacting the file
+            scope.spawn(|| {
+                for _ in 0..rounds / 4 {
+                    Cache::open(&dir).unwrap();
+                }
+            });
+        });
+        let cache = Cache::open(&dir).unwrap();
+        for writer in ["a", "b"] {
+            for i in 0..rounds {
+                let source = format!("{}{}", writer, i);
+                assert_eq!(
+                    cache.get("model", "German", &source),
+                    Some(source.to_uppercase()),
+                    "{}",
+                    source
+                );
+            }
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
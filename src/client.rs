@@ -1,2 +1,145 @@
+pub mod anthropic;
+pub mod budget;
+pub mod capability;
+pub mod deepl;
 pub mod gemini;
+pub mod keys;
+pub mod limiter;
+pub mod ollama;
 pub mod open_ai;
+pub mod preflight;
+pub mod pricing;
+pub mod quota;
+pub mod ratelimit;
+pub mod retry;
+pub mod safety;
+pub mod shutdown;
+mod sse;
+pub mod totals;
+
+use crate::error::Error;
+use crate::translate::translator::Context;
+use log::warn;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use retry::RetryOn;
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+/// Add the extra headers given with `--header` to an outgoing request.
+fn with_headers(builder: RequestBuilder, context: &Context) -> RequestBuilder {
+    context
+        .headers
+        .iter()
+        .fold(builder, |builder, (name, value)| {
+            builder.header(name, value)
+        })
+}
+
+/// Send the request made by `build`, sending it again after waiting out 429
+/// responses, and after connection failures and 5xx responses as the retry
+/// policy allows; `None` when the run stops for exhausted quota.
+///
+/// With `--request-timeout`, a request that is not answered in full within
+/// it, streamed responses included, is cancelled and counts as a timeout.
+///
+/// `build` makes the request with the API key to send it with, the next one
+/// of the pool for each attempt.
+async fn send(
+    context: &Context,
+    build: impl Fn(&str) -> RequestBuilder,
+) -> Result<Option<Response>, reqwest::Error> {
+    let mut attempt = 0;
+    let mut failures = 0;
+    loop {
+        if context.quota.is_exhausted() {
+            return Ok(None);
+        }
+        let key = context.keys.pick();
+        let api_key = key.map_or(context.api_key.as_str(), |(_, key)| key);
+        let mut builder = with_headers(build(api_key), context);
+        if let Some(timeout) = context.request_timeout {
+            builder = builder.timeout(timeout);
+        }
+        let response = match builder.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                let e = e.without_url();
+                timed_out(context, &e);
+                context.limiter.congested();
+                failures += 1;
+                let Some(wait) = context.retry.wait(RetryOn::of(&e), failures) else {
+                    return Err(e);
+                };
+                let failure = match e.is_timeout() {
+                    true => "timed out".to_string(),
+                    false => e.to_string(),
+                };
+                backoff(context, &failure, wait).await;
+                continue;
+            }
+        };
+        if response.status().is_server_error() {
+            context.limiter.congested();
+            if let Some(wait) = context.retry.wait(RetryOn::Server, failures + 1) {
+                failures += 1;
+                backoff(context, &response.status().to_string(), wait).await;
+                continue;
+            }
+        }
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            return Ok(Some(response));
+        }
+        context.limiter.congested();
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+        let wait = quota::retry_after(&headers, &body, attempt + 1);
+        if let Some((index, _)) = key.filter(|_| context.keys.len() > 1) {
+            if context.keys.bench(index, wait) {
+                warn!(
+                    stage = "request";
+                    "rate limited on key {}/{}, sending with the next key",
+                    index + 1,
+                    context.keys.len()
+                );
+                context.totals.retry();
+                continue;
+            }
+        }
+        attempt += 1;
+        if !context.quota.pause(wait, attempt).await {
+            return Ok(None);
+        }
+        context.totals.retry();
+    }
+}
+
+/// Read the body of a response, counting a timeout.
+async fn text(context: &Context, response: Response) -> Result<String, reqwest::Error> {
+    response.text().await.inspect_err(|e| timed_out(context, e))
+}
+
+fn timed_out(context: &Context, error: &reqwest::Error) {
+    if error.is_timeout() {
+        context.totals.timeout();
+    }
+}
+
+async fn backoff(context: &Context, failure: &str, wait: Duration) {
+    warn!(
+        stage = "request";
+        "request failed ({}), sending it again in {:.1}sec",
+        failure,
+        wait.as_secs_f64()
+    );
+    tokio::time::sleep(wait).await;
+    context.totals.retry();
+}
+
+/// Decode a response body, or fail with the status when the API answered
+/// with something else, such as an error page of a proxy.
+fn decode<T: DeserializeOwned>(status: StatusCode, body: &str) -> Result<T, Error> {
+    serde_json::from_str(body).map_err(|e| {
+        log::trace!("response body: {}", body);
+        Error::Api(format!("unexpected response ({}): {}", status, e))
+    })
+}
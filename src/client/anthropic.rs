@@ -0,0 +1,375 @@
+use crate::client::capability::{json_mode, response_schema};
+use crate::client::preflight;
+use crate::client::ratelimit::Ratelimit;
+use crate::client::sse;
+use crate::client::{decode, send, text, timed_out, with_headers};
+use crate::error::Error;
+use crate::translate::translator::Context;
+use log::{debug, info, trace};
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+const BASE_URL: &str = "https://api.anthropic.com/v1";
+
+/// Version of the Messages API the requests are written for.
+const API_VERSION: &str = "2023-06-01";
+
+/// Output tokens a response may have, which the Messages API wants given;
+/// every current Claude model allows this many. A chunk translated past it
+/// is cut short and translated again in halves.
+const MAX_TOKENS: u32 = 8192;
+
+/// Stop reason of a response cut short at its `max_tokens`.
+const STOP_MAX_TOKENS: &str = "max_tokens";
+
+/// Name of the tool the response schema is sent as.
+const TOOL: &str = "translation";
+
+#[derive(Serialize)]
+struct ClientRequest {
+    model: String,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    system: String,
+    messages: Vec<MessageRequest>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct MessageRequest {
+    role: String,
+    content: Vec<Content>,
+}
+
+#[derive(Serialize)]
+struct Content {
+    #[serde(rename = "type")]
+    _type: String,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct Tool {
+    name: String,
+    description: String,
+    input_schema: Value,
+}
+
+#[derive(Serialize)]
+struct ToolChoice {
+    #[serde(rename = "type")]
+    _type: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ClientResponse {
+    #[serde(default)]
+    content: Vec<ContentBlock>,
+    usage: Option<Usage>,
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    _type: String,
+    #[serde(default)]
+    text: String,
+    input: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct StreamEvent {
+    #[serde(rename = "type")]
+    _type: String,
+    message: Option<StreamMessage>,
+    delta: Option<Delta>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamMessage {
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    text: Option<String>,
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct Usage {
+    #[serde(default)]
+    input_tokens: i32,
+    #[serde(default)]
+    output_tokens: i32,
+    #[serde(default)]
+    cache_creation_input_tokens: i32,
+    #[serde(default)]
+    cache_read_input_tokens: i32,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub input_tokens: i32,
+    pub output_tokens: i32,
+}
+
+impl From<Usage> for Stats {
+    /// Tokens written to and read from the prompt cache are input tokens
+    /// too, counted apart by the API.
+    fn from(usage: Usage) -> Self {
+        Self {
+            input_tokens: usage.input_tokens
+                + usage.cache_creation_input_tokens
+                + usage.cache_read_input_tokens,
+            output_tokens: usage.output_tokens,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Response {
+    pub stats: Stats,
+    pub text: String,
+    pub ratelimit: Ratelimit,
+    /// the response was cut short at `MAX_TOKENS`
+    pub truncated: bool,
+}
+
+/// `system_instruction` goes to the top-level `system` prompt, the task
+/// `prompt` is sent as the first user content ahead of the paragraphs.
+///
+/// The Messages API has no JSON response mode: in JSON mode the response
+/// `schema`, when there is one, is sent as the input schema of a tool the
+/// model is made to call, whose input is the response; without one, the
+/// answer is started with `{` for the model to go on with.
+pub async fn request(
+    context: &Context,
+    system_instruction: &str,
+    prompt: &str,
+    user_contents: &Vec<String>,
+    schema: Option<Value>,
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let mut request_body = to_request_body(
+        &context.request_model(),
+        system_instruction,
+        prompt,
+        user_contents,
+    );
+    let mut prefill = "";
+    if json_mode(context) {
+        match schema.filter(|_| response_schema(context)) {
+            Some(schema) => {
+                request_body.tools = vec![Tool {
+                    name: TOOL.to_string(),
+                    description: "Give the translation.".to_string(),
+                    input_schema: schema,
+                }];
+                request_body.tool_choice = Some(ToolChoice {
+                    _type: "tool".to_string(),
+                    name: TOOL.to_string(),
+                });
+            }
+            None => {
+                prefill = "{";
+                request_body.messages.push(MessageRequest {
+                    role: "assistant".to_string(),
+                    content: vec![Content {
+                        _type: "text".to_string(),
+                        text: prefill.to_string(),
+                    }],
+                });
+            }
+        }
+    }
+    let build = |key: &str| post(&client, context, key).json(&request_body);
+    let Some(response) = send(context, build).await? else {
+        return Ok(Response::default());
+    };
+    let ratelimit = Ratelimit::from_headers(response.headers());
+
+    let status = response.status();
+    let response_text = text(context, response).await?;
+    let response_body: ClientResponse = decode(status, &response_text)?;
+
+    if response_body.content.is_empty() {
+        info!(stage = "request"; "response status: {}", status.to_string());
+        trace!("response error: {}", response_text);
+    }
+
+    pace(context, &ratelimit).await;
+
+    let text = match response_body
+        .content
+        .iter()
+        .find_map(|block| block.input.as_ref().filter(|_| block._type == "tool_use"))
+    {
+        Some(input) => input.to_string(),
+        None => {
+            let text: String = response_body
+                .content
+                .iter()
+                .filter(|block| block._type == "text")
+                .map(|block| block.text.as_str())
+                .collect();
+            format!("{}{}", prefill, text)
+        }
+    };
+    Ok(Response {
+        text,
+        ratelimit,
+        truncated: response_body.stop_reason.as_deref() == Some(STOP_MAX_TOKENS),
+        stats: response_body.usage.map(Stats::from).unwrap_or_default(),
+    })
+}
+
+/// [`request`] in text output mode, streamed over SSE with each text delta
+/// passed to `on_text` as it arrives; the request is dropped when it breaks.
+/// The input tokens come with the start of the message, the output tokens
+/// with its end.
+pub async fn stream_request(
+    context: &Context,
+    system_instruction: &str,
+    prompt: &str,
+    user_contents: &Vec<String>,
+    mut on_text: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let mut request_body = to_request_body(
+        &context.request_model(),
+        system_instruction,
+        prompt,
+        user_contents,
+    );
+    request_body.stream = Some(true);
+    let build = |key: &str| post(&client, context, key).json(&request_body);
+    let Some(response) = send(context, build).await? else {
+        return Ok(Response::default());
+    };
+    let ratelimit = Ratelimit::from_headers(response.headers());
+
+    let mut text = String::new();
+    let mut stats = Stats::default();
+    let mut truncated = false;
+    sse::for_each_data(response, |data| {
+        let Ok(event) = serde_json::from_str::<StreamEvent>(data) else {
+            trace!("stream error: {}", data);
+            return ControlFlow::Continue(());
+        };
+        match event._type.as_str() {
+            "message_start" => {
+                let usage = event.message.and_then(|message| message.usage);
+                stats = usage.map(Stats::from).unwrap_or_default();
+            }
+            "message_delta" => {
+                if let Some(usage) = event.usage {
+                    stats.output_tokens = usage.output_tokens;
+                }
+                truncated |= event
+                    .delta
+                    .is_some_and(|delta| delta.stop_reason.as_deref() == Some(STOP_MAX_TOKENS));
+            }
+            "content_block_delta" => {
+                if let Some(delta) = event.delta.and_then(|delta| delta.text) {
+                    text.push_str(&delta);
+                    on_text(&delta)?;
+                }
+            }
+            "error" => trace!("stream error: {}", data),
+            _ => (),
+        }
+        ControlFlow::Continue(())
+    })
+    .await
+    .inspect_err(|e| timed_out(context, e))?;
+
+    pace(context, &ratelimit).await;
+
+    Ok(Response {
+        text,
+        ratelimit,
+        stats,
+        truncated,
+    })
+}
+
+/// Send a minimal request to check that the API key can use the model.
+pub async fn probe(context: &Context) -> Result<(), String> {
+    let mut request_body = to_request_body(&context.model, "", preflight::PROMPT, &vec![]);
+    request_body.max_tokens = 1;
+    let response = with_headers(post(&Client::new(), context, &context.api_key), context)
+        .json(&request_body)
+        .send()
+        .await
+        .map_err(|e| e.without_url().to_string())?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(preflight::explain(&context.model, status, &body))
+}
+
+/// Messages request to `--base-url`, with the key in an `x-api-key` header.
+fn post(client: &Client, context: &Context, key: &str) -> RequestBuilder {
+    let base_url = context.base_url.as_deref().unwrap_or(BASE_URL);
+    client
+        .post(format!("{}/messages", base_url.trim_end_matches('/')))
+        .header("x-api-key", key)
+        .header("anthropic-version", API_VERSION)
+}
+
+async fn pace(context: &Context, ratelimit: &Ratelimit) {
+    ratelimit.log();
+    let fallback = if context.limiter.is_paced() {
+        Duration::ZERO
+    } else {
+        ratelimit.reset_tokens.unwrap_or(Duration::from_secs(1))
+    };
+    let wait = ratelimit.wait(context.throttle, fallback);
+    debug!("sleep: {}sec", wait.as_secs_f64());
+    tokio::time::sleep(wait).await;
+}
+
+fn to_request_body(
+    model: &str,
+    system_instruction: &str,
+    prompt: &str,
+    user_contents_text_vec: &Vec<String>,
+) -> ClientRequest {
+    let mut user_contents = vec![Content {
+        _type: "text".to_string(),
+        text: prompt.to_owned(),
+    }];
+    for text in user_contents_text_vec {
+        user_contents.push(Content {
+            _type: "text".to_string(),
+            text: text.clone(),
+        });
+    }
+
+    ClientRequest {
+        model: model.to_owned(),
+        max_tokens: MAX_TOKENS,
+        system: system_instruction.to_owned(),
+        messages: vec![MessageRequest {
+            role: "user".to_string(),
+            content: user_contents,
+        }],
+        tools: Vec::new(),
+        tool_choice: None,
+        stream: None,
+    }
+}
@@ -0,0 +1,203 @@
+use crate::client::pricing;
+use crate::translate::translator::Stats;
+use log::{error, warn};
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// The spending caps of `--max-cost` and `--max-tokens`, shared by the books
+/// of a batch. Once one is reached no more requests are sent until the run
+/// is told to go on, at the terminal or with `--yes-continue`, up to the
+/// next multiple of the cap, where it is asked again; otherwise it stops and
+/// the book is written with what is translated.
+pub struct Budget {
+    max_cost: Option<f64>,
+    max_tokens: Option<u64>,
+    yes_continue: bool,
+    /// tokens and estimated cost of the requests so far
+    spent: Mutex<(u64, f64)>,
+    /// the cost and the tokens the run goes on to, multiples of the caps
+    caps: Mutex<(Option<f64>, Option<u64>)>,
+    /// held while the run is asked whether to go on, so it is asked once
+    asking: tokio::sync::Mutex<()>,
+    exceeded: AtomicBool,
+}
+
+impl Budget {
+    pub fn new(max_cost: Option<f64>, max_tokens: Option<u64>, yes_continue: bool) -> Self {
+        Self {
+            max_cost,
+            max_tokens,
+            yes_continue,
+            spent: Mutex::new((0, 0.0)),
+            caps: Mutex::new((max_cost, max_tokens)),
+            asking: tokio::sync::Mutex::new(()),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    /// Count a request sent to `model` toward the caps, its cost estimated
+    /// from the list price, as in the totals.
+    pub fn charge(&self, model: &str, stats: &Stats) {
+        let (prompt, output, cached) = (
+            stats.prompt_tokens.max(0) as u64,
+            stats.output_tokens.max(0) as u64,
+            stats.cached_tokens.max(0) as u64,
+        );
+        let cost = pricing::price(model).map_or(0.0, |price| {
+            price.cost(prompt, output) - price.discount(cached)
+        });
+        let mut spent = self.spent.lock().unwrap();
+        spent.0 += stats.total_tokens.max(0) as u64;
+        spent.1 += cost;
+    }
+
+    /// Whether the stop was for a cap reached.
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Whether another request may be sent to `model`: the caps are not
+    /// reached, or the run was told to go past them. The first request over
+    /// them asks, while the others wait for the answer. A model without a
+    /// known price, as a `--fallback` or one given through `--control`,
+    /// would cost nothing toward `--max-cost`, so it stops the run.
+    pub async fn allows(&self, model: &str) -> bool {
+        if self.is_exceeded() {
+            return false;
+        }
+        if self.max_cost.is_some() && pricing::price(model).is_none() {
+            error!(
+                "--max-cost needs the price of {}, which is not known, stopping; cap the tokens with --max-tokens",
+                model
+            );
+            self.exceeded.store(true, Ordering::Relaxed);
+            return false;
+        }
+        if self.reached().is_none() {
+            return true;
+        }
+        let _asking = self.asking.lock().await;
+        // told already, while this request waited
+        if self.is_exceeded() {
+            return false;
+        }
+        let Some(reached) = self.reached() else {
+            return true;
+        };
+        let next = self.next();
+        let go_on = if self.yes_continue {
+            warn!("{}, going on to {} with --yes-continue", reached, next);
+            true
+        } else if std::io::stdin().is_terminal() {
+            ask(&reached, &next).await
+        } else {
+            false
+        };
+        if !go_on {
+            error!("{}, stopping", reached);
+            self.exceeded.store(true, Ordering::Relaxed);
+            return false;
+        }
+        self.raise();
+        true
+    }
+
+    /// The cap reached, if any.
+    fn reached(&self) -> Option<String> {
+        let (tokens, cost) = *self.spent.lock().unwrap();
+        match *self.caps.lock().unwrap() {
+            (Some(cap), _) if cost >= cap => Some(format!(
+                "estimated cost ${:.4} reached ${} (--max-cost ${})",
+                cost,
+                cap,
+                self.max_cost.unwrap_or_default()
+            )),
+            (_, Some(cap)) if tokens >= cap => Some(format!(
+                "{} tokens reached {} (--max-tokens {})",
+                tokens,
+                cap,
+                self.max_tokens.unwrap_or_default()
+            )),
+            _ => None,
+        }
+    }
+
+    /// The caps above what is spent, the next multiples of `--max-cost` and
+    /// `--max-tokens`.
+    fn next_caps(&self) -> (Option<f64>, Option<u64>) {
+        let (tokens, cost) = *self.spent.lock().unwrap();
+        (
+            self.max_cost
+                .map(|max_cost| max_cost * ((cost / max_cost).floor() + 1.0)),
+            self.max_tokens
+                .map(|max_tokens| max_tokens * (tokens / max_tokens + 1)),
+        )
+    }
+
+    /// What the run goes on to, to tell when it is asked.
+    fn next(&self) -> String {
+        match self.next_caps() {
+            (Some(cost), Some(tokens)) => format!("${} and {} tokens", cost, tokens),
+            (Some(cost), None) => format!("${}", cost),
+            (None, Some(tokens)) => format!("{} tokens", tokens),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Go on to the next multiples of the caps.
+    fn raise(&self) {
+        *self.caps.lock().unwrap() = self.next_caps();
+    }
+}
+
+/// Ask at the terminal whether to go past a cap, on to `next`.
+async fn ask(reached: &str, next: &str) -> bool {
+    eprint!("\n{}; go on to {}? [y/N] ", reached, next);
+    let _ = std::io::stderr().flush();
+    tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).ok();
+        matches!(answer.trim(), "y" | "Y" | "yes")
+    })
+    .await
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(total_tokens: i32) -> Stats {
+        Stats {
+            total_tokens,
+            ..Stats::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn the_caps_are_armed_again_at_their_next_multiple() {
+        let budget = Budget::new(None, Some(100), true);
+        budget.charge("gpt-4o", &stats(60));
+        assert!(budget.allows("gpt-4o").await);
+        budget.charge("gpt-4o", &stats(60));
+        assert!(budget.allows("gpt-4o").await);
+        assert_eq!(*budget.caps.lock().unwrap(), (None, Some(200)));
+        budget.charge("gpt-4o", &stats(190));
+        assert!(budget.allows("gpt-4o").await);
+        assert_eq!(*budget.caps.lock().unwrap(), (None, Some(400)));
+        assert!(!budget.is_exceeded());
+    }
+
+    #[tokio::test]
+    async fn models_without_a_price_stop_a_cost_cap() {
+        let budget = Budget::new(None, Some(100), false);
+        assert!(budget.allows("local").await);
+
+        let budget = Budget::new(Some(1.0), None, true);
+        assert!(budget.allows("gpt-4o").await);
+        assert!(!budget.allows("local").await);
+        assert!(budget.is_exceeded());
+        assert!(!budget.allows("gpt-4o").await);
+    }
+}
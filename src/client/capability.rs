@@ -0,0 +1,62 @@
+use crate::translate::translator::Context;
+use clap::ValueEnum;
+
+/// Model name prefixes known to ignore or reject the JSON response mode.
+const WITHOUT_JSON_MODE: [&str; 7] = [
+    "gemini-1.0",
+    "gemini-pro",
+    "gemma-",
+    "gpt-4-0314",
+    "gpt-4-0613",
+    "gpt-3.5-turbo-0301",
+    "gpt-3.5-turbo-0613",
+];
+
+/// Model name prefixes known to support the JSON response mode but not a
+/// response schema.
+const WITHOUT_RESPONSE_SCHEMA: [&str; 2] = ["gpt-3.5-", "gpt-4-"];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum JsonMode {
+    /// Use JSON mode with the response schema, or without it or not at all
+    /// when the model is known not to support them
+    #[default]
+    Auto,
+    /// Always request JSON mode with the response schema
+    On,
+    /// Always request JSON mode, without a response schema, for servers that
+    /// reject one
+    Object,
+    /// Never request JSON mode and rely on the prompt and JSON repair
+    Off,
+}
+
+/// Whether to ask the provider for a JSON response. Without it the prompt
+/// still asks for JSON and the response goes through JSON repair.
+pub fn json_mode(context: &Context) -> bool {
+    match context.json_mode {
+        JsonMode::On | JsonMode::Object => true,
+        JsonMode::Off => false,
+        JsonMode::Auto => !WITHOUT_JSON_MODE
+            .iter()
+            .copied()
+            .chain(context.models_without_json_mode.iter().map(String::as_str))
+            .any(|prefix| context.request_model().starts_with(prefix)),
+    }
+}
+
+/// Whether to send the schema of the response along with JSON mode, so the
+/// provider enforces it. The response still goes through JSON repair when
+/// the provider ignores it.
+pub fn response_schema(context: &Context) -> bool {
+    match context.json_mode {
+        JsonMode::On => true,
+        JsonMode::Object | JsonMode::Off => false,
+        JsonMode::Auto => {
+            json_mode(context)
+                && !WITHOUT_RESPONSE_SCHEMA
+                    .iter()
+                    .any(|prefix| context.request_model().starts_with(prefix))
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::client::{decode, send, text, with_headers};
+use crate::error::Error;
+use crate::language;
+use crate::translate::translator::Context;
+use log::trace;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+
+const BASE_URL: &str = "https://api.deepl.com/v2";
+
+/// The API of the keys of DeepL API Free, which end with `:fx`.
+const FREE_BASE_URL: &str = "https://api-free.deepl.com/v2";
+
+/// Most texts the API takes in one request.
+const MAX_TEXTS: usize = 50;
+
+#[derive(Serialize)]
+struct ClientRequest<'a> {
+    text: &'a [String],
+    target_lang: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_lang: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    context: String,
+    preserve_formatting: bool,
+    show_billed_characters: bool,
+}
+
+#[derive(Deserialize)]
+struct ClientResponse {
+    #[serde(default)]
+    translations: Vec<Translation>,
+}
+
+#[derive(Deserialize)]
+struct Translation {
+    text: String,
+    billed_characters: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    character_count: u64,
+    character_limit: u64,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub billed_characters: i32,
+}
+
+#[derive(Default)]
+pub struct Response {
+    pub stats: Stats,
+    pub texts: Vec<String>,
+}
+
+/// Translate `lines` into `language`, 50 texts a request as the API allows,
+/// with `context`, the paragraphs before them, sent along untranslated. The
+/// source language is that of the run, detected by DeepL when unknown.
+pub async fn request(
+    context: &Context,
+    language: &str,
+    lines: &[String],
+    preceding: &str,
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let mut response = Response::default();
+    for lines in lines.chunks(MAX_TEXTS) {
+        let request_body = ClientRequest {
+            text: lines,
+            target_lang: target_lang(language),
+            source_lang: context
+                .source_language
+                .as_deref()
+                .map(|language| language::code(language).to_uppercase()),
+            context: preceding.to_string(),
+            preserve_formatting: true,
+            show_billed_characters: true,
+        };
+        let build = |key: &str| {
+            authorize(client.post(url(context, key, "translate")), key).json(&request_body)
+        };
+        let Some(answer) = send(context, build).await? else {
+            return Ok(Response::default());
+        };
+        let status = answer.status();
+        let response_text = text(context, answer).await?;
+        if !status.is_success() {
+            trace!("response error: {}", response_text);
+            return Err(Error::Api(failure(status, &response_text)));
+        }
+        let response_body: ClientResponse = decode(status, &response_text)?;
+        for translation in response_body.translations {
+            response.stats.billed_characters += translation.billed_characters.unwrap_or_default();
+            response.texts.push(translation.text);
+        }
+    }
+    Ok(response)
+}
+
+/// Check that the API key works and has characters left this billing period.
+pub async fn probe(context: &Context) -> Result<(), String> {
+    let key = &context.api_key;
+    let builder = authorize(Client::new().get(url(context, key, "usage")), key);
+    let response = with_headers(builder, context)
+        .send()
+        .await
+        .map_err(|e| e.without_url().to_string())?;
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(failure(status, &body));
+    }
+    let usage: Usage = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+    if usage.character_count >= usage.character_limit {
+        return Err(format!(
+            "the DeepL character limit of {} is used up for this billing period",
+            usage.character_limit
+        ));
+    }
+    Ok(())
+}
+
+/// What a failed response means, with the message of its body.
+fn failure(status: StatusCode, body: &str) -> String {
+    let message = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.trim().to_string());
+    let hint = match status.as_u16() {
+        403 => "the API key is invalid; check --api-key",
+        456 => "the DeepL character limit is used up for this billing period",
+        _ => "DeepL rejected the request",
+    };
+    format!("{} ({}: {})", hint, status, message)
+}
+
+/// The code DeepL takes for the target `language`: English and Portuguese
+/// need a variant, American English and Brazilian Portuguese unless one is
+/// given as in `en-GB` or `pt-PT`.
+fn target_lang(language: &str) -> String {
+    let code = language::code(language).to_uppercase();
+    match language.trim().split_once(['-', '_']) {
+        Some((_, variant)) if language::find(language).is_some() => {
+            format!("{}-{}", code, variant.to_uppercase())
+        }
+        _ => match code.as_str() {
+            "EN" => "EN-US".to_string(),
+            "PT" => "PT-BR".to_string(),
+            _ => code,
+        },
+    }
+}
+
+/// `--base-url`, or the API of DeepL API Free for its keys, with `path`.
+fn url(context: &Context, key: &str, path: &str) -> String {
+    let base_url = context
+        .base_url
+        .as_deref()
+        .unwrap_or(if key.ends_with(":fx") {
+            FREE_BASE_URL
+        } else {
+            BASE_URL
+        });
+    format!("{}/{}", base_url.trim_end_matches('/'), path)
+}
+
+fn authorize(builder: RequestBuilder, key: &str) -> RequestBuilder {
+    builder.header("Authorization", format!("DeepL-Auth-Key {}", key))
+}
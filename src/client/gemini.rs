@@ -1,25 +1,63 @@
-use log::{info, trace};
-use reqwest::{Client, Error};
+use crate::client::capability::{json_mode, response_schema};
+use crate::client::preflight;
+use crate::client::ratelimit::Ratelimit;
+use crate::client::safety::{self, OnBlocked, SafetySetting, Threshold};
+use crate::client::sse;
+use crate::client::{decode, send, text, timed_out, with_headers};
+use crate::error::Error;
+use crate::translate::translator::Context;
+use log::{debug, info, trace, warn};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use serde_json::Value;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+const BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
 
 #[derive(Serialize)]
 struct ClientRequest {
     contents: Vec<Content>,
-    #[serde(rename = "systemInstruction")]
-    system_instruction: Content,
+    #[serde(rename = "systemInstruction", skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content>,
+    #[serde(rename = "cachedContent", skip_serializing_if = "Option::is_none")]
+    cached_content: Option<String>,
     #[serde(rename = "generationConfig")]
     generation_config: GenerationConfig,
+    #[serde(rename = "safetySettings", skip_serializing_if = "Vec::is_empty")]
+    safety_settings: Vec<SafetySetting>,
+}
+
+#[derive(Serialize)]
+struct CachedContentRequest {
+    model: String,
+    #[serde(rename = "systemInstruction")]
+    system_instruction: Content,
+    ttl: String,
+}
+
+#[derive(Deserialize)]
+struct CachedContent {
+    name: String,
 }
 
 #[derive(Serialize)]
 struct GenerationConfig {
-    #[serde(rename = "responseMimeType")]
-    response_mime_type: String,
+    #[serde(rename = "responseMimeType", skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<String>,
+    #[serde(rename = "responseSchema", skip_serializing_if = "Option::is_none")]
+    response_schema: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(rename = "topP", skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(rename = "maxOutputTokens", skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 struct Content {
+    #[serde(default)]
     parts: Vec<Part>,
 }
 
@@ -34,107 +72,502 @@ struct ClientResponse {
     candidates: Vec<Candidate>,
     #[serde(rename = "usageMetadata")]
     usage_metadata: Option<UsageMetadata>,
+    #[serde(rename = "promptFeedback")]
+    prompt_feedback: Option<PromptFeedback>,
 }
 
 #[derive(Deserialize)]
 struct Candidate {
+    #[serde(default)]
     content: Content,
+    #[serde(rename = "finishReason")]
+    finish_reason: Option<String>,
+    #[serde(rename = "safetyRatings", default)]
+    safety_ratings: Vec<SafetyRating>,
+}
+
+#[derive(Deserialize)]
+struct PromptFeedback {
+    #[serde(rename = "blockReason")]
+    block_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SafetyRating {
+    category: String,
+    #[serde(default)]
+    blocked: bool,
+}
+
+impl Candidate {
+    fn is_truncated(&self) -> bool {
+        self.finish_reason.as_deref() == Some("MAX_TOKENS")
+    }
+}
+
+impl ClientResponse {
+    /// Why the safety filters withheld the response, with the categories
+    /// they blocked it for, if they did.
+    fn blocked(&self) -> Option<String> {
+        let feedback = self.prompt_feedback.as_ref();
+        if let Some(reason) = feedback.and_then(|feedback| feedback.block_reason.as_ref()) {
+            return Some(format!("prompt blocked, {}", reason));
+        }
+        let candidate = self.candidates.first()?;
+        let reason = candidate
+            .finish_reason
+            .as_deref()
+            .filter(|reason| safety::is_blocked(reason))?;
+        let categories: Vec<&str> = candidate
+            .safety_ratings
+            .iter()
+            .filter(|rating| rating.blocked)
+            .map(|rating| rating.category.as_str())
+            .collect();
+        Some(match categories.is_empty() {
+            true => reason.to_string(),
+            false => format!("{} ({})", reason, categories.join(", ")),
+        })
+    }
 }
 
 #[derive(Deserialize)]
 struct UsageMetadata {
-    #[serde(rename = "promptTokenCount")]
+    #[serde(rename = "promptTokenCount", default)]
     prompt_token_count: i32,
-    #[serde(rename = "candidatesTokenCount")]
+    // left out of a response the safety filters blocked
+    #[serde(rename = "candidatesTokenCount", default)]
     candidates_token_count: i32,
-    #[serde(rename = "totalTokenCount")]
+    #[serde(rename = "totalTokenCount", default)]
     total_token_count: i32,
+    #[serde(rename = "cachedContentTokenCount", default)]
+    cached_content_token_count: i32,
 }
 
+#[derive(Default)]
 pub struct Stats {
     pub prompt_token_count: i32,
     pub candidates_token_count: i32,
     pub total_token_count: i32,
+    pub cached_content_token_count: i32,
 }
 
-impl Stats {
-    pub fn log(&self) {
-        info!(
-            "prompt tokens: {} candidates tokens: {} total tokens: {}",
-            self.prompt_token_count, self.candidates_token_count, self.total_token_count
-        );
+impl From<UsageMetadata> for Stats {
+    fn from(usage: UsageMetadata) -> Self {
+        Self {
+            total_token_count: usage.total_token_count,
+            prompt_token_count: usage.prompt_token_count,
+            candidates_token_count: usage.candidates_token_count,
+            cached_content_token_count: usage.cached_content_token_count,
+        }
     }
 }
 
+#[derive(Default)]
 pub struct Response {
     pub stats: Stats,
     pub text: String,
+    /// the response was cut short at the output token limit
+    pub truncated: bool,
 }
 
+/// `system_instruction` goes to `systemInstruction`, the task `prompt` is sent
+/// as the first user part ahead of the paragraphs. In JSON mode the response
+/// `schema`, when there is one, goes to `responseSchema`. A `cached` preamble
+/// is referred to by its name and holds the system instruction.
+///
+/// A response the safety filters withheld fails as [`Error::Blocked`], after
+/// it is sent again once with the filters off for `--on-blocked relax`.
 pub async fn request(
-    model: &str,
-    api_key: &String,
+    context: &Context,
+    system_instruction: &str,
+    cached: Option<String>,
     prompt: &str,
     user_contents: &Vec<String>,
+    schema: Option<Value>,
 ) -> Result<Response, Error> {
     let client = Client::new();
-    let request_body = to_request_body(prompt, user_contents);
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-        model, api_key
-    );
-    let response = client.post(url).json(&request_body).send().await;
-
-    if response.is_err() {
-        return Err(response.err().unwrap());
+    let mut request_body = to_request_body(context, system_instruction, prompt, user_contents);
+    cache(&mut request_body, cached);
+    if json_mode(context) {
+        request_body.generation_config.response_mime_type = Some("application/json".to_string());
+        request_body.generation_config.response_schema =
+            schema.filter(|_| response_schema(context));
     }
-    let response = response.unwrap();
+    let mut threshold = context.safety_threshold;
+    let response_body = loop {
+        request_body.safety_settings = safety::settings(threshold);
+        let build = |key: &str| {
+            client
+                .post(url(context, key, "generateContent"))
+                .json(&request_body)
+        };
+        let Some(response) = send(context, build).await? else {
+            return Ok(Response::default());
+        };
+        let ratelimit = Ratelimit::from_headers(response.headers());
 
-    let status = response.status();
-    let response_text = response.text().await.expect("API Response to Text error");
-    let response_body: ClientResponse =
-        serde_json::from_str(&response_text).expect("API Response to JSON error");
+        let status = response.status();
+        let response_text = text(context, response).await?;
+        let response_body: ClientResponse = decode(status, &response_text)?;
+
+        if response_body.candidates.is_empty() {
+            info!(stage = "request"; "response status: {}", status.to_string());
+            trace!("response error: {}", response_text);
+        }
+
+        pace(context, &ratelimit).await;
+
+        match response_body.blocked() {
+            Some(reason) => unblock(context, &mut threshold, reason)?,
+            None => break response_body,
+        }
+    };
+
+    let candidate = response_body.candidates.first();
+    let text = candidate
+        .and_then(|candidate| candidate.content.parts.first())
+        .map_or("", |part| &part.text)
+        .to_string();
+    let truncated = candidate.is_some_and(Candidate::is_truncated);
+
+    Ok(Response {
+        text,
+        truncated,
+        stats: response_body
+            .usage_metadata
+            .map(Stats::from)
+            .unwrap_or_default(),
+    })
+}
+
+/// [`request`] in text output mode, streamed over SSE with each text delta
+/// passed to `on_text` as it arrives; the request is dropped when it breaks.
+/// A response the safety filters withheld fails as [`Error::Blocked`], as
+/// the text streamed so far is already passed on.
+pub async fn stream_request(
+    context: &Context,
+    system_instruction: &str,
+    cached: Option<String>,
+    prompt: &str,
+    user_contents: &Vec<String>,
+    mut on_text: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let mut request_body = to_request_body(context, system_instruction, prompt, user_contents);
+    cache(&mut request_body, cached);
+    request_body.generation_config.response_mime_type = Some("text/plain".to_string());
+    request_body.safety_settings = safety::settings(context.safety_threshold);
+    let build = |key: &str| {
+        client
+            .post(url(context, key, "streamGenerateContent?alt=sse"))
+            .json(&request_body)
+    };
+    let Some(response) = send(context, build).await? else {
+        return Ok(Response::default());
+    };
+    let ratelimit = Ratelimit::from_headers(response.headers());
+
+    let mut text = String::new();
+    let mut usage = None;
+    let mut blocked = None;
+    let mut truncated = false;
+    sse::for_each_data(response, |data| {
+        let Ok(chunk) = serde_json::from_str::<ClientResponse>(data) else {
+            trace!("stream error: {}", data);
+            return ControlFlow::Continue(());
+        };
+        blocked = blocked.take().or_else(|| chunk.blocked());
+        if chunk.usage_metadata.is_some() {
+            usage = chunk.usage_metadata;
+        }
+        for candidate in chunk.candidates.iter().take(1) {
+            truncated |= candidate.is_truncated();
+            for part in &candidate.content.parts {
+                text.push_str(&part.text);
+                on_text(&part.text)?;
+            }
+        }
+        ControlFlow::Continue(())
+    })
+    .await
+    .inspect_err(|e| timed_out(context, e))?;
+
+    pace(context, &ratelimit).await;
+
+    if let Some(reason) = blocked {
+        return Err(Error::Blocked(reason));
+    }
+    Ok(Response {
+        text,
+        truncated,
+        stats: usage.map(Stats::from).unwrap_or_default(),
+    })
+}
+
+/// Turn the filters off to send a blocked request again with
+/// `--on-blocked relax`, unless they are off already; fail with the
+/// `reason` it was blocked otherwise.
+fn unblock(
+    context: &Context,
+    threshold: &mut Option<Threshold>,
+    reason: String,
+) -> Result<(), Error> {
+    if context.on_blocked != OnBlocked::Relax || !safety::relaxable(*threshold) {
+        return Err(Error::Blocked(reason));
+    }
+    warn!("safety: {}, sending again with the filters off", reason);
+    *threshold = Some(Threshold::Off);
+    Ok(())
+}
+
+/// Time a cached preamble is kept by the API.
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// The preamble of the translation requests of a run, cached once with the
+/// cached-content API for `--cache-prompt` and referred to by name instead
+/// of being sent with each request. A preamble about to expire is cached
+/// again; one the API does not cache, such as one under the minimum size of
+/// the model, is sent with each request.
+#[derive(Default)]
+pub struct PromptCache {
+    state: tokio::sync::Mutex<Cached>,
+}
+
+#[derive(Default)]
+enum Cached {
+    #[default]
+    Unmade,
+    Made {
+        name: String,
+        expires: Instant,
+    },
+    Refused,
+}
 
-    if response_body.candidates.is_empty() {
-        info!("response status: {}", status.to_string());
+impl PromptCache {
+    /// The name of the cached `preamble` of the model of the run, made on
+    /// first use.
+    pub async fn name(&self, context: &Context, preamble: &str) -> Option<String> {
+        let mut cached = self.state.lock().await;
+        match &*cached {
+            Cached::Made { name, expires } if Instant::now() < *expires => {
+                return Some(name.clone())
+            }
+            Cached::Refused => return None,
+            _ => (),
+        }
+        match create_cache(context, preamble).await {
+            Ok(name) => {
+                info!("prompt cache: {}", name);
+                // a minute early, for the requests already sent with it
+                let expires = Instant::now() + CACHE_TTL - Duration::from_secs(60);
+                *cached = Cached::Made {
+                    name: name.clone(),
+                    expires,
+                };
+                Some(name)
+            }
+            Err(e) => {
+                warn!(
+                    "prompt cache: {}; the preamble is sent with each request",
+                    e
+                );
+                *cached = Cached::Refused;
+                None
+            }
+        }
+    }
+}
+
+async fn create_cache(context: &Context, preamble: &str) -> Result<String, String> {
+    let client = Client::new();
+    let request_body = CachedContentRequest {
+        model: format!("models/{}", context.model),
+        system_instruction: Content {
+            parts: vec![Part {
+                text: preamble.to_string(),
+            }],
+        },
+        ttl: format!("{}s", CACHE_TTL.as_secs()),
+    };
+    let base_url = context.base_url.as_deref().unwrap_or(BASE_URL);
+    let build = |key: &str| {
+        client
+            .post(format!(
+                "{}/cachedContents?key={}",
+                base_url.trim_end_matches('/'),
+                key
+            ))
+            .json(&request_body)
+    };
+    let response = send(context, build)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("not sent")?;
+    let status = response.status();
+    let response_text = text(context, response).await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
         trace!("response error: {}", response_text);
+        let message = serde_json::from_str::<Value>(&response_text)
+            .ok()
+            .and_then(|body| body["error"]["message"].as_str().map(str::to_string))
+            .unwrap_or(response_text);
+        return Err(format!("{}: {}", status, message.trim()));
+    }
+    let cached: CachedContent = decode(status, &response_text).map_err(|e| e.to_string())?;
+    Ok(cached.name)
+}
+
+/// Refer to the `cached` preamble, which holds the system instruction.
+fn cache(request_body: &mut ClientRequest, cached: Option<String>) {
+    if cached.is_some() {
+        request_body.system_instruction = None;
+        request_body.cached_content = cached;
     }
+}
 
+/// Ask the model for the text of an image, sent inline as `mime` data.
+#[cfg(feature = "ocr")]
+pub async fn transcribe(
+    context: &Context,
+    prompt: &str,
+    mime: &str,
+    image: &[u8],
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let request_body = serde_json::json!({
+        "contents": [{
+            "parts": [
+                {"text": prompt},
+                {"inline_data": {"mime_type": mime, "data": base64(image)}},
+            ],
+        }],
+    });
+    let build = |key: &str| {
+        client
+            .post(url(context, key, "generateContent"))
+            .json(&request_body)
+    };
+    let Some(response) = send(context, build).await? else {
+        return Ok(Response::default());
+    };
+    let ratelimit = Ratelimit::from_headers(response.headers());
+    let status = response.status();
+    let response_text = text(context, response).await?;
+    let response_body: ClientResponse = decode(status, &response_text)?;
     let text = response_body
         .candidates
         .first()
-        .map_or("", |candidate| {
-            &candidate.content.parts.first().unwrap().text
+        .map(|candidate| {
+            let parts = candidate.content.parts.iter();
+            parts.map(|part| part.text.as_str()).collect::<String>()
         })
-        .to_string();
-    let usage = response_body.usage_metadata.unwrap();
-
-    tokio::time::sleep(Duration::from_secs(30)).await;
-
+        .unwrap_or_default();
+    pace(context, &ratelimit).await;
     Ok(Response {
         text,
-        stats: Stats {
-            total_token_count: usage.total_token_count,
-            prompt_token_count: usage.prompt_token_count,
-            candidates_token_count: usage.candidates_token_count,
-        },
+        truncated: false,
+        stats: response_body
+            .usage_metadata
+            .map(Stats::from)
+            .unwrap_or_default(),
     })
 }
 
-fn to_request_body(prompt: &str, user_contents_text_vec: &Vec<String>) -> ClientRequest {
-    let mut parts = vec![];
+/// Standard base64 with padding, for inline data.
+#[cfg(feature = "ocr")]
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let word = chunk.iter().enumerate().fold(0u32, |word, (i, byte)| {
+            word | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(word >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Send a minimal request to check that the API key can use the model.
+pub async fn probe(context: &Context) -> Result<(), String> {
+    let request_body = to_request_body(context, "", preflight::PROMPT, &vec![]);
+    let url = url(context, &context.api_key, "generateContent");
+    let response = with_headers(Client::new().post(url), context)
+        .json(&request_body)
+        .send()
+        .await
+        .map_err(|e| e.without_url().to_string())?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(preflight::explain(&context.model, status, &body))
+}
+
+/// URL of a model method under `--base-url`, with the API key appended to
+/// the query string.
+fn url(context: &Context, key: &str, method: &str) -> String {
+    let base_url = context.base_url.as_deref().unwrap_or(BASE_URL);
+    let separator = if method.contains('?') { '&' } else { '?' };
+    format!(
+        "{}/models/{}:{}{}key={}",
+        base_url.trim_end_matches('/'),
+        context.request_model(),
+        method,
+        separator,
+        key
+    )
+}
+
+async fn pace(context: &Context, ratelimit: &Ratelimit) {
+    ratelimit.log();
+    let fallback = if context.limiter.is_paced() {
+        Duration::ZERO
+    } else {
+        Duration::from_secs(30)
+    };
+    let wait = ratelimit.wait(context.throttle, fallback);
+    debug!("sleep: {}sec", wait.as_secs_f64());
+    tokio::time::sleep(wait).await;
+}
+
+/// The request of `prompt` and the paragraphs, with the sampling options of
+/// the run in its `generationConfig`.
+fn to_request_body(
+    context: &Context,
+    system_instruction: &str,
+    prompt: &str,
+    user_contents_text_vec: &Vec<String>,
+) -> ClientRequest {
+    let mut parts = vec![Part {
+        text: prompt.to_string(),
+    }];
     for text in user_contents_text_vec {
         parts.push(Part { text: text.clone() });
     }
 
     ClientRequest {
-        system_instruction: Content {
+        system_instruction: Some(Content {
             parts: vec![Part {
-                text: prompt.to_string(),
+                text: system_instruction.to_string(),
             }],
-        },
+        }),
+        cached_content: None,
+        safety_settings: Vec::new(),
         generation_config: GenerationConfig {
-            response_mime_type: "application/json".to_string(),
+            response_mime_type: None,
+            response_schema: None,
+            temperature: context.temperature,
+            top_p: context.top_p,
+            max_output_tokens: context.max_output_tokens,
         },
         contents: vec![Content { parts }],
     }
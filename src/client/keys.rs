@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// API keys of a run from `--api-keys` or `--api-keys-file`, used in turn.
+///
+/// Quotas such as those of the Gemini free tier are per key, so a key that
+/// is answered with 429 is benched for the cooldown and the request goes out
+/// again on the next key; only once every key is benched does the request
+/// wait like it would with a single key.
+#[derive(Default)]
+pub struct Keys {
+    keys: Vec<String>,
+    next: AtomicUsize,
+    /// until when each key is benched
+    benched: Mutex<Vec<Option<Instant>>>,
+    cooldown: Duration,
+}
+
+impl Keys {
+    pub fn new(keys: Vec<String>, cooldown: Duration) -> Self {
+        Self {
+            benched: Mutex::new(vec![None; keys.len()]),
+            keys,
+            next: AtomicUsize::new(0),
+            cooldown,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The next key that is not benched, with its index; when every key is
+    /// benched, the one back the soonest.
+    pub fn pick(&self) -> Option<(usize, &str)> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        let benched = self.benched.lock().unwrap();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let index = (0..self.keys.len())
+            .map(|offset| (start + offset) % self.keys.len())
+            .find(|index| benched[*index].is_none_or(|until| until <= now))
+            .unwrap_or_else(|| {
+                (0..self.keys.len())
+                    .min_by_key(|index| benched[*index])
+                    .unwrap()
+            });
+        Some((index, &self.keys[index]))
+    }
+
+    /// Bench the key at `index` for the cooldown, or as long as the 429
+    /// response asked to `wait` when that is longer. Returns whether another
+    /// key can be used right away.
+    pub fn bench(&self, index: usize, wait: Duration) -> bool {
+        let now = Instant::now();
+        let mut benched = self.benched.lock().unwrap();
+        benched[index] = Some(now + self.cooldown.max(wait));
+        benched
+            .iter()
+            .any(|until| until.is_none_or(|until| until <= now))
+    }
+}
@@ -0,0 +1,421 @@
+use log::{debug, info};
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{Duration, Instant};
+
+/// Concurrency budget shared by every request of a run, retries and chapters
+/// in flight included, so nested retry streams cannot exceed `--requests`.
+///
+/// With `--rpm` or `--tpm` it also paces the requests to stay under a
+/// per-minute request and estimated-token budget.
+///
+/// With `--adaptive-requests` the concurrency starts at one and is raised and
+/// lowered within `--requests` as the responses come back, the way TCP sizes
+/// its congestion window.
+pub struct Limiter {
+    semaphore: Semaphore,
+    requests_per_minute: Option<Bucket>,
+    tokens_per_minute: Option<Bucket>,
+    window: Mutex<Window>,
+}
+
+/// A request allowed to go out, which gives its place back when dropped.
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let Some(permit) = self.permit.take() else {
+            return;
+        };
+        // a lowered window is paid back with the places of the requests that
+        // were in flight when it was lowered
+        let mut window = self.limiter.window.lock().unwrap();
+        if window.debt > 0 {
+            window.debt -= 1;
+            permit.forget();
+        }
+    }
+}
+
+/// The requests let out at once, `size` of at most `max`. When adaptive it
+/// is raised by one for each healthy response up to `threshold` (slow
+/// start), then by one for each window of healthy responses, and halved on
+/// a 429, a 5xx or a failed connection.
+struct Window {
+    max: usize,
+    size: usize,
+    /// places to take back from the requests in flight
+    debt: usize,
+    adaptive: bool,
+    threshold: usize,
+    /// healthy responses since the window was last raised
+    healthy: usize,
+    /// the shortest time a response took per estimated token
+    fastest: Option<f64>,
+    /// the time the last response took, within which the window is lowered
+    /// only once, as the other requests in flight met the same congestion
+    latency: Duration,
+    lowered: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new(requests: usize) -> Self {
+        let requests = requests.max(1);
+        Self {
+            semaphore: Semaphore::new(requests),
+            requests_per_minute: None,
+            tokens_per_minute: None,
+            window: Mutex::new(Window {
+                max: requests,
+                size: requests,
+                debt: 0,
+                adaptive: false,
+                threshold: requests,
+                healthy: 0,
+                fastest: None,
+                latency: Duration::ZERO,
+                lowered: None,
+            }),
+        }
+    }
+
+    /// Size the concurrency to the responses, up to the `--requests` given
+    /// to `new`, starting from one request at a time.
+    pub fn adaptive(self, enabled: bool) -> Self {
+        if !enabled {
+            return self;
+        }
+        {
+            let mut window = self.window.lock().unwrap();
+            window.adaptive = true;
+            self.resize(&mut window, 1);
+        }
+        self
+    }
+
+    pub fn with_rates(self, rpm: Option<u32>, tpm: Option<u32>) -> Self {
+        Self {
+            requests_per_minute: rpm.map(Bucket::per_minute),
+            tokens_per_minute: tpm.map(Bucket::per_minute),
+            ..self
+        }
+    }
+
+    /// Whether requests are paced by `--rpm` or `--tpm`, which replaces the
+    /// static pacing of the provider.
+    pub fn is_paced(&self) -> bool {
+        self.requests_per_minute.is_some() || self.tokens_per_minute.is_some()
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("limiter semaphore closed");
+        Permit {
+            limiter: self,
+            permit: Some(permit),
+        }
+    }
+
+    /// The requests let out at once now, and at most.
+    pub fn requests(&self) -> (usize, usize) {
+        let window = self.window.lock().unwrap();
+        (window.size, window.max)
+    }
+
+    /// Change `--requests` during the run: the requests in flight beyond it
+    /// finish, and none are let out until there are fewer. An adaptive
+    /// window is kept within it.
+    pub fn set_requests(&self, requests: usize) {
+        let mut window = self.window.lock().unwrap();
+        window.max = requests.max(1);
+        window.threshold = window.threshold.min(window.max);
+        let size = match window.adaptive {
+            true => window.size.min(window.max),
+            false => window.max,
+        };
+        self.resize(&mut window, size);
+    }
+
+    /// Count a response that took `elapsed` for about `tokens` tokens toward
+    /// the adaptive window: it is raised while the responses come back
+    /// within twice the fastest time per token, and held once they slow down.
+    pub fn observe(&self, elapsed: Duration, tokens: usize) {
+        let mut window = self.window.lock().unwrap();
+        if !window.adaptive {
+            return;
+        }
+        window.latency = elapsed;
+        let per_token = elapsed.as_secs_f64() / tokens.max(1) as f64;
+        let fastest = window
+            .fastest
+            .map_or(per_token, |fastest| fastest.min(per_token));
+        window.fastest = Some(fastest);
+        if per_token > 2.0 * fastest {
+            window.healthy = 0;
+            return;
+        }
+        if window.size >= window.max {
+            return;
+        }
+        window.healthy += 1;
+        if window.size >= window.threshold && window.healthy < window.size {
+            return;
+        }
+        window.healthy = 0;
+        let size = window.size + 1;
+        self.resize(&mut window, size);
+        debug!(stage = "request"; "raising the concurrency to {}", size);
+    }
+
+    /// Halve the adaptive window after a 429, a 5xx or a failed connection.
+    pub fn congested(&self) {
+        let mut window = self.window.lock().unwrap();
+        if !window.adaptive
+            || window
+                .lowered
+                .is_some_and(|lowered| lowered.elapsed() < window.latency)
+        {
+            return;
+        }
+        let size = (window.size / 2).max(1);
+        window.threshold = size;
+        window.healthy = 0;
+        window.lowered = Some(Instant::now());
+        if size < window.size {
+            self.resize(&mut window, size);
+            info!(stage = "request"; "lowering the concurrency to {}", size);
+        }
+    }
+
+    /// Let out `size` requests at once, taking the places of a smaller
+    /// window from those free and then from the requests in flight.
+    fn resize(&self, window: &mut Window, size: usize) {
+        if size > window.size {
+            let grown = size - window.size;
+            let paid = grown.min(window.debt);
+            window.debt -= paid;
+            self.semaphore.add_permits(grown - paid);
+        } else {
+            let debt = window.debt + window.size - size;
+            window.debt = debt - self.semaphore.forget_permits(debt);
+        }
+        window.size = size;
+    }
+
+    /// Wait until one more request of about `tokens` tokens fits the
+    /// per-minute budgets.
+    pub async fn reserve(&self, tokens: usize) {
+        if let Some(bucket) = &self.requests_per_minute {
+            bucket.take(1.0).await;
+        }
+        if let Some(bucket) = &self.tokens_per_minute {
+            bucket.take(tokens as f64).await;
+        }
+    }
+
+    /// Correct the token budget by the tokens a request `reserved` and the
+    /// tokens the provider reported it `used`, when it reported any.
+    pub fn settle(&self, reserved: usize, used: i32) {
+        if let (Some(bucket), Ok(used)) = (&self.tokens_per_minute, usize::try_from(used)) {
+            if used > 0 {
+                bucket.give(reserved as f64 - used as f64);
+            }
+        }
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+/// Token bucket refilled evenly over a minute up to its per-minute capacity.
+/// The level goes negative when a request used more than it reserved, which
+/// delays the next ones.
+struct Bucket {
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn per_minute(capacity: u32) -> Self {
+        let capacity = f64::from(capacity.max(1));
+        Self {
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    fn refill(&self, level: &mut f64, at: &mut Instant) {
+        let now = Instant::now();
+        let per_second = self.capacity / 60.0;
+        *level = (*level + now.duration_since(*at).as_secs_f64() * per_second).min(self.capacity);
+        *at = now;
+    }
+
+    /// Take `amount`, waiting for the refill; more than the capacity is taken
+    /// from a full bucket.
+    async fn take(&self, amount: f64) {
+        let amount = amount.min(self.capacity);
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let (level, at) = &mut *state;
+                self.refill(level, at);
+                if *level >= amount {
+                    *level -= amount;
+                    return;
+                }
+                (amount - *level) / (self.capacity / 60.0)
+            };
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+
+    fn give(&self, amount: f64) {
+        let mut state = self.state.lock().unwrap();
+        let (level, at) = &mut *state;
+        self.refill(level, at);
+        *level = (*level + amount).min(self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The requests let out now, and the permits free to take.
+    fn state(limiter: &Limiter) -> (usize, usize) {
+        (limiter.requests().0, limiter.semaphore.available_permits())
+    }
+
+    /// A response as fast per token as the fastest, which took 20 ms.
+    fn healthy(limiter: &Limiter) {
+        limiter.observe(Duration::from_millis(20), 2000);
+    }
+
+    #[tokio::test]
+    async fn the_window_starts_slow_and_grows_to_the_limit() {
+        let limiter = Limiter::new(6).adaptive(true);
+        assert_eq!(limiter.requests(), (1, 6));
+        assert_eq!(state(&limiter), (1, 1));
+        // slow start: one more for each healthy response
+        for size in 2..=6 {
+            healthy(&limiter);
+            assert_eq!(state(&limiter), (size, size));
+        }
+        healthy(&limiter);
+        assert_eq!(state(&limiter), (6, 6));
+    }
+
+    #[tokio::test]
+    async fn slow_responses_hold_the_window() {
+        let limiter = Limiter::new(4).adaptive(true);
+        healthy(&limiter);
+        assert_eq!(state(&limiter), (2, 2));
+        // over twice the fastest time per token
+        limiter.observe(Duration::from_millis(5), 100);
+        assert_eq!(state(&limiter), (2, 2));
+        healthy(&limiter);
+        assert_eq!(state(&limiter), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn congestion_halves_the_window_once_per_latency() {
+        let limiter = Limiter::new(8).adaptive(true);
+        for _ in 0..7 {
+            healthy(&limiter);
+        }
+        assert_eq!(state(&limiter), (8, 8));
+        limiter.congested();
+        assert_eq!(state(&limiter), (4, 4));
+        // the other requests in flight met the same congestion
+        limiter.congested();
+        assert_eq!(state(&limiter), (4, 4));
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        limiter.congested();
+        assert_eq!(state(&limiter), (2, 2));
+        // past the threshold, one more for each window of healthy responses
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        limiter.congested();
+        assert_eq!(state(&limiter), (1, 1));
+        healthy(&limiter);
+        assert_eq!(state(&limiter), (2, 2));
+        healthy(&limiter);
+        assert_eq!(state(&limiter), (2, 2));
+        healthy(&limiter);
+        assert_eq!(state(&limiter), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn a_lowered_window_is_paid_back_by_the_requests_in_flight() {
+        let limiter = Limiter::new(4).adaptive(true);
+        for _ in 0..3 {
+            healthy(&limiter);
+        }
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(limiter.acquire().await);
+        }
+        limiter.congested();
+        assert_eq!(state(&limiter), (2, 0));
+        permits.truncate(2);
+        assert_eq!(state(&limiter), (2, 0));
+        permits.clear();
+        assert_eq!(state(&limiter), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn requests_can_be_changed_during_the_run() {
+        let limiter = Limiter::new(2);
+        let permits = [limiter.acquire().await, limiter.acquire().await];
+        limiter.set_requests(1);
+        assert_eq!(limiter.requests(), (1, 1));
+        drop(permits);
+        assert_eq!(state(&limiter), (1, 1));
+        limiter.set_requests(3);
+        assert_eq!(state(&limiter), (3, 3));
+        // without --adaptive-requests, responses change nothing
+        healthy(&limiter);
+        limiter.congested();
+        assert_eq!(state(&limiter), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn requests_per_minute_are_paced() {
+        // ten a second
+        let limiter = Limiter::new(1).with_rates(Some(600), None);
+        assert!(limiter.is_paced());
+        let started = Instant::now();
+        for _ in 0..600 {
+            limiter.reserve(0).await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(50));
+        limiter.reserve(0).await;
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[tokio::test]
+    async fn unused_tokens_are_given_back() {
+        let limiter = Limiter::new(1).with_rates(None, Some(6000));
+        let started = Instant::now();
+        // more than a minute of tokens is taken from a full bucket
+        limiter.reserve(10_000).await;
+        limiter.settle(6000, 3000);
+        limiter.reserve(3000).await;
+        // a provider that reported nothing changes nothing
+        limiter.settle(3000, 0);
+        assert!(started.elapsed() < Duration::from_millis(50));
+        // a hundred tokens a second
+        limiter.reserve(10).await;
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        assert!(!Limiter::new(1).is_paced());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::client::capability::{json_mode, response_schema};
+use crate::client::preflight;
+use crate::client::sse;
+use crate::client::{decode, send, text, timed_out, with_headers};
+use crate::error::Error;
+use crate::translate::translator::Context;
+use log::{info, trace};
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::ControlFlow;
+
+const BASE_URL: &str = "http://localhost:11434";
+
+#[derive(Serialize)]
+struct ClientRequest {
+    model: String,
+    messages: Vec<Message>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Value>,
+    options: ModelOptions,
+}
+
+#[derive(Serialize)]
+struct ModelOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Message {
+    role: String,
+    content: String,
+}
+
+/// A whole response, or one line of a streamed one; only the last line is
+/// `done` and carries the counts.
+#[derive(Deserialize)]
+struct ClientResponse {
+    message: Option<Message>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    prompt_eval_count: i32,
+    #[serde(default)]
+    eval_count: i32,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ShowRequest<'a> {
+    model: &'a str,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub prompt_eval_count: i32,
+    pub eval_count: i32,
+}
+
+impl From<&ClientResponse> for Stats {
+    fn from(response: &ClientResponse) -> Self {
+        Self {
+            prompt_eval_count: response.prompt_eval_count,
+            eval_count: response.eval_count,
+        }
+    }
+}
+
+impl Stats {
+    pub fn total(&self) -> i32 {
+        self.prompt_eval_count + self.eval_count
+    }
+}
+
+#[derive(Default)]
+pub struct Response {
+    pub stats: Stats,
+    pub text: String,
+}
+
+/// `system_instruction` goes to the system message, the task `prompt` and the
+/// paragraphs to two user messages. In JSON mode the response `schema`, when
+/// there is one, is sent as the `format`.
+///
+/// A local server answers one request at a time, so there is no pacing
+/// between requests; `--num-ctx` raises the context window, which is small
+/// by default and silently truncates longer prompts.
+pub async fn request(
+    context: &Context,
+    system_instruction: &str,
+    prompt: &str,
+    user_contents: &[String],
+    schema: Option<Value>,
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let mut request_body = to_request_body(context, system_instruction, prompt, user_contents);
+    if json_mode(context) {
+        request_body.format = Some(
+            schema
+                .filter(|_| response_schema(context))
+                .unwrap_or_else(|| Value::from("json")),
+        );
+    }
+    let build = |_: &str| post(&client, context, "chat").json(&request_body);
+    let Some(response) = send(context, build).await? else {
+        return Ok(Response::default());
+    };
+
+    let status = response.status();
+    let response_text = text(context, response).await?;
+    let response_body: ClientResponse = decode(status, &response_text)?;
+    if let Some(error) = &response_body.error {
+        info!(stage = "request"; "response status: {}", status);
+        trace!("response error: {}", error);
+    }
+
+    Ok(Response {
+        stats: Stats::from(&response_body),
+        text: response_body
+            .message
+            .map(|message| message.content)
+            .unwrap_or_default(),
+    })
+}
+
+/// [`request`] in text output mode, streamed as newline-delimited JSON with
+/// each piece of content passed to `on_text` as it arrives.
+pub async fn stream_request(
+    context: &Context,
+    system_instruction: &str,
+    prompt: &str,
+    user_contents: &[String],
+    mut on_text: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let mut request_body = to_request_body(context, system_instruction, prompt, user_contents);
+    request_body.stream = true;
+    let build = |_: &str| post(&client, context, "chat").json(&request_body);
+    let Some(response) = send(context, build).await? else {
+        return Ok(Response::default());
+    };
+
+    let mut text = String::new();
+    let mut stats = Stats::default();
+    sse::for_each_line(response, |line| {
+        if line.is_empty() {
+            return ControlFlow::Continue(());
+        }
+        let Ok(chunk) = serde_json::from_str::<ClientResponse>(line) else {
+            trace!("stream error: {}", line);
+            return ControlFlow::Continue(());
+        };
+        if chunk.done {
+            stats = Stats::from(&chunk);
+        }
+        if let Some(message) = &chunk.message {
+            text.push_str(&message.content);
+            on_text(&message.content)?;
+        }
+        ControlFlow::Continue(())
+    })
+    .await
+    .inspect_err(|e| timed_out(context, e))?;
+
+    Ok(Response { stats, text })
+}
+
+/// Check that the model has been pulled to the server.
+pub async fn probe(context: &Context) -> Result<(), String> {
+    let response = with_headers(post(&Client::new(), context, "show"), context)
+        .json(&ShowRequest {
+            model: &context.model,
+        })
+        .send()
+        .await
+        .map_err(|e| e.without_url().to_string())?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(preflight::explain(&context.model, status, &body))
+}
+
+fn post(client: &Client, context: &Context, endpoint: &str) -> RequestBuilder {
+    let base_url = context.base_url.as_deref().unwrap_or(BASE_URL);
+    client.post(format!(
+        "{}/api/{}",
+        base_url.trim_end_matches('/'),
+        endpoint
+    ))
+}
+
+fn to_request_body(
+    context: &Context,
+    system_instruction: &str,
+    prompt: &str,
+    user_contents: &[String],
+) -> ClientRequest {
+    let message = |role: &str, content: String| Message {
+        role: role.to_string(),
+        content,
+    };
+    ClientRequest {
+        model: context.request_model(),
+        messages: vec![
+            message("system", system_instruction.to_string()),
+            message("user", prompt.to_string()),
+            message("user", user_contents.join("\n")),
+        ],
+        stream: false,
+        format: None,
+        options: ModelOptions {
+            num_ctx: context.num_ctx,
+        },
+    }
+}
@@ -1,19 +1,49 @@
+use crate::client::capability::{json_mode, response_schema};
+use crate::client::preflight;
+use crate::client::ratelimit::Ratelimit;
+use crate::client::sse;
+use crate::client::{decode, send, text, timed_out, with_headers};
+use crate::error::Error;
+use crate::translate::translator::Context;
 use log::{debug, info, trace};
-use reqwest::{Client, Error};
+use reqwest::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::ControlFlow;
 use std::time::Duration;
 
+const BASE_URL: &str = "https://api.openai.com/v1";
+
 #[derive(Serialize)]
 struct ClientRequest {
     model: String,
-    response_format: ResponseFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
     messages: Vec<MessageRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
 }
 
 #[derive(Serialize)]
 struct ResponseFormat {
     #[serde(rename = "type")]
     _type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_schema: Option<JsonSchema>,
+}
+
+#[derive(Serialize)]
+struct JsonSchema {
+    name: String,
+    strict: bool,
+    schema: Value,
 }
 
 #[derive(Serialize)]
@@ -39,6 +69,7 @@ struct ClientResponse {
 #[derive(Deserialize)]
 struct Choice {
     message: MessageResponse,
+    finish_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -46,6 +77,24 @@ struct MessageResponse {
     content: String,
 }
 
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct Usage {
     prompt_tokens: i32,
@@ -53,159 +102,230 @@ struct Usage {
     total_tokens: i32,
 }
 
+#[derive(Default)]
 pub struct Stats {
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
     pub total_tokens: i32,
 }
 
-impl Stats {
-    pub fn log(&self) {
-        info!(
-            "prompt tokens: {} completion tokens: {} total tokens: {}",
-            self.prompt_tokens, self.completion_tokens, self.total_tokens
-        );
-    }
-}
-
-pub struct Ratelimit {
-    pub limit_requests: String,
-    pub limit_tokens: String,
-    pub remaining_requests: String,
-    pub remaining_tokens: String,
-    pub reset_requests: String,
-    pub reset_tokens: String,
-}
-
-impl Ratelimit {
-    pub fn log(&self) {
-        debug!("ratelimit limit requests: {}", self.limit_requests);
-        debug!("ratelimit limit tokens: {}", self.limit_tokens);
-        debug!("ratelimit remaining requests: {}", self.remaining_requests);
-        debug!("ratelimit remaining tokens: {}", self.remaining_tokens);
-        debug!("ratelimit reset requests: {}", self.reset_requests);
-        debug!("ratelimit reset tokens: {}", self.reset_tokens);
-    }
-
-    pub fn reset_tokens_duration(&self) -> Duration {
-        // TODO: add format ex 6m0s
-        if let Some(milliseconds_str) = self.reset_tokens.strip_suffix("ms") {
-            if let Ok(milliseconds) = milliseconds_str.parse::<u64>() {
-                return Duration::from_millis(milliseconds);
-            }
-        } else if let Some(seconds_str) = self.reset_tokens.strip_suffix('s') {
-            if let Ok(seconds) = seconds_str.parse::<f64>() {
-                return Duration::from_secs_f64(seconds);
-            }
+impl From<Usage> for Stats {
+    fn from(usage: Usage) -> Self {
+        Self {
+            total_tokens: usage.total_tokens,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
         }
-        Duration::from_secs(1)
     }
 }
 
+#[derive(Default)]
 pub struct Response {
     pub stats: Stats,
     pub choice: String,
     pub ratelimit: Ratelimit,
+    /// the response was cut short at the output token limit
+    pub truncated: bool,
 }
 
+/// Finish reason of a response cut short at the output token limit.
+const FINISH_LENGTH: &str = "length";
+
+/// `system_instruction` goes to the system message, the task `prompt` is sent
+/// as the first user content ahead of the paragraphs. In JSON mode the
+/// response `schema`, when there is one, is sent as a strict `json_schema`
+/// response format.
 pub async fn request(
-    model: &str,
-    api_key: &String,
+    context: &Context,
+    system_instruction: &str,
     prompt: &str,
     user_contents: &Vec<String>,
+    schema: Option<Value>,
 ) -> Result<Response, Error> {
     let client = Client::new();
-    let request_body = to_request_body(model, prompt, user_contents);
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&request_body)
-        .send()
-        .await;
-
-    if response.is_err() {
-        return Err(response.err().unwrap());
+    let mut request_body = to_request_body(
+        &context.request_model(),
+        system_instruction,
+        prompt,
+        user_contents,
+    );
+    if json_mode(context) {
+        request_body.response_format = Some(match schema.filter(|_| response_schema(context)) {
+            Some(schema) => ResponseFormat {
+                _type: "json_schema".to_string(),
+                json_schema: Some(JsonSchema {
+                    name: "translation".to_string(),
+                    strict: true,
+                    schema,
+                }),
+            },
+            None => ResponseFormat {
+                _type: "json_object".to_string(),
+                json_schema: None,
+            },
+        });
     }
-    let response = response.unwrap();
-    let headers = response.headers();
-    let ratelimit = Ratelimit {
-        limit_requests: headers
-            .get("x-ratelimit-limit-requests")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        limit_tokens: headers
-            .get("x-ratelimit-limit-tokens")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        remaining_requests: headers
-            .get("x-ratelimit-remaining-requests")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        remaining_tokens: headers
-            .get("x-ratelimit-remaining-tokens")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        reset_requests: headers
-            .get("x-ratelimit-reset-requests")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        reset_tokens: headers
-            .get("x-ratelimit-reset-tokens")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
+    let build = |key: &str| post(&client, context, key).json(&request_body);
+    let Some(response) = send(context, build).await? else {
+        return Ok(Response::default());
     };
+    let ratelimit = Ratelimit::from_headers(response.headers());
 
     let status = response.status();
-    let response_text = response.text().await.expect("API Response to Text error");
-    let response_body: ClientResponse =
-        serde_json::from_str(&response_text).expect("API Response to JSON error");
+    let response_text = text(context, response).await?;
+    let response_body: ClientResponse = decode(status, &response_text)?;
 
     if response_body.choices.is_empty() {
-        info!("response status: {}", status.to_string());
+        info!(stage = "request"; "response status: {}", status.to_string());
         trace!("response error: {}", response_text);
     }
 
-    debug!(
-        "sleep: {}sec",
-        ratelimit.reset_tokens_duration().as_secs_f64()
-    );
-    tokio::time::sleep(ratelimit.reset_tokens_duration()).await;
+    pace(context, &ratelimit).await;
 
-    let choice = response_body
-        .choices
-        .first()
+    let first = response_body.choices.first();
+    let choice = first
         .map_or("", |choice| &choice.message.content)
         .to_string();
-    let usage = response_body.usage.unwrap();
     Ok(Response {
         choice,
         ratelimit,
-        stats: Stats {
-            total_tokens: usage.total_tokens,
-            prompt_tokens: usage.prompt_tokens,
-            completion_tokens: usage.completion_tokens,
-        },
+        truncated: first
+            .is_some_and(|choice| choice.finish_reason.as_deref() == Some(FINISH_LENGTH)),
+        stats: response_body.usage.map(Stats::from).unwrap_or_default(),
     })
 }
 
+/// [`request`] in text output mode, streamed over SSE with each content delta
+/// passed to `on_text` as it arrives.
+pub async fn stream_request(
+    context: &Context,
+    system_instruction: &str,
+    prompt: &str,
+    user_contents: &Vec<String>,
+    mut on_text: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<Response, Error> {
+    let client = Client::new();
+    let mut request_body = to_request_body(
+        &context.request_model(),
+        system_instruction,
+        prompt,
+        user_contents,
+    );
+    request_body.stream = Some(true);
+    request_body.stream_options = Some(StreamOptions {
+        include_usage: true,
+    });
+    let build = |key: &str| post(&client, context, key).json(&request_body);
+    let Some(response) = send(context, build).await? else {
+        return Ok(Response::default());
+    };
+    let ratelimit = Ratelimit::from_headers(response.headers());
+
+    let mut choice = String::new();
+    let mut usage = None;
+    let mut truncated = false;
+    sse::for_each_data(response, |data| {
+        if data == "[DONE]" {
+            return ControlFlow::Continue(());
+        }
+        let Ok(chunk) = serde_json::from_str::<StreamChunk>(data) else {
+            trace!("stream error: {}", data);
+            return ControlFlow::Continue(());
+        };
+        if chunk.usage.is_some() {
+            usage = chunk.usage;
+        }
+        truncated |= chunk
+            .choices
+            .iter()
+            .any(|c| c.finish_reason.as_deref() == Some(FINISH_LENGTH));
+        for content in chunk
+            .choices
+            .iter()
+            .filter_map(|c| c.delta.content.as_ref())
+        {
+            choice.push_str(content);
+            on_text(content)?;
+        }
+        ControlFlow::Continue(())
+    })
+    .await
+    .inspect_err(|e| timed_out(context, e))?;
+
+    pace(context, &ratelimit).await;
+
+    Ok(Response {
+        choice,
+        ratelimit,
+        truncated,
+        stats: usage.map(Stats::from).unwrap_or_default(),
+    })
+}
+
+/// Send a minimal request to check that the API key can use the model.
+pub async fn probe(context: &Context) -> Result<(), String> {
+    let request_body = to_request_body(&context.model, "", preflight::PROMPT, &vec![]);
+    let response = with_headers(post(&Client::new(), context, &context.api_key), context)
+        .json(&request_body)
+        .send()
+        .await
+        .map_err(|e| e.without_url().to_string())?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(preflight::explain(&context.model, status, &body))
+}
+
+/// Chat completions request to `--base-url`, keeping a query string such as
+/// the `api-version` of Azure OpenAI after the path.
+///
+/// Azure OpenAI takes the key in an `api-key` header; everything else takes
+/// a bearer token.
+fn post(client: &Client, context: &Context, key: &str) -> RequestBuilder {
+    let base_url = context.base_url.as_deref().unwrap_or(BASE_URL);
+    let (path, query) = match base_url.split_once('?') {
+        Some((path, query)) => (path, format!("?{}", query)),
+        None => (base_url, String::new()),
+    };
+    let url = format!("{}/chat/completions{}", path.trim_end_matches('/'), query);
+    let builder = client.post(url);
+    if is_azure(path) {
+        builder.header("api-key", key)
+    } else {
+        builder.header("Authorization", format!("Bearer {}", key))
+    }
+}
+
+fn is_azure(url: &str) -> bool {
+    url.split("://")
+        .nth(1)
+        .and_then(|rest| rest.split(['/', ':']).next())
+        .is_some_and(|host| host.ends_with(".openai.azure.com"))
+}
+
+async fn pace(context: &Context, ratelimit: &Ratelimit) {
+    let fallback = if context.limiter.is_paced() {
+        Duration::ZERO
+    } else {
+        ratelimit.reset_tokens.unwrap_or(Duration::from_secs(1))
+    };
+    let wait = ratelimit.wait(context.throttle, fallback);
+    debug!("sleep: {}sec", wait.as_secs_f64());
+    tokio::time::sleep(wait).await;
+}
+
 fn to_request_body(
     model: &str,
+    system_instruction: &str,
     prompt: &str,
     user_contents_text_vec: &Vec<String>,
 ) -> ClientRequest {
-    let mut user_contents = vec![];
+    let mut user_contents = vec![Content {
+        _type: "text".to_string(),
+        text: prompt.to_owned(),
+    }];
     for text in user_contents_text_vec {
         user_contents.push(Content {
             _type: "text".to_string(),
@@ -215,15 +335,15 @@ fn to_request_body(
 
     ClientRequest {
         model: model.to_owned(),
-        response_format: ResponseFormat {
-            _type: "json_object".to_string(),
-        },
+        response_format: None,
+        stream: None,
+        stream_options: None,
         messages: vec![
             MessageRequest {
                 role: "system".to_string(),
                 content: vec![Content {
                     _type: "text".to_string(),
-                    text: prompt.to_owned(),
+                    text: system_instruction.to_owned(),
                 }],
             },
             MessageRequest {
@@ -0,0 +1,50 @@
+use clap::ValueEnum;
+use reqwest::StatusCode;
+
+/// Prompt of the probe request; the answer is ignored.
+pub const PROMPT: &str = "Reply with OK.";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Preflight {
+    /// Log a warning and translate anyway
+    #[default]
+    Warn,
+    /// Stop before translating
+    Abort,
+    /// Do not probe the model
+    Off,
+}
+
+/// Explain why the probe of `model` failed, with what to do about it.
+pub fn explain(model: &str, status: StatusCode, body: &str) -> String {
+    let detail = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| {
+            let error = &body["error"];
+            error["message"]
+                .as_str()
+                .or(error.as_str())
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| body.trim().to_string());
+    let hint = match status {
+        StatusCode::UNAUTHORIZED => "the API key is invalid; check --api-key".to_string(),
+        StatusCode::FORBIDDEN => format!(
+            "the API key has no access to model {}; it may need another plan or region",
+            model
+        ),
+        StatusCode::NOT_FOUND => format!(
+            "model {} does not exist or is not available to this API key; check --model",
+            model
+        ),
+        StatusCode::TOO_MANY_REQUESTS => {
+            "the API key is out of quota or rate limited; check the billing of the account"
+                .to_string()
+        }
+        StatusCode::BAD_REQUEST if detail.contains("API key") => {
+            "the API key is invalid; check --api-key".to_string()
+        }
+        _ => format!("model {} rejected the probe request", model),
+    };
+    format!("{} ({}: {})", hint, status, detail)
+}
@@ -0,0 +1,57 @@
+/// List price of a model in USD per million tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl Price {
+    pub fn cost(&self, input_tokens: u64, output_tokens: u64) -> f64 {
+        (input_tokens as f64 * self.input + output_tokens as f64 * self.output) / 1_000_000.0
+    }
+
+    /// What is saved on `cached_tokens` input tokens read from a context
+    /// cache, which Gemini bills at a quarter of the input price; the storage
+    /// of the cache is billed apart by the hour.
+    pub fn discount(&self, cached_tokens: u64) -> f64 {
+        cached_tokens as f64 * self.input * (1.0 - CACHED_INPUT) / 1_000_000.0
+    }
+}
+
+/// Share of the input price billed for cached input tokens.
+const CACHED_INPUT: f64 = 0.25;
+
+/// Published prices by model name prefix, the more specific prefixes first.
+/// Prices change; `--input-price` and `--output-price` override them.
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("gpt-4o", 2.50, 10.00),
+    ("gpt-4.1-nano", 0.10, 0.40),
+    ("gpt-4.1-mini", 0.40, 1.60),
+    ("gpt-4.1", 2.00, 8.00),
+    ("gpt-4-turbo", 10.00, 30.00),
+    ("gpt-3.5-turbo", 0.50, 1.50),
+    ("gemini-2.0-flash-lite", 0.075, 0.30),
+    ("gemini-2.0-flash", 0.10, 0.40),
+    ("gemini-1.5-flash-8b", 0.0375, 0.15),
+    ("gemini-1.5-flash", 0.075, 0.30),
+    ("gemini-1.5-pro", 1.25, 5.00),
+    ("claude-3-5-haiku", 0.80, 4.00),
+    ("claude-3-haiku", 0.25, 1.25),
+    ("claude-3-5-sonnet", 3.00, 15.00),
+    ("claude-3-7-sonnet", 3.00, 15.00),
+    ("claude-sonnet-4", 3.00, 15.00),
+    ("claude-3-opus", 15.00, 75.00),
+    ("claude-opus-4", 15.00, 75.00),
+    // per million characters, counted as prompt tokens
+    ("deepl", 25.00, 0.0),
+];
+
+/// The list price of `model`, if it is a known hosted model.
+pub fn price(model: &str) -> Option<Price> {
+    let model = model.rsplit('/').next().unwrap_or(model);
+    PRICES
+        .iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))
+        .map(|&(_, input, output)| Price { input, output })
+}
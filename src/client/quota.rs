@@ -0,0 +1,128 @@
+use crate::client::ratelimit::parse_duration;
+use clap::ValueEnum;
+use log::{error, warn};
+use reqwest::header::HeaderMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Consecutive 429 responses to one request after which the quota is taken
+/// as exhausted rather than briefly rate limited.
+const PERSISTENT_ATTEMPTS: u32 = 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnQuota {
+    /// Wait until the quota resets and carry on
+    #[default]
+    Wait,
+    /// Stop requesting, finish the book with what is translated and exit
+    Exit,
+}
+
+/// Quota state shared by every request of a run.
+pub struct Quota {
+    on_quota: OnQuota,
+    max_wait: Duration,
+    resume_at: Mutex<Option<SystemTime>>,
+}
+
+impl Quota {
+    pub fn new(on_quota: OnQuota, max_wait: Duration) -> Self {
+        Self {
+            on_quota,
+            max_wait,
+            resume_at: Mutex::new(None),
+        }
+    }
+
+    /// When the run stopped for exhausted quota, the time to resume after.
+    pub fn resume_at(&self) -> Option<SystemTime> {
+        *self.resume_at.lock().unwrap()
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.resume_at().is_some()
+    }
+
+    /// Handle the `attempt`-th consecutive 429 response of a request: wait
+    /// and return `true` to send it again, or return `false` once the quota
+    /// is exhausted and the policy is to exit.
+    pub async fn pause(&self, wait: Duration, attempt: u32) -> bool {
+        let persistent = attempt >= PERSISTENT_ATTEMPTS || wait > self.max_wait;
+        let until = SystemTime::now() + wait;
+        if persistent && self.on_quota == OnQuota::Exit {
+            let mut resume_at = self.resume_at.lock().unwrap();
+            if resume_at.is_none() {
+                error!("quota exhausted, stopping; resume after {}", utc(until));
+                *resume_at = Some(until);
+            }
+            return false;
+        }
+        let reason = if persistent {
+            "quota exhausted"
+        } else {
+            "rate limited"
+        };
+        warn!(
+            "{}, waiting {}sec until {}",
+            reason,
+            wait.as_secs(),
+            utc(until)
+        );
+        tokio::time::sleep(wait).await;
+        true
+    }
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self::new(OnQuota::Wait, Duration::from_secs(300))
+    }
+}
+
+/// How long a 429 response asks to wait: the `Retry-After` header, the
+/// `retryDelay` of a Gemini error body, or an exponential backoff.
+pub fn retry_after(headers: &HeaderMap, body: &str, attempt: u32) -> Duration {
+    let header = headers
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_duration);
+    let delay = || {
+        let body: serde_json::Value = serde_json::from_str(body).ok()?;
+        body["error"]["details"]
+            .as_array()?
+            .iter()
+            .find_map(|detail| detail["retryDelay"].as_str())
+            .and_then(parse_duration)
+    };
+    header.or_else(delay).unwrap_or_else(|| {
+        Duration::from_secs(30 << attempt.saturating_sub(1).min(7)).min(Duration::from_secs(3600))
+    })
+}
+
+/// `YYYY-MM-DD hh:mm:ss UTC`
+pub fn utc(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rest) = (seconds / 86400, seconds % 86400);
+    // civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
@@ -0,0 +1,197 @@
+use clap::ValueEnum;
+use log::debug;
+use reqwest::header::HeaderMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Below this share of the limit the next request waits for the window to reset.
+const LOW_QUOTA_RATIO: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Throttle {
+    /// Pace requests from the remaining-quota headers, static pacing without them
+    #[default]
+    Headers,
+    /// Always use the static pacing of the provider
+    Static,
+}
+
+#[derive(Default)]
+pub struct Ratelimit {
+    pub limit_requests: Option<u64>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub reset_requests: Option<Duration>,
+    pub reset_tokens: Option<Duration>,
+}
+
+impl Ratelimit {
+    /// The remaining quota in the `x-ratelimit-*` headers of OpenAI and
+    /// most compatible servers, or the `anthropic-ratelimit-*` headers of
+    /// the Anthropic API.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            limit_requests: number(
+                headers,
+                &[
+                    "x-ratelimit-limit-requests",
+                    "x-ratelimit-limit",
+                    "anthropic-ratelimit-requests-limit",
+                ],
+            ),
+            limit_tokens: number(
+                headers,
+                &[
+                    "x-ratelimit-limit-tokens",
+                    "anthropic-ratelimit-tokens-limit",
+                ],
+            ),
+            remaining_requests: number(
+                headers,
+                &[
+                    "x-ratelimit-remaining-requests",
+                    "x-ratelimit-remaining",
+                    "anthropic-ratelimit-requests-remaining",
+                ],
+            ),
+            remaining_tokens: number(
+                headers,
+                &[
+                    "x-ratelimit-remaining-tokens",
+                    "anthropic-ratelimit-tokens-remaining",
+                ],
+            ),
+            reset_requests: duration(
+                headers,
+                &[
+                    "x-ratelimit-reset-requests",
+                    "x-ratelimit-reset",
+                    "anthropic-ratelimit-requests-reset",
+                ],
+            ),
+            reset_tokens: duration(
+                headers,
+                &[
+                    "x-ratelimit-reset-tokens",
+                    "anthropic-ratelimit-tokens-reset",
+                ],
+            ),
+        }
+    }
+
+    pub fn log(&self) {
+        debug!("ratelimit limit requests: {:?}", self.limit_requests);
+        debug!("ratelimit limit tokens: {:?}", self.limit_tokens);
+        debug!(
+            "ratelimit remaining requests: {:?}",
+            self.remaining_requests
+        );
+        debug!("ratelimit remaining tokens: {:?}", self.remaining_tokens);
+        debug!("ratelimit reset requests: {:?}", self.reset_requests);
+        debug!("ratelimit reset tokens: {:?}", self.reset_tokens);
+    }
+
+    /// How long to wait before the next request: nothing while quota is
+    /// plentiful, until the window resets once it runs low, and `None` when
+    /// the provider sent no remaining-quota headers.
+    pub fn pacing(&self) -> Option<Duration> {
+        if self.remaining_requests.is_none() && self.remaining_tokens.is_none() {
+            return None;
+        }
+        let mut wait = Duration::ZERO;
+        if is_low(self.remaining_requests, self.limit_requests) {
+            wait = wait.max(self.reset_requests.unwrap_or(Duration::from_secs(1)));
+        }
+        if is_low(self.remaining_tokens, self.limit_tokens) {
+            wait = wait.max(self.reset_tokens.unwrap_or(Duration::from_secs(1)));
+        }
+        Some(wait)
+    }
+
+    /// The wait before the next request for the chosen throttle, `fallback`
+    /// being the static pacing of the provider.
+    pub fn wait(&self, throttle: Throttle, fallback: Duration) -> Duration {
+        match (throttle, self.pacing()) {
+            (Throttle::Headers, Some(wait)) => wait,
+            _ => fallback,
+        }
+    }
+}
+
+fn is_low(remaining: Option<u64>, limit: Option<u64>) -> bool {
+    match (remaining, limit) {
+        (Some(remaining), Some(limit)) if limit > 0 => {
+            (remaining as f64) < limit as f64 * LOW_QUOTA_RATIO
+        }
+        (Some(remaining), _) => remaining == 0,
+        _ => false,
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|value| value.to_str().ok())
+}
+
+fn number(headers: &HeaderMap, names: &[&str]) -> Option<u64> {
+    header(headers, names).and_then(|value| value.trim().parse().ok())
+}
+
+fn duration(headers: &HeaderMap, names: &[&str]) -> Option<Duration> {
+    header(headers, names).and_then(|value| parse_duration(value).or_else(|| until(value)))
+}
+
+/// The time until an RFC 3339 timestamp in UTC such as
+/// `2024-06-01T12:00:30Z`, zero once it has passed.
+fn until(value: &str) -> Option<Duration> {
+    let value = value.trim().strip_suffix('Z')?;
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.splitn(3, ':');
+    let hours: f64 = time.next()?.parse().ok()?;
+    let minutes: f64 = time.next()?.parse().ok()?;
+    let seconds: f64 = time.next()?.parse().ok()?;
+    // days since 1970-01-01 from the civil date (Howard Hinnant's algorithm)
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let at = days as f64 * 86400.0 + hours * 3600.0 + minutes * 60.0 + seconds;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    Some(Duration::from_secs_f64((at - now.as_secs_f64()).max(0.0)))
+}
+
+/// Parse durations like `20ms`, `1.5s`, `6m0s` or `1h2m3s`; a bare number is
+/// seconds.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Some(Duration::from_secs_f64(seconds));
+    }
+    let mut seconds = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let (number, tail) = rest.split_at(split);
+        let number: f64 = number.parse().ok()?;
+        let split = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(split);
+        seconds += number
+            * match unit {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                "ms" => 0.001,
+                _ => return None,
+            };
+        rest = tail;
+    }
+    Some(Duration::from_secs_f64(seconds))
+}
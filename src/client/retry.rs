@@ -0,0 +1,81 @@
+use clap::ValueEnum;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+/// Longest wait between two attempts of a request, however many failed.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Failures of a request that can be sent again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RetryOn {
+    /// The connection could not be made
+    Connect,
+    /// The request or the response timed out
+    Timeout,
+    /// The connection broke otherwise while sending
+    Transport,
+    /// The server answered with a 5xx status
+    #[value(name = "5xx")]
+    Server,
+}
+
+impl RetryOn {
+    pub fn of(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Self::Timeout
+        } else if error.is_connect() {
+            Self::Connect
+        } else {
+            Self::Transport
+        }
+    }
+}
+
+/// How a request is sent again when the connection fails or the server
+/// errs, apart from the 429 waits of the [quota](super::quota) and the
+/// retries of chunks that come back with another number of paragraphs.
+///
+/// The wait doubles with each failed attempt from `delay`, up to a minute,
+/// and is spread by up to `jitter` of itself either way so the requests in
+/// flight do not come back all at once.
+pub struct RetryPolicy {
+    /// attempts of a request in all, the first one included
+    pub attempts: u32,
+    pub delay: Duration,
+    /// fraction of the wait it is spread by, from 0 to 1
+    pub jitter: f64,
+    pub on: Vec<RetryOn>,
+}
+
+impl RetryPolicy {
+    /// The wait before sending a request again after its `failures`-th
+    /// failure of the class `failure`, or `None` when it is not retried.
+    pub fn wait(&self, failure: RetryOn, failures: u32) -> Option<Duration> {
+        if failures >= self.attempts || !self.on.contains(&failure) {
+            return None;
+        }
+        let backoff = self
+            .delay
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(MAX_DELAY);
+        let jitter = self.jitter.clamp(0.0, 1.0) * (2.0 * random() - 1.0);
+        Some(backoff.mul_f64(1.0 + jitter))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            delay: Duration::from_secs(1),
+            jitter: 0.5,
+            on: RetryOn::value_variants().to_vec(),
+        }
+    }
+}
+
+/// A number in `[0, 1)` from the random keys of the standard hasher.
+fn random() -> f64 {
+    (RandomState::new().hash_one(()) >> 11) as f64 / (1u64 << 53) as f64
+}
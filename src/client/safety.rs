@@ -0,0 +1,85 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// The harm categories the Gemini API filters text for.
+const CATEGORIES: [&str; 4] = [
+    "HARM_CATEGORY_HARASSMENT",
+    "HARM_CATEGORY_HATE_SPEECH",
+    "HARM_CATEGORY_SEXUALLY_EXPLICIT",
+    "HARM_CATEGORY_DANGEROUS_CONTENT",
+];
+
+/// Finish reasons of a candidate withheld by the filters of the API.
+const BLOCKED: [&str; 4] = ["SAFETY", "PROHIBITED_CONTENT", "BLOCKLIST", "SPII"];
+
+/// What the safety filters of the Gemini API block, with
+/// `--safety-threshold`, in every harm category.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Threshold {
+    /// Turn the filters off
+    Off,
+    /// Block nothing, still rating the content
+    BlockNone,
+    /// Block content of a high probability of harm
+    BlockOnlyHigh,
+    /// Block content of a medium or high probability of harm
+    BlockMediumAndAbove,
+    /// Block content of a low, medium or high probability of harm
+    BlockLowAndAbove,
+}
+
+impl Threshold {
+    fn name(self) -> &'static str {
+        match self {
+            Threshold::Off => "OFF",
+            Threshold::BlockNone => "BLOCK_NONE",
+            Threshold::BlockOnlyHigh => "BLOCK_ONLY_HIGH",
+            Threshold::BlockMediumAndAbove => "BLOCK_MEDIUM_AND_ABOVE",
+            Threshold::BlockLowAndAbove => "BLOCK_LOW_AND_ABOVE",
+        }
+    }
+}
+
+/// What becomes of a chunk the safety filters block, with `--on-blocked`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnBlocked {
+    /// Fail the chunk: it is retried in smaller parts, sent to the
+    /// `--fallback` models, then reported with the failed chunks
+    #[default]
+    Flag,
+    /// Send it again once with the filters off, then as with flag; a
+    /// streamed chunk is failed at once
+    Relax,
+}
+
+#[derive(Serialize)]
+pub struct SafetySetting {
+    category: &'static str,
+    threshold: &'static str,
+}
+
+/// The `safetySettings` of `threshold` in every category, none for the
+/// defaults of the model.
+pub fn settings(threshold: Option<Threshold>) -> Vec<SafetySetting> {
+    let Some(threshold) = threshold else {
+        return Vec::new();
+    };
+    CATEGORIES
+        .iter()
+        .map(|category| SafetySetting {
+            category,
+            threshold: threshold.name(),
+        })
+        .collect()
+}
+
+/// Whether the settings of `threshold` can be relaxed for a blocked chunk.
+pub fn relaxable(threshold: Option<Threshold>) -> bool {
+    !matches!(threshold, Some(Threshold::Off | Threshold::BlockNone))
+}
+
+/// Whether a candidate that finished for `finish_reason` was withheld by
+/// the filters.
+pub fn is_blocked(finish_reason: &str) -> bool {
+    BLOCKED.contains(&finish_reason)
+}
@@ -0,0 +1,35 @@
+use reqwest::{Error, Response};
+use std::ops::ControlFlow;
+
+/// Pass the `data:` payload of each server-sent event to `on_data` as the
+/// response body arrives, until it breaks.
+pub async fn for_each_data(
+    response: Response,
+    mut on_data: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<(), Error> {
+    for_each_line(response, |line| match line.strip_prefix("data:") {
+        Some(data) => on_data(data.trim_start()),
+        None => ControlFlow::Continue(()),
+    })
+    .await
+}
+
+/// Pass each line of the response body, without its line ending, to
+/// `on_line` as it arrives. When `on_line` breaks, the rest of the body is
+/// not read and the connection is closed, which stops the generation.
+pub async fn for_each_line(
+    mut response: Response,
+    mut on_line: impl FnMut(&str) -> ControlFlow<()>,
+) -> Result<(), Error> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            if on_line(String::from_utf8_lossy(&line).trim_end()).is_break() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
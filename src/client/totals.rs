@@ -0,0 +1,223 @@
+use crate::client::pricing;
+use crate::translate::translator::Stats;
+use log::info;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Token usage summed over every request of a run.
+pub struct Totals {
+    started: Instant,
+    requests: AtomicU64,
+    retries: AtomicU64,
+    timeouts: AtomicU64,
+    escalations: AtomicU64,
+    splits: AtomicU64,
+    prompt_tokens: AtomicU64,
+    output_tokens: AtomicU64,
+    total_tokens: AtomicU64,
+    cached_tokens: AtomicU64,
+    /// prompt, output and cached tokens by the model the requests were sent
+    /// to, each priced at its own list price
+    models: Mutex<BTreeMap<String, (u64, u64, u64)>>,
+}
+
+/// The totals of a run at its end, as written by `--stats-out`.
+#[derive(Serialize)]
+pub struct Summary {
+    pub model: String,
+    pub requests: u64,
+    pub retries: u64,
+    /// requests cancelled by `--request-timeout`
+    pub timeouts: u64,
+    /// paragraphs sent to a `--fallback` model
+    pub escalations: u64,
+    /// chunks translated again in halves for a response cut short at the
+    /// output token limit, a sign that `--lines` is too high
+    pub splits: u64,
+    pub prompt_tokens: u64,
+    pub output_tokens: u64,
+    pub total_tokens: u64,
+    /// prompt tokens read from a cached preamble with `--cache-prompt`, part
+    /// of `prompt_tokens`
+    pub cached_tokens: u64,
+    pub seconds: f64,
+    /// Estimated from the list prices of the models the requests were sent
+    /// to, when they are all known.
+    pub cost: Option<f64>,
+    /// The totals of the `--refine` pass, apart from the translation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refine: Option<Box<Summary>>,
+    /// The totals of the drafter of `--draft-with`, whose drafts the model
+    /// post-edits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub draft: Option<Box<Summary>>,
+}
+
+impl Default for Totals {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::default(),
+            retries: AtomicU64::default(),
+            timeouts: AtomicU64::default(),
+            escalations: AtomicU64::default(),
+            splits: AtomicU64::default(),
+            prompt_tokens: AtomicU64::default(),
+            output_tokens: AtomicU64::default(),
+            total_tokens: AtomicU64::default(),
+            cached_tokens: AtomicU64::default(),
+            models: Mutex::default(),
+        }
+    }
+}
+
+impl Totals {
+    /// Count a request sent to `model`.
+    pub fn add(&self, model: &str, stats: &Stats) {
+        let (prompt, output, cached) = (
+            stats.prompt_tokens.max(0) as u64,
+            stats.output_tokens.max(0) as u64,
+            stats.cached_tokens.max(0) as u64,
+        );
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.prompt_tokens.fetch_add(prompt, Ordering::Relaxed);
+        self.output_tokens.fetch_add(output, Ordering::Relaxed);
+        self.total_tokens
+            .fetch_add(stats.total_tokens.max(0) as u64, Ordering::Relaxed);
+        // prompt tokens read from a cached preamble
+        self.cached_tokens.fetch_add(cached, Ordering::Relaxed);
+        let mut models = self.models.lock().unwrap();
+        let tokens = models.entry(model.to_string()).or_default();
+        tokens.0 += prompt;
+        tokens.1 += output;
+        tokens.2 += cached;
+    }
+
+    /// Count a request sent again, after a 429 or for a failed chunk.
+    pub fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `requests` requests sent again, one for each paragraph of a
+    /// chunk translated again alone.
+    pub fn retry_each(&self, requests: usize) {
+        self.retries.fetch_add(requests as u64, Ordering::Relaxed);
+    }
+
+    /// Count a request cancelled for taking longer than the timeout.
+    pub fn timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a chunk split in halves for a response cut short.
+    pub fn split(&self) {
+        self.splits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a paragraph sent to a fallback model.
+    pub fn escalate(&self) {
+        self.escalations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub fn tokens(&self) -> u64 {
+        self.total_tokens.load(Ordering::Relaxed)
+    }
+
+    /// The totals of a run of `model`, their cost that of the requests to
+    /// each model they were sent to, unknown when one of them has no price.
+    pub fn summary(&self, model: &str) -> Summary {
+        let models = self.models.lock().unwrap();
+        let cost = match models.is_empty() {
+            true => pricing::price(model).map(|_| 0.0),
+            false => models
+                .iter()
+                .map(|(model, (prompt, output, cached))| {
+                    pricing::price(model)
+                        .map(|price| price.cost(*prompt, *output) - price.discount(*cached))
+                })
+                .sum(),
+        };
+        Summary {
+            model: model.to_string(),
+            requests: self.requests.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            escalations: self.escalations.load(Ordering::Relaxed),
+            splits: self.splits.load(Ordering::Relaxed),
+            prompt_tokens: self.prompt_tokens.load(Ordering::Relaxed),
+            output_tokens: self.output_tokens.load(Ordering::Relaxed),
+            total_tokens: self.total_tokens.load(Ordering::Relaxed),
+            cached_tokens: self.cached_tokens.load(Ordering::Relaxed),
+            seconds: self.started.elapsed().as_secs_f64(),
+            cost,
+            refine: None,
+            draft: None,
+        }
+    }
+}
+
+impl Summary {
+    pub fn log(&self) {
+        let cached = match self.cached_tokens {
+            0 => String::new(),
+            cached => format!(" cached tokens: {}", cached),
+        };
+        info!(
+            "requests: {} prompt tokens: {}{} output tokens: {} total tokens: {}",
+            self.requests, self.prompt_tokens, cached, self.output_tokens, self.total_tokens
+        );
+        let cost = match self.cost {
+            Some(cost) => format!(" estimated cost: ${:.4}", cost),
+            None => String::new(),
+        };
+        let timeouts = match self.timeouts {
+            0 => String::new(),
+            timeouts => format!(" timeouts: {}", timeouts),
+        };
+        let escalations = match self.escalations {
+            0 => String::new(),
+            escalations => format!(" escalations: {}", escalations),
+        };
+        let splits = match self.splits {
+            0 => String::new(),
+            splits => format!(" splits: {}", splits),
+        };
+        info!(
+            "retries: {}{}{}{} time: {:.1}sec{}",
+            self.retries, timeouts, escalations, splits, self.seconds, cost
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(prompt_tokens: i32) -> Stats {
+        Stats {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+            ..Stats::default()
+        }
+    }
+
+    #[test]
+    fn requests_are_priced_at_the_model_they_were_sent_to() {
+        let totals = Totals::default();
+        totals.add("gpt-4o-mini", &stats(1_000_000));
+        totals.add("gpt-4o", &stats(1_000_000));
+        let summary = totals.summary("gpt-4o-mini");
+        assert_eq!(summary.requests, 2);
+        assert!((summary.cost.unwrap() - 2.65).abs() < 1e-9);
+
+        totals.add("local", &stats(1_000));
+        assert_eq!(totals.summary("gpt-4o-mini").cost, None);
+    }
+}
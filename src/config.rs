@@ -0,0 +1,312 @@
+/// Name of the configuration file looked up in the current directory.
+pub const FILE_NAME: &str = "trans-epub.toml";
+
+/// A value of the configuration file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    /// an integer or a float, as written
+    Number(String),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// Options of a table, as keys and values in file order.
+type Table = Vec<(String, Value)>;
+
+/// The options of a `trans-epub.toml`: the keys at the top apply to every
+/// translating subcommand, the keys of a `[gemini]`, `[open-ai]`,
+/// `[ollama]`, `[anthropic]` or `[deepl]` table only to that subcommand.
+///
+/// Only the part of TOML that options need is read: keys and tables, basic
+/// and literal strings, numbers, booleans and arrays of them.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// tables by name, `None` for the top of the file
+    tables: Vec<(Option<String>, Table)>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut tables = vec![(None, Vec::new())];
+        let mut lines = text.lines().enumerate();
+        while let Some((number, line)) = lines.next() {
+            let error = |message: &str| format!("line {}: {}", number + 1, message);
+            let mut line = strip_comment(line).trim().to_string();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("unclosed table header"))?;
+                tables.push((Some(key(name.trim())), Vec::new()));
+                continue;
+            }
+            let (name, _) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`"))?;
+            let name = key(name.trim().trim_matches('"'));
+            // an array may run over several lines
+            while depth(&line) > 0 {
+                let Some((_, next)) = lines.next() else {
+                    return Err(error("unclosed array"));
+                };
+                line.push(' ');
+                line.push_str(strip_comment(next).trim());
+            }
+            let (_, value) = line.split_once('=').unwrap();
+            let (value, rest) = parse_value(value.trim()).map_err(|e| error(&e))?;
+            if !rest.trim().is_empty() {
+                return Err(error(&format!("unexpected `{}`", rest.trim())));
+            }
+            tables.last_mut().unwrap().1.push((name, value));
+        }
+        Ok(Self { tables })
+    }
+
+    /// The options of `subcommand`: its table after the top of the file, so
+    /// a key given in both takes the value of the table.
+    pub fn options(&self, subcommand: &str) -> Table {
+        let mut options = Table::new();
+        for (_, table) in self.tables.iter().filter(|(name, _)| name.is_none()).chain(
+            self.tables
+                .iter()
+                .filter(|(name, _)| name.as_deref() == Some(subcommand)),
+        ) {
+            for (key, value) in table {
+                options.retain(|(known, _)| known != key);
+                options.push((key.clone(), value.clone()));
+            }
+        }
+        options
+    }
+}
+
+/// Keys are the long names of the options, with `_` or `-` alike.
+fn key(name: &str) -> String {
+    name.replace('_', "-")
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => (),
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Brackets left open on a line, outside strings.
+fn depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => (),
+        }
+        escaped = false;
+    }
+    depth
+}
+
+/// Parse the value at the start of `text` and return it with the rest.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('u') => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| chars.next())
+                            .map(|(_, c)| c)
+                            .collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid escape `\\u{}`", hex))?;
+                        value.push(c);
+                    }
+                    c => return Err(format!("invalid escape `\\{}`", c.unwrap_or(' '))),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unclosed string".to_string());
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unclosed string")?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), rest));
+            }
+            let (value, tail) = parse_value(rest)?;
+            values.push(value);
+            rest = tail.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+    let end = text
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "" => return Err("missing value".to_string()),
+        word if word.replace('_', "").parse::<f64>().is_ok() => {
+            Value::Number(word.replace('_', ""))
+        }
+        word => return Err(format!("unexpected `{}`, strings are quoted", word)),
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> Value {
+        Value::String(text.to_string())
+    }
+
+    #[test]
+    fn comments_end_outside_strings() {
+        assert_eq!(strip_comment("model = \"a#b\" # c"), "model = \"a#b\" ");
+        assert_eq!(strip_comment("model = 'a#b'#c"), "model = 'a#b'");
+        assert_eq!(
+            strip_comment(r##"name = "a\"#b" # c"##),
+            r##"name = "a\"#b" "##
+        );
+        assert_eq!(strip_comment("# all of it"), "");
+        let config = Config::parse("model = \"gpt#4\" # the model\n").unwrap();
+        assert_eq!(
+            config.options("open-ai"),
+            vec![("model".into(), string("gpt#4"))]
+        );
+    }
+
+    #[test]
+    fn brackets_are_counted_outside_strings() {
+        assert_eq!(depth("language = ["), 1);
+        assert_eq!(depth("language = [\"[\", ']'"), 1);
+        assert_eq!(depth("language = [[1], [2]]"), 0);
+        assert_eq!(depth("]"), -1);
+    }
+
+    #[test]
+    fn values_are_parsed() {
+        assert_eq!(
+            parse_value("\"a\\n\\u00e9\" rest"),
+            Ok((string("a\né"), " rest"))
+        );
+        assert_eq!(parse_value(r"'C:\books'"), Ok((string(r"C:\books"), "")));
+        assert_eq!(parse_value("1_000"), Ok((Value::Number("1000".into()), "")));
+        assert_eq!(parse_value("0.5,"), Ok((Value::Number("0.5".into()), ",")));
+        assert_eq!(parse_value("true"), Ok((Value::Bool(true), "")));
+        assert_eq!(
+            parse_value("[\"French\", 'German',]"),
+            Ok((Value::Array(vec![string("French"), string("German")]), ""))
+        );
+        assert!(parse_value("French")
+            .unwrap_err()
+            .contains("strings are quoted"));
+        assert_eq!(parse_value("\"French"), Err("unclosed string".to_string()));
+        assert!(parse_value("\"\\x\"")
+            .unwrap_err()
+            .contains("invalid escape"));
+        assert_eq!(parse_value(""), Err("missing value".to_string()));
+    }
+
+    #[test]
+    fn arrays_run_over_lines() {
+        let config = Config::parse(
+            "language = [\n  \"French\", # first\n  \"German\",\n]\nmax_tokens = 100\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.options("gemini"),
+            vec![
+                (
+                    "language".into(),
+                    Value::Array(vec![string("French"), string("German")])
+                ),
+                ("max-tokens".into(), Value::Number("100".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn tables_apply_to_their_subcommand() {
+        let config = Config::parse(
+            "model = \"top\"\nlanguage = \"French\"\n\n[gemini]\nmodel = \"gemini-pro\"\n\n[open-ai]\nmodel = \"gpt-4o\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.options("gemini"),
+            vec![
+                ("language".into(), string("French")),
+                ("model".into(), string("gemini-pro")),
+            ]
+        );
+        assert_eq!(
+            config.options("open-ai"),
+            vec![
+                ("language".into(), string("French")),
+                ("model".into(), string("gpt-4o")),
+            ]
+        );
+        assert_eq!(
+            config.options("mock"),
+            vec![
+                ("model".into(), string("top")),
+                ("language".into(), string("French")),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_reported_by_number() {
+        let error = |text: &str| Config::parse(text).unwrap_err();
+        assert_eq!(error("\n[gemini\n"), "line 2: unclosed table header");
+        assert_eq!(error("model\n"), "line 1: expected `key = value`");
+        assert_eq!(error("language = [\"French\",\n"), "line 1: unclosed array");
+        assert_eq!(error("model = \"a\" \"b\"\n"), "line 1: unexpected `\"b\"`");
+        assert_eq!(
+            error("a = 1\nmodel = gpt\n"),
+            "line 2: unexpected `gpt`, strings are quoted"
+        );
+        assert_eq!(error("model = \n"), "line 1: missing value");
+    }
+}
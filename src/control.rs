@@ -0,0 +1,312 @@
+use crate::client::limiter::Limiter;
+use crate::error::Error;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// What a run is told through `--control` while it goes on: whether to hold
+/// back the chunks not sent yet, and which model to send them to.
+#[derive(Default)]
+pub struct Control {
+    paused: AtomicBool,
+    notify: Notify,
+    model: Mutex<Option<String>>,
+}
+
+impl Control {
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the run is not paused.
+    pub async fn resumed(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // registered before the flag is read, so a resume in between is not
+        // missed
+        notified.as_mut().enable();
+        if self.is_paused() {
+            notified.await;
+        }
+    }
+
+    /// The model the requests are sent to instead of that of the run.
+    pub fn model(&self) -> Option<String> {
+        self.model.lock().unwrap().clone()
+    }
+
+    pub fn set_model(&self, model: Option<String>) {
+        *self.model.lock().unwrap() = model;
+    }
+}
+
+/// The `--control` socket of a run, taking commands until it is dropped,
+/// when the socket file is removed.
+pub struct Socket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Socket {
+    /// Listen on the Unix socket `path` for the commands to `control` and
+    /// to the `limiter` of a run of `model`, one a line, each answered with
+    /// a line:
+    ///
+    /// - `pause` holds back the chunks not sent yet; those in flight finish
+    /// - `resume` sends them again
+    /// - `requests <N>` changes `--requests`
+    /// - `model <NAME>` sends the next requests to another model
+    /// - `status` tells which of these is in effect
+    #[cfg(unix)]
+    pub fn open(
+        path: &Path,
+        control: Arc<Control>,
+        limiter: Arc<Limiter>,
+        model: String,
+    ) -> Result<Self, Error> {
+        use std::os::unix::fs::FileTypeExt;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::UnixListener;
+
+        // left behind by a run that did not end cleanly
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        info!("control: listening on {}", path.display());
+        let task = tokio::spawn(async move {
+            // each connection is served by a task of its own, so a client
+            // left open does not keep the others out; aborted with the loop
+            let mut connections = tokio::task::JoinSet::new();
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            warn!("control: {}", e);
+                            continue;
+                        }
+                    },
+                    // reap the connections that ended
+                    Some(_) = connections.join_next() => continue,
+                };
+                let (control, limiter, model) = (control.clone(), limiter.clone(), model.clone());
+                connections.spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply = command(&line, &control, &limiter, &model);
+                        if writer
+                            .write_all(format!("{}\n", reply).as_bytes())
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+            task,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(
+        _path: &Path,
+        _control: Arc<Control>,
+        _limiter: Arc<Limiter>,
+        _model: String,
+    ) -> Result<Self, Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "--control needs Unix domain sockets",
+        )
+        .into())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Carry out a command of the socket and give its answer.
+fn command(line: &str, control: &Control, limiter: &Limiter, model: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let changes = words != ["status"];
+    let reply = match words.as_slice() {
+        ["pause"] => {
+            control.pause();
+            "paused, the requests in flight finish".to_string()
+        }
+        ["resume"] => {
+            control.resume();
+            "resumed".to_string()
+        }
+        ["requests", requests] => match requests.parse::<usize>() {
+            Ok(requests) if requests > 0 => {
+                limiter.set_requests(requests);
+                format!("requests {}", requests)
+            }
+            _ => return format!("error: {} is not a number of requests", requests),
+        },
+        ["model", name] => {
+            control.set_model(Some(name.to_string()).filter(|name| name != model));
+            format!("model {}", name)
+        }
+        ["status"] => {
+            let (requests, max) = limiter.requests();
+            format!(
+                "{}, {} of {} requests, model {}",
+                match control.is_paused() {
+                    true => "paused",
+                    false => "running",
+                },
+                requests,
+                max,
+                control.model().as_deref().unwrap_or(model)
+            )
+        }
+        _ => {
+            return format!(
+                "error: unknown command {:?}, expected pause, resume, requests <N>, model <NAME> or status",
+                line.trim()
+            )
+        }
+    };
+    if changes {
+        info!("control: {}", reply);
+    }
+    reply
+}
+
+/// Send a `command` to the `--control` socket at `path` and give its answer.
+#[cfg(unix)]
+pub async fn send(path: &Path, command: &str) -> Result<String, Error> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut reply = String::new();
+    BufReader::new(reader).read_line(&mut reply).await?;
+    Ok(reply.trim_end().to_string())
+}
+
+#[cfg(not(unix))]
+pub async fn send(_path: &Path, _command: &str) -> Result<String, Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--control needs Unix domain sockets",
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_change_the_run() {
+        let control = Control::default();
+        let limiter = Limiter::new(4);
+        let command = |line: &str| command(line, &control, &limiter, "gpt-4o");
+        assert_eq!(command("status"), "running, 4 of 4 requests, model gpt-4o");
+        assert_eq!(command("pause"), "paused, the requests in flight finish");
+        assert!(control.is_paused());
+        assert_eq!(command("  resume "), "resumed");
+        assert!(!control.is_paused());
+        assert_eq!(command("requests 2"), "requests 2");
+        assert_eq!(limiter.requests(), (2, 2));
+        assert_eq!(
+            command("requests 0"),
+            "error: 0 is not a number of requests"
+        );
+        assert_eq!(
+            command("requests many"),
+            "error: many is not a number of requests"
+        );
+        assert_eq!(limiter.requests(), (2, 2));
+        assert_eq!(command("model gpt-4o-mini"), "model gpt-4o-mini");
+        assert_eq!(control.model().as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(
+            command("status"),
+            "running, 2 of 2 requests, model gpt-4o-mini"
+        );
+        // back to the model of the run
+        assert_eq!(command("model gpt-4o"), "model gpt-4o");
+        assert_eq!(control.model(), None);
+        for unknown in ["stop", "", "pause now", "model"] {
+            assert!(
+                command(unknown).starts_with("error: unknown command"),
+                "{:?}",
+                unknown
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn paused_runs_wait_for_resume() {
+        let control = Arc::new(Control::default());
+        control.pause();
+        let waiting = tokio::spawn({
+            let control = control.clone();
+            async move { control.resumed().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        control.resume();
+        waiting.await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connections_are_served_at_once() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("trans-epub-control-{}", std::process::id()));
+        let control = Arc::new(Control::default());
+        let socket = Socket::open(
+            &path,
+            control.clone(),
+            Arc::new(Limiter::new(1)),
+            "model".to_string(),
+        )
+        .unwrap();
+        // a client that connects and sends half a command
+        let mut idle = UnixStream::connect(&path).await.unwrap();
+        idle.write_all(b"pau").await.unwrap();
+        let reply = tokio::time::timeout(std::time::Duration::from_secs(5), send(&path, "pause"));
+        assert_eq!(
+            reply
+                .await
+                .expect("served while the other is open")
+                .unwrap(),
+            "paused, the requests in flight finish"
+        );
+        assert!(control.is_paused());
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
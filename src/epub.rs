@@ -1,13 +1,44 @@
+pub mod attributes;
+pub mod chapter;
+pub mod cover;
+pub mod diff;
+pub mod epub3;
+pub mod estimate;
+pub mod inspect;
+pub mod layout;
+pub mod markup;
+pub mod metadata;
+pub mod notes;
+#[cfg(feature = "ocr")]
+pub mod ocr;
+pub mod package;
+pub mod provenance;
+pub mod retranslate;
+pub mod stitch;
+pub mod svg;
+pub mod toc;
+pub mod validate;
+
+use crate::epub::cover::Cover;
+use crate::epub::epub3::Nav;
+use crate::epub::layout::Layout;
+use crate::epub::notes::Links;
+use crate::epub::package::{read_entry, Package};
+use crate::epub::stitch::{translate_ends, Ends};
+use crate::error::Error;
 use crate::translate::translator::Translator;
+use futures::{stream, StreamExt};
 use log::{debug, info};
+use quick_xml::escape::{partial_escape, resolve_html5_entity};
 use quick_xml::events::{BytesText, Event};
 use quick_xml::{Reader, Writer};
 use regex::Regex;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Read, Seek, Write};
 use std::path::PathBuf;
 use zip::write::SimpleFileOptions;
-use zip::ZipArchive;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
 pub struct Epub {
     input_path: PathBuf,
@@ -22,57 +53,437 @@ impl Epub {
         }
     }
 
-    pub async fn translate(self, translator: Translator) {
-        debug!("translate start");
-        let input_file = File::open(self.input_path).expect("input file open fail");
-        let mut archive = ZipArchive::new(input_file).expect("input file unzip fail");
+    /// Translate the book, writing whatever could be translated even when
+    /// some chunks failed, with a report of those next to the output as
+    /// `<output>.failures.json`.
+    pub async fn translate(self, translator: &Translator) -> Result<(), Error> {
+        let input = File::open(&self.input_path)?;
+        let output = File::create(&self.output_path)?;
+        let result = translate_epub(input, output, translator).await;
+        translator
+            .context()
+            .report
+            .conclude(&self.output_path, result)
+    }
+}
+
+/// Translate an EPUB held in memory and return the repackaged EPUB.
+///
+/// It takes the [`Translator`] rather than its [`Context`](crate::translate::translator::Context):
+/// the context holds the options of a run but not the backend that sends
+/// the requests, which the translator pairs with it. [`Epub::translate`] does
+/// not go through it either; both hand their reader and writer to
+/// [`translate_epub`], so a book on disk is streamed from its file rather than
+/// read into memory whole.
+pub async fn translate_epub_bytes(input: &[u8], translator: &Translator) -> Result<Vec<u8>, Error> {
+    let mut output = Cursor::new(Vec::new());
+    translate_epub(Cursor::new(input), &mut output, translator).await?;
+    Ok(output.into_inner())
+}
+
+/// The paragraphs of the spine documents read from `input`, in reading
+/// order, as they would be sent for translation.
+pub async fn spine_paragraphs<R: Read + Seek>(input: R) -> Result<Vec<String>, Error> {
+    let mut archive = ZipArchive::new(input)?;
+    let package = Package::read(&mut archive)?;
+    let mut paragraphs = Vec::new();
+    for name in &package.spine {
+        let content = read_entry(&mut archive, name)?;
+        paragraphs.extend(document_lines(name, &content)?);
+    }
+    Ok(paragraphs)
+}
 
-        let mut file_contents = Vec::new();
-        for i in 0..archive.len() {
-            let mut file = archive.by_index(i).unwrap();
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer).unwrap();
-            file_contents.push((file.name().to_string(), buffer));
+/// Translate the EPUB read from `input` into `output`.
+///
+/// Up to `max_chapters_in_flight` entries are translated at once and written
+/// in archive order as soon as they complete, so memory use is bounded by the
+/// chapters in flight rather than by the size of the book.
+pub async fn translate_epub<R: Read + Seek, W: Write + Seek>(
+    input: R,
+    output: W,
+    translator: &Translator,
+) -> Result<(), Error> {
+    debug!("translate start");
+    let mut archive = ZipArchive::new(input)?;
+    let mut zip = ZipWriter::new(output);
+    let context = translator.context();
+    let package = if context.chapters.is_empty()
+        && context.skip.is_empty()
+        && context.chapter_languages.is_empty()
+        && !context.translate_metadata
+        && !context.epub3
+        && !context.retitle_cover
+    {
+        None
+    } else {
+        Some(Package::read(&mut archive)?)
+    };
+    let package_path = package.as_ref().map(|package| package.path.clone());
+    let spine = package
+        .as_ref()
+        .map_or_else(Vec::new, |package| package.spine.clone());
+    let navigation: Vec<String> = match &package {
+        Some(package) if context.translate_metadata => {
+            package.navigation().map(|item| item.href.clone()).collect()
         }
+        _ => Vec::new(),
+    };
+    let nav = package
+        .as_ref()
+        .filter(|_| context.epub3)
+        .and_then(Nav::missing);
+    let mut names: Vec<String> = archive.file_names().map(String::from).collect();
+    let cover = match &package {
+        Some(package) if context.retitle_cover => Cover::new(package, translator).await,
+        _ => None,
+    };
+    names.extend(cover.as_ref().map(|cover| cover.path.clone()));
+    let ends = if translator.context().stitch_paragraphs {
+        translate_ends(&mut archive, translator).await?
+    } else {
+        HashMap::new()
+    };
+    #[cfg(feature = "ocr")]
+    let transcribed = if context.ocr {
+        ocr::transcribe(&mut archive, translator).await
+    } else {
+        HashMap::new()
+    };
+
+    let size = archive.len();
+    // the paragraphs of each chapter are counted as it is read
+    let chapters = archive
+        .file_names()
+        .filter(|name| {
+            is_content_document(name)
+                && !navigation.iter().any(|href| href == name)
+                && chapter::language(context, &spine, name).is_some()
+        })
+        .count();
+    context.progress.start_reading(chapters);
 
-        let mut translated_contents = Vec::new();
-        let mut count = 1;
-        let size = file_contents.len();
-        for (name, content) in file_contents {
-            info!("{}/{} {}", count, size, name);
-            count += 1;
-            if name.ends_with(".xhtml")
-                || name.ends_with(".xml")
-                || name.ends_with(".html")
-                || name.ends_with(".htm")
-            {
-                let content = strip_xml_content(&content);
-                let lines = translate_lines(&content).await;
-                let lines = translator.translate(lines).await;
-                let translated_content = translate_xml_content(lines, &content).await;
-                translated_contents.push((name, translated_content));
-            } else {
-                translated_contents.push((name, content));
+    let mut entries = stream::iter(0..size)
+        .map(|i| {
+            let entry = read_entry_at(&mut archive, i);
+            let ends = &ends;
+            let spine = &spine;
+            let package_path = &package_path;
+            let navigation = &navigation;
+            let names = &names;
+            let nav = &nav;
+            let cover = &cover;
+            #[cfg(feature = "ocr")]
+            let transcribed = &transcribed;
+            async move {
+                let (name, mut content) = entry?;
+                info!("{}/{} {}", i + 1, size, name);
+                if package_path.as_ref() == Some(&name) {
+                    if context.translate_metadata {
+                        content = metadata::translate(&content, translator)
+                            .await
+                            .map_err(in_entry(&name))?;
+                    }
+                    if context.epub3 {
+                        let href = nav.as_ref().map(|nav| nav.href.as_str());
+                        content = epub3::package(&content, &name, &context.language, names, href);
+                    }
+                    if let Some(cover) = cover {
+                        content = cover.package(&content);
+                    }
+                    return Ok((name, content, false));
+                }
+                if navigation.contains(&name) {
+                    // after the chapters, to reuse the translated headings
+                    return Ok((name, content, true));
+                }
+                let language = chapter::language(context, spine, &name);
+                let content = match language {
+                    Some(language) if is_content_document(&name) => {
+                        let mut content = translate_document(
+                            &name,
+                            &content,
+                            translator,
+                            ends.get(&name),
+                            language,
+                        )
+                        .await?;
+                        #[cfg(feature = "ocr")]
+                        if !transcribed.is_empty() {
+                            content =
+                                ocr::caption(&name, &content, transcribed, translator, language)
+                                    .await;
+                        }
+                        if let Some(cover) = cover {
+                            content = cover.point(&name, &content);
+                        }
+                        if context.epub3 {
+                            content = epub3::set_language(&content, language);
+                        }
+                        context.progress.chapter_done(&name, &context.totals);
+                        content
+                    }
+                    Some(language)
+                        if name.ends_with(".svg") && !context.translate_attributes.is_empty() =>
+                    {
+                        translate_svg(&content, translator, language).await
+                    }
+                    None => {
+                        info!("skip {}", name);
+                        content
+                    }
+                    _ => content,
+                };
+                Ok::<_, Error>((name, content, false))
             }
+        })
+        .buffered(translator.context().max_chapters_in_flight.max(1));
+
+    let mut deferred = Vec::new();
+    let mut ncx = None;
+    while let Some(entry) = entries.next().await {
+        let (name, content, defer) = entry?;
+        if defer {
+            deferred.push((name, content));
+            continue;
         }
-        debug!("translate end");
+        if nav.as_ref().is_some_and(|nav| nav.ncx == name) {
+            ncx = Some(content.clone());
+        }
+        zip.start_file(name.as_str(), file_options(&name))?;
+        zip.write_all(&content)?;
+    }
+    drop(entries);
+    for (name, content) in deferred {
+        info!("toc {}", name);
+        let content = if name.ends_with(".ncx") {
+            toc::translate_ncx(&content, translator).await
+        } else if context.epub3 {
+            let content = toc::translate_nav(&content, translator).await;
+            epub3::set_language(&content, &context.language)
+        } else {
+            toc::translate_nav(&content, translator).await
+        };
+        if nav.as_ref().is_some_and(|nav| nav.ncx == name) {
+            ncx = Some(content.clone());
+        }
+        zip.start_file(name.as_str(), file_options(&name))?;
+        zip.write_all(&content)?;
+    }
+    if let Some(cover) = &cover {
+        zip.start_file(cover.path.as_str(), SimpleFileOptions::default())?;
+        zip.write_all(&cover.svg)?;
+    }
+    if let (Some(nav), Some(ncx)) = (&nav, &ncx) {
+        info!("nav {} from {}", nav.path, nav.ncx);
+        zip.start_file(nav.path.as_str(), SimpleFileOptions::default())?;
+        zip.write_all(&epub3::nav_from_ncx(ncx, &context.language))?;
+    }
+    debug!("translate end");
+    zip.finish()?;
+    translator.finish()
+}
 
-        debug!("output file start");
-        let file = File::create(self.output_path).expect("output file open fail");
-        let mut zip = zip::ZipWriter::new(file);
+/// The `mimetype` entry is stored uncompressed, as the EPUB container wants
+/// it.
+fn file_options(name: &str) -> SimpleFileOptions {
+    match name {
+        "mimetype" => SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+        _ => SimpleFileOptions::default(),
+    }
+}
 
-        for (name, content) in translated_contents {
-            zip.start_file(name, SimpleFileOptions::default())
-                .expect("output file zip fail");
-            zip.write_all(&content).expect("output file zip fail");
-        }
+fn read_entry_at<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    index: usize,
+) -> Result<(String, Vec<u8>), Error> {
+    let mut file = archive.by_index(index)?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    Ok((file.name().to_string(), content))
+}
+
+async fn translate_document(
+    name: &str,
+    content: &[u8],
+    translator: &Translator,
+    ends: Option<&Ends>,
+    language: &str,
+) -> Result<Vec<u8>, Error> {
+    let content = strip_xml_content(content).map_err(in_entry(name))?;
+    let markup = translator.context().preserve_markup;
+    translator.context().notes.record(name, &content, markup);
+    let lines = translate_lines(&content, markup).map_err(in_entry(name))?;
+    translator.context().progress.read(lines.len());
+    let provenance = translator.context().provenance.as_ref();
+    let original_lines = match provenance {
+        Some(_) => lines.clone(),
+        None => Vec::new(),
+    };
+    let sources = match translator.context().translate_metadata {
+        true => lines.iter().map(|line| markup::strip(line)).collect(),
+        false => Vec::new(),
+    };
+    let originals = match translator.context().script_check {
+        Some(_) => lines.clone(),
+        None => Vec::new(),
+    };
+    let mut lines = match ends {
+        Some(ends) => ends.translate(lines, translator, language).await,
+        None => translator.translate_into(lines, language).await,
+    };
+    if !originals.is_empty() {
+        translator
+            .check_scripts(name, &originals, &mut lines, language)
+            .await;
+    }
+    if let Some(names) = &translator.context().names {
+        names.record(name, &lines);
+    }
+    if !sources.is_empty() {
+        let headings = toc::headings(&content);
+        let translated: Vec<String> = lines.iter().map(|line| markup::strip(line)).collect();
+        translator
+            .context()
+            .headings
+            .record(&headings, &sources, &translated);
+    }
+    let names = &translator.context().translate_attributes;
+    let values = attributes::collect(&content, names);
+    let values = if values.is_empty() {
+        values
+    } else {
+        translator.translate_into(values, language).await
+    };
+    let content = match provenance {
+        Some(provenance) => provenance
+            .apply(name, &content, markup, language, &original_lines, &lines)
+            .map_err(in_entry(name))?,
+        None => content,
+    };
+    let content = write_document(&content, lines, translator.context().layout, markup)
+        .map_err(in_entry(name))?;
+    let content = attributes::rewrite(&content, names, &values);
+    if names.is_empty() {
+        return Ok(content);
+    }
+    Ok(translate_svg(&content, translator, language).await)
+}
 
-        zip.finish().expect("output file zip fail");
-        debug!("output file end");
+/// Translate the texts of the SVGs of a document, or of an SVG file, with
+/// `--translate-attributes`.
+async fn translate_svg(content: &[u8], translator: &Translator, language: &str) -> Vec<u8> {
+    let labels = svg::collect(content);
+    if labels.is_empty() {
+        return content.to_vec();
     }
+    let labels = translator.translate_into(labels, language).await;
+    svg::rewrite(content, &labels)
+}
+
+/// Write the translated `lines` of a document in the `layout`.
+pub(crate) fn write_document(
+    content: &[u8],
+    lines: Vec<String>,
+    layout: Layout,
+    markup: bool,
+) -> Result<Vec<u8>, Error> {
+    match layout {
+        Layout::Inline => translate_xml_content(lines, content, markup),
+        layout => Ok(layout::rewrite(layout, lines, content, markup)),
+    }
+}
+
+fn is_content_document(name: &str) -> bool {
+    name.ends_with(".xhtml")
+        || name.ends_with(".xml")
+        || name.ends_with(".html")
+        || name.ends_with(".htm")
+}
+
+/// Elements translated as one paragraph each, with what they contain; `v`,
+/// `subtitle` and `text-author` are the verses and headings of FB2.
+pub(crate) const PARAGRAPHS: &[&str] = &[
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "figcaption",
+    "v",
+    "subtitle",
+    "text-author",
+];
+
+/// Text is decoded with the HTML5 entity set, since content documents often
+/// use `&nbsp;` and friends that plain XML does not define. An entity outside
+/// it, a bad character reference or a bare `&` is kept as written, so it is
+/// escaped on write as `&amp;` and the document comes out well-formed.
+pub(crate) fn unescape(e: &BytesText) -> String {
+    match e.unescape_with(resolve_html5_entity) {
+        Ok(text) => text.into_owned(),
+        Err(_) => unescape_leniently(&String::from_utf8_lossy(e)),
+    }
+}
+
+fn unescape_leniently(raw: &str) -> String {
+    let mut text = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('&') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let resolved = rest
+            .find(|c: char| c == ';' || c == '&' || c.is_whitespace())
+            .filter(|&end| rest[end..].starts_with(';'))
+            .and_then(|end| Some((end, resolve_entity(&rest[..end])?)));
+        match resolved {
+            Some((end, resolved)) => {
+                text.push_str(&resolved);
+                rest = &rest[end + 1..];
+            }
+            None => text.push('&'),
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+fn resolve_entity(name: &str) -> Option<String> {
+    let Some(number) = name.strip_prefix('#') else {
+        return resolve_html5_entity(name).map(str::to_string);
+    };
+    let code = match number.strip_prefix(['x', 'X']) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => number.parse().ok()?,
+    };
+    char::from_u32(code)
+        .filter(|&c| c != '\0')
+        .map(String::from)
+}
+
+/// Only `<`, `>` and `&` are escaped on write; everything else, decoded
+/// entities included, is written as UTF-8.
+pub(crate) fn escaped_text(text: &str) -> Event<'_> {
+    Event::Text(BytesText::from_escaped(partial_escape(text)))
+}
+
+/// An error of the entry `name` of a book, to tell which document is broken.
+pub(crate) fn in_entry(name: &str) -> impl Fn(Error) -> Error + '_ {
+    move |e| Error::Epub(format!("{}: {}", name, e))
 }
 
-fn strip_xml_content(content: &[u8]) -> Vec<u8> {
+/// The paragraphs of the content document `name`, as they are sent for
+/// translation without `--preserve-markup`.
+pub(crate) fn document_lines(name: &str, content: &[u8]) -> Result<Vec<String>, Error> {
+    strip_xml_content(content)
+        .and_then(|content| translate_lines(&content, false))
+        .map_err(in_entry(name))
+}
+
+pub(crate) fn strip_xml_content(content: &[u8]) -> Result<Vec<u8>, Error> {
     let mut reader = Reader::from_reader(content);
     reader.config_mut().trim_text(true);
 
@@ -93,13 +504,15 @@ fn strip_xml_content(content: &[u8]) -> Vec<u8> {
             },
             Ok(Event::Text(e)) if !is_rt => writer.write_event(Event::Text(e)).unwrap(),
             Ok(Event::Text(_)) if is_rt => continue,
-            event => writer.write_event(event.unwrap()).unwrap(),
+            event => writer.write_event(event?).unwrap(),
         }
     }
-    writer.into_inner().into_inner()
+    Ok(writer.into_inner().into_inner())
 }
 
-async fn translate_lines(content: &[u8]) -> Vec<String> {
+/// The paragraphs of a content document. With `markup`, the inline elements
+/// of a paragraph are kept as numbered markers (see [`markup`]).
+pub(crate) fn translate_lines(content: &[u8], markup: bool) -> Result<Vec<String>, Error> {
     let ignore_text = Regex::new(r"^[\s\p{Cc}\p{So}0-9[:punct:]–]*$").unwrap();
     let mut reader = Reader::from_reader(content);
     reader.config_mut().trim_text(true);
@@ -108,57 +521,80 @@ async fn translate_lines(content: &[u8]) -> Vec<String> {
     let mut translate_tag: String = String::new();
     let mut depth = 0;
     let mut translate: String = String::new();
+    let mut elements = 0;
+    let mut open: Vec<usize> = Vec::new();
     let mut result = Vec::new();
+    let mut links = Links::default();
 
     loop {
         match reader.read_event() {
             Ok(Event::Eof) => break,
             Ok(Event::Start(e)) => {
-                let tag = std::str::from_utf8(e.name().0).unwrap();
+                let tag = std::str::from_utf8(e.name().0).map_err(quick_xml::Error::from)?;
+                if is_translate {
+                    if *tag == translate_tag {
+                        depth += 1;
+                    }
+                    if !markup {
+                        links.start(&e);
+                    }
+                    if markup {
+                        elements += 1;
+                        open.push(elements);
+                        translate.push_str(&markup::open(elements));
+                    }
+                    continue;
+                }
                 match tag {
-                    "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "li" => {
-                        if !is_translate {
-                            translate_tag = tag.to_string();
-                            is_translate = true;
-                            translate = String::new();
-                        }
-                        if *tag == translate_tag {
-                            depth += 1;
-                        }
+                    tag if PARAGRAPHS.contains(&tag) => {
+                        translate_tag = tag.to_string();
+                        is_translate = true;
+                        translate = String::new();
+                        depth = 1;
+                        elements = 0;
                     }
                     _ => (),
                 }
             }
-            Ok(Event::End(e)) => {
-                let tag = std::str::from_utf8(e.name().0).unwrap();
-                match tag {
-                    "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "li" => {
-                        if *tag == translate_tag {
-                            depth -= 1;
-                            if depth == 0 {
-                                is_translate = false;
-                                if !ignore_text.is_match(&translate) {
-                                    result.push(translate.clone());
-                                }
-                            }
-                        }
+            Ok(Event::End(e)) if is_translate => {
+                let tag = std::str::from_utf8(e.name().0).map_err(quick_xml::Error::from)?;
+                if *tag == translate_tag {
+                    depth -= 1;
+                }
+                links.end();
+                if depth > 0 {
+                    if let Some(number) = open.pop().filter(|_| markup) {
+                        translate.push_str(&markup::close(number));
                     }
-                    _ => (),
+                    continue;
+                }
+                is_translate = false;
+                if !ignore_text.is_match(&markup::strip(&translate)) {
+                    result.push(translate.clone());
                 }
             }
+            Ok(Event::Empty(_)) if is_translate && markup => {
+                elements += 1;
+                translate.push_str(&markup::empty(elements));
+            }
             Ok(Event::Text(e)) => {
-                let original_text = e.unescape().unwrap().into_owned();
-                if is_translate {
+                let original_text = unescape(&e);
+                if is_translate && !links.inside() {
                     translate.push_str(&original_text);
                 }
             }
+            Err(e) => return Err(e.into()),
             _ => (),
         }
     }
-    result
+    Ok(result)
 }
 
-async fn translate_xml_content(lines: Vec<String>, content: &[u8]) -> Vec<u8> {
+pub(crate) fn translate_xml_content(
+    lines: Vec<String>,
+    content: &[u8],
+    markup: bool,
+) -> Result<Vec<u8>, Error> {
     let ignore_text = Regex::new(r"^[\s\p{Cc}\p{So}0-9[:punct:]–]*$").unwrap();
     let mut reader = Reader::from_reader(content);
     reader.config_mut().trim_text(true);
@@ -168,48 +604,68 @@ async fn translate_xml_content(lines: Vec<String>, content: &[u8]) -> Vec<u8> {
     let mut translate_tag: String = String::new();
     let mut depth = 0;
     let mut translate: String = String::new();
+    let mut elements: Vec<Event<'static>> = Vec::new();
     let mut index = 0;
+    let mut links = Links::default();
 
     loop {
         match reader.read_event() {
             Ok(Event::Eof) => break,
             Ok(Event::Start(e)) => {
-                let tag = std::str::from_utf8(e.name().0).unwrap();
+                let tag = std::str::from_utf8(e.name().0).map_err(quick_xml::Error::from)?;
+                if is_translate && !markup {
+                    links.start(&e);
+                }
                 match tag {
-                    "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "li" => {
-                        if !is_translate {
+                    tag if PARAGRAPHS.contains(&tag) => {
+                        if is_translate {
+                            elements.push(Event::Start(e.clone().into_owned()));
+                        } else {
                             translate_tag = tag.to_string();
                             is_translate = true;
                             translate = String::new();
+                            elements.clear();
                         }
                         if *tag == translate_tag {
                             depth += 1;
                         }
                         writer.write_event(Event::Start(e)).unwrap();
                     }
+                    _ if is_translate => {
+                        elements.push(Event::Start(e.clone().into_owned()));
+                        writer.write_event(Event::Start(e)).unwrap();
+                    }
                     _ => writer.write_event(Event::Start(e)).unwrap(),
                 }
             }
             Ok(Event::End(e)) => {
-                let tag = std::str::from_utf8(e.name().0).unwrap();
+                let tag = std::str::from_utf8(e.name().0).map_err(quick_xml::Error::from)?;
+                links.end();
                 match tag {
-                    "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "li" => {
+                    tag if PARAGRAPHS.contains(&tag) => {
                         if *tag == translate_tag {
                             depth -= 1;
                             if depth == 0 {
                                 is_translate = false;
                                 if !ignore_text.is_match(&translate) {
-                                    writer
-                                        .write_event(Event::Text(BytesText::new("<<")))
-                                        .unwrap();
-                                    writer
-                                        .write_event(Event::Text(BytesText::new(
-                                            lines.get(index).unwrap(),
-                                        )))
-                                        .unwrap();
-                                    writer
-                                        .write_event(Event::Text(BytesText::new(">>")))
-                                        .unwrap();
+                                    let line = lines.get(index).ok_or_else(|| {
+                                        Error::Epub(format!(
+                                            "{} translations for more paragraphs",
+                                            lines.len()
+                                        ))
+                                    })?;
+                                    if !line.is_empty() {
+                                        writer.write_event(escaped_text("<<")).unwrap();
+                                        match markup {
+                                            true => {
+                                                markup::write(&mut writer, line, &elements, false)
+                                            }
+                                            false => {
+                                                writer.write_event(escaped_text(line)).unwrap()
+                                            }
+                                        }
+                                        writer.write_event(escaped_text(">>")).unwrap();
+                                    }
                                     index += 1;
                                 }
                             }
@@ -220,16 +676,94 @@ async fn translate_xml_content(lines: Vec<String>, content: &[u8]) -> Vec<u8> {
                 }
             }
             Ok(Event::Text(e)) => {
-                let original_text = e.unescape().unwrap().into_owned();
-                if is_translate {
+                let original_text = unescape(&e);
+                if is_translate && !links.inside() {
                     translate.push_str(&original_text);
                 }
-                writer
-                    .write_event(Event::Text(BytesText::new(&original_text)))
-                    .unwrap();
+                writer.write_event(escaped_text(&original_text)).unwrap();
+            }
+            Ok(Event::Empty(e)) if is_translate => {
+                elements.push(Event::Empty(e.clone().into_owned()));
+                writer.write_event(Event::Empty(e)).unwrap();
             }
-            event => writer.write_event(event.unwrap()).unwrap(),
+            event => writer.write_event(event?).unwrap(),
         }
     }
-    writer.into_inner().into_inner()
+    Ok(writer.into_inner().into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTITIES: &[u8] = include_bytes!("../fixtures/entities.xhtml");
+
+    fn texts(content: &[u8]) -> Vec<String> {
+        let mut reader = Reader::from_reader(content);
+        let mut texts = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Eof => break,
+                Event::Text(e) => {
+                    texts.push(e.unescape_with(resolve_html5_entity).unwrap().into_owned())
+                }
+                _ => (),
+            }
+        }
+        texts
+    }
+
+    #[test]
+    fn entities_are_decoded_or_kept() {
+        let lines = document_lines("entities.xhtml", ENTITIES).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "Fish & chips <hot> \"fresh\" 'daily'",
+                "Café — naïve",
+                "Wait\u{a0}for\u{a0}it… © été",
+                "Rock &roll &foo; &#xZZ; and Q&A;",
+            ]
+        );
+    }
+
+    #[test]
+    fn entities_round_trip() {
+        let content = strip_xml_content(ENTITIES).unwrap();
+        let lines = translate_lines(&content, false).unwrap();
+        let output = write_document(&content, lines.clone(), Layout::Inline, false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Rock &amp;roll &amp;foo; &amp;#xZZ; and Q&amp;A;"));
+        assert!(output.contains("Fish &amp; chips &lt;hot&gt; \"fresh\" 'daily'"));
+        // every text decodes without the lenient fallback, since what it
+        // kept is escaped
+        let texts = texts(output.as_bytes());
+        for line in &lines {
+            let bilingual = format!("{}<<{}>>", line, line);
+            assert!(texts.contains(&bilingual), "{:?} in {:?}", bilingual, texts);
+        }
+    }
+
+    #[test]
+    fn malformed_chapters_are_errors() {
+        for content in [
+            &b"<html><body><p>Open</div></body></html>"[..],
+            b"<html><body><p>Cut short</p",
+            b"<html><body><\xff>Bad</\xff></body></html>",
+        ] {
+            let error = document_lines("text/broken.xhtml", content).unwrap_err();
+            assert!(
+                matches!(&error, Error::Epub(message) if message.starts_with("text/broken.xhtml: ")),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn missing_translations_are_errors() {
+        let content = strip_xml_content(b"<body><p>One</p><p>Two</p></body>").unwrap();
+        let error = write_document(&content, vec!["Eins".to_string()], Layout::Inline, false);
+        assert!(matches!(error, Err(Error::Epub(_))));
+    }
 }
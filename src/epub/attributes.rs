@@ -0,0 +1,137 @@
+use quick_xml::escape::resolve_html5_entity;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use regex::Regex;
+use std::io::Cursor;
+
+/// Attributes holding human-readable text, translated with
+/// `--translate-attributes`.
+pub const SAFE: &[&str] = &["alt", "title", "aria-label", "placeholder"];
+
+/// Attributes that scripts, styles and links depend on; they look like text
+/// but translating them breaks the book.
+const STRUCTURAL: &[&str] = &[
+    "id",
+    "class",
+    "style",
+    "href",
+    "src",
+    "srcset",
+    "lang",
+    "xml:lang",
+    "epub:type",
+    "role",
+    "name",
+    "type",
+    "rel",
+    "for",
+    "value",
+    "content",
+    "xlink:href",
+    "xmlns",
+];
+
+pub fn is_structural(name: &str) -> bool {
+    STRUCTURAL.contains(&name) || name.starts_with("data-") || name.starts_with("xmlns:")
+}
+
+/// Values of the `names` attributes worth translating, in document order.
+pub fn collect(content: &[u8], names: &[String]) -> Vec<String> {
+    let mut values = Vec::new();
+    if names.is_empty() {
+        return values;
+    }
+    let ignore_text = ignore_text();
+    let mut reader = Reader::from_reader(content);
+    loop {
+        match reader.read_event().unwrap() {
+            Event::Eof => break,
+            Event::Start(e) | Event::Empty(e) => {
+                for (_, value) in translatable(&e, names, &ignore_text) {
+                    values.push(value);
+                }
+            }
+            _ => (),
+        }
+    }
+    values
+}
+
+/// Write each collected attribute value as `original<<translated>>`, like
+/// the paragraphs; values with an empty translation are left alone.
+pub fn rewrite(content: &[u8], names: &[String], translated: &[String]) -> Vec<u8> {
+    if names.is_empty() || translated.is_empty() {
+        return content.to_vec();
+    }
+    let ignore_text = ignore_text();
+    let mut translated = translated.iter();
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(e)) => {
+                let element = rewrite_element(&e, names, &ignore_text, &mut translated);
+                writer.write_event(Event::Start(element)).unwrap();
+            }
+            Ok(Event::Empty(e)) => {
+                let element = rewrite_element(&e, names, &ignore_text, &mut translated);
+                writer.write_event(Event::Empty(element)).unwrap();
+            }
+            event => writer.write_event(event.unwrap()).unwrap(),
+        }
+    }
+    writer.into_inner().into_inner()
+}
+
+fn rewrite_element<'a>(
+    e: &BytesStart,
+    names: &[String],
+    ignore_text: &Regex,
+    translated: &mut impl Iterator<Item = &'a String>,
+) -> BytesStart<'static> {
+    let targets = translatable(e, names, ignore_text);
+    if targets.is_empty() {
+        return e.clone().into_owned();
+    }
+    let name = String::from_utf8_lossy(e.name().as_ref()).into_owned();
+    let mut element = BytesStart::new(name);
+    for attribute in e.attributes().with_checks(false).flatten() {
+        let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+        match targets.iter().find(|(name, _)| *name == key) {
+            Some((_, value)) => {
+                let line = translated.next().map(String::as_str).unwrap_or_default();
+                if line.is_empty() {
+                    element.push_attribute((key.as_str(), value.as_str()));
+                } else {
+                    let value = format!("{}<<{}>>", value, line);
+                    element.push_attribute((key.as_str(), value.as_str()));
+                }
+            }
+            None => element.push_attribute(attribute),
+        }
+    }
+    element
+}
+
+fn translatable(e: &BytesStart, names: &[String], ignore_text: &Regex) -> Vec<(String, String)> {
+    e.attributes()
+        .with_checks(false)
+        .flatten()
+        .filter_map(|attribute| {
+            let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+            if is_structural(&key) || !names.contains(&key) {
+                return None;
+            }
+            let value = attribute
+                .unescape_value_with(resolve_html5_entity)
+                .ok()?
+                .into_owned();
+            (!ignore_text.is_match(&value)).then_some((key, value))
+        })
+        .collect()
+}
+
+fn ignore_text() -> Regex {
+    Regex::new(r"^[\s\p{Cc}\p{So}0-9[:punct:]–]*$").unwrap()
+}
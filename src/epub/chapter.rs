@@ -0,0 +1,126 @@
+use crate::translate::translator::Context;
+use regex::Regex;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Language(String),
+    /// Pass the chapter through untranslated
+    Skip,
+}
+
+/// Chapters by 1-based spine position, `5`, `5-12` or `5..12`, open ended as
+/// `40..` or `..3`, or by path, `OEBPS/ch005.xhtml` or `ch005.xhtml`. A path
+/// with `*` or `?` is a pattern matched against the path and the file name,
+/// as `*copyright*`.
+#[derive(Clone, Debug)]
+pub enum Chapters {
+    /// 1-based spine positions, inclusive
+    Positions(usize, usize),
+    Path(String),
+}
+
+impl FromStr for Chapters {
+    type Err = String;
+
+    fn from_str(chapters: &str) -> Result<Self, Self::Err> {
+        let chapters = chapters.trim();
+        if chapters.is_empty() {
+            return Err("expected chapters, got an empty string".to_string());
+        }
+        let range = chapters
+            .split_once("..")
+            .or_else(|| chapters.split_once('-'));
+        let positions = match range {
+            Some((start, end)) => {
+                let start = match start.trim() {
+                    "" => Ok(1),
+                    start => start.parse(),
+                };
+                let end = match end.trim() {
+                    "" => Ok(usize::MAX),
+                    end => end.parse(),
+                };
+                start.and_then(|start| end.map(|end| (start, end)))
+            }
+            None => chapters.parse().map(|position| (position, position)),
+        };
+        Ok(match positions {
+            Ok((start, end)) => Chapters::Positions(start, end),
+            Err(_) => Chapters::Path(chapters.to_string()),
+        })
+    }
+}
+
+impl Chapters {
+    fn matches(&self, position: Option<usize>, name: &str) -> bool {
+        match self {
+            Chapters::Positions(start, end) => {
+                position.is_some_and(|position| (*start..=*end).contains(&position))
+            }
+            Chapters::Path(path) if path.contains(['*', '?']) => {
+                let file_name = name.rsplit('/').next().unwrap_or(name);
+                let pattern = pattern(path);
+                pattern.is_match(name) || pattern.is_match(file_name)
+            }
+            Chapters::Path(path) => name == path || name.ends_with(&format!("/{}", path)),
+        }
+    }
+}
+
+fn pattern(path: &str) -> Regex {
+    let pattern = regex::escape(path).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("(?i)^{}$", pattern)).unwrap()
+}
+
+/// A `--chapter-language` rule: `3=skip` or `5-7=English` by spine position,
+/// `OEBPS/appendix.xhtml=skip` by path.
+#[derive(Clone, Debug)]
+pub struct ChapterLanguage {
+    chapters: Chapters,
+    target: Target,
+}
+
+impl FromStr for ChapterLanguage {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let Some((chapters, target)) = rule.rsplit_once('=') else {
+            return Err(format!("expected `CHAPTERS=LANGUAGE`, got `{}`", rule));
+        };
+        let (chapters, target) = (chapters.trim(), target.trim());
+        if chapters.is_empty() || target.is_empty() {
+            return Err(format!("expected `CHAPTERS=LANGUAGE`, got `{}`", rule));
+        }
+        let chapters = chapters.parse()?;
+        let target = if target.eq_ignore_ascii_case("skip") {
+            Target::Skip
+        } else {
+            Target::Language(target.to_string())
+        };
+        Ok(Self { chapters, target })
+    }
+}
+
+/// Target language of the entry `name`, or `None` for a skipped chapter:
+/// one outside the `--chapters` selection, one of `--skip`, or one a rule
+/// skips. The first matching rule wins; unmatched entries use the language
+/// of the run.
+pub fn language<'a>(context: &'a Context, spine: &[String], name: &str) -> Option<&'a str> {
+    let position = spine.iter().position(|href| href == name).map(|i| i + 1);
+    let selected = |chapters: &Chapters| chapters.matches(position, name);
+    if !context.chapters.is_empty() && !context.chapters.iter().any(selected)
+        || context.skip.iter().any(selected)
+    {
+        return None;
+    }
+    let rule = context
+        .chapter_languages
+        .iter()
+        .find(|rule| rule.chapters.matches(position, name));
+    match rule.map(|rule| &rule.target) {
+        Some(Target::Skip) => None,
+        Some(Target::Language(language)) => Some(language),
+        None => Some(&context.language),
+    }
+}
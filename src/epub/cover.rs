@@ -0,0 +1,434 @@
+use crate::epub::package::{attribute, join, Package};
+use crate::translate::translator::Translator;
+use log::{info, warn};
+use quick_xml::escape::escape;
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::io::Cursor;
+
+/// Id of the cover generated with the translated title.
+const COVER_ID: &str = "trans-epub-cover";
+
+/// File name of the cover generated with the translated title.
+const COVER_NAME: &str = "trans-epub-cover.svg";
+
+/// Width and height of the generated cover, in the proportions of most
+/// ebook covers.
+const WIDTH: usize = 600;
+const HEIGHT: usize = 900;
+
+/// Columns of a line of the title and of the author, a wide character
+/// counting for two.
+const TITLE_COLUMNS: usize = 22;
+const AUTHOR_COLUMNS: usize = 36;
+
+/// A cover regenerated with `--retitle-cover`: the translated title and
+/// author over the cover image of the book, or on a plain page when it has
+/// none, written as an SVG next to the original image.
+pub struct Cover {
+    /// path of the SVG in the archive
+    pub path: String,
+    /// the SVG
+    pub svg: Vec<u8>,
+    /// href of the SVG from the package document
+    href: String,
+    /// path of the original cover image in the archive, if any
+    image: Option<String>,
+}
+
+impl Cover {
+    /// The cover of `package` with its title and author translated, `None`
+    /// when it has no title.
+    pub async fn new(package: &Package, translator: &Translator) -> Option<Self> {
+        let Some(title) = package.metadata("dc:title") else {
+            warn!("cover: the book has no title");
+            return None;
+        };
+        let author = package.metadata("dc:creator").unwrap_or_default();
+        let mut values = vec![title.to_string()];
+        if !author.trim().is_empty() {
+            values.push(author.to_string());
+        }
+        let translated = translator.translate(values.clone()).await;
+        let translated: Vec<&str> = translated
+            .iter()
+            .zip(&values)
+            .map(|(line, value)| if line.is_empty() { value } else { line })
+            .map(String::as_str)
+            .collect();
+        let title = translated[0];
+        let author = translated.get(1).copied().unwrap_or_default();
+        let image = image(package);
+        let next_to = image.as_deref().unwrap_or(&package.path);
+        let dir = next_to.rfind('/').map_or("", |index| &next_to[..=index]);
+        let path = format!("{}{}", dir, COVER_NAME);
+        let base = match package.path.rfind('/') {
+            Some(index) => &package.path[..=index],
+            None => "",
+        };
+        let href = path.strip_prefix(base).unwrap_or(&path).to_string();
+        info!("cover {}: {}", path, title);
+        let background = image
+            .as_ref()
+            .map(|image| image.rsplit('/').next().unwrap_or(image).to_string());
+        Some(Self {
+            svg: svg(title, author, background.as_deref()),
+            path,
+            href,
+            image,
+        })
+    }
+
+    /// Make the generated cover the cover of the package document `opf`:
+    /// added to the manifest as the `cover-image` of an EPUB 3 package, and
+    /// named by the `cover` meta of EPUB 2 readers.
+    pub fn package(&self, opf: &[u8]) -> Vec<u8> {
+        let mut reader = Reader::from_reader(opf);
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        let mut epub3 = false;
+        let mut has_meta = false;
+        loop {
+            let event = match reader.read_event() {
+                Ok(Event::Eof) => break,
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("cover: {}", e);
+                    return opf.to_vec();
+                }
+            };
+            match event {
+                Event::Start(e) if e.local_name().as_ref() == b"package" => {
+                    let version = attribute(&e, "version").ok().flatten();
+                    epub3 = version.is_some_and(|version| version.starts_with('3'));
+                    writer.write_event(Event::Start(e)).unwrap();
+                }
+                Event::Empty(e) if e.local_name().as_ref() == b"item" => {
+                    writer
+                        .write_event(Event::Empty(without_cover_image(&e)))
+                        .unwrap();
+                }
+                Event::Empty(e) if is_cover_meta(&e) => {
+                    has_meta = true;
+                    writer.write_event(Event::Empty(cover_meta(&e))).unwrap();
+                }
+                Event::End(e) if e.local_name().as_ref() == b"metadata" => {
+                    if !has_meta {
+                        let meta = cover_meta(&BytesStart::new("meta"));
+                        writer.write_event(Event::Empty(meta)).unwrap();
+                    }
+                    writer.write_event(Event::End(e)).unwrap();
+                }
+                Event::End(e) if e.local_name().as_ref() == b"manifest" => {
+                    let mut item = BytesStart::new("item");
+                    item.push_attribute(("id", COVER_ID));
+                    item.push_attribute(("href", self.href.as_str()));
+                    item.push_attribute(("media-type", "image/svg+xml"));
+                    if epub3 {
+                        item.push_attribute(("properties", "cover-image"));
+                    }
+                    writer.write_event(Event::Empty(item)).unwrap();
+                    writer.write_event(Event::End(e)).unwrap();
+                }
+                event => writer.write_event(event).unwrap(),
+            }
+        }
+        writer.into_inner().into_inner()
+    }
+
+    /// Point the images of the content document `name` showing the original
+    /// cover, like those of a cover page, to the generated one.
+    pub fn point(&self, name: &str, content: &[u8]) -> Vec<u8> {
+        let Some(image) = &self.image else {
+            return content.to_vec();
+        };
+        let dir = name.rsplit_once('/').map_or("", |(dir, _)| dir);
+        let mut reader = Reader::from_reader(content);
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        loop {
+            let event = match reader.read_event() {
+                Ok(Event::Eof) => break,
+                Ok(event) => event,
+                Err(_) => return content.to_vec(),
+            };
+            match event {
+                Event::Empty(e) => {
+                    let e = repointed(&e, dir, image);
+                    writer.write_event(Event::Empty(e)).unwrap();
+                }
+                Event::Start(e) => {
+                    let e = repointed(&e, dir, image);
+                    writer.write_event(Event::Start(e)).unwrap();
+                }
+                event => writer.write_event(event).unwrap(),
+            }
+        }
+        writer.into_inner().into_inner()
+    }
+}
+
+/// The path of the cover image of `package`: the `cover-image` item of EPUB
+/// 3, or the item named by the `cover` meta of EPUB 2.
+fn image(package: &Package) -> Option<String> {
+    let images = || {
+        package
+            .manifest
+            .iter()
+            .filter(|item| item.media_type.starts_with("image/"))
+    };
+    images()
+        .find(|item| {
+            item.properties
+                .split_whitespace()
+                .any(|property| property == "cover-image")
+        })
+        .or_else(|| {
+            let id = package.metadata("cover")?;
+            images().find(|item| item.id == id)
+        })
+        .map(|item| item.href.clone())
+}
+
+/// The SVG of a cover with `title` and `author`, over the image `background`
+/// next to it if any.
+fn svg(title: &str, author: &str, background: Option<&str>) -> Vec<u8> {
+    let title = wrap(title, TITLE_COLUMNS);
+    let author = wrap(author, AUTHOR_COLUMNS);
+    let (title_size, author_size) = (44, 26);
+    let height = title.len() * title_size * 5 / 4 + author.len() * author_size * 3 / 2;
+    let mut svg = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" version=\"1.1\" width=\"{WIDTH}\" height=\"{HEIGHT}\" viewBox=\"0 0 {WIDTH} {HEIGHT}\">\n"
+    );
+    let (color, mut y) = match background {
+        Some(background) => {
+            let band = height + 120;
+            svg.push_str(&format!(
+                "<image width=\"{WIDTH}\" height=\"{HEIGHT}\" preserveAspectRatio=\"xMidYMid slice\" xlink:href=\"{}\"/>\n<rect y=\"{}\" width=\"{WIDTH}\" height=\"{band}\" fill=\"#000\" fill-opacity=\"0.6\"/>\n",
+                escape(background),
+                HEIGHT - band
+            ));
+            ("#fff", HEIGHT - band + 60)
+        }
+        None => {
+            svg.push_str(&format!(
+                "<rect width=\"{WIDTH}\" height=\"{HEIGHT}\" fill=\"#f4efe6\"/>\n<rect x=\"40\" y=\"40\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#333\" stroke-width=\"2\"/>\n",
+                WIDTH - 80,
+                HEIGHT - 80
+            ));
+            ("#222", (HEIGHT - height) / 2)
+        }
+    };
+    for line in &title {
+        y += title_size;
+        svg.push_str(&text(line, y, title_size, color, "bold"));
+        y += title_size / 4;
+    }
+    y += author_size / 2;
+    for line in &author {
+        y += author_size;
+        svg.push_str(&text(line, y, author_size, color, "normal"));
+        y += author_size / 2;
+    }
+    svg.push_str("</svg>\n");
+    svg.into_bytes()
+}
+
+/// A centered line of the cover with its baseline at `y`.
+fn text(line: &str, y: usize, size: usize, color: &str, weight: &str) -> String {
+    format!(
+        "<text x=\"{}\" y=\"{y}\" text-anchor=\"middle\" font-family=\"serif\" font-size=\"{size}\" font-weight=\"{weight}\" fill=\"{color}\">{}</text>\n",
+        WIDTH / 2,
+        escape(line)
+    )
+}
+
+/// `text` broken into lines of at most `columns`, at spaces, or anywhere in
+/// a word too long for a line, like the unspaced runs of CJK.
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let width = |text: &str| {
+        text.chars()
+            .map(|c| if c > '\u{2e80}' { 2 } else { 1 })
+            .sum::<usize>()
+    };
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && width(&line) + 1 + width(word) > columns {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        for c in word.chars() {
+            if width(&line) + width(c.encode_utf8(&mut [0; 4])) > columns {
+                lines.push(std::mem::take(&mut line));
+            }
+            line.push(c);
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// `e` without the `cover-image` property, which goes to the generated
+/// cover.
+fn without_cover_image(e: &BytesStart) -> BytesStart<'static> {
+    let properties = attribute(e, "properties").ok().flatten();
+    let Some(properties) = properties.filter(|p| p.split_whitespace().any(|p| p == "cover-image"))
+    else {
+        return e.clone().into_owned();
+    };
+    let properties: Vec<&str> = properties
+        .split_whitespace()
+        .filter(|property| *property != "cover-image")
+        .collect();
+    let mut element = BytesStart::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+    for attribute in e.attributes().with_checks(false).flatten() {
+        if attribute.key.as_ref() != b"properties" {
+            element.push_attribute(attribute);
+        }
+    }
+    if !properties.is_empty() {
+        element.push_attribute(("properties", properties.join(" ").as_str()));
+    }
+    element
+}
+
+fn is_cover_meta(e: &BytesStart) -> bool {
+    e.local_name().as_ref() == b"meta"
+        && attribute(e, "name").ok().flatten().as_deref() == Some("cover")
+}
+
+/// The `cover` meta naming the generated cover.
+fn cover_meta(e: &BytesStart) -> BytesStart<'static> {
+    let mut meta = BytesStart::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+    meta.push_attribute(("name", "cover"));
+    meta.push_attribute(("content", COVER_ID));
+    meta
+}
+
+/// `e` with its `src`, `href` or `xlink:href` pointed to the generated
+/// cover when it names the original `image`, from the directory `dir`.
+fn repointed(e: &BytesStart, dir: &str, image: &str) -> BytesStart<'static> {
+    let names: &[&[u8]] = &[b"src", b"href", b"xlink:href"];
+    let names_image = |attribute: &Attribute| {
+        names.contains(&attribute.key.as_ref())
+            && join(dir, &attribute.unescape_value().unwrap_or_default()) == image
+    };
+    if !e
+        .attributes()
+        .with_checks(false)
+        .flatten()
+        .any(|a| names_image(&a))
+    {
+        return e.clone().into_owned();
+    }
+    let mut element = BytesStart::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+    for attribute in e.attributes().with_checks(false).flatten() {
+        if names_image(&attribute) {
+            let value = attribute.unescape_value().unwrap_or_default();
+            let href = match value.rsplit_once('/') {
+                Some((dir, _)) => format!("{}/{}", dir, COVER_NAME),
+                None => COVER_NAME.to_string(),
+            };
+            let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+            element.push_attribute((key.as_str(), href.as_str()));
+        } else {
+            element.push_attribute(attribute);
+        }
+    }
+    element
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{Config, Provider};
+
+    const OPF: &str = r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>The Lighthouse</dc:title><dc:creator>A. Keeper</dc:creator></metadata><manifest><item id="img" href="images/cover.jpg" media-type="image/jpeg" properties="cover-image svg"/><item id="page" href="text/cover.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="page"/></spine></package>"#;
+
+    fn translator() -> Translator {
+        let config = Config::new(Provider::Mock, "mock", "German");
+        config.provider.translator(config.context)
+    }
+
+    async fn cover_of(opf: &str) -> Option<Cover> {
+        let package = Package::parse("OEBPS/content.opf".to_string(), opf.as_bytes()).unwrap();
+        Cover::new(&package, &translator()).await
+    }
+
+    fn text(content: Vec<u8>) -> String {
+        String::from_utf8(content).unwrap()
+    }
+
+    #[tokio::test]
+    async fn covers_are_written_over_the_original_image() {
+        let cover = cover_of(OPF).await.unwrap();
+        assert_eq!(cover.path, format!("OEBPS/images/{}", COVER_NAME));
+        assert_eq!(cover.href, format!("images/{}", COVER_NAME));
+        assert_eq!(cover.image.as_deref(), Some("OEBPS/images/cover.jpg"));
+        let svg = text(cover.svg.clone());
+        assert!(svg.contains(r#"xlink:href="cover.jpg""#));
+        // the translated title takes two lines
+        assert!(svg.contains(r##"font-weight="bold" fill="#fff">[German] The</text>"##));
+        assert!(svg.contains(r##"font-weight="bold" fill="#fff">Lighthouse</text>"##));
+        assert!(svg.contains(">[German] A. Keeper</text>"));
+
+        let opf = text(cover.package(OPF.as_bytes()));
+        assert!(opf.contains(r#"<item id="img" href="images/cover.jpg" media-type="image/jpeg" properties="svg"/>"#), "{}", opf);
+        assert!(opf.contains(&format!(
+            r#"<item id="{}" href="images/{}" media-type="image/svg+xml" properties="cover-image"/></manifest>"#,
+            COVER_ID, COVER_NAME
+        )));
+        assert!(opf.contains(&format!(
+            r#"<meta name="cover" content="{}"/></metadata>"#,
+            COVER_ID
+        )));
+
+        let page = br#"<body><img src="../images/cover.jpg" alt="Cover"/><svg><image xlink:href="../images/other.jpg"/></svg></body>"#;
+        assert_eq!(
+            text(cover.point("OEBPS/text/cover.xhtml", page)),
+            format!(
+                r#"<body><img src="../images/{}" alt="Cover"/><svg><image xlink:href="../images/other.jpg"/></svg></body>"#,
+                COVER_NAME
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn books_without_a_cover_image_get_a_plain_page() {
+        let opf = r#"<package version="2.0"><metadata><dc:title>Untitled &amp; Alone</dc:title><meta name="cover" content="page"/></metadata><manifest><item id="page" href="cover.xhtml" media-type="application/xhtml+xml"/></manifest></package>"#;
+        let cover = cover_of(opf).await.unwrap();
+        assert_eq!(cover.path, format!("OEBPS/{}", COVER_NAME));
+        assert_eq!(cover.image, None);
+        let svg = text(cover.svg.clone());
+        assert!(!svg.contains("<image"));
+        assert!(svg.contains(">[German] Untitled &amp;</text>"), "{}", svg);
+        let package = text(cover.package(opf.as_bytes()));
+        assert!(package.contains(&format!(
+            r#"<meta name="cover" content="{}"/></metadata>"#,
+            COVER_ID
+        )));
+        assert_eq!(package.matches(r#"name="cover""#).count(), 1);
+        assert!(package.contains(r#"media-type="image/svg+xml"/></manifest>"#));
+        assert!(cover_of(r#"<package version="3.0"><metadata/></package>"#)
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn titles_are_wrapped_by_columns() {
+        assert_eq!(
+            wrap("The Keeper of the Northern Light", 22),
+            ["The Keeper of the", "Northern Light"]
+        );
+        assert_eq!(wrap("灯台守の物語です", 10), ["灯台守の物", "語です"]);
+        assert_eq!(
+            wrap("Supercalifragilistic", 8),
+            ["Supercal", "ifragili", "stic"]
+        );
+        assert!(wrap("  ", 8).is_empty());
+    }
+}
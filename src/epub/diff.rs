@@ -0,0 +1,489 @@
+use crate::epub::document_lines;
+use crate::epub::package::{read_entry, Package};
+use crate::epub::validate::without_copies;
+use crate::error::Error;
+use quick_xml::escape::escape;
+use std::fmt::{self, Write};
+use std::io::Cursor;
+use zip::ZipArchive;
+
+/// How many paragraphs ahead a paragraph is looked for on the other side
+/// before the two are taken to be a translation of one another.
+const LOOKAHEAD: usize = 50;
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }\n\
+    table { border-collapse: collapse; width: 100%; margin-bottom: 2em; }\n\
+    th, td { border: 1px solid #ccc; padding: 0.4em; vertical-align: top; text-align: left; }\n\
+    td.number { color: #888; width: 3em; }\n\
+    tr.dropped td { background: #fdd; }\n\
+    tr.added td { background: #ddf; }\n\
+    tr.untranslated td { background: #ffd; }\n\
+    .problem { color: #b00; }";
+
+/// The paragraphs of a translated EPUB set against those of its source,
+/// chapter by chapter in the order of the source spine.
+pub struct Diff {
+    pub chapters: Vec<Chapter>,
+}
+
+pub struct Chapter {
+    pub name: String,
+    /// the chapter is in the spine of the source, of the translation, or both
+    pub in_source: bool,
+    pub in_translation: bool,
+    pub rows: Vec<Row>,
+}
+
+/// A line of the side-by-side report.
+pub enum Row {
+    /// a source paragraph and what it became, the same text or none when it
+    /// was left untranslated
+    Paired {
+        original: String,
+        translation: String,
+    },
+    /// a source paragraph with nothing in the translation
+    Dropped(String),
+    /// a paragraph of the translation matching nothing of the source
+    Added(String),
+}
+
+impl Row {
+    fn is_untranslated(&self) -> bool {
+        match self {
+            Row::Paired {
+                original,
+                translation,
+            } => translation.is_empty() || normalize(translation) == normalize(original),
+            _ => false,
+        }
+    }
+}
+
+impl Chapter {
+    pub fn dropped(&self) -> usize {
+        self.count(|row| matches!(row, Row::Dropped(_)))
+    }
+
+    pub fn added(&self) -> usize {
+        self.count(|row| matches!(row, Row::Added(_)))
+    }
+
+    pub fn untranslated(&self) -> usize {
+        self.count(Row::is_untranslated)
+    }
+
+    /// Whether the chapter was left out of the translation or none of its
+    /// paragraphs were translated.
+    pub fn is_skipped(&self) -> bool {
+        self.in_source
+            && (!self.in_translation
+                || self
+                    .rows
+                    .iter()
+                    .all(|row| row.is_untranslated() || matches!(row, Row::Dropped(_))))
+    }
+
+    fn count(&self, matches: impl Fn(&Row) -> bool) -> usize {
+        self.rows.iter().filter(|row| matches(row)).count()
+    }
+
+    /// What is wrong with the chapter, if anything.
+    fn problems(&self) -> Vec<String> {
+        if !self.in_translation {
+            return vec!["missing from the translation".to_string()];
+        }
+        if !self.in_source {
+            return vec!["not in the source".to_string()];
+        }
+        if self.is_skipped() && !self.rows.is_empty() {
+            return vec!["not translated".to_string()];
+        }
+        let mut problems = Vec::new();
+        for (count, what) in [
+            (self.dropped(), "dropped"),
+            (self.added(), "added"),
+            (self.untranslated(), "untranslated"),
+        ] {
+            if count > 0 {
+                problems.push(format!("{} {}", count, what));
+            }
+        }
+        problems
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chapter in &self.chapters {
+            let problems = chapter.problems();
+            if !problems.is_empty() {
+                writeln!(f, "{}: {}", chapter.name, problems.join(", "))?;
+            }
+        }
+        let sum = |count: fn(&Chapter) -> usize| self.chapters.iter().map(count).sum::<usize>();
+        writeln!(
+            f,
+            "{} chapters, {} skipped; {} paragraphs dropped, {} added, {} untranslated",
+            self.chapters.len(),
+            self.chapters.iter().filter(|c| c.is_skipped()).count(),
+            sum(Chapter::dropped),
+            sum(Chapter::added),
+            sum(Chapter::untranslated)
+        )
+    }
+}
+
+impl Diff {
+    /// The report as a standalone HTML page: a summary of the chapters, then
+    /// each as a table of the source paragraphs beside their translations,
+    /// the dropped, added and untranslated ones highlighted.
+    pub fn html(&self, original: &str, translated: &str) -> String {
+        let mut html = String::new();
+        let title = format!("{} / {}", original, translated);
+        writeln!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\"/>"
+        )
+        .unwrap();
+        writeln!(html, "<title>{}</title>", escape(&title)).unwrap();
+        writeln!(html, "<style>\n{}\n</style>\n</head>\n<body>", STYLE).unwrap();
+        writeln!(html, "<h1>{}</h1>", escape(&title)).unwrap();
+        writeln!(html, "<pre>{}</pre>", escape(&self.to_string())).unwrap();
+
+        html.push_str("<table>\n<tr><th>chapter</th><th>source</th><th>translation</th><th>problems</th></tr>\n");
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            writeln!(
+                html,
+                "<tr><td><a href=\"#chapter-{}\">{}</a></td><td>{}</td><td>{}</td><td class=\"problem\">{}</td></tr>",
+                i + 1,
+                escape(&chapter.name),
+                chapter.count(|row| !matches!(row, Row::Added(_))),
+                chapter.count(|row| !matches!(row, Row::Dropped(_))),
+                escape(&chapter.problems().join(", "))
+            )
+            .unwrap();
+        }
+        html.push_str("</table>\n");
+
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            writeln!(
+                html,
+                "<h2 id=\"chapter-{}\">{}</h2>",
+                i + 1,
+                escape(&chapter.name)
+            )
+            .unwrap();
+            let problems = chapter.problems();
+            if !problems.is_empty() {
+                writeln!(
+                    html,
+                    "<p class=\"problem\">{}</p>",
+                    escape(&problems.join(", "))
+                )
+                .unwrap();
+            }
+            if chapter.rows.is_empty() {
+                continue;
+            }
+            html.push_str("<table>\n<tr><th></th><th>source</th><th>translation</th></tr>\n");
+            let mut number = 0;
+            for row in &chapter.rows {
+                let (class, original, translation) = match row {
+                    Row::Paired {
+                        original,
+                        translation,
+                    } => {
+                        let class = if row.is_untranslated() {
+                            "untranslated"
+                        } else {
+                            "paired"
+                        };
+                        (class, original.as_str(), translation.as_str())
+                    }
+                    Row::Dropped(original) => ("dropped", original.as_str(), ""),
+                    Row::Added(translation) => ("added", "", translation.as_str()),
+                };
+                let label = match row {
+                    Row::Added(_) => String::new(),
+                    _ => {
+                        number += 1;
+                        number.to_string()
+                    }
+                };
+                writeln!(
+                    html,
+                    "<tr class=\"{}\"><td class=\"number\">{}</td><td>{}</td><td>{}</td></tr>",
+                    class,
+                    label,
+                    escape(original),
+                    escape(translation)
+                )
+                .unwrap();
+            }
+            html.push_str("</table>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+/// Set the paragraphs of each chapter of the EPUB `translated` against those
+/// of the EPUB `original` it was translated from.
+///
+/// With the inline layout each paragraph still holds its original before
+/// the `<<translation>>`, by which it is matched to the source, so that a
+/// dropped or added paragraph does not shift the rest. With other layouts
+/// the paragraphs are taken in order, the copies of the originals left out.
+pub fn diff_epub_bytes(original: &[u8], translated: &[u8]) -> Result<Diff, Error> {
+    let mut source_archive = ZipArchive::new(Cursor::new(original))?;
+    let source_package = Package::read(&mut source_archive)?;
+    let mut archive = ZipArchive::new(Cursor::new(translated))?;
+    let package = Package::read(&mut archive)?;
+
+    let mut chapters = Vec::new();
+    for name in &source_package.spine {
+        let source = document_lines(name, &read_entry(&mut source_archive, name)?)?;
+        let in_translation =
+            package.spine.contains(name) && archive.file_names().any(|entry| entry == name);
+        let rows = match in_translation {
+            true => {
+                let content = read_entry(&mut archive, name)?;
+                let output = document_lines(name, &without_copies(&content))?;
+                let inline = output.iter().any(|line| split(line).is_some());
+                let output = output
+                    .iter()
+                    .map(|line| split(line).unwrap_or_else(|| (line.clone(), line.clone())))
+                    .collect();
+                align(source, output, inline)
+            }
+            false => source.into_iter().map(Row::Dropped).collect(),
+        };
+        chapters.push(Chapter {
+            name: name.clone(),
+            in_source: true,
+            in_translation,
+            rows,
+        });
+    }
+    for name in package
+        .spine
+        .iter()
+        .filter(|name| !source_package.spine.contains(name))
+    {
+        let content = read_entry(&mut archive, name)?;
+        chapters.push(Chapter {
+            name: name.clone(),
+            in_source: false,
+            in_translation: true,
+            rows: document_lines(name, &without_copies(&content))?
+                .into_iter()
+                .map(Row::Added)
+                .collect(),
+        });
+    }
+    Ok(Diff { chapters })
+}
+
+/// The original and the translation of an inline paragraph,
+/// `original<<translation>>`.
+fn split(line: &str) -> Option<(String, String)> {
+    line.strip_suffix(">>")
+        .and_then(|line| line.rsplit_once("<<"))
+        .map(|(original, translation)| (original.to_string(), translation.to_string()))
+}
+
+/// Pair the `source` paragraphs with the `output` originals and
+/// translations. With `inline` originals, a paragraph is paired with the one
+/// of the same original, looking ahead on both sides past those dropped or
+/// added; otherwise, and for two that match nothing nearby, in order.
+fn align(source: Vec<String>, output: Vec<(String, String)>, inline: bool) -> Vec<Row> {
+    let source_keys: Vec<String> = source.iter().map(|line| normalize(line)).collect();
+    let output_keys: Vec<String> = output.iter().map(|(key, _)| normalize(key)).collect();
+    let mut rows = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < source.len() && j < output.len() {
+        if inline && source_keys[i] != output_keys[j] {
+            let ahead = |keys: &[String], start: usize, key: &str| {
+                keys.iter()
+                    .skip(start)
+                    .take(LOOKAHEAD)
+                    .position(|k| k == key)
+            };
+            if let Some(added) = ahead(&output_keys, j + 1, &source_keys[i]) {
+                rows.extend(
+                    output[j..=j + added]
+                        .iter()
+                        .map(|(_, translation)| Row::Added(translation.clone())),
+                );
+                j += added + 1;
+                continue;
+            }
+            if let Some(dropped) = ahead(&source_keys, i + 1, &output_keys[j]) {
+                rows.extend(source[i..=i + dropped].iter().cloned().map(Row::Dropped));
+                i += dropped + 1;
+                continue;
+            }
+        }
+        rows.push(Row::Paired {
+            original: source[i].clone(),
+            translation: output[j].1.clone(),
+        });
+        i += 1;
+        j += 1;
+    }
+    rows.extend(source[i..].iter().cloned().map(Row::Dropped));
+    rows.extend(
+        output[j..]
+            .iter()
+            .map(|(_, translation)| Row::Added(translation.clone())),
+    );
+    rows
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    /// An EPUB with the chapters `(name, paragraphs)` in its spine.
+    fn epub(chapters: &[(&str, &[&str])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        zip.start_file("META-INF/container.xml", options).unwrap();
+        zip.write_all(
+            br#"<container><rootfiles><rootfile full-path="content.opf"/></rootfiles></container>"#,
+        )
+        .unwrap();
+        let (mut items, mut itemrefs) = (String::new(), String::new());
+        for (i, (name, paragraphs)) in chapters.iter().enumerate() {
+            items.push_str(&format!(
+                r#"<item id="c{}" href="{}" media-type="application/xhtml+xml"/>"#,
+                i, name
+            ));
+            itemrefs.push_str(&format!(r#"<itemref idref="c{}"/>"#, i));
+            let body: String = paragraphs
+                .iter()
+                .map(|paragraph| format!("<p>{}</p>", escape(*paragraph)))
+                .collect();
+            zip.start_file(*name, options).unwrap();
+            write!(zip, "<html><body>{}</body></html>", body).unwrap();
+        }
+        zip.start_file("content.opf", options).unwrap();
+        write!(
+            zip,
+            r#"<package version="3.0"><manifest>{}</manifest><spine>{}</spine></package>"#,
+            items, itemrefs
+        )
+        .unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    /// The rows as `=original|translation`, `-dropped` and `+added`.
+    fn rows(rows: &[Row]) -> Vec<String> {
+        rows.iter()
+            .map(|row| match row {
+                Row::Paired {
+                    original,
+                    translation,
+                } => format!("={}|{}", original, translation),
+                Row::Dropped(original) => format!("-{}", original),
+                Row::Added(translation) => format!("+{}", translation),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn inline_paragraphs_are_matched_by_their_original() {
+        let output = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(original, translation)| (original.to_string(), translation.to_string()))
+                .collect()
+        };
+        let source = texts(&["One", "Two", "Three", "Four"]);
+        let shifted = output(&[
+            ("One", "Eins"),
+            ("Extra", "Mehr"),
+            ("Two", "Zwei"),
+            ("Four", "Four"),
+        ]);
+        assert_eq!(
+            rows(&align(source.clone(), shifted.clone(), true)),
+            ["=One|Eins", "+Mehr", "=Two|Zwei", "-Three", "=Four|Four"]
+        );
+        // without the originals the paragraphs are taken in order
+        assert_eq!(
+            rows(&align(source.clone(), shifted[..2].to_vec(), false)),
+            ["=One|Eins", "=Two|Mehr", "-Three", "-Four"]
+        );
+        // as are two matching nothing nearby
+        let replaced = output(&[("One", "Eins"), ("Other", "Anders")]);
+        assert_eq!(
+            rows(&align(source, replaced, true)),
+            ["=One|Eins", "=Two|Anders", "-Three", "-Four"]
+        );
+    }
+
+    #[test]
+    fn chapters_are_set_against_the_source() {
+        let source = epub(&[
+            ("a.xhtml", &["One", "Two", "Three"]),
+            ("b.xhtml", &["Four"]),
+            ("c.xhtml", &["Five"]),
+        ]);
+        let translated = epub(&[
+            (
+                "a.xhtml",
+                &["One<<Eins>>", "Three<<Three>>", "Extra<<Mehr>>"],
+            ),
+            ("b.xhtml", &["Four<<>>"]),
+            ("d.xhtml", &["Sechs"]),
+        ]);
+        let diff = diff_epub_bytes(&source, &translated).unwrap();
+        let names: Vec<&str> = diff.chapters.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["a.xhtml", "b.xhtml", "c.xhtml", "d.xhtml"]);
+        assert_eq!(
+            rows(&diff.chapters[0].rows),
+            ["=One|Eins", "-Two", "=Three|Three", "+Mehr"]
+        );
+        assert!(diff.chapters[1].is_skipped());
+        assert!(diff.chapters[2].is_skipped());
+        assert_eq!(
+            diff.to_string(),
+            "a.xhtml: 1 dropped, 1 added, 1 untranslated\n\
+             b.xhtml: not translated\n\
+             c.xhtml: missing from the translation\n\
+             d.xhtml: not in the source\n\
+             4 chapters, 2 skipped; 2 paragraphs dropped, 2 added, 2 untranslated\n"
+        );
+    }
+
+    #[test]
+    fn reports_highlight_what_went_wrong() {
+        let source = epub(&[("a.xhtml", &["Fish & chips", "Two"])]);
+        let translated = epub(&[("a.xhtml", &["Fisch & Pommes"])]);
+        let diff = diff_epub_bytes(&source, &translated).unwrap();
+        let html = diff.html("book.epub", "book.de.epub");
+        assert!(html.contains("<title>book.epub / book.de.epub</title>"));
+        assert!(html.contains(
+            r##"<tr><td><a href="#chapter-1">a.xhtml</a></td><td>2</td><td>1</td><td class="problem">1 dropped</td></tr>"##
+        ), "{}", html);
+        assert!(html.contains(
+            r#"<tr class="paired"><td class="number">1</td><td>Fish &amp; chips</td><td>Fisch &amp; Pommes</td></tr>"#
+        ));
+        assert!(html.contains(
+            r#"<tr class="dropped"><td class="number">2</td><td>Two</td><td></td></tr>"#
+        ));
+    }
+}
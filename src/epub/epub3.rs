@@ -0,0 +1,465 @@
+use crate::client::quota;
+use crate::epub::escaped_text;
+use crate::epub::package::{attribute, join, Package};
+use crate::epub::unescape;
+use crate::language;
+use log::warn;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::io::Cursor;
+use std::time::SystemTime;
+
+/// Id of the navigation document generated from the NCX.
+const NAV_ID: &str = "trans-epub-nav";
+
+/// File name of the navigation document generated from the NCX.
+const NAV_NAME: &str = "trans-epub-nav.xhtml";
+
+/// The navigation document to generate for a book with an NCX alone, EPUB 3
+/// requiring one.
+pub struct Nav {
+    /// path of the NCX in the archive
+    pub ncx: String,
+    /// path of the navigation document in the archive, next to the NCX
+    pub path: String,
+    /// href of the navigation document from the package document
+    pub href: String,
+}
+
+impl Nav {
+    pub fn missing(package: &Package) -> Option<Self> {
+        if package
+            .navigation()
+            .any(|item| item.properties.split_whitespace().any(|p| p == "nav"))
+        {
+            return None;
+        }
+        let ncx = package.navigation().next()?.href.clone();
+        let path = match ncx.rfind('/') {
+            Some(index) => format!("{}{}", &ncx[..=index], NAV_NAME),
+            None => NAV_NAME.to_string(),
+        };
+        let base = match package.path.rfind('/') {
+            Some(index) => &package.path[..=index],
+            None => "",
+        };
+        let href = path.strip_prefix(base).unwrap_or(&path).to_string();
+        Some(Self { ncx, path, href })
+    }
+}
+
+/// Set the language of a translated content document on its root element:
+/// `lang` and `xml:lang`, and `dir="rtl"` when the language is written right
+/// to left. A `dir` of the original is dropped otherwise.
+pub fn set_language(content: &[u8], language: &str) -> Vec<u8> {
+    let code = language::code(language);
+    let rtl = language::is_rtl(language);
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut root = true;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(e) => {
+                warn!("epub3: {}", e);
+                return content.to_vec();
+            }
+        };
+        match event {
+            Event::Start(e) if root => {
+                root = false;
+                let mut element = without(&e, &["lang", "xml:lang", "dir"]);
+                element.push_attribute(("lang", code.as_str()));
+                element.push_attribute(("xml:lang", code.as_str()));
+                if rtl {
+                    element.push_attribute(("dir", "rtl"));
+                }
+                writer.write_event(Event::Start(element)).unwrap();
+            }
+            event => writer.write_event(event).unwrap(),
+        }
+    }
+    writer.into_inner().into_inner()
+}
+
+/// Rewrite the package document `opf` at `path` as EPUB 3: version 3.0,
+/// `dc:language` and the `dcterms:modified` date of this run, the page
+/// progression of the direction of `language`, and the manifest checked
+/// against the `entries` of the archive, items of missing files being
+/// dropped with their spine references. `nav` is the href of a navigation
+/// document generated for a book that had none.
+pub fn package(
+    opf: &[u8],
+    path: &str,
+    language: &str,
+    entries: &[String],
+    nav: Option<&str>,
+) -> Vec<u8> {
+    let base = match path.rfind('/') {
+        Some(index) => &path[..=index],
+        None => "",
+    };
+    let code = language::code(language);
+    let direction = if language::is_rtl(language) {
+        "rtl"
+    } else {
+        "ltr"
+    };
+    let mut reader = Reader::from_reader(opf);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut dropped: Vec<String> = Vec::new();
+    let mut element: Option<&str> = None;
+    let mut has_language = false;
+    let mut has_modified = false;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(e) => {
+                warn!("epub3: {}: {}", path, e);
+                return opf.to_vec();
+            }
+        };
+        match event {
+            Event::Start(e) if e.local_name().as_ref() == b"package" => {
+                let mut package = without(&e, &["version"]);
+                package.push_attribute(("version", "3.0"));
+                writer.write_event(Event::Start(package)).unwrap();
+            }
+            Event::Start(e) if e.name().as_ref() == b"dc:language" => {
+                has_language = true;
+                element = Some("language");
+                writer.write_event(Event::Start(e)).unwrap();
+            }
+            Event::Start(e) if is_modified(&e) => {
+                has_modified = true;
+                element = Some("modified");
+                writer.write_event(Event::Start(e)).unwrap();
+            }
+            Event::Text(_) if element.is_some() => {
+                let text = match element {
+                    Some("language") => code.clone(),
+                    _ => modified(),
+                };
+                writer
+                    .write_event(Event::Text(BytesText::new(&text)))
+                    .unwrap();
+            }
+            Event::End(e) if e.local_name().as_ref() == b"metadata" => {
+                if !has_language {
+                    write_element(&mut writer, BytesStart::new("dc:language"), &code);
+                }
+                if !has_modified {
+                    let mut meta = BytesStart::new("meta");
+                    meta.push_attribute(("property", "dcterms:modified"));
+                    write_element(&mut writer, meta, &modified());
+                }
+                writer.write_event(Event::End(e)).unwrap();
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"item" => {
+                let href = attribute(&e, "href").ok().flatten().unwrap_or_default();
+                let href = join(base, &href);
+                if entries.contains(&href) {
+                    writer.write_event(Event::Empty(e)).unwrap();
+                } else {
+                    warn!("epub3: {} is missing, dropped from the manifest", href);
+                    dropped.extend(attribute(&e, "id").ok().flatten());
+                }
+            }
+            Event::End(e) if e.local_name().as_ref() == b"manifest" => {
+                if let Some(nav) = nav {
+                    let mut item = BytesStart::new("item");
+                    item.push_attribute(("id", NAV_ID));
+                    item.push_attribute(("href", nav));
+                    item.push_attribute(("media-type", "application/xhtml+xml"));
+                    item.push_attribute(("properties", "nav"));
+                    writer.write_event(Event::Empty(item)).unwrap();
+                }
+                writer.write_event(Event::End(e)).unwrap();
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"spine" => {
+                let mut spine = without(&e, &["page-progression-direction"]);
+                spine.push_attribute(("page-progression-direction", direction));
+                writer.write_event(Event::Start(spine)).unwrap();
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"itemref" => {
+                let idref = attribute(&e, "idref").ok().flatten().unwrap_or_default();
+                if !dropped.contains(&idref) {
+                    writer.write_event(Event::Empty(e)).unwrap();
+                }
+            }
+            Event::End(e) => {
+                element = None;
+                writer.write_event(Event::End(e)).unwrap();
+            }
+            event => writer.write_event(event).unwrap(),
+        }
+    }
+    writer.into_inner().into_inner()
+}
+
+struct Point {
+    label: String,
+    src: String,
+    children: Vec<Point>,
+}
+
+/// An EPUB 3 navigation document with the table of contents of the NCX
+/// `ncx`, whose links it keeps, so it is to be written next to it.
+pub fn nav_from_ncx(ncx: &[u8], language: &str) -> Vec<u8> {
+    let mut reader = Reader::from_reader(ncx);
+    reader.config_mut().trim_text(true);
+    let mut title = String::new();
+    // the points being read, innermost last, under a root
+    let mut stack = vec![Point {
+        label: String::new(),
+        src: String::new(),
+        children: Vec::new(),
+    }];
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(e) => {
+                warn!("epub3: ncx: {}", e);
+                break;
+            }
+        };
+        match event {
+            Event::Start(e) => {
+                if e.local_name().as_ref() == b"navPoint" {
+                    stack.push(Point {
+                        label: String::new(),
+                        src: String::new(),
+                        children: Vec::new(),
+                    });
+                }
+                path.push(e.local_name().as_ref().to_vec());
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"content" => {
+                let src = attribute(&e, "src").ok().flatten().unwrap_or_default();
+                if let Some(point) = stack.last_mut().filter(|point| point.src.is_empty()) {
+                    point.src = src;
+                }
+            }
+            Event::Text(e) if path.last().is_some_and(|name| name == b"text") => {
+                let text = unescape(&e);
+                if path.iter().any(|name| name == b"docTitle") {
+                    title.push_str(&text);
+                } else if path.iter().any(|name| name == b"navLabel") {
+                    if let Some(point) = stack.last_mut() {
+                        point.label.push_str(&text);
+                    }
+                }
+            }
+            Event::End(e) => {
+                path.pop();
+                if e.local_name().as_ref() == b"navPoint" && stack.len() > 1 {
+                    let point = stack.pop().unwrap();
+                    stack.last_mut().unwrap().children.push(point);
+                }
+            }
+            _ => (),
+        }
+    }
+    let points = stack.swap_remove(0).children;
+
+    let code = language::code(language);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    writer
+        .write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))
+        .unwrap();
+    let mut html = BytesStart::new("html");
+    html.push_attribute(("xmlns", "http://www.w3.org/1999/xhtml"));
+    html.push_attribute(("xmlns:epub", "http://www.idpf.org/2007/ops"));
+    html.push_attribute(("lang", code.as_str()));
+    html.push_attribute(("xml:lang", code.as_str()));
+    if language::is_rtl(language) {
+        html.push_attribute(("dir", "rtl"));
+    }
+    writer.write_event(Event::Start(html)).unwrap();
+    writer
+        .write_event(Event::Start(BytesStart::new("head")))
+        .unwrap();
+    write_element(&mut writer, BytesStart::new("title"), title.trim());
+    writer
+        .write_event(Event::End(BytesEnd::new("head")))
+        .unwrap();
+    writer
+        .write_event(Event::Start(BytesStart::new("body")))
+        .unwrap();
+    let mut nav = BytesStart::new("nav");
+    nav.push_attribute(("epub:type", "toc"));
+    nav.push_attribute(("id", "toc"));
+    writer.write_event(Event::Start(nav)).unwrap();
+    write_points(&mut writer, &points);
+    writer
+        .write_event(Event::End(BytesEnd::new("nav")))
+        .unwrap();
+    writer
+        .write_event(Event::End(BytesEnd::new("body")))
+        .unwrap();
+    writer
+        .write_event(Event::End(BytesEnd::new("html")))
+        .unwrap();
+    writer.into_inner().into_inner()
+}
+
+fn write_points(writer: &mut Writer<Cursor<Vec<u8>>>, points: &[Point]) {
+    if points.is_empty() {
+        return;
+    }
+    writer
+        .write_event(Event::Start(BytesStart::new("ol")))
+        .unwrap();
+    for point in points {
+        writer
+            .write_event(Event::Start(BytesStart::new("li")))
+            .unwrap();
+        let mut a = BytesStart::new("a");
+        a.push_attribute(("href", point.src.as_str()));
+        write_element(writer, a, point.label.trim());
+        write_points(writer, &point.children);
+        writer.write_event(Event::End(BytesEnd::new("li"))).unwrap();
+    }
+    writer.write_event(Event::End(BytesEnd::new("ol"))).unwrap();
+}
+
+fn write_element(writer: &mut Writer<Cursor<Vec<u8>>>, element: BytesStart, text: &str) {
+    let end = element.to_end().into_owned();
+    writer.write_event(Event::Start(element)).unwrap();
+    writer.write_event(escaped_text(text)).unwrap();
+    writer.write_event(Event::End(end)).unwrap();
+}
+
+fn is_modified(e: &BytesStart) -> bool {
+    e.local_name().as_ref() == b"meta"
+        && attribute(e, "property").ok().flatten().as_deref() == Some("dcterms:modified")
+}
+
+/// `YYYY-MM-DDThh:mm:ssZ`, as `dcterms:modified` wants it.
+fn modified() -> String {
+    let utc = quota::utc(SystemTime::now());
+    format!("{}Z", utc[..19].replacen(' ', "T", 1))
+}
+
+/// A copy of `element` without the attributes `names`.
+fn without(element: &BytesStart, names: &[&str]) -> BytesStart<'static> {
+    let name = String::from_utf8_lossy(element.name().0).into_owned();
+    let mut copy = BytesStart::new(name);
+    copy.extend_attributes(element.attributes().flatten().filter(|attribute| {
+        !names
+            .iter()
+            .any(|name| attribute.key.as_ref() == name.as_bytes())
+    }));
+    copy.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>Book</dc:title></metadata>
+<manifest>
+<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+<item id="ch1" href="text/ch1.xhtml" media-type="application/xhtml+xml"/>
+<item id="ch2" href="text/ch2.xhtml" media-type="application/xhtml+xml"/>
+</manifest>
+<spine toc="ncx" page-progression-direction="ltr"><itemref idref="ch1"/><itemref idref="ch2"/></spine>
+</package>"#;
+
+    const NCX: &str = r#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/"><docTitle><text>The Book</text></docTitle><navMap>
+<navPoint id="p1"><navLabel><text>Part &amp; One</text></navLabel><content src="text/ch1.xhtml"/>
+<navPoint id="p2"><navLabel><text>Chapter 1</text></navLabel><content src="text/ch1.xhtml#c1"/></navPoint>
+</navPoint>
+<navPoint id="p3"><navLabel><text>Part Two</text></navLabel><content src="text/ch2.xhtml"/></navPoint>
+</navMap></ncx>"#;
+
+    fn text(content: Vec<u8>) -> String {
+        String::from_utf8(content).unwrap()
+    }
+
+    #[test]
+    fn documents_get_the_language_of_the_translation() {
+        let content = br#"<html xmlns="http://www.w3.org/1999/xhtml" lang="en" dir="ltr"><body><p lang="en">x</p></body></html>"#;
+        assert_eq!(
+            text(set_language(content, "German")),
+            r#"<html xmlns="http://www.w3.org/1999/xhtml" lang="de" xml:lang="de"><body><p lang="en">x</p></body></html>"#
+        );
+        assert!(text(set_language(content, "Arabic")).starts_with(
+            r#"<html xmlns="http://www.w3.org/1999/xhtml" lang="ar" xml:lang="ar" dir="rtl">"#
+        ));
+    }
+
+    #[test]
+    fn packages_are_rewritten_as_epub3() {
+        let entries = [
+            "OEBPS/toc.ncx".to_string(),
+            "OEBPS/text/ch2.xhtml".to_string(),
+        ];
+        let opf = text(package(
+            OPF.as_bytes(),
+            "OEBPS/content.opf",
+            "Hebrew",
+            &entries,
+            Some(NAV_NAME),
+        ));
+        assert!(
+            opf.contains(r#"unique-identifier="id" version="3.0">"#),
+            "{}",
+            opf
+        );
+        assert!(opf.contains("<dc:language>he</dc:language>"));
+        let date = Regex::new(
+            r#"<meta property="dcterms:modified">\d{4}-\d\d-\d\dT\d\d:\d\d:\d\dZ</meta></metadata>"#,
+        )
+        .unwrap();
+        assert!(date.is_match(&opf), "{}", opf);
+        // the missing chapter is dropped from the manifest and the spine
+        assert!(!opf.contains("ch1"));
+        assert!(opf.contains(&format!(
+            r#"<item id="{}" href="{}" media-type="application/xhtml+xml" properties="nav"/></manifest>"#,
+            NAV_ID, NAV_NAME
+        )));
+        assert!(opf.contains(
+            r#"<spine toc="ncx" page-progression-direction="rtl"><itemref idref="ch2"/></spine>"#
+        ));
+        // a second pass keeps the language and the date, updated
+        let again = text(package(
+            opf.as_bytes(),
+            "OEBPS/content.opf",
+            "German",
+            &entries,
+            None,
+        ));
+        assert_eq!(again.matches("dc:language>").count(), 2);
+        assert!(again.contains("<dc:language>de</dc:language>"));
+        assert_eq!(again.matches("dcterms:modified").count(), 1);
+    }
+
+    #[test]
+    fn books_with_an_ncx_alone_get_a_nav() {
+        let package = Package::parse("OEBPS/content.opf".to_string(), OPF.as_bytes()).unwrap();
+        let nav = Nav::missing(&package).unwrap();
+        assert_eq!(nav.ncx, "OEBPS/toc.ncx");
+        assert_eq!(nav.path, format!("OEBPS/{}", NAV_NAME));
+        assert_eq!(nav.href, NAV_NAME);
+        let nav = text(nav_from_ncx(NCX.as_bytes(), "German"));
+        assert!(nav.contains(r#"lang="de" xml:lang="de"><head><title>The Book</title></head>"#));
+        assert!(nav.contains(
+            r##"<nav epub:type="toc" id="toc"><ol><li><a href="text/ch1.xhtml">Part &amp; One</a><ol><li><a href="text/ch1.xhtml#c1">Chapter 1</a></li></ol></li><li><a href="text/ch2.xhtml">Part Two</a></li></ol></nav>"##
+        ), "{}", nav);
+
+        let opf = OPF.replace(
+            r#"<item id="ch1""#,
+            r#"<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/><item id="ch1""#,
+        );
+        let package = Package::parse("OEBPS/content.opf".to_string(), opf.as_bytes()).unwrap();
+        assert!(Nav::missing(&package).is_none());
+    }
+}
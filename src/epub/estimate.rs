@@ -0,0 +1,103 @@
+use crate::client::pricing::Price;
+use crate::epub::spine_paragraphs;
+use crate::error::Error;
+use crate::translate::chunk;
+use std::fmt;
+use std::io::Cursor;
+
+/// Estimated tokens of the instructions sent with every chunk.
+const PROMPT_TOKENS: usize = 300;
+
+/// Estimated tokens of the JSON wrapped around each translated paragraph.
+const PARAGRAPH_OUTPUT_TOKENS: usize = 10;
+
+/// Projected requests and token usage of translating an EPUB, built without
+/// calling any API. Token counts are approximations; the output is taken to
+/// be about as long as the source.
+pub struct Estimate {
+    pub model: String,
+    pub paragraphs: usize,
+    pub characters: usize,
+    pub requests: usize,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub price: Option<Price>,
+}
+
+impl Estimate {
+    pub fn cost(&self) -> Option<f64> {
+        self.price
+            .map(|price| price.cost(self.input_tokens, self.output_tokens))
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "model: {}", self.model)?;
+        writeln!(f, "paragraphs: {}", self.paragraphs)?;
+        writeln!(f, "characters: {}", self.characters)?;
+        writeln!(f, "requests: {}", self.requests)?;
+        writeln!(f, "input tokens: ~{}", self.input_tokens)?;
+        writeln!(f, "output tokens: ~{}", self.output_tokens)?;
+        match (self.price, self.cost()) {
+            (Some(price), Some(cost)) => writeln!(
+                f,
+                "cost: ~${:.2} (${} input, ${} output per 1M tokens)",
+                cost, price.input, price.output
+            ),
+            _ => writeln!(
+                f,
+                "cost: unknown price of {}; give it with --input-price and --output-price",
+                self.model
+            ),
+        }
+    }
+}
+
+/// Estimate the translation of the spine of `input` in chunks of `lines`
+/// paragraphs, or of `max_chunk_tokens` estimated tokens.
+pub async fn estimate_epub_bytes(
+    input: &[u8],
+    model: &str,
+    lines: usize,
+    max_chunk_tokens: Option<usize>,
+    price: Option<Price>,
+) -> Result<Estimate, Error> {
+    let paragraphs = spine_paragraphs(Cursor::new(input)).await?;
+    Ok(estimate(&paragraphs, model, lines, max_chunk_tokens, price))
+}
+
+/// Estimate the translation of `paragraphs` in chunks of `lines`
+/// paragraphs, or of `max_chunk_tokens` estimated tokens.
+pub fn estimate(
+    paragraphs: &[String],
+    model: &str,
+    lines: usize,
+    max_chunk_tokens: Option<usize>,
+    price: Option<Price>,
+) -> Estimate {
+    let chunk_lines = match max_chunk_tokens {
+        Some(_) => usize::MAX,
+        None => lines,
+    };
+    let chunks = chunk::split(paragraphs, chunk_lines, max_chunk_tokens, false);
+    let mut input_tokens = 0;
+    let mut output_tokens = 0;
+    for chunked in &chunks {
+        let tokens: usize = chunked
+            .iter()
+            .map(|line| chunk::estimate_tokens(line))
+            .sum();
+        input_tokens += (PROMPT_TOKENS + tokens) as u64;
+        output_tokens += (tokens + PARAGRAPH_OUTPUT_TOKENS * chunked.len()) as u64;
+    }
+    Estimate {
+        model: model.to_string(),
+        paragraphs: paragraphs.len(),
+        characters: paragraphs.iter().map(|line| line.chars().count()).sum(),
+        requests: chunks.len(),
+        input_tokens,
+        output_tokens,
+        price,
+    }
+}
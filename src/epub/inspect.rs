@@ -0,0 +1,130 @@
+use crate::epub::document_lines;
+use crate::epub::package::{read_entry, Package};
+use crate::error::Error;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::fmt;
+use std::io::Cursor;
+use zip::ZipArchive;
+
+pub struct Chapter {
+    pub name: String,
+    pub paragraphs: usize,
+    /// first paragraph, to tell the chapter by
+    pub opening: String,
+    pub footnotes: bool,
+    pub images: bool,
+    pub svg_text: bool,
+}
+
+/// Structural summary of an EPUB, built without calling any API.
+pub struct Inspection {
+    pub package: Package,
+    pub entries: usize,
+    pub chapters: Vec<Chapter>,
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "package: {}", self.package.path)?;
+        for (name, value) in &self.package.metadata {
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        writeln!(f, "entries: {}", self.entries)?;
+        writeln!(
+            f,
+            "content documents: {}",
+            self.package.content_documents().count()
+        )?;
+        writeln!(
+            f,
+            "paragraphs: {}",
+            self.chapters.iter().map(|c| c.paragraphs).sum::<usize>()
+        )?;
+        writeln!(f, "spine:")?;
+        for (index, chapter) in self.chapters.iter().enumerate() {
+            write!(
+                f,
+                "{:>4} {} paragraphs: {}",
+                index + 1,
+                chapter.name,
+                chapter.paragraphs
+            )?;
+            if chapter.footnotes {
+                write!(f, " footnotes")?;
+            }
+            if chapter.images {
+                write!(f, " images")?;
+            }
+            if chapter.svg_text {
+                write!(f, " svg-text")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn inspect_epub_bytes(input: &[u8]) -> Result<Inspection, Error> {
+    let mut archive = ZipArchive::new(Cursor::new(input))?;
+    let package = Package::read(&mut archive)?;
+
+    let mut chapters = Vec::new();
+    for name in &package.spine {
+        let content = read_entry(&mut archive, name)?;
+        let mut chapter = features(name, &content)?;
+        let lines = document_lines(name, &content)?;
+        chapter.paragraphs = lines.len();
+        chapter.opening = lines.into_iter().next().unwrap_or_default();
+        chapters.push(chapter);
+    }
+
+    Ok(Inspection {
+        entries: archive.len(),
+        package,
+        chapters,
+    })
+}
+
+fn features(name: &str, content: &[u8]) -> Result<Chapter, Error> {
+    let mut chapter = Chapter {
+        name: name.to_string(),
+        paragraphs: 0,
+        opening: String::new(),
+        footnotes: false,
+        images: false,
+        svg_text: false,
+    };
+    let mut reader = Reader::from_reader(content);
+    let mut svg_depth = 0;
+    loop {
+        let (e, is_start) = match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) => (e, true),
+            Event::Empty(e) => (e, false),
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"svg" {
+                    svg_depth -= 1;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        match e.local_name().as_ref() {
+            b"img" | b"image" => chapter.images = true,
+            b"svg" if is_start => svg_depth += 1,
+            b"text" if svg_depth > 0 => chapter.svg_text = true,
+            _ => (),
+        }
+        if let Some(epub_type) = e.try_get_attribute("epub:type")? {
+            let epub_type = epub_type.unescape_value()?;
+            if ["noteref", "footnote", "endnote", "rearnote"]
+                .iter()
+                .any(|t| epub_type.split_whitespace().any(|v| v == *t))
+            {
+                chapter.footnotes = true;
+            }
+        }
+    }
+    Ok(chapter)
+}
@@ -0,0 +1,374 @@
+use crate::epub::notes::{self, Links};
+use crate::epub::provenance;
+use crate::epub::{escaped_text, markup, unescape, PARAGRAPHS};
+use clap::ValueEnum;
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use regex::Regex;
+use std::io::Cursor;
+
+const EPUB_NAMESPACE: &str = "http://www.idpf.org/2007/ops";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Layout {
+    /// The original paragraph followed by `<<translated>>`
+    #[default]
+    Inline,
+    /// The translated paragraph with the original in a linked footnote aside
+    Annotated,
+    /// The translated paragraph followed by the original in a `<div class="original">`
+    Bilingual,
+    /// The translated paragraph alone
+    TranslatedOnly,
+}
+
+/// Style of the originals in the bilingual layout; adding `hide-original` to
+/// the class of the body hides them.
+const BILINGUAL_STYLE: &str = ".original { opacity: 0.7; font-size: 0.9em; margin-bottom: 1em; }\n\
+    .hide-original .original { display: none; }";
+
+/// Replace each paragraph with its translation, keeping the original as the
+/// layout says:
+///
+/// - annotated: an EPUB 3 footnote (`aside epub:type="footnote"`) at the end
+///   of the body, linked from the paragraph. Reading systems without popup
+///   footnotes show the asides as endnotes.
+/// - bilingual: a copy of the paragraph, without its `id`, in a
+///   `<div class="original">` after it, or inside it for list items and
+///   figure captions, which cannot have a sibling `div`.
+/// - translated only: nowhere.
+///
+/// The inline markup of a translated paragraph is only kept with `markup`,
+/// from the markers of its translation; the `id`s inside it then move to the
+/// translation, leaving the bilingual copy of the original without them.
+pub(crate) fn rewrite(layout: Layout, lines: Vec<String>, content: &[u8], markup: bool) -> Vec<u8> {
+    let ignore_text = Regex::new(r"^[\s\p{Cc}\p{So}0-9[:punct:]–]*$").unwrap();
+    let mut reader = Reader::from_reader(content);
+    reader.config_mut().trim_text(true);
+
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut is_translate = false;
+    let mut translate_tag: String = String::new();
+    let mut depth = 0;
+    let mut translate: String = String::new();
+    let mut start = BytesStart::new("");
+    let mut inner: Vec<Event<'static>> = Vec::new();
+    let mut original: Option<Vec<Event<'static>>> = None;
+    let mut notes: Vec<(String, String)> = Vec::new();
+    let mut index = 0;
+    let mut links = Links::default();
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(e)) => {
+                let tag = std::str::from_utf8(e.name().0).unwrap();
+                match tag {
+                    "html" if layout == Layout::Annotated => writer
+                        .write_event(Event::Start(with_epub_namespace(e)))
+                        .unwrap(),
+                    _ if is_translate => {
+                        if *tag == translate_tag {
+                            depth += 1;
+                        }
+                        if !markup {
+                            links.start(&e);
+                        }
+                        inner.push(Event::Start(e.into_owned()));
+                    }
+                    tag if PARAGRAPHS.contains(&tag) => {
+                        translate_tag = tag.to_string();
+                        is_translate = true;
+                        translate = String::new();
+                        depth = 1;
+                        start = e.clone().into_owned();
+                        writer.write_event(Event::Start(e)).unwrap();
+                    }
+                    _ => writer.write_event(Event::Start(e)).unwrap(),
+                }
+            }
+            Ok(Event::End(e)) => {
+                let tag = std::str::from_utf8(e.name().0).unwrap();
+                if is_translate && *tag == translate_tag {
+                    depth -= 1;
+                }
+                links.end();
+                if is_translate && depth > 0 {
+                    inner.push(Event::End(e.into_owned()));
+                    continue;
+                }
+                if is_translate {
+                    is_translate = false;
+                    let line = if ignore_text.is_match(&translate) {
+                        None
+                    } else {
+                        index += 1;
+                        lines.get(index - 1).filter(|line| !line.is_empty())
+                    };
+                    match line {
+                        Some(line) => {
+                            if markup {
+                                markup::write(&mut writer, line, &markup::elements(&inner), true);
+                                inner = inner.into_iter().map(without_ids).collect();
+                            } else {
+                                // the links of the notes are kept so they
+                                // still lead to the notes and back
+                                let (leading, trailing) = take_links(&mut inner);
+                                for event in leading {
+                                    writer.write_event(event).unwrap();
+                                }
+                                writer.write_event(escaped_text(line)).unwrap();
+                                for event in trailing {
+                                    writer.write_event(event).unwrap();
+                                }
+                            }
+                            match layout {
+                                Layout::Annotated => {
+                                    let id = format!("trans-epub-source-{}", index);
+                                    write_noteref(&mut writer, &id);
+                                    notes.push((id, translate.clone()));
+                                }
+                                Layout::Bilingual if matches!(tag, "li" | "figcaption") => {
+                                    write_original(&mut writer, None, inner.drain(..));
+                                }
+                                Layout::Bilingual => original = Some(std::mem::take(&mut inner)),
+                                Layout::Inline | Layout::TranslatedOnly => (),
+                            }
+                        }
+                        None => {
+                            for event in inner.drain(..) {
+                                writer.write_event(event).unwrap();
+                            }
+                        }
+                    }
+                    inner.clear();
+                } else if tag == "body" {
+                    write_notes(&mut writer, &notes);
+                } else if tag == "head" && layout == Layout::Bilingual {
+                    write_style(&mut writer);
+                }
+                writer.write_event(Event::End(e)).unwrap();
+                if let Some(events) = original.take() {
+                    write_original(&mut writer, Some(&start), events);
+                }
+            }
+            Ok(Event::Text(e)) => {
+                let original_text = unescape(&e);
+                if is_translate {
+                    if !links.inside() {
+                        translate.push_str(&original_text);
+                    }
+                    inner.push(escaped_text(&original_text).into_owned());
+                } else {
+                    writer.write_event(escaped_text(&original_text)).unwrap();
+                }
+            }
+            Ok(event) if is_translate => inner.push(event.into_owned()),
+            event => writer.write_event(event.unwrap()).unwrap(),
+        }
+    }
+    writer.into_inner().into_inner()
+}
+
+fn with_epub_namespace(e: BytesStart) -> BytesStart {
+    if e.try_get_attribute("xmlns:epub").ok().flatten().is_some() {
+        return e;
+    }
+    let mut e = e;
+    e.push_attribute(("xmlns:epub", EPUB_NAMESPACE));
+    e
+}
+
+fn write_noteref(writer: &mut Writer<Cursor<Vec<u8>>>, id: &str) {
+    let href = format!("#{}", id);
+    let mut a = BytesStart::new("a");
+    a.push_attribute(("epub:type", "noteref"));
+    a.push_attribute(("href", href.as_str()));
+    writer.write_event(Event::Start(a)).unwrap();
+    writer.write_event(escaped_text("*")).unwrap();
+    writer.write_event(Event::End(BytesEnd::new("a"))).unwrap();
+}
+
+/// Write the original paragraph in a `<div class="original">`, in a copy of
+/// `element` when it is written after the translated element.
+fn write_original(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    element: Option<&BytesStart>,
+    events: impl IntoIterator<Item = Event<'static>>,
+) {
+    let mut div = BytesStart::new("div");
+    div.push_attribute(("class", "original"));
+    writer.write_event(Event::Start(div)).unwrap();
+    let element = element.map(without_id);
+    if let Some(element) = &element {
+        writer.write_event(Event::Start(element.borrow())).unwrap();
+    }
+    for event in events {
+        writer.write_event(event).unwrap();
+    }
+    if let Some(element) = &element {
+        writer.write_event(Event::End(element.to_end())).unwrap();
+    }
+    writer
+        .write_event(Event::End(BytesEnd::new("div")))
+        .unwrap();
+}
+
+/// The note links of a paragraph (see [`notes::is_link`]) written before
+/// its text and after it, which move to its translation; in `inner` they
+/// are left without their `id`s.
+fn take_links(inner: &mut [Event<'static>]) -> (Vec<Event<'static>>, Vec<Event<'static>>) {
+    let (mut leading, mut trailing) = (Vec::new(), Vec::new());
+    let mut text = false;
+    let mut link: Option<(Vec<Event<'static>>, usize)> = None;
+    for event in inner.iter_mut() {
+        if let Some((events, depth)) = &mut link {
+            match event {
+                Event::Start(_) => *depth += 1,
+                Event::End(_) => *depth -= 1,
+                _ => (),
+            }
+            events.push(event.clone());
+            if *depth == 0 {
+                let (events, _) = link.take().unwrap();
+                if text {
+                    trailing.extend(events);
+                } else {
+                    leading.extend(events);
+                }
+            }
+            continue;
+        }
+        match event {
+            Event::Start(e) if notes::is_link(e) => {
+                link = Some((vec![Event::Start(e.clone())], 1));
+                *e = without_id(e);
+            }
+            Event::Text(e) if !e.iter().all(u8::is_ascii_whitespace) => text = true,
+            _ => (),
+        }
+    }
+    (leading, trailing)
+}
+
+/// `event`, without the `id` of the element it starts.
+fn without_ids(event: Event<'static>) -> Event<'static> {
+    match event {
+        Event::Start(e) => Event::Start(without_id(&e)),
+        Event::Empty(e) => Event::Empty(without_id(&e)),
+        event => event,
+    }
+}
+
+/// A copy of `element` without its `id`, which must stay unique, nor the
+/// attributes of `--provenance`, which belong to the translation.
+pub(crate) fn without_id(element: &BytesStart) -> BytesStart<'static> {
+    let name = String::from_utf8_lossy(element.name().0).into_owned();
+    let mut copy = BytesStart::new(name);
+    copy.extend_attributes(element.attributes().flatten().filter(|attribute| {
+        attribute.key.0 != b"id"
+            && !provenance::ATTRIBUTES
+                .iter()
+                .any(|name| name.as_bytes() == attribute.key.0)
+    }));
+    copy.into_owned()
+}
+
+fn write_style(writer: &mut Writer<Cursor<Vec<u8>>>) {
+    writer
+        .write_event(Event::Start(BytesStart::new("style")))
+        .unwrap();
+    writer.write_event(escaped_text(BILINGUAL_STYLE)).unwrap();
+    writer
+        .write_event(Event::End(BytesEnd::new("style")))
+        .unwrap();
+}
+
+fn write_notes(writer: &mut Writer<Cursor<Vec<u8>>>, notes: &[(String, String)]) {
+    for (id, original) in notes {
+        let mut aside = BytesStart::new("aside");
+        aside.push_attribute(("epub:type", "footnote"));
+        aside.push_attribute(("id", id.as_str()));
+        writer.write_event(Event::Start(aside)).unwrap();
+        writer
+            .write_event(Event::Start(BytesStart::new("p")))
+            .unwrap();
+        writer.write_event(escaped_text(original)).unwrap();
+        writer.write_event(Event::End(BytesEnd::new("p"))).unwrap();
+        writer
+            .write_event(Event::End(BytesEnd::new("aside")))
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAPTER: &str = r#"<html><head><title>One</title></head><body><p id="p1">The <em id="e">sea</em> rose.</p><p>* * *</p><ul><li>A boat.</li></ul></body></html>"#;
+
+    fn rewritten(layout: Layout, markup: bool, lines: &[&str]) -> String {
+        let lines = lines.iter().map(|line| line.to_string()).collect();
+        String::from_utf8(rewrite(layout, lines, CHAPTER.as_bytes(), markup)).unwrap()
+    }
+
+    #[test]
+    fn originals_are_kept_as_the_layout_says() {
+        let lines = ["Das Meer stieg.", "Ein Boot."];
+        let translated = r#"<p id="p1">Das Meer stieg.</p>"#;
+        let output = rewritten(Layout::TranslatedOnly, false, &lines);
+        assert!(output.contains(translated), "{}", output);
+        assert!(output.contains("<p>* * *</p><ul><li>Ein Boot.</li></ul>"));
+        assert!(!output.contains("sea"));
+
+        let output = rewritten(Layout::Annotated, false, &lines);
+        assert!(output.starts_with(&format!(r#"<html xmlns:epub="{}">"#, EPUB_NAMESPACE)));
+        assert!(output.contains(
+            r##"<li>Ein Boot.<a epub:type="noteref" href="#trans-epub-source-2">*</a></li>"##
+        ));
+        assert!(output.ends_with(
+            r#"<aside epub:type="footnote" id="trans-epub-source-2"><p>A boat.</p></aside></body></html>"#
+        ));
+
+        let output = rewritten(Layout::Bilingual, false, &lines);
+        assert!(output.contains(&format!("<style>{}</style></head>", BILINGUAL_STYLE)));
+        // a list item holds its original, a paragraph is followed by it
+        assert!(output.contains(&format!(r#"{}<div class="original"><p>"#, translated)));
+        assert!(output.contains(r#"<li>Ein Boot.<div class="original">A boat.</div></li>"#));
+    }
+
+    #[test]
+    fn ids_move_to_the_translated_markup() {
+        let output = rewritten(
+            Layout::Bilingual,
+            true,
+            &["Das ⟦1⟧Meer⟦/1⟧ stieg.", "Ein Boot."],
+        );
+        assert!(output.contains(r#"<p id="p1">Das <em id="e">Meer</em> stieg.</p>"#));
+        assert_eq!(output.matches(r#"id="e""#).count(), 1);
+        assert!(output.contains("<em>sea</em>"));
+    }
+
+    #[test]
+    fn untranslated_paragraphs_are_left_alone() {
+        let output = rewritten(Layout::Bilingual, false, &["", "Ein Boot."]);
+        assert!(output.contains(r#"<p id="p1">The"#));
+        assert!(output.contains(r#"<em id="e">sea</em>"#));
+        assert_eq!(output.matches(r#"class="original""#).count(), 1);
+    }
+
+    #[test]
+    fn copies_lose_their_ids() {
+        let mut element = BytesStart::new("p");
+        element.push_attribute(("id", "p1"));
+        element.push_attribute(("class", "first"));
+        for attribute in provenance::ATTRIBUTES {
+            element.push_attribute((attribute, "x"));
+        }
+        let copy = without_id(&element);
+        assert_eq!(copy.name().as_ref(), b"p");
+        let attributes: Vec<_> = copy.attributes().flatten().collect();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(attributes[0].key.as_ref(), b"class");
+    }
+}
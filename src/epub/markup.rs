@@ -0,0 +1,231 @@
+use crate::epub::escaped_text;
+use crate::epub::layout::without_id;
+use log::warn;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Writer;
+use regex::Regex;
+use std::io::Cursor;
+
+/// Instruction added to the prompt when a chunk contains marked paragraphs.
+pub const INSTRUCTION: &str = "The inline formatting of a paragraph is marked with numbered tags: `⟦1⟧` opens and `⟦/1⟧` closes element 1, and `⟦2/⟧` stands for the empty element 2. Keep every tag in the translation, around the words that translate the words it marks, and do not add any.\n";
+
+pub fn open(number: usize) -> String {
+    format!("⟦{}⟧", number)
+}
+
+pub fn close(number: usize) -> String {
+    format!("⟦/{}⟧", number)
+}
+
+pub fn empty(number: usize) -> String {
+    format!("⟦{}/⟧", number)
+}
+
+pub fn is_marked(line: &str) -> bool {
+    marker().is_match(line)
+}
+
+/// The text of a marked paragraph without its markers.
+pub fn strip(line: &str) -> String {
+    marker().replace_all(line, "").into_owned()
+}
+
+/// The numbers of the elements a marked line opens or stands for, in the
+/// order of its markers.
+pub(crate) fn opened(line: &str) -> Vec<usize> {
+    marker()
+        .captures_iter(line)
+        .filter(|captures| captures[1].is_empty())
+        .filter_map(|captures| captures[2].parse().ok())
+        .collect()
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Open(usize),
+    Close(usize),
+    Empty(usize),
+}
+
+/// Write a translated paragraph, replacing its markers with the inline
+/// `elements` of the original paragraph, numbered from 1 in document order.
+///
+/// Markers the model dropped lose their element. When the markers are not
+/// well formed — unknown, repeated, closing the wrong element or left open —
+/// the paragraph is written as plain text instead. `id`s are only kept with
+/// `keep_ids`, where the original paragraph is not written alongside.
+pub(crate) fn write(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    line: &str,
+    elements: &[Event<'static>],
+    keep_ids: bool,
+) {
+    let tokens = match tokens(line, elements) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            warn!("markup: {}, writing as plain text: {}", e, line);
+            writer.write_event(escaped_text(&strip(line))).unwrap();
+            return;
+        }
+    };
+    for token in tokens {
+        match token {
+            Token::Text(text) => writer.write_event(escaped_text(text)).unwrap(),
+            Token::Open(number) => {
+                if let Event::Start(e) = &elements[number - 1] {
+                    writer.write_event(Event::Start(copy(e, keep_ids))).unwrap();
+                }
+            }
+            Token::Close(number) => {
+                if let Event::Start(e) = &elements[number - 1] {
+                    writer.write_event(Event::End(e.to_end())).unwrap();
+                }
+            }
+            Token::Empty(number) => {
+                if let Event::Empty(e) = &elements[number - 1] {
+                    writer.write_event(Event::Empty(copy(e, keep_ids))).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Split `line` at its markers, checking that they nest and name elements
+/// of the right kind.
+fn tokens<'a>(line: &'a str, elements: &[Event<'static>]) -> Result<Vec<Token<'a>>, String> {
+    let mut tokens = Vec::new();
+    let mut used = vec![false; elements.len()];
+    let mut unclosed = Vec::new();
+    let mut last = 0;
+    for captures in marker().captures_iter(line) {
+        let marker = captures.get(0).unwrap();
+        tokens.push(Token::Text(&line[last..marker.start()]));
+        last = marker.end();
+        let element = captures[2]
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| number.checked_sub(1))
+            .and_then(|index| elements.get(index))
+            .ok_or_else(|| format!("unknown {}", marker.as_str()))?;
+        let number: usize = captures[2].parse().unwrap();
+        let closing = !captures[1].is_empty();
+        let empty = !captures[3].is_empty();
+        match (closing, empty, element) {
+            (true, false, Event::Start(_)) => {
+                if unclosed.pop() != Some(number) {
+                    return Err(format!("misplaced {}", marker.as_str()));
+                }
+                tokens.push(Token::Close(number));
+                continue;
+            }
+            (false, false, Event::Start(_)) => {
+                unclosed.push(number);
+                tokens.push(Token::Open(number));
+            }
+            (false, true, Event::Empty(_)) => tokens.push(Token::Empty(number)),
+            _ => return Err(format!("misplaced {}", marker.as_str())),
+        }
+        if std::mem::replace(&mut used[number - 1], true) {
+            return Err(format!("repeated {}", marker.as_str()));
+        }
+    }
+    if let Some(number) = unclosed.pop() {
+        return Err(format!("unclosed {}", open(number)));
+    }
+    tokens.push(Token::Text(&line[last..]));
+    Ok(tokens)
+}
+
+fn copy(element: &BytesStart, keep_id: bool) -> BytesStart<'static> {
+    match keep_id {
+        true => element.clone().into_owned(),
+        false => without_id(element),
+    }
+}
+
+/// The elements of a paragraph's inner events, as numbered by its markers.
+pub(crate) fn elements(events: &[Event<'static>]) -> Vec<Event<'static>> {
+    events
+        .iter()
+        .filter(|event| matches!(event, Event::Start(_) | Event::Empty(_)))
+        .cloned()
+        .collect()
+}
+
+fn marker() -> Regex {
+    Regex::new(r"⟦(/?)(\d+)(/?)⟧").unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quick_xml::Reader;
+
+    /// The elements of the inner markup `xml`, as numbered by the markers.
+    fn parsed(xml: &str) -> Vec<Event<'static>> {
+        let mut reader = Reader::from_str(xml);
+        let mut events = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Eof => break,
+                event => events.push(event.into_owned()),
+            }
+        }
+        elements(&events)
+    }
+
+    fn written(line: &str, elements: &[Event<'static>], keep_ids: bool) -> String {
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        write(&mut writer, line, elements, keep_ids);
+        String::from_utf8(writer.into_inner().into_inner()).unwrap()
+    }
+
+    #[test]
+    fn markers_are_replaced_with_their_elements() {
+        let elements = parsed(r#"<em id="e">a</em> b<br/> <a href="x.xhtml">c</a>"#);
+        let line = format!(
+            "{}{}b & c{}{}d{}",
+            open(3),
+            close(3),
+            empty(2),
+            open(1),
+            close(1)
+        );
+        assert!(is_marked(&line));
+        assert_eq!(opened(&line), [3, 2, 1]);
+        assert_eq!(strip(&line), "b & cd");
+        assert_eq!(
+            written(&line, &elements, false),
+            r#"<a href="x.xhtml"></a>b &amp; c<br/><em>d</em>"#
+        );
+        assert_eq!(
+            written(&line, &elements, true),
+            r#"<a href="x.xhtml"></a>b &amp; c<br/><em id="e">d</em>"#
+        );
+    }
+
+    #[test]
+    fn dropped_markers_lose_their_element() {
+        let elements = parsed("<em>a</em><br/>");
+        assert_eq!(
+            written(&format!("x {}y{}", open(1), close(1)), &elements, false),
+            "x <em>y</em>"
+        );
+        assert!(!is_marked("no markers"));
+    }
+
+    #[test]
+    fn malformed_markers_give_plain_text() {
+        let elements = parsed("<em>a</em><b>b</b><br/>");
+        for line in [
+            format!("{}a", open(1)),
+            format!("{}a{}", open(4), close(4)),
+            format!("{}a{}b{}{}", open(1), close(1), open(1), close(1)),
+            format!("{}{}a{}{}", open(1), open(2), close(1), close(2)),
+            format!("{}a", empty(1)),
+            format!("{}a{}", open(3), close(3)),
+        ] {
+            assert_eq!(written(&line, &elements, false), strip(&line), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::epub::package::{attribute, meta_name};
+use crate::epub::unescape;
+use crate::error::Error;
+use crate::language;
+use crate::translate::translator::Translator;
+use quick_xml::events::{BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::io::Cursor;
+
+/// Human-readable metadata translated with `--translate-metadata`: the
+/// title, description, subjects and the series name (EPUB 3
+/// `belongs-to-collection` and calibre `calibre:series`). Identifiers, dates,
+/// languages, creators and publishers are left untouched.
+pub const TRANSLATED: &[&str] = &[
+    "dc:title",
+    "dc:description",
+    "dc:subject",
+    "belongs-to-collection",
+    "calibre:series",
+];
+
+/// Translate the metadata of the package document `opf`, replacing each
+/// value with its translation, and set `dc:language` to the language of the
+/// run.
+pub async fn translate(opf: &[u8], translator: &Translator) -> Result<Vec<u8>, Error> {
+    let values = collect(opf)?;
+    let translated = if values.is_empty() {
+        values
+    } else {
+        translator.translate(values).await
+    };
+    let language = language::code(&translator.context().language);
+    rewrite(opf, &translated, &language)
+}
+
+fn collect(opf: &[u8]) -> Result<Vec<String>, Error> {
+    let mut values = Vec::new();
+    let mut reader = Reader::from_reader(opf);
+    let mut in_element = false;
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) => match translated_content(&e) {
+                Some(Some(content)) => values.push(content),
+                Some(None) => in_element = true,
+                None => (),
+            },
+            Event::Empty(e) => {
+                if let Some(Some(content)) = translated_content(&e) {
+                    values.push(content);
+                }
+            }
+            Event::Text(e) if in_element => {
+                values.push(unescape(&e));
+            }
+            Event::End(_) => in_element = false,
+            _ => (),
+        }
+    }
+    values.retain(|value| !value.trim().is_empty());
+    Ok(values)
+}
+
+fn rewrite(opf: &[u8], translated: &[String], language: &str) -> Result<Vec<u8>, Error> {
+    let mut translated = translated.iter();
+    let mut reader = Reader::from_reader(opf);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut in_element = false;
+    let mut in_language = false;
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) if e.name().as_ref() == b"dc:language" => {
+                in_language = true;
+                writer.write_event(Event::Start(e)).unwrap();
+            }
+            Event::Text(_) if in_language => {
+                in_language = false;
+                writer
+                    .write_event(Event::Text(BytesText::new(language)))
+                    .unwrap();
+            }
+            Event::Start(e) => match translated_content(&e) {
+                Some(Some(content)) if !content.trim().is_empty() => {
+                    let element = with_content(&e, translated.next());
+                    writer.write_event(Event::Start(element)).unwrap();
+                }
+                Some(None) => {
+                    in_element = true;
+                    writer.write_event(Event::Start(e)).unwrap();
+                }
+                _ => writer.write_event(Event::Start(e)).unwrap(),
+            },
+            Event::Empty(e) => match translated_content(&e) {
+                Some(Some(content)) if !content.trim().is_empty() => {
+                    let element = with_content(&e, translated.next());
+                    writer.write_event(Event::Empty(element)).unwrap();
+                }
+                _ => writer.write_event(Event::Empty(e)).unwrap(),
+            },
+            Event::Text(e) if in_element => {
+                let original = unescape(&e);
+                let line = if original.trim().is_empty() {
+                    None
+                } else {
+                    translated.next().filter(|line| !line.is_empty())
+                };
+                match line {
+                    Some(line) => writer
+                        .write_event(Event::Text(BytesText::new(line)))
+                        .unwrap(),
+                    None => writer.write_event(Event::Text(e)).unwrap(),
+                }
+            }
+            Event::End(e) => {
+                in_element = false;
+                in_language = false;
+                writer.write_event(Event::End(e)).unwrap();
+            }
+            event => writer.write_event(event).unwrap(),
+        }
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+/// `Some(Some(content))` for a translated `meta` with a `content`
+/// attribute, `Some(None)` for a translated element with text content.
+fn translated_content(e: &BytesStart) -> Option<Option<String>> {
+    let name = meta_name(e).ok()?;
+    if !TRANSLATED.contains(&name.as_str()) {
+        return None;
+    }
+    attribute(e, "content").ok()
+}
+
+fn with_content(e: &BytesStart, line: Option<&String>) -> BytesStart<'static> {
+    let Some(line) = line.filter(|line| !line.is_empty()) else {
+        return e.clone().into_owned();
+    };
+    let name = String::from_utf8_lossy(e.name().as_ref()).into_owned();
+    let mut element = BytesStart::new(name);
+    for attribute in e.attributes().with_checks(false).flatten() {
+        if attribute.key.as_ref() == b"content" {
+            element.push_attribute(("content", line.as_str()));
+        } else {
+            element.push_attribute(attribute);
+        }
+    }
+    element
+}
@@ -0,0 +1,230 @@
+use crate::epub::package::join;
+use crate::epub::{markup, unescape, PARAGRAPHS};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Longest part of a note quoted in the prompt to point it out.
+const QUOTED_CHARS: usize = 60;
+
+/// Characters that end a sentence.
+const SENTENCE_ENDS: &[char] = &['.', '!', '?', '。', '！', '？'];
+
+/// The footnotes and endnotes of a book (`epub:type="footnote"`, `endnote`
+/// or `rearnote`) and the sentences that refer to them through a noteref,
+/// recorded as the content documents are translated so a note is translated
+/// in the sense of its reference, even from another document.
+#[derive(Default)]
+pub struct Notes {
+    recorded: Mutex<Recorded>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    /// referring sentences by the `path#id` of the note referred to
+    references: HashMap<String, String>,
+    /// referring sentences by the text of a paragraph of a note
+    sentences: HashMap<String, String>,
+}
+
+impl Notes {
+    /// Record the noterefs and the notes of the content document `name`,
+    /// read with its `markup` kept or not.
+    pub fn record(&self, name: &str, content: &[u8], markup: bool) {
+        let (references, bodies) = read(name, content, markup);
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.references.extend(references);
+        for (target, text) in bodies {
+            if let Some(sentence) = recorded.references.get(&target).cloned() {
+                recorded.sentences.insert(text, sentence);
+            }
+        }
+    }
+
+    /// The prompt text giving the referring sentence of each of `lines` that
+    /// is a note, empty when there is none.
+    pub fn render(&self, lines: &[String]) -> String {
+        let recorded = self.recorded.lock().unwrap();
+        let notes: Vec<String> = lines
+            .iter()
+            .filter_map(|line| {
+                let text = markup::strip(line);
+                let sentence = recorded.sentences.get(text.trim())?;
+                Some(format!(
+                    "- \"{}\" is a note to: \"{}\"\n",
+                    quote(&text),
+                    sentence
+                ))
+            })
+            .collect();
+        if notes.is_empty() {
+            return String::new();
+        }
+        format!(
+            "Some paragraphs are footnotes; translate each in the sense of the sentence that refers to it, given for context only:\n{}",
+            notes.concat()
+        )
+    }
+}
+
+/// Whether `e` is a link of the notes, a noteref or a backlink.
+pub(crate) fn is_link(e: &BytesStart) -> bool {
+    e.name().0 == b"a" && has_type(e, &["noteref", "backlink"])
+}
+
+/// Where a reader of a paragraph is in its note links, whose text, a note
+/// number or "Back", is left out of the paragraph when its markup is not
+/// kept: the links are written back around the translation instead.
+#[derive(Default)]
+pub(crate) struct Links {
+    depth: usize,
+}
+
+impl Links {
+    pub(crate) fn start(&mut self, e: &BytesStart) {
+        if self.depth > 0 || is_link(e) {
+            self.depth += 1;
+        }
+    }
+
+    pub(crate) fn end(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    pub(crate) fn inside(&self) -> bool {
+        self.depth > 0
+    }
+}
+
+/// Whether the `epub:type` or `role` of `e` is one of `types`.
+fn has_type(e: &BytesStart, types: &[&str]) -> bool {
+    ["epub:type", "role"].iter().any(|name| {
+        let Some(value) = e.try_get_attribute(*name).ok().flatten() else {
+            return false;
+        };
+        let value = value.unescape_value().unwrap_or_default();
+        value.split_whitespace().any(|value| {
+            types
+                .iter()
+                .any(|t| value == *t || value.strip_prefix("doc-") == Some(*t))
+        })
+    })
+}
+
+/// Texts with the `path#id` of the note they belong or refer to.
+type Targeted = Vec<(String, String)>;
+
+/// The noterefs of a document, as the `path#id` they refer to with their
+/// sentence, and the paragraphs of its notes, as the `path#id` of their note
+/// with their text, joined as `translate_lines` joins a paragraph.
+fn read(name: &str, content: &[u8], markup: bool) -> (Targeted, Targeted) {
+    let dir = name.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let mut reader = Reader::from_reader(content);
+    reader.config_mut().trim_text(true);
+    let mut references = Vec::new();
+    let mut bodies = Vec::new();
+    // the note each open element is in, if any
+    let mut open: Vec<Option<String>> = Vec::new();
+    let mut paragraph: Option<(Vec<u8>, usize)> = None;
+    let mut text = String::new();
+    let mut targets: Vec<(String, usize)> = Vec::new();
+    let mut links = Links::default();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(Event::Start(e)) => {
+                let note = e
+                    .try_get_attribute("id")
+                    .ok()
+                    .flatten()
+                    .filter(|_| has_type(&e, &["footnote", "endnote", "rearnote"]))
+                    .and_then(|id| id.unescape_value().ok())
+                    .map(|id| format!("{}#{}", name, id));
+                open.push(note.or_else(|| open.last().cloned().flatten()));
+                let tag = e.name().0.to_vec();
+                match &mut paragraph {
+                    Some((paragraph, depth)) => {
+                        if *paragraph == tag {
+                            *depth += 1;
+                        }
+                        if !markup {
+                            links.start(&e);
+                        }
+                        if has_type(&e, &["noteref"]) {
+                            if let Some(href) = e.try_get_attribute("href").ok().flatten() {
+                                let href = href.unescape_value().unwrap_or_default();
+                                if let Some((path, id)) = href.split_once('#') {
+                                    let path = match path {
+                                        "" => name.to_string(),
+                                        path => join(dir, path),
+                                    };
+                                    targets.push((format!("{}#{}", path, id), text.len()));
+                                }
+                            }
+                        }
+                    }
+                    None if PARAGRAPHS.contains(&std::str::from_utf8(&tag).unwrap_or_default()) => {
+                        paragraph = Some((tag, 1));
+                        text.clear();
+                        targets.clear();
+                    }
+                    None => (),
+                }
+            }
+            Ok(Event::End(e)) => {
+                let note = open.pop().flatten();
+                let Some((tag, depth)) = &mut paragraph else {
+                    continue;
+                };
+                if *tag == e.name().0 {
+                    *depth -= 1;
+                }
+                links.end();
+                if *depth > 0 {
+                    continue;
+                }
+                paragraph = None;
+                for (target, offset) in targets.drain(..) {
+                    references.push((target, sentence(&text, offset)));
+                }
+                if let Some(note) = note {
+                    bodies.push((note, text.trim().to_string()));
+                }
+            }
+            Ok(Event::Text(e)) if paragraph.is_some() && !links.inside() => {
+                text.push_str(&unescape(&e))
+            }
+            _ => (),
+        }
+    }
+    (references, bodies)
+}
+
+/// The sentence of `text` around the note reference at byte `offset`, which
+/// usually follows the end of its sentence.
+fn sentence(text: &str, offset: usize) -> String {
+    let before = text[..offset].trim_end();
+    let ended = before.ends_with(SENTENCE_ENDS);
+    let head = before.trim_end_matches(SENTENCE_ENDS);
+    let start = head.rfind(SENTENCE_ENDS).map_or(0, |i| {
+        i + head[i..].chars().next().map_or(0, char::len_utf8)
+    });
+    let end = if ended {
+        before.len()
+    } else {
+        text[offset..].find(SENTENCE_ENDS).map_or(text.len(), |i| {
+            offset + i + text[offset + i..].chars().next().map_or(0, char::len_utf8)
+        })
+    };
+    text[start..end].trim().to_string()
+}
+
+/// The start of a note, to point it out in the prompt.
+fn quote(text: &str) -> String {
+    let text = text.trim();
+    match text.char_indices().nth(QUOTED_CHARS) {
+        Some((i, _)) => format!("{}…", &text[..i]),
+        None => text.to_string(),
+    }
+}
@@ -0,0 +1,159 @@
+use crate::epub::package::join;
+use crate::epub::{escaped_text, unescape};
+use crate::translate::translator::Translator;
+use log::{info, warn};
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek};
+use zip::ZipArchive;
+
+/// Image types read by the model, by extension.
+const IMAGES: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+];
+
+/// Asked of the model for each image.
+const PROMPT: &str = "Transcribe the text in this image exactly, one paragraph per line, without any comment. If there is no text, answer with nothing.";
+
+/// Class of the captions written after the images.
+const CAPTION_CLASS: &str = "trans-epub-ocr";
+
+/// The text of each image of the archive that has some, by entry name:
+/// the scanned pages and the pictures of text the translation would
+/// otherwise miss.
+pub async fn transcribe<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    translator: &Translator,
+) -> HashMap<String, String> {
+    let mut images = Vec::new();
+    for i in 0..archive.len() {
+        let Ok(mut file) = archive.by_index(i) else {
+            continue;
+        };
+        let extension = file.name().rsplit_once('.').map(|(_, e)| e.to_lowercase());
+        let Some((_, mime)) = IMAGES
+            .iter()
+            .find(|(image, _)| extension.as_deref() == Some(image))
+        else {
+            continue;
+        };
+        let mut content = Vec::new();
+        if file.read_to_end(&mut content).is_ok() {
+            images.push((file.name().to_string(), *mime, content));
+        }
+    }
+    let mut texts = HashMap::new();
+    for (name, mime, content) in images {
+        info!("ocr {}", name);
+        match translator.transcribe(PROMPT, mime, &content).await {
+            Ok(text) if !text.trim().is_empty() => {
+                texts.insert(name, text.trim().to_string());
+            }
+            Ok(_) => (),
+            Err(e) => warn!("ocr {}: {}", name, e),
+        }
+    }
+    texts
+}
+
+/// Add the translated text of the images of the content document `name`
+/// after each of their `img`, as a caption with the class `trans-epub-ocr`.
+pub async fn caption(
+    name: &str,
+    content: &[u8],
+    texts: &HashMap<String, String>,
+    translator: &Translator,
+    language: &str,
+) -> Vec<u8> {
+    let dir = name.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let sources = images(content, dir);
+    let mut captions: HashMap<&str, Vec<String>> = HashMap::new();
+    for source in sources {
+        let Some(text) = texts.get(&source) else {
+            continue;
+        };
+        if captions.contains_key(text.as_str()) {
+            continue;
+        }
+        let lines: Vec<String> = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(String::from)
+            .collect();
+        captions.insert(text, translator.translate_into(lines, language).await);
+    }
+    if captions.is_empty() {
+        return content.to_vec();
+    }
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut pending = None;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(_) => return content.to_vec(),
+        };
+        let image = match &event {
+            Event::Empty(e) if e.name().0 == b"img" => source(e, dir),
+            Event::Start(e) if e.name().0 == b"img" => {
+                pending = source(e, dir);
+                None
+            }
+            Event::End(e) if e.name().0 == b"img" => pending.take(),
+            _ => None,
+        };
+        match event {
+            Event::Text(e) => writer.write_event(escaped_text(&unescape(&e))).unwrap(),
+            event => writer.write_event(event).unwrap(),
+        }
+        let lines = image
+            .and_then(|image| texts.get(&image))
+            .and_then(|text| captions.get(text.as_str()));
+        if let Some(lines) = lines {
+            let mut span = BytesStart::new("span");
+            span.push_attribute(("class", CAPTION_CLASS));
+            writer.write_event(Event::Start(span)).unwrap();
+            for (i, line) in lines.iter().enumerate() {
+                if i > 0 {
+                    writer
+                        .write_event(Event::Empty(BytesStart::new("br")))
+                        .unwrap();
+                }
+                writer.write_event(escaped_text(line)).unwrap();
+            }
+            writer
+                .write_event(Event::End(BytesEnd::new("span")))
+                .unwrap();
+        }
+    }
+    writer.into_inner().into_inner()
+}
+
+/// The entry names of the `img` of a document in the directory `dir`.
+fn images(content: &[u8], dir: &str) -> Vec<String> {
+    let mut reader = Reader::from_reader(content);
+    let mut images = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.name().0 == b"img" => {
+                images.extend(source(&e, dir));
+            }
+            _ => (),
+        }
+    }
+    images
+}
+
+/// The entry name of the `src` of an `img` in the directory `dir`.
+fn source(e: &BytesStart, dir: &str) -> Option<String> {
+    let src = e.try_get_attribute("src").ok().flatten()?;
+    let src = src.unescape_value().ok()?;
+    Some(join(dir, &src))
+}
@@ -0,0 +1,178 @@
+use crate::epub::unescape;
+use crate::error::Error;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::io::{Read, Seek};
+use zip::ZipArchive;
+
+pub struct Item {
+    pub id: String,
+    pub href: String,
+    pub media_type: String,
+    pub properties: String,
+}
+
+/// The parts of the OPF package document the translator cares about.
+pub struct Package {
+    pub path: String,
+    pub metadata: Vec<(String, String)>,
+    pub manifest: Vec<Item>,
+    pub spine: Vec<String>,
+}
+
+impl Package {
+    pub fn read<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Self, Error> {
+        let container = read_entry(archive, "META-INF/container.xml")?;
+        let path = rootfile_path(&container)?;
+        let opf = read_entry(archive, &path)?;
+        Self::parse(path, &opf)
+    }
+
+    pub fn parse(path: String, opf: &[u8]) -> Result<Self, Error> {
+        let base = match path.rfind('/') {
+            Some(index) => &path[..=index],
+            None => "",
+        };
+        let mut reader = Reader::from_reader(opf);
+        reader.config_mut().trim_text(true);
+
+        let mut metadata = Vec::new();
+        let mut manifest = Vec::new();
+        let mut spine_ids = Vec::new();
+        let mut element: Option<String> = None;
+        loop {
+            match reader.read_event()? {
+                Event::Eof => break,
+                Event::Start(e) => {
+                    let name = tag_name(&e);
+                    if name.starts_with("dc:") || name == "meta" {
+                        if let Some(content) = attribute(&e, "content")? {
+                            metadata.push((meta_name(&e)?, content));
+                        } else {
+                            element = Some(meta_name(&e)?);
+                        }
+                    }
+                }
+                Event::Empty(e) => match tag_name(&e).as_str() {
+                    "item" => manifest.push(Item {
+                        id: attribute(&e, "id")?.unwrap_or_default(),
+                        href: join(base, &attribute(&e, "href")?.unwrap_or_default()),
+                        media_type: attribute(&e, "media-type")?.unwrap_or_default(),
+                        properties: attribute(&e, "properties")?.unwrap_or_default(),
+                    }),
+                    "itemref" => spine_ids.extend(attribute(&e, "idref")?),
+                    "meta" => {
+                        if let Some(content) = attribute(&e, "content")? {
+                            metadata.push((meta_name(&e)?, content));
+                        }
+                    }
+                    _ => (),
+                },
+                Event::Text(e) => {
+                    if let Some(name) = element.take() {
+                        metadata.push((name, unescape(&e)));
+                    }
+                }
+                Event::End(_) => element = None,
+                _ => (),
+            }
+        }
+
+        let spine = spine_ids
+            .iter()
+            .filter_map(|id| manifest.iter().find(|item| &item.id == id))
+            .map(|item| item.href.clone())
+            .collect();
+        Ok(Self {
+            path,
+            metadata,
+            manifest,
+            spine,
+        })
+    }
+
+    pub fn metadata(&self, name: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The EPUB 3 navigation document and the EPUB 2 NCX, when present.
+    pub fn navigation(&self) -> impl Iterator<Item = &Item> {
+        self.manifest.iter().filter(|item| {
+            item.properties
+                .split_whitespace()
+                .any(|property| property == "nav")
+                || item.media_type == "application/x-dtbncx+xml"
+        })
+    }
+
+    pub fn content_documents(&self) -> impl Iterator<Item = &Item> {
+        self.manifest
+            .iter()
+            .filter(|item| item.media_type == "application/xhtml+xml")
+    }
+}
+
+pub fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Vec<u8>, Error> {
+    let mut file = archive.by_name(name)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn rootfile_path(container: &[u8]) -> Result<String, Error> {
+    let mut reader = Reader::from_reader(container);
+    loop {
+        match reader.read_event()? {
+            Event::Eof => return Err(Error::Epub("rootfile not found".to_string())),
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                if let Some(path) = attribute(&e, "full-path")? {
+                    return Ok(path);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn tag_name(e: &BytesStart) -> String {
+    String::from_utf8_lossy(e.name().as_ref()).into_owned()
+}
+
+/// `dc:*` elements are keyed by tag name, `meta` by its `name` or `property`.
+pub(crate) fn meta_name(e: &BytesStart) -> Result<String, Error> {
+    let name = tag_name(e);
+    if name != "meta" {
+        return Ok(name);
+    }
+    Ok(attribute(e, "name")?
+        .or(attribute(e, "property")?)
+        .unwrap_or(name))
+}
+
+pub(crate) fn attribute(e: &BytesStart, name: &str) -> Result<Option<String>, Error> {
+    match e.try_get_attribute(name)? {
+        Some(attribute) => Ok(Some(attribute.unescape_value()?.into_owned())),
+        None => Ok(None),
+    }
+}
+
+/// Resolve `href` against the directory of the package document.
+pub(crate) fn join(base: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = base.split('/').filter(|part| !part.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            ".." => {
+                parts.pop();
+            }
+            "." | "" => (),
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
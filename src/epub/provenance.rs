@@ -0,0 +1,378 @@
+use crate::epub::package::attribute;
+use crate::epub::{markup, translate_lines, PARAGRAPHS};
+use crate::error::Error;
+use clap::ValueEnum;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The attributes of `--provenance attributes`, which the copies of a
+/// paragraph are written without.
+pub const ATTRIBUTES: [&str; 4] = [
+    "data-src-hash",
+    "data-model",
+    "data-run-id",
+    "data-recalled",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProvenanceMode {
+    /// `data-src-hash`, `data-model`, `data-run-id` and, for a translation
+    /// not requested in the run, `data-recalled` attributes on each
+    /// translated paragraph
+    Attributes,
+    /// A list of the translated paragraphs next to the output as
+    /// `<output>.provenance.json`
+    Sidecar,
+}
+
+/// Where each translated paragraph of a run comes from: a hash of its
+/// source, the model and the run, so a later run can tell the paragraphs of
+/// an old model or a buggy run from the others.
+pub struct Provenance {
+    pub mode: ProvenanceMode,
+    pub run_id: String,
+    recorded: Mutex<Vec<Entry>>,
+    /// where the translations of the run come from, by language and source
+    origins: Mutex<HashMap<(String, String), Origin>>,
+}
+
+/// Where a translation comes from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Origin {
+    /// the model that wrote it, when known: the one its request was sent
+    /// to, or the one a cached translation was requested from
+    pub model: Option<String>,
+    /// taken from the memory, the cache, `--tmx` or an XLIFF file instead
+    /// of requested in the run
+    pub recalled: bool,
+}
+
+/// A translated paragraph in `<output>.provenance.json`, with its source and
+/// translation as they were sent and received, markers included, so it can
+/// be translated again with `retranslate`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub document: String,
+    /// the number of the paragraph in its document, from 1, counting those
+    /// left untranslated
+    pub paragraph: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub language: String,
+    pub src_hash: String,
+    /// the model that wrote the translation, unless it was recalled from a
+    /// memory or a TMX file that does not tell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub run_id: String,
+    /// the translation was recalled rather than requested in the run
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub recalled: bool,
+    pub source: String,
+    pub translation: String,
+}
+
+impl Provenance {
+    /// The provenance of a run named `run_id` or after the time it started
+    /// and its process.
+    pub fn new(mode: ProvenanceMode, run_id: Option<String>) -> Self {
+        Self {
+            mode,
+            run_id: run_id.unwrap_or_else(new_run_id),
+            recorded: Mutex::new(Vec::new()),
+            origins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Credit the translations of `sources` into `language` to `origin`,
+    /// in place of where an earlier translation of the same source came
+    /// from.
+    pub fn credit(&self, language: &str, sources: &[String], origin: &Origin) {
+        let mut origins = self.origins.lock().unwrap();
+        for source in sources {
+            origins.insert((language.to_string(), source.clone()), origin.clone());
+        }
+    }
+
+    /// Where the translation of `source` into `language` comes from, if it
+    /// was credited.
+    pub fn origin(&self, language: &str, source: &str) -> Option<Origin> {
+        let origins = self.origins.lock().unwrap();
+        origins
+            .get(&(language.to_string(), source.to_string()))
+            .cloned()
+    }
+
+    /// Mark the paragraphs of the content document `name` translated from
+    /// `sources` into `translated`, given as by `translate_lines` with
+    /// `markup`, with where each translation comes from: with their
+    /// attributes in the `content` returned, or recorded for the sidecar. A
+    /// translation not credited, as what `--on-failure` leaves, is marked
+    /// without a model.
+    pub fn apply(
+        &self,
+        name: &str,
+        content: &[u8],
+        markup: bool,
+        language: &str,
+        sources: &[String],
+        translated: &[String],
+    ) -> Result<Vec<u8>, Error> {
+        let mut entries = Vec::new();
+        let marked = paragraphs(content, markup, |index, e| {
+            let (Some(source), Some(translation)) = (sources.get(index), translated.get(index))
+            else {
+                return;
+            };
+            if translation.is_empty() {
+                return;
+            }
+            let origin = self.origin(language, source).unwrap_or_default();
+            match self.mode {
+                ProvenanceMode::Attributes => {
+                    e.push_attribute(("data-src-hash", source_hash(source).as_str()));
+                    if let Some(model) = &origin.model {
+                        e.push_attribute(("data-model", model.as_str()));
+                    }
+                    e.push_attribute(("data-run-id", self.run_id.as_str()));
+                    if origin.recalled {
+                        e.push_attribute(("data-recalled", "true"));
+                    }
+                }
+                ProvenanceMode::Sidecar => entries.push(Entry {
+                    document: name.to_string(),
+                    paragraph: index + 1,
+                    id: attribute(e, "id").ok().flatten(),
+                    language: language.to_string(),
+                    src_hash: source_hash(source),
+                    model: origin.model,
+                    run_id: self.run_id.clone(),
+                    recalled: origin.recalled,
+                    source: source.clone(),
+                    translation: translation.clone(),
+                }),
+            }
+        })?;
+        match self.mode {
+            ProvenanceMode::Attributes => Ok(marked),
+            ProvenanceMode::Sidecar => {
+                self.recorded.lock().unwrap().extend(entries);
+                Ok(content.to_vec())
+            }
+        }
+    }
+
+    /// Finish the run that wrote `output`: write the recorded paragraphs
+    /// next to it as `<output>.provenance.json`, in the order of the book.
+    pub fn conclude(&self, output: &Path) -> Result<(), Error> {
+        if self.mode != ProvenanceMode::Sidecar {
+            return Ok(());
+        }
+        let mut recorded = self.recorded.lock().unwrap();
+        // the documents are translated concurrently
+        recorded.sort_by(|a, b| {
+            (a.document.as_str(), a.paragraph).cmp(&(b.document.as_str(), b.paragraph))
+        });
+        write(&sidecar(output), &recorded)
+    }
+}
+
+/// The id of a run not named with `--run-id`: the time it started, in
+/// seconds, and its process id.
+pub fn new_run_id() -> String {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    format!("{}-{}", started, std::process::id())
+}
+
+/// The sidecar of the EPUB `output`, `<output>.provenance.json`.
+pub fn sidecar(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".provenance.json");
+    PathBuf::from(path)
+}
+
+pub fn read(path: &Path) -> Result<Vec<Entry>, Error> {
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+pub fn write(path: &Path, entries: &[Entry]) -> Result<(), Error> {
+    std::fs::write(path, serde_json::to_string_pretty(entries)? + "\n")?;
+    Ok(())
+}
+
+/// The hash of a source paragraph in `data-src-hash`: the 64-bit FNV-1a of
+/// its text without markup and with its whitespace collapsed, in hex, so it
+/// can be computed again from the source alone.
+pub fn source_hash(source: &str) -> String {
+    let text = markup::strip(source);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let hash = text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Pass the start tag of each paragraph of `content`, with its index among
+/// those `translate_lines` reads, to `mark`, and the content rewritten with
+/// the tags it changed.
+fn paragraphs(
+    content: &[u8],
+    markup: bool,
+    mut mark: impl FnMut(usize, &mut BytesStart<'static>),
+) -> Result<Vec<u8>, Error> {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    // the events of the paragraph being read, its tag and depth
+    let mut paragraph: Option<(Vec<Event<'static>>, Vec<u8>, usize)> = None;
+    let mut index = 0;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event.into_owned(),
+            Err(e) => return Err(e.into()),
+        };
+        let Some((events, tag, depth)) = &mut paragraph else {
+            match &event {
+                Event::Start(e) if PARAGRAPHS.iter().any(|tag| tag.as_bytes() == e.name().0) => {
+                    paragraph = Some((vec![event.clone()], e.name().0.to_vec(), 1));
+                }
+                _ => writer.write_event(event).unwrap(),
+            }
+            continue;
+        };
+        match &event {
+            Event::Start(e) if e.name().0 == tag.as_slice() => *depth += 1,
+            Event::End(e) if e.name().0 == tag.as_slice() => *depth -= 1,
+            _ => (),
+        }
+        events.push(event);
+        if *depth > 0 {
+            continue;
+        }
+        let (mut events, _, _) = paragraph.take().unwrap();
+        if is_paragraph(&events, markup) {
+            if let Event::Start(e) = &mut events[0] {
+                mark(index, e);
+            }
+            index += 1;
+        }
+        for event in events {
+            writer.write_event(event).unwrap();
+        }
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+/// Whether the events of an element are a paragraph `translate_lines`
+/// reads, rather than one it leaves out for holding no text.
+fn is_paragraph(events: &[Event<'static>], markup: bool) -> bool {
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    for event in events {
+        writer.write_event(event.borrow()).unwrap();
+    }
+    translate_lines(&writer.into_inner().into_inner(), markup).is_ok_and(|lines| !lines.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &[u8] = b"<body><p>One</p><p>Two</p><p>Three</p></body>";
+
+    fn sources() -> Vec<String> {
+        translate_lines(CONTENT, false).unwrap()
+    }
+
+    #[test]
+    fn paragraphs_are_credited_to_where_they_come_from() {
+        let provenance = Provenance::new(ProvenanceMode::Sidecar, Some("run".to_string()));
+        let requested = Origin {
+            model: Some("backup".to_string()),
+            recalled: false,
+        };
+        let recalled = Origin {
+            model: None,
+            recalled: true,
+        };
+        provenance.credit("German", &sources()[..1], &requested);
+        provenance.credit("German", &sources()[1..2], &recalled);
+        let translated = ["Eins", "Zwei", "Drei"].map(String::from);
+        provenance
+            .apply(
+                "ch1.xhtml",
+                CONTENT,
+                false,
+                "German",
+                &sources(),
+                &translated,
+            )
+            .unwrap();
+        let recorded = provenance.recorded.lock().unwrap();
+        let origins: Vec<(Option<&str>, bool)> = recorded
+            .iter()
+            .map(|entry| (entry.model.as_deref(), entry.recalled))
+            .collect();
+        // the last one was not credited, as what --on-failure leaves
+        assert_eq!(
+            origins,
+            [(Some("backup"), false), (None, true), (None, false)]
+        );
+        drop(recorded);
+
+        let provenance = Provenance {
+            mode: ProvenanceMode::Attributes,
+            ..provenance
+        };
+        let marked = provenance
+            .apply(
+                "ch1.xhtml",
+                CONTENT,
+                false,
+                "German",
+                &sources(),
+                &translated,
+            )
+            .unwrap();
+        let marked = String::from_utf8(marked).unwrap();
+        let tags: Vec<&str> = marked
+            .split("<p ")
+            .skip(1)
+            .map(|tag| &tag[tag.find("data-run-id").unwrap()..tag.find("</p>").unwrap()])
+            .collect();
+        assert_eq!(
+            tags,
+            [
+                r#"data-run-id="run">One"#,
+                r#"data-run-id="run" data-recalled="true">Two"#,
+                r#"data-run-id="run">Three"#,
+            ]
+        );
+        assert!(marked.contains(r#"data-model="backup" data-run-id"#));
+        assert_eq!(marked.matches("data-model").count(), 1);
+    }
+
+    #[test]
+    fn malformed_documents_are_errors() {
+        let provenance = Provenance::new(ProvenanceMode::Attributes, None);
+        let content = b"<body><p>One</p></div></body>";
+        let translated = ["Eins".to_string()];
+        let marked = provenance.apply(
+            "ch1.xhtml",
+            content,
+            false,
+            "German",
+            &sources(),
+            &translated,
+        );
+        assert!(marked.is_err());
+    }
+}
@@ -0,0 +1,527 @@
+use crate::epub::package::attribute;
+use crate::epub::provenance::{Entry, Origin};
+use crate::epub::validate::is_copy;
+use crate::epub::{escaped_text, markup, notes, translate_lines, unescape, PARAGRAPHS};
+use crate::error::Error;
+use crate::translate::translator::Translator;
+use log::{info, warn};
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Cursor, Read, Write};
+use std::str::FromStr;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// A condition on the paragraphs to translate again, with `--filter`.
+#[derive(Clone, Debug)]
+pub enum Filter {
+    /// `contains:TEXT`: the translation contains the text
+    Contains(String),
+    /// `source:TEXT`: the source contains the text
+    Source(String),
+    /// `model:NAME`: the paragraph was translated with the model
+    Model(String),
+    /// `run:ID`: the paragraph was translated in the run
+    Run(String),
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = filter.split_once(':').ok_or_else(|| {
+            format!(
+                "{}: expected contains:, source:, model: or run: and a value",
+                filter
+            )
+        })?;
+        let value = value.to_string();
+        match kind {
+            "contains" => Ok(Filter::Contains(value)),
+            "source" => Ok(Filter::Source(value)),
+            "model" => Ok(Filter::Model(value)),
+            "run" => Ok(Filter::Run(value)),
+            kind => Err(format!(
+                "unknown filter {}: expected contains, source, model or run",
+                kind
+            )),
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Filter::Contains(text) => markup::strip(&entry.translation).contains(text.as_str()),
+            Filter::Source(text) => markup::strip(&entry.source).contains(text.as_str()),
+            Filter::Model(model) => entry.model.as_deref() == Some(model.as_str()),
+            Filter::Run(run) => entry.run_id == *run,
+        }
+    }
+}
+
+/// A paragraph listed with `--paragraphs`.
+enum Listed {
+    /// its document and its number in it, from 1
+    Paragraph(String, usize),
+    /// the `data-src-hash` of its source
+    Hash(String),
+}
+
+/// The paragraphs to translate again: those listed, if a list is given,
+/// that match every filter.
+pub struct Selection {
+    listed: Option<Vec<Listed>>,
+    filters: Vec<Filter>,
+}
+
+impl Selection {
+    pub fn new(filters: Vec<Filter>) -> Self {
+        Self {
+            listed: None,
+            filters,
+        }
+    }
+
+    /// Read the list of `--paragraphs`, one a line: `<document> paragraph
+    /// <n>`, as the log names a paragraph, so lines of the log can be kept
+    /// as they are, `<document>:<n>`, or the hash of its source.
+    pub fn list(&mut self, text: &str) -> Result<(), Error> {
+        let logged = Regex::new(r"(\S+) paragraph (\d+)").unwrap();
+        let numbered = Regex::new(r"^(\S+):(\d+)$").unwrap();
+        let hash = Regex::new(r"^[0-9a-f]{16}$").unwrap();
+        let mut listed = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if hash.is_match(line) {
+                listed.push(Listed::Hash(line.to_string()));
+                continue;
+            }
+            let Some(captures) = logged.captures(line).or_else(|| numbered.captures(line)) else {
+                return Err(Error::Input(format!(
+                    "--paragraphs line {}: {}: expected `<document> paragraph <n>`, `<document>:<n>` or a source hash",
+                    number + 1,
+                    line
+                )));
+            };
+            let paragraph = captures[2].parse().map_err(|e| {
+                Error::Input(format!(
+                    "--paragraphs line {}: {}: {}",
+                    number + 1,
+                    &captures[2],
+                    e
+                ))
+            })?;
+            listed.push(Listed::Paragraph(captures[1].to_string(), paragraph));
+        }
+        self.listed = Some(listed);
+        Ok(())
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        let listed = self.listed.as_ref().is_none_or(|listed| {
+            listed.iter().any(|listed| match listed {
+                Listed::Paragraph(document, paragraph) => {
+                    entry.document == *document && entry.paragraph == *paragraph
+                }
+                Listed::Hash(hash) => entry.src_hash == *hash,
+            })
+        });
+        listed && self.filters.iter().all(|filter| filter.matches(entry))
+    }
+}
+
+/// A new translation of a paragraph and the one it replaces.
+struct Replacement<'a> {
+    old: &'a str,
+    new: &'a str,
+    source: &'a str,
+}
+
+/// Translate again the paragraphs of the translated EPUB `epub` recorded in
+/// its provenance `entries` and chosen by `selection`, and write each new
+/// translation in place of the old one, in whichever layout the book was
+/// written; the rest of the book is left as it is. The entries of the
+/// paragraphs replaced are updated to their new translation, where it
+/// comes from, as the provenance of the `translator` tells, and `run_id`.
+/// Returns the rewritten EPUB and the number of paragraphs replaced.
+pub async fn retranslate_epub(
+    epub: &[u8],
+    entries: &mut [Entry],
+    selection: &Selection,
+    translator: &Translator,
+    run_id: &str,
+) -> Result<(Vec<u8>, usize), Error> {
+    let chosen: Vec<usize> = (0..entries.len())
+        .filter(|i| selection.matches(&entries[*i]))
+        .collect();
+    if chosen.is_empty() {
+        return Err(Error::Input(
+            "no recorded paragraph is chosen to translate again".to_string(),
+        ));
+    }
+    info!("retranslate: {} paragraphs", chosen.len());
+
+    let languages: BTreeSet<&str> = chosen
+        .iter()
+        .map(|i| entries[*i].language.as_str())
+        .collect();
+    let mut translations: HashMap<usize, String> = HashMap::new();
+    for language in languages {
+        let of_language: Vec<usize> = chosen
+            .iter()
+            .copied()
+            .filter(|i| entries[*i].language == language)
+            .collect();
+        let sources = of_language
+            .iter()
+            .map(|i| entries[*i].source.clone())
+            .collect();
+        let translated = translator.translate_into(sources, language).await;
+        for (i, translation) in of_language.into_iter().zip(translated) {
+            if translation.is_empty() {
+                warn!(
+                    "retranslate: {} paragraph {} could not be translated, left as it is",
+                    entries[i].document, entries[i].paragraph
+                );
+                continue;
+            }
+            translations.insert(i, translation);
+        }
+    }
+
+    let mut by_document: HashMap<&str, HashMap<usize, (usize, Replacement)>> = HashMap::new();
+    for (i, new) in &translations {
+        let entry = &entries[*i];
+        by_document
+            .entry(entry.document.as_str())
+            .or_default()
+            .insert(
+                entry.paragraph,
+                (
+                    *i,
+                    Replacement {
+                        old: &entry.translation,
+                        new,
+                        source: &entry.source,
+                    },
+                ),
+            );
+    }
+
+    let mut archive = ZipArchive::new(Cursor::new(epub))?;
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut replaced = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        if let Some(replacements) = by_document.get(name.as_str()) {
+            let (rewritten, done) = splice(&content, replacements);
+            for paragraph in replacements.keys().filter(|p| !done.contains(p)) {
+                warn!(
+                    "retranslate: {} paragraph {} does not hold its recorded translation, left as it is",
+                    name, paragraph
+                );
+            }
+            replaced.extend(done.iter().map(|paragraph| replacements[paragraph].0));
+            content = rewritten;
+        }
+        let options = match name.as_str() {
+            "mimetype" => {
+                SimpleFileOptions::default().compression_method(CompressionMethod::Stored)
+            }
+            _ => SimpleFileOptions::default(),
+        };
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(&content)?;
+    }
+    let written = zip.finish()?.into_inner();
+
+    let context = translator.context();
+    for i in &replaced {
+        let entry = &mut entries[*i];
+        let origin = match &context.provenance {
+            Some(provenance) => provenance
+                .origin(&entry.language, &entry.source)
+                .unwrap_or_default(),
+            // kept by the `retranslate` subcommand, not by every caller
+            None => Origin {
+                model: Some(context.request_model()),
+                recalled: false,
+            },
+        };
+        entry.translation = translations.remove(i).unwrap();
+        entry.model = origin.model;
+        entry.recalled = origin.recalled;
+        entry.run_id = run_id.to_string();
+    }
+    Ok((written, replaced.len()))
+}
+
+/// Write `content` with the paragraphs numbered as the keys of
+/// `replacements` translated anew, counting paragraphs as they were read
+/// for translation and leaving out the copies of the originals. Returns the
+/// content and the numbers of the paragraphs replaced, which are those that
+/// still hold their recorded translation.
+fn splice(
+    content: &[u8],
+    replacements: &HashMap<usize, (usize, Replacement)>,
+) -> (Vec<u8>, Vec<usize>) {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    // the events of the paragraph being read, its tag and depth
+    let mut paragraph: Option<(Vec<Event<'static>>, Vec<u8>, usize)> = None;
+    let mut copy = 0;
+    let mut index = 0;
+    let mut done = Vec::new();
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event.into_owned(),
+            Err(_) => return (content.to_vec(), Vec::new()),
+        };
+        if let Some((events, tag, depth)) = &mut paragraph {
+            match &event {
+                Event::Start(e) if e.name().0 == tag.as_slice() => *depth += 1,
+                Event::End(e) if e.name().0 == tag.as_slice() => *depth -= 1,
+                _ => (),
+            }
+            events.push(event);
+            if *depth > 0 {
+                continue;
+            }
+            let (events, _, _) = paragraph.take().unwrap();
+            if !is_paragraph(&events) {
+                write_all(&mut writer, events);
+                continue;
+            }
+            index += 1;
+            match replacements.get(&index) {
+                Some((_, replacement)) if replace(&mut writer, &events, replacement) => {
+                    done.push(index)
+                }
+                _ => write_all(&mut writer, events),
+            }
+            continue;
+        }
+        match &event {
+            Event::Start(e) if copy > 0 || is_copy(e) => copy += 1,
+            Event::End(_) if copy > 0 => copy -= 1,
+            Event::Start(e) if PARAGRAPHS.iter().any(|tag| tag.as_bytes() == e.name().0) => {
+                paragraph = Some((vec![event.clone()], e.name().0.to_vec(), 1));
+                continue;
+            }
+            _ => (),
+        }
+        writer.write_event(event).unwrap();
+    }
+    (writer.into_inner().into_inner(), done)
+}
+
+/// Write the paragraph of `events` with the new translation of the
+/// `replacement`, if it holds the old one: after its original and `<<` in
+/// the inline layout, or in place of its text, between the note links and
+/// before the link to or the copy of its original, in the others.
+fn replace(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    events: &[Event<'static>],
+    replacement: &Replacement,
+) -> bool {
+    let (Event::Start(start), Event::End(end)) = (&events[0], &events[events.len() - 1]) else {
+        return false;
+    };
+    let inner = &events[1..events.len() - 1];
+    let old = normalize(&markup::strip(replacement.old));
+    let marked = markup::is_marked(replacement.source);
+
+    // inline: original<<translation>>
+    let text = texts(inner);
+    let inline = text
+        .strip_suffix(">>")
+        .and_then(|translated| translated.rfind("<<"))
+        .filter(|at| normalize(&text[at + 2..text.len() - 2]) == old);
+    if let Some(at) = inline {
+        writer.write_event(Event::Start(start.clone())).unwrap();
+        let mut offset = 0;
+        let mut original = Vec::new();
+        for event in inner {
+            let Event::Text(e) = event else {
+                original.push(event.clone());
+                continue;
+            };
+            let text = unescape(e);
+            if offset + text.len() > at {
+                original.push(escaped_text(&text[..at - offset]).into_owned());
+                break;
+            }
+            offset += text.len();
+            original.push(event.clone());
+        }
+        let elements = markup::elements(&original);
+        write_all(writer, original);
+        writer.write_event(escaped_text("<<")).unwrap();
+        match marked {
+            true => markup::write(writer, replacement.new, &elements, false),
+            false => writer.write_event(escaped_text(replacement.new)).unwrap(),
+        }
+        writer.write_event(escaped_text(">>")).unwrap();
+        writer.write_event(Event::End(end.clone())).unwrap();
+        return true;
+    }
+
+    // other layouts: the translation between the note links, before the
+    // link to its original in a footnote or the copy of it
+    let items = items(inner);
+    let (mut first, mut last) = (0, items.len());
+    while last > first {
+        let is_original = match &inner[items[last - 1].0] {
+            Event::Start(e) => {
+                is_copy(e)
+                    || attribute(e, "href")
+                        .ok()
+                        .flatten()
+                        .is_some_and(|href| href.starts_with("#trans-epub-source-"))
+            }
+            _ => false,
+        };
+        if !is_original {
+            break;
+        }
+        last -= 1;
+    }
+    let tail = last;
+    if !marked {
+        let is_link = |item: &(usize, usize)| match &inner[item.0] {
+            Event::Start(e) => notes::is_link(e),
+            _ => false,
+        };
+        while first < last && is_link(&items[first]) {
+            first += 1;
+        }
+        while last > first && is_link(&items[last - 1]) {
+            last -= 1;
+        }
+    }
+    let range = |from: usize, to: usize| match (items.get(from), to.checked_sub(1)) {
+        (Some(first), Some(last)) if from < to => first.0..items[last].1,
+        _ => 0..0,
+    };
+    let translation = &inner[range(first, last)];
+    if normalize(&texts(translation)) != old {
+        return false;
+    }
+    let elements = match marked {
+        true => renumbered(replacement.old, markup::elements(translation)),
+        false => Some(Vec::new()),
+    };
+    writer.write_event(Event::Start(start.clone())).unwrap();
+    write_all(writer, inner[range(0, first)].to_vec());
+    match elements {
+        Some(elements) if marked => markup::write(writer, replacement.new, &elements, true),
+        _ => writer
+            .write_event(escaped_text(&markup::strip(replacement.new)))
+            .unwrap(),
+    }
+    write_all(writer, inner[range(last, tail)].to_vec());
+    write_all(writer, inner[range(tail, items.len())].to_vec());
+    writer.write_event(Event::End(end.clone())).unwrap();
+    true
+}
+
+/// The elements of a translation written from the markers of `old`,
+/// numbered as in its source, or none when not all of them are there.
+fn renumbered(old: &str, written: Vec<Event<'static>>) -> Option<Vec<Event<'static>>> {
+    let numbers = markup::opened(old);
+    if numbers.len() != written.len() {
+        return None;
+    }
+    let mut elements = vec![None; numbers.iter().copied().max().unwrap_or_default()];
+    for (number, element) in numbers.into_iter().zip(written) {
+        elements[number - 1] = Some(element);
+    }
+    elements.into_iter().collect()
+}
+
+/// The ranges of the events of each child of a paragraph: an element with
+/// what it contains, or a text.
+fn items(inner: &[Event<'static>]) -> Vec<(usize, usize)> {
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut from = 0;
+    for (i, event) in inner.iter().enumerate() {
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            items.push((from, i + 1));
+            from = i + 1;
+        }
+    }
+    items
+}
+
+fn texts(events: &[Event<'static>]) -> String {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Text(e) => Some(unescape(e)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether the events of an element are a paragraph read for translation,
+/// rather than one left out for holding no text.
+fn is_paragraph(events: &[Event<'static>]) -> bool {
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    write_all(&mut writer, events.to_vec());
+    translate_lines(&writer.into_inner().into_inner(), false).is_ok_and(|lines| !lines.is_empty())
+}
+
+fn write_all(writer: &mut Writer<Cursor<Vec<u8>>>, events: Vec<Event<'static>>) {
+    for event in events {
+        writer.write_event(event).unwrap();
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_paragraphs_are_read_or_reported_by_line() {
+        let mut selection = Selection::new(Vec::new());
+        selection
+            .list("text/ch1.xhtml paragraph 3\n\ntext/ch2.xhtml:12\n0123456789abcdef\n")
+            .unwrap();
+        assert_eq!(selection.listed.as_ref().map(Vec::len), Some(3));
+
+        let error = selection
+            .list("text/ch1.xhtml:1\n\ntext/ch1.xhtml:99999999999999999999999\n")
+            .unwrap_err();
+        assert!(
+            matches!(&error, Error::Input(message) if message.starts_with("--paragraphs line 3: ")),
+            "{}",
+            error
+        );
+        let error = selection.list("text/ch1.xhtml\n").unwrap_err();
+        assert!(
+            matches!(&error, Error::Input(message) if message.starts_with("--paragraphs line 1: ")),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use crate::epub::chapter;
+use crate::epub::document_lines;
+use crate::epub::package::{read_entry, Package};
+use crate::epub::provenance::Origin;
+use crate::error::Error;
+use crate::translate::translator::Translator;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek};
+use zip::ZipArchive;
+
+/// Translations of the paragraphs at the ends of a content document that
+/// were stitched with the neighbouring document.
+#[derive(Clone, Default)]
+pub struct Ends {
+    pub first: Option<String>,
+    pub last: Option<String>,
+    /// where the translations of the stitched paragraphs come from, first
+    /// and last, for `--provenance`
+    origins: (Option<Origin>, Option<Origin>),
+}
+
+impl Ends {
+    /// Translate the paragraphs of a document, taking the stitched ends as
+    /// already translated.
+    pub async fn translate(
+        &self,
+        lines: Vec<String>,
+        translator: &Translator,
+        language: &str,
+    ) -> Vec<String> {
+        let head = self.first.is_some() as usize;
+        let tail = self.last.is_some() as usize;
+        if lines.len() < head + tail {
+            return translator.translate_into(lines, language).await;
+        }
+        if let Some(provenance) = &translator.context().provenance {
+            let ends = [
+                (self.origins.0.as_ref(), lines.first()),
+                (self.origins.1.as_ref(), lines.last()),
+            ];
+            for (origin, source) in ends {
+                if let (Some(origin), Some(source)) = (origin, source) {
+                    provenance.credit(language, std::slice::from_ref(source), origin);
+                }
+            }
+        }
+        let middle = lines[head..lines.len() - tail].to_vec();
+        let mut translated = Vec::with_capacity(lines.len());
+        translated.extend(self.first.clone());
+        translated.extend(translator.translate_into(middle, language).await);
+        translated.extend(self.last.clone());
+        translated
+    }
+}
+
+struct Split<'a> {
+    previous: String,
+    next: String,
+    language: &'a str,
+    /// the stitched source text
+    source: String,
+    /// share of the stitched source text coming from the previous document
+    ratio: f64,
+}
+
+/// Find paragraphs split across the boundary of two content documents in
+/// spine order, translate each stitched paragraph as one and split the
+/// translation back across the boundary.
+///
+/// A paragraph continues into the next document when the previous document
+/// ends without sentence-final punctuation and the next one starts with a
+/// lower case letter. The detection is heuristic, so this is opt-in.
+pub async fn translate_ends<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    translator: &Translator,
+) -> Result<HashMap<String, Ends>, Error> {
+    let package = Package::read(archive)?;
+    let mut documents = Vec::new();
+    for href in &package.spine {
+        match read_entry(archive, href).and_then(|content| document_lines(href, &content)) {
+            Ok(lines) => documents.push((href.clone(), lines)),
+            Err(e) => warn!("{}: {}", href, e),
+        }
+    }
+
+    let mut splits = Vec::new();
+    let mut sources: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    let mut stitched_first: Option<&str> = None;
+    for pair in documents.windows(2) {
+        let (previous, previous_lines) = &pair[0];
+        let (next, next_lines) = &pair[1];
+        let (Some(tail), Some(head)) = (previous_lines.last(), next_lines.first()) else {
+            continue;
+        };
+        // a one-paragraph document already stitched to its predecessor
+        if previous_lines.len() == 1 && stitched_first == Some(previous.as_str()) {
+            stitched_first = None;
+            continue;
+        }
+        stitched_first = None;
+        if !continues(tail, head) {
+            continue;
+        }
+        let context = translator.context();
+        let language = chapter::language(context, &package.spine, previous);
+        let Some(language) = language
+            .filter(|language| chapter::language(context, &package.spine, next) == Some(*language))
+        else {
+            continue;
+        };
+        info!("stitch paragraph {} -> {}", previous, next);
+        let tail = tail.trim_end();
+        let head = head.trim_start();
+        let length = (tail.chars().count() + head.chars().count()).max(1);
+        let source = format!("{} {}", tail, head);
+        sources.entry(language).or_default().push(source.clone());
+        splits.push(Split {
+            previous: previous.clone(),
+            next: next.clone(),
+            language,
+            source,
+            ratio: tail.chars().count() as f64 / length as f64,
+        });
+        stitched_first = Some(next.as_str());
+    }
+
+    let mut ends: HashMap<String, Ends> = HashMap::new();
+    if splits.is_empty() {
+        return Ok(ends);
+    }
+    let mut translated = HashMap::new();
+    for (language, sources) in sources {
+        let lines = translator.translate_into(sources, language).await;
+        translated.insert(language, lines.into_iter());
+    }
+    for split in splits {
+        let text = translated
+            .get_mut(split.language)
+            .and_then(Iterator::next)
+            .unwrap_or_default();
+        let (first, last) = split_at_ratio(&text, split.ratio);
+        let origin = translator
+            .context()
+            .provenance
+            .as_ref()
+            .and_then(|provenance| provenance.origin(split.language, &split.source));
+        let previous = ends.entry(split.previous).or_default();
+        previous.last = Some(first);
+        previous.origins.1 = origin.clone();
+        let next = ends.entry(split.next).or_default();
+        next.first = Some(last);
+        next.origins.0 = origin;
+    }
+    Ok(ends)
+}
+
+/// Characters that end a paragraph at a sentence boundary.
+const SENTENCE_END: &str = ".!?…:\"”’»)」』。！？";
+
+fn continues(tail: &str, head: &str) -> bool {
+    let ends_sentence = tail
+        .trim_end()
+        .chars()
+        .last()
+        .is_some_and(|c| SENTENCE_END.contains(c));
+    let starts_lower = head
+        .trim_start()
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_lowercase());
+    !ends_sentence && starts_lower
+}
+
+/// Split `text` at the whitespace nearest to `ratio` of its length, or at
+/// that character when the language does not separate words.
+fn split_at_ratio(text: &str, ratio: f64) -> (String, String) {
+    let chars: Vec<char> = text.chars().collect();
+    let target = (chars.len() as f64 * ratio).round() as usize;
+    let split = chars
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.is_whitespace())
+        .map(|(i, _)| i)
+        .min_by_key(|i| i.abs_diff(target))
+        .unwrap_or(target.min(chars.len()));
+    let first: String = chars[..split].iter().collect();
+    let last: String = chars[split..].iter().collect();
+    (first.trim_end().to_string(), last.trim_start().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{Config, Provider};
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+<manifest>
+<item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+<item id="b" href="b.xhtml" media-type="application/xhtml+xml"/>
+<item id="c" href="c.xhtml" media-type="application/xhtml+xml"/>
+</manifest>
+<spine><itemref idref="a"/><itemref idref="b"/><itemref idref="c"/></spine>
+</package>"#;
+
+    /// A book of the documents `a`, `b` and `c` holding `paragraphs`.
+    fn book(paragraphs: [&[&str]; 3]) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let container =
+            r#"<container><rootfiles><rootfile full-path="content.opf"/></rootfiles></container>"#;
+        zip.start_file("META-INF/container.xml", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(container.as_bytes()).unwrap();
+        zip.start_file("content.opf", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(OPF.as_bytes()).unwrap();
+        for (name, paragraphs) in ["a.xhtml", "b.xhtml", "c.xhtml"].iter().zip(paragraphs) {
+            let body: String = paragraphs
+                .iter()
+                .map(|paragraph| format!("<p>{}</p>", paragraph))
+                .collect();
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            write!(zip, "<html><body>{}</body></html>", body).unwrap();
+        }
+        ZipArchive::new(Cursor::new(zip.finish().unwrap().into_inner())).unwrap()
+    }
+
+    fn translator() -> Translator {
+        let config = Config::new(Provider::Mock, "mock", "German");
+        config.provider.translator(config.context)
+    }
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[test]
+    fn paragraphs_continue_without_a_sentence_end() {
+        assert!(continues("she walked down the", "  stairs and out."));
+        assert!(!continues("she walked down.", "stairs and out."));
+        assert!(!continues("“Go,” she said”", "and went."));
+        assert!(!continues("she walked down the", "Stairs and out."));
+    }
+
+    #[test]
+    fn translations_are_split_at_the_nearest_space() {
+        assert_eq!(
+            split_at_ratio("one two three four", 0.5),
+            ("one two".to_string(), "three four".to_string())
+        );
+        assert_eq!(
+            split_at_ratio("一二三四", 0.25),
+            ("一".to_string(), "二三四".to_string())
+        );
+        assert_eq!(split_at_ratio("", 0.5), (String::new(), String::new()));
+    }
+
+    #[tokio::test]
+    async fn split_paragraphs_are_translated_whole() {
+        let mut archive = book([
+            &["It began.", "she walked down the"],
+            &["stairs"],
+            &["and out", "The end."],
+        ]);
+        let translator = translator();
+        let ends = translate_ends(&mut archive, &translator).await.unwrap();
+        // the one-paragraph document is stitched to the one before only
+        assert_eq!(ends.len(), 2);
+        let (a, b) = (&ends["a.xhtml"], &ends["b.xhtml"]);
+        assert_eq!(a.first, None);
+        assert_eq!(a.last.as_deref(), Some("[German] she walked down the"));
+        assert_eq!(b.first.as_deref(), Some("stairs"));
+        assert_eq!(b.last, None);
+        assert_eq!(
+            a.translate(
+                texts(&["It began.", "she walked down the"]),
+                &translator,
+                "German"
+            )
+            .await,
+            ["[German] It began.", "[German] she walked down the"]
+        );
+        assert_eq!(
+            b.translate(texts(&["stairs"]), &translator, "German").await,
+            ["stairs"]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use crate::epub::{escaped_text, unescape, PARAGRAPHS};
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+use regex::Regex;
+use std::io::Cursor;
+
+/// Elements of an SVG whose text is translated: labels, and the title and
+/// description read out by screen readers.
+const TEXT: &[&[u8]] = &[b"text", b"tspan", b"textPath", b"title", b"desc"];
+
+/// The texts of the SVGs of a document, inline or an SVG file, in order;
+/// an SVG inside a paragraph is translated with the paragraph.
+pub fn collect(content: &[u8]) -> Vec<String> {
+    let mut labels = Vec::new();
+    walk(content, |label| {
+        labels.push(label.to_string());
+        None
+    });
+    labels
+}
+
+/// Replace each collected text of the SVGs with its translation; texts with
+/// an empty translation are left alone. A diagram has no room for both, so
+/// the original is not kept alongside.
+pub fn rewrite(content: &[u8], translated: &[String]) -> Vec<u8> {
+    if translated.is_empty() {
+        return content.to_vec();
+    }
+    let mut translated = translated.iter();
+    walk(content, |_| {
+        translated.next().filter(|line| !line.is_empty()).cloned()
+    })
+}
+
+/// Copy `content`, passing each text of its SVGs, trimmed, to `label` and
+/// writing what it returns in place of the text.
+fn walk(content: &[u8], mut label: impl FnMut(&str) -> Option<String>) -> Vec<u8> {
+    let ignore_text = Regex::new(r"^[\s\p{Cc}\p{So}0-9[:punct:]–]*$").unwrap();
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let (mut svg, mut text, mut paragraph) = (0, 0, 0);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(e)) => {
+                let name = e.local_name();
+                match name.as_ref() {
+                    b"svg" => svg += 1,
+                    name if svg > 0 && TEXT.contains(&name) => text += 1,
+                    name if PARAGRAPHS
+                        .iter()
+                        .any(|paragraph| paragraph.as_bytes() == name) =>
+                    {
+                        paragraph += 1
+                    }
+                    _ => (),
+                }
+                writer.write_event(Event::Start(e)).unwrap();
+            }
+            Ok(Event::End(e)) => {
+                let name = e.local_name();
+                match name.as_ref() {
+                    b"svg" => svg -= 1,
+                    name if svg > 0 && TEXT.contains(&name) => text -= 1,
+                    name if PARAGRAPHS
+                        .iter()
+                        .any(|paragraph| paragraph.as_bytes() == name) =>
+                    {
+                        paragraph -= 1
+                    }
+                    _ => (),
+                }
+                writer.write_event(Event::End(e)).unwrap();
+            }
+            Ok(Event::Text(e)) if svg > 0 && text > 0 && paragraph == 0 => {
+                let original = unescape(&e);
+                let trimmed = original.trim();
+                let written = if ignore_text.is_match(trimmed) {
+                    None
+                } else {
+                    label(trimmed)
+                };
+                match written {
+                    Some(translation) => {
+                        let start = original.len() - original.trim_start().len();
+                        let end = start + trimmed.len();
+                        let text =
+                            format!("{}{}{}", &original[..start], translation, &original[end..]);
+                        writer.write_event(escaped_text(&text)).unwrap();
+                    }
+                    None => writer.write_event(escaped_text(&original)).unwrap(),
+                }
+            }
+            Ok(Event::Text(e)) => writer.write_event(escaped_text(&unescape(&e))).unwrap(),
+            Ok(event) => writer.write_event(event).unwrap(),
+            Err(_) => return content.to_vec(),
+        }
+    }
+    writer.into_inner().into_inner()
+}
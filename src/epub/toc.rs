@@ -0,0 +1,326 @@
+use crate::epub::{escaped_text, unescape};
+use crate::translate::json;
+use crate::translate::translator::Translator;
+use log::{debug, warn};
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+
+const HEADINGS: &[&[u8]] = &[b"h1", b"h2", b"h3", b"h4", b"h5", b"h6"];
+
+/// Translations of the chapter headings, recorded as the chapters are
+/// translated so the table of contents gives the same titles.
+#[derive(Default)]
+pub struct Headings {
+    translations: Mutex<HashMap<String, String>>,
+}
+
+impl Headings {
+    /// Record the translations of the `sources` paragraphs of a document
+    /// that are among its `headings`.
+    pub fn record(&self, headings: &[String], sources: &[String], translated: &[String]) {
+        let mut translations = self.translations.lock().unwrap();
+        for (source, translation) in sources.iter().zip(translated) {
+            if !translation.is_empty() && headings.contains(source) {
+                translations.insert(normalize(source), translation.clone());
+            }
+        }
+    }
+
+    fn get(&self, label: &str) -> Option<String> {
+        let translations = self.translations.lock().unwrap();
+        translations.get(&normalize(label)).cloned()
+    }
+}
+
+#[derive(Deserialize)]
+struct Titles {
+    titles: Vec<String>,
+}
+
+/// The text of the `h1`–`h6` headings of a content document, joined as
+/// `translate_lines` joins a paragraph.
+pub fn headings(content: &[u8]) -> Vec<String> {
+    let mut reader = Reader::from_reader(content);
+    reader.config_mut().trim_text(true);
+    let mut headings = Vec::new();
+    let mut depth = 0;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(Event::Start(e)) if HEADINGS.contains(&e.name().as_ref()) => {
+                if depth == 0 {
+                    headings.push(String::new());
+                }
+                depth += 1;
+            }
+            Ok(Event::End(e)) if HEADINGS.contains(&e.name().as_ref()) => {
+                depth -= 1;
+            }
+            Ok(Event::Text(e)) if depth > 0 => {
+                if let Some(heading) = headings.last_mut() {
+                    heading.push_str(&unescape(&e));
+                }
+            }
+            _ => (),
+        }
+    }
+    headings
+}
+
+/// Translate the labels of the `<nav>` elements of an EPUB 3 navigation
+/// document.
+pub async fn translate_nav(content: &[u8], translator: &Translator) -> Vec<u8> {
+    translate_labels(content, translator, |path| {
+        path.iter().any(|name| name == b"nav")
+    })
+    .await
+}
+
+/// Translate the document title and the `navLabel`s of an EPUB 2 NCX.
+pub async fn translate_ncx(content: &[u8], translator: &Translator) -> Vec<u8> {
+    translate_labels(content, translator, |path| {
+        path.last().is_some_and(|name| name == b"text")
+            && path
+                .iter()
+                .any(|name| name == b"navLabel" || name == b"docTitle")
+    })
+    .await
+}
+
+async fn translate_labels(
+    content: &[u8],
+    translator: &Translator,
+    inside: impl Fn(&[Vec<u8>]) -> bool + Copy,
+) -> Vec<u8> {
+    let mut labels = Vec::new();
+    walk(content, inside, |text| {
+        labels.push(text.to_string());
+        None
+    });
+    if labels.is_empty() {
+        return content.to_vec();
+    }
+    let translated = translate_titles(&labels, translator).await;
+    let mut translated = translated.into_iter();
+    walk(content, inside, |_| {
+        translated.next().filter(|label| !label.is_empty())
+    })
+}
+
+/// Take the translation of each label that is also a translated chapter
+/// heading, and translate the others together as a short list of titles.
+async fn translate_titles(labels: &[String], translator: &Translator) -> Vec<String> {
+    let context = translator.context();
+    let mut translated: Vec<Option<String>> = labels
+        .iter()
+        .map(|label| context.headings.get(label))
+        .collect();
+    let missing: Vec<String> = labels
+        .iter()
+        .zip(&translated)
+        .filter(|(_, heading)| heading.is_none())
+        .map(|(label, _)| label.clone())
+        .collect();
+    debug!(
+        "toc: {}/{} labels from headings",
+        labels.len() - missing.len(),
+        labels.len()
+    );
+    let mut remaining = if missing.is_empty() {
+        Vec::new()
+    } else {
+        let titles = translator
+            .complete(&prompt(&context.language, &missing))
+            .await
+            .and_then(|text| json::parse::<Titles>(&text).map_err(|e| e.to_string()))
+            .map(|titles| titles.titles);
+        match titles {
+            Ok(titles) if titles.len() == missing.len() => titles,
+            Ok(titles) => {
+                warn!(
+                    "toc: {} titles for {} labels, translating them as paragraphs",
+                    titles.len(),
+                    missing.len()
+                );
+                translator.translate(missing).await
+            }
+            Err(e) => {
+                debug!("toc: {}, translating the labels as paragraphs", e);
+                translator.translate(missing).await
+            }
+        }
+    }
+    .into_iter();
+    translated
+        .iter_mut()
+        .map(|label| {
+            label
+                .take()
+                .or_else(|| remaining.next())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn prompt(language: &str, labels: &[String]) -> String {
+    let titles = serde_json::to_string(labels).unwrap_or_default();
+    format!(
+        "Translate these titles from the table of contents of a book into {}, \
+        as short as the originals, keeping any numbering.\n\
+        Please output the following JSON with one title for each title given, in the same order.\n\
+        {{\"titles\": [string]}}\n\
+        Here are the titles:\n{}",
+        language, titles
+    )
+}
+
+/// Pass each non-blank text inside the elements accepted by `inside` to
+/// `on_text`, replacing it with what `on_text` returns.
+fn walk(
+    content: &[u8],
+    inside: impl Fn(&[Vec<u8>]) -> bool,
+    mut on_text: impl FnMut(&str) -> Option<String>,
+) -> Vec<u8> {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(e) => {
+                warn!("toc: {}", e);
+                return content.to_vec();
+            }
+        };
+        match &event {
+            Event::Start(e) => path.push(e.local_name().as_ref().to_vec()),
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(e) if inside(&path) => {
+                let text = unescape(e);
+                if !text.trim().is_empty() {
+                    if let Some(translation) = on_text(text.trim()) {
+                        writer.write_event(escaped_text(&translation)).unwrap();
+                        continue;
+                    }
+                }
+            }
+            _ => (),
+        }
+        writer.write_event(event).unwrap();
+    }
+    writer.into_inner().into_inner()
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::pipeline::{Config, Provider};
+    use crate::translate::translator::{Backend, BulkTranslated, Completion, Context, Preceding};
+    use futures::future::BoxFuture;
+
+    const NAV: &str = r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Contents</title></head><body><nav><ol><li><a href="ch1.xhtml">The  Storm</a></li><li><a href="ch2.xhtml">Morning</a></li></ol></nav></body></html>"#;
+
+    const NCX: &str = r#"<ncx><head><meta name="x" content="y"/></head><docTitle><text>The Lighthouse</text></docTitle><navMap><navPoint><navLabel><text>Morning</text></navLabel></navPoint></navMap></ncx>"#;
+
+    /// Answers the titles of the prompts of the table of contents upper
+    /// cased, and translates paragraphs as the mock does.
+    struct Titles;
+
+    impl Backend for Titles {
+        fn translate_bulk<'a>(
+            &'a self,
+            _context: &'a Context,
+            language: &'a str,
+            lines: &'a [String],
+            _preceding: &'a [Preceding],
+        ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+            Box::pin(async move {
+                Ok(BulkTranslated {
+                    translated_lines: lines
+                        .iter()
+                        .map(|line| format!("[{}] {}", language, line))
+                        .collect(),
+                    ..BulkTranslated::default()
+                })
+            })
+        }
+
+        fn complete<'a>(
+            &'a self,
+            _context: &'a Context,
+            prompt: &'a str,
+        ) -> BoxFuture<'a, Result<Completion, String>> {
+            Box::pin(async move {
+                let titles: Vec<String> =
+                    serde_json::from_str(prompt.lines().last().unwrap()).unwrap();
+                let titles: Vec<String> = titles.iter().map(|title| title.to_uppercase()).collect();
+                Ok(Completion {
+                    text: serde_json::json!({ "titles": titles }).to_string(),
+                    stats: Default::default(),
+                })
+            })
+        }
+    }
+
+    fn context() -> Context {
+        Config::new(Provider::Mock, "mock", "German").context
+    }
+
+    fn text(content: Vec<u8>) -> String {
+        String::from_utf8(content).unwrap()
+    }
+
+    #[test]
+    fn headings_are_read_with_their_inline_text() {
+        let content = b"<body><h1>The Storm</h1><p>Text</p><h2><span>II</span></h2><h3/></body>";
+        assert_eq!(headings(content), ["The Storm", "II"]);
+    }
+
+    #[tokio::test]
+    async fn labels_take_the_translated_headings() {
+        let context = context();
+        context.headings.record(
+            &["The Storm".to_string()],
+            &["The Storm".to_string(), "Text".to_string()],
+            &["Der Sturm".to_string(), "Text".to_string()],
+        );
+        let translator = Translator::new(context, Titles);
+        let nav = text(translate_nav(NAV.as_bytes(), &translator).await);
+        assert!(nav.contains("<title>Contents</title>"));
+        assert!(
+            nav.contains(r#"<a href="ch1.xhtml">Der Sturm</a>"#),
+            "{}",
+            nav
+        );
+        assert!(
+            nav.contains(r#"<a href="ch2.xhtml">MORNING</a>"#),
+            "{}",
+            nav
+        );
+    }
+
+    #[tokio::test]
+    async fn labels_are_translated_as_paragraphs_without_titles() {
+        let config = Config::new(Provider::Mock, "mock", "German");
+        let translator = config.provider.translator(config.context);
+        let ncx = text(translate_ncx(NCX.as_bytes(), &translator).await);
+        assert_eq!(
+            ncx,
+            r#"<ncx><head><meta name="x" content="y"/></head><docTitle><text>[German] The Lighthouse</text></docTitle><navMap><navPoint><navLabel><text>[German] Morning</text></navLabel></navPoint></navMap></ncx>"#
+        );
+        let broken = b"<nav><a>One</b></nav>";
+        assert_eq!(translate_nav(broken, &translator).await, broken);
+    }
+}
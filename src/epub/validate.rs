@@ -0,0 +1,414 @@
+use crate::epub::document_lines;
+use crate::epub::package::{attribute, join, read_entry, Package};
+use crate::error::Error;
+use quick_xml::escape::resolve_html5_entity;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Cursor;
+use zip::ZipArchive;
+
+/// Media types of the documents that are parsed as XML.
+const XML_TYPES: [&str; 3] = [
+    "application/xhtml+xml",
+    "application/x-dtbncx+xml",
+    "image/svg+xml",
+];
+
+/// What is wrong with a translated EPUB, found without calling any API.
+pub struct Validation {
+    /// one line each, naming the entry concerned
+    pub problems: Vec<String>,
+    pub documents: usize,
+    pub links: usize,
+    /// whether the spine and the paragraphs were compared with the source
+    pub compared: bool,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        write!(
+            f,
+            "{} documents, {} internal links checked",
+            self.documents, self.links
+        )?;
+        if self.compared {
+            write!(f, ", spine and paragraphs compared with the source")?;
+        }
+        match self.problems.len() {
+            0 => writeln!(f, ": ok"),
+            problems => writeln!(f, ": {} problems", problems),
+        }
+    }
+}
+
+/// The ids and internal links of a parsed document.
+#[derive(Default)]
+struct Document {
+    ids: HashSet<String>,
+    links: Vec<String>,
+}
+
+/// Check the EPUB `output`: every manifest item is in the archive, the XML
+/// documents are well-formed and the internal links and anchors of the
+/// content documents resolve. With the `source` it was translated from, its
+/// spine must be in the same order and each chapter must have as many
+/// paragraphs, leaving out the copies of the originals of the layout.
+pub fn validate_epub_bytes(output: &[u8], source: Option<&[u8]>) -> Result<Validation, Error> {
+    let mut archive = ZipArchive::new(Cursor::new(output))?;
+    let package = Package::read(&mut archive)?;
+    let names: HashSet<String> = archive.file_names().map(String::from).collect();
+    let mut problems = Vec::new();
+
+    let mut documents = HashMap::new();
+    for item in &package.manifest {
+        let href = decode(&item.href);
+        if !names.contains(&href) {
+            problems.push(format!(
+                "{}: manifest item {} is missing from the archive",
+                item.href, item.id
+            ));
+            continue;
+        }
+        if !XML_TYPES.contains(&item.media_type.as_str()) {
+            continue;
+        }
+        let content = read_entry(&mut archive, &href)?;
+        match parse(&content) {
+            Ok(document) => {
+                documents.insert(href, document);
+            }
+            Err(e) => problems.push(format!("{}: not well-formed: {}", href, e)),
+        }
+    }
+
+    let mut links = 0;
+    let content_documents: HashSet<String> = package
+        .content_documents()
+        .map(|item| decode(&item.href))
+        .collect();
+    let mut checked: Vec<&String> = documents
+        .keys()
+        .filter(|name| content_documents.contains(*name))
+        .collect();
+    checked.sort();
+    for name in checked {
+        let base = match name.rfind('/') {
+            Some(index) => &name[..=index],
+            None => "",
+        };
+        for href in &documents[name].links {
+            let Some((path, fragment)) = internal(href) else {
+                continue;
+            };
+            links += 1;
+            let target = match path {
+                "" => name.clone(),
+                path => join(base, &decode(path)),
+            };
+            if !names.contains(&target) {
+                problems.push(format!("{}: link to {} finds no file", name, href));
+            } else if let (Some(fragment), Some(document)) = (fragment, documents.get(&target)) {
+                if !document.ids.contains(&decode(fragment)) {
+                    problems.push(format!("{}: link to {} finds no such id", name, href));
+                }
+            }
+        }
+    }
+
+    if let Some(source) = source {
+        let mut source_archive = ZipArchive::new(Cursor::new(source))?;
+        let source_package = Package::read(&mut source_archive)?;
+        if let Some(position) = (0..package.spine.len().max(source_package.spine.len()))
+            .find(|i| package.spine.get(*i) != source_package.spine.get(*i))
+        {
+            problems.push(format!(
+                "spine: item {} is {} instead of {}, as in the source",
+                position + 1,
+                package
+                    .spine
+                    .get(position)
+                    .map_or("missing", String::as_str),
+                source_package
+                    .spine
+                    .get(position)
+                    .map_or("nothing", String::as_str)
+            ));
+        }
+        for name in source_package
+            .spine
+            .iter()
+            .filter(|name| package.spine.contains(name))
+            .map(|name| decode(name))
+            .filter(|name| names.contains(name))
+        {
+            let original = document_lines(&name, &read_entry(&mut source_archive, &name)?)?.len();
+            let content = read_entry(&mut archive, &name)?;
+            let translated = match document_lines(&name, &without_copies(&content)) {
+                Ok(lines) => lines.len(),
+                Err(e) => {
+                    problems.push(e.to_string());
+                    continue;
+                }
+            };
+            if translated != original {
+                problems.push(format!(
+                    "{}: {} paragraphs, {} in the source",
+                    name, translated, original
+                ));
+            }
+        }
+    }
+
+    Ok(Validation {
+        problems,
+        documents: documents.len(),
+        links,
+        compared: source.is_some(),
+    })
+}
+
+/// Read the whole of an XML document, with its ids and the `href`s of its
+/// links.
+fn parse(content: &[u8]) -> Result<Document, String> {
+    let mut reader = Reader::from_reader(content);
+    let mut document = Document::default();
+    let mut depth = 0;
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| format!("{} at byte {}", e, reader.error_position()))?;
+        match event {
+            Event::Eof => break,
+            Event::Start(e) => {
+                depth += 1;
+                collect(&mut document, &e).map_err(|e| e.to_string())?;
+            }
+            Event::Empty(e) => collect(&mut document, &e).map_err(|e| e.to_string())?,
+            Event::End(_) => depth -= 1,
+            Event::Text(e) => {
+                e.unescape_with(resolve_html5_entity)
+                    .map_err(|e| format!("{} at byte {}", e, reader.buffer_position()))?;
+            }
+            _ => (),
+        }
+    }
+    if depth > 0 {
+        return Err(format!("{} elements are not closed", depth));
+    }
+    Ok(document)
+}
+
+fn collect(document: &mut Document, e: &BytesStart) -> Result<(), Error> {
+    if let Some(id) = attribute(e, "id")? {
+        document.ids.insert(id);
+    }
+    if [b"a".as_slice(), b"area", b"link"].contains(&e.local_name().as_ref()) {
+        document.links.extend(attribute(e, "href")?);
+    }
+    Ok(())
+}
+
+/// The path and the fragment of an `href` within the book, none for one to
+/// another site or scheme.
+fn internal(href: &str) -> Option<(&str, Option<&str>)> {
+    let href = href.trim();
+    if href.is_empty() {
+        return None;
+    }
+    if let Some((scheme, _)) = href.split_once(':') {
+        if !scheme.contains(['/', '#']) {
+            return None;
+        }
+    }
+    Some(match href.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment).filter(|f| !f.is_empty())),
+        None => (href, None),
+    })
+}
+
+/// Decode the `%XX` escapes of an `href`.
+fn decode(href: &str) -> String {
+    let bytes = href.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A content document without what the layouts and `--ocr` add to it: the
+/// originals of the bilingual and annotated layouts and the transcribed
+/// captions, which are not paragraphs of the source.
+pub(crate) fn without_copies(content: &[u8]) -> Vec<u8> {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut skipped = 0;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(event) => event,
+        };
+        match &event {
+            Event::Start(e) if skipped > 0 || is_copy(e) => {
+                skipped += 1;
+                continue;
+            }
+            Event::End(_) if skipped > 0 => {
+                skipped -= 1;
+                continue;
+            }
+            Event::Empty(e) if skipped > 0 || is_copy(e) => continue,
+            _ if skipped > 0 => continue,
+            _ => (),
+        }
+        writer.write_event(event).unwrap();
+    }
+    writer.into_inner().into_inner()
+}
+
+pub(crate) fn is_copy(e: &BytesStart) -> bool {
+    let class = attribute(e, "class").ok().flatten().unwrap_or_default();
+    let id = attribute(e, "id").ok().flatten().unwrap_or_default();
+    class
+        .split_whitespace()
+        .any(|class| class == "original" || class == "trans-epub-ocr")
+        || (e.local_name().as_ref() == b"aside" && id.starts_with("trans-epub-source-"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const CONTAINER: &str = r#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#;
+
+    const OPF: &str = r#"<package version="3.0"><manifest><item id="a" href="text/a%20b.xhtml" media-type="application/xhtml+xml"/><item id="c" href="text/c.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="a"/><itemref idref="c"/></spine></package>"#;
+
+    const A: &str = r##"<html><body><p id="one">One</p><p>Two <a href="c.xhtml#end">on</a> <a href="#one">up</a> <a href="https://example.com/x#y">out</a></p></body></html>"##;
+
+    const C: &str = r#"<html><body><p id="end">Three</p></body></html>"#;
+
+    /// An EPUB of the package document `opf` and the documents `a b.xhtml`
+    /// and `c.xhtml`.
+    fn epub(opf: &str, a: &str, c: &str) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in [
+            ("META-INF/container.xml", CONTAINER),
+            ("OEBPS/content.opf", opf),
+            ("OEBPS/text/a b.xhtml", a),
+            ("OEBPS/text/c.xhtml", c),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn sound_books_are_valid() {
+        let book = epub(OPF, A, C);
+        let validation = validate_epub_bytes(&book, Some(&book)).unwrap();
+        assert!(validation.is_valid(), "{}", validation);
+        assert_eq!((validation.documents, validation.links), (2, 2));
+        assert_eq!(
+            validation.to_string(),
+            "2 documents, 2 internal links checked, spine and paragraphs compared with the source: ok\n"
+        );
+    }
+
+    #[test]
+    fn problems_name_their_entry() {
+        let opf = OPF.replace(
+            "</manifest>",
+            r#"<item id="gone" href="gone.css" media-type="text/css"/></manifest>"#,
+        );
+        let a = A
+            .replace("c.xhtml#end", "c.xhtml#nowhere")
+            .replace("#one", "d.xhtml");
+        let validation = validate_epub_bytes(&epub(&opf, &a, C), None).unwrap();
+        assert_eq!(
+            validation.problems,
+            [
+                "OEBPS/gone.css: manifest item gone is missing from the archive",
+                "OEBPS/text/a b.xhtml: link to c.xhtml#nowhere finds no such id",
+                "OEBPS/text/a b.xhtml: link to d.xhtml finds no file",
+            ]
+        );
+        assert!(validation.to_string().ends_with(": 3 problems\n"));
+
+        let broken = "<html><body><p>Three</body></html>";
+        let validation = validate_epub_bytes(&epub(OPF, A, broken), None).unwrap();
+        assert_eq!(validation.documents, 1);
+        assert_eq!(validation.problems.len(), 1);
+        assert!(validation.problems[0].starts_with("OEBPS/text/c.xhtml: not well-formed: "));
+    }
+
+    #[test]
+    fn translations_keep_the_spine_and_paragraphs_of_the_source() {
+        let source = epub(OPF, A, C);
+        let reordered = OPF.replace(
+            r#"<itemref idref="a"/><itemref idref="c"/>"#,
+            r#"<itemref idref="c"/><itemref idref="a"/>"#,
+        );
+        let validation = validate_epub_bytes(&epub(&reordered, A, C), Some(&source)).unwrap();
+        assert_eq!(
+            validation.problems,
+            ["spine: item 1 is OEBPS/text/c.xhtml instead of OEBPS/text/a%20b.xhtml, as in the source"]
+        );
+        // the originals of a layout are no paragraphs
+        let bilingual = C.replace(
+            "</body>",
+            r#"<div class="original"><p>Drei</p></div><aside id="trans-epub-source-1"><p>x</p></aside></body>"#,
+        );
+        let validation = validate_epub_bytes(&epub(OPF, A, &bilingual), Some(&source)).unwrap();
+        assert!(validation.is_valid(), "{}", validation);
+        let added = C.replace("</body>", "<p>Four</p></body>");
+        let dropped = A.replace(r#"<p id="one">One</p>"#, "");
+        let validation = validate_epub_bytes(&epub(OPF, &dropped, &added), Some(&source)).unwrap();
+        assert_eq!(
+            validation.problems,
+            [
+                "OEBPS/text/a b.xhtml: link to #one finds no such id",
+                "OEBPS/text/a b.xhtml: 1 paragraphs, 2 in the source",
+                "OEBPS/text/c.xhtml: 2 paragraphs, 1 in the source",
+            ]
+        );
+    }
+
+    #[test]
+    fn hrefs_are_split_and_decoded() {
+        assert_eq!(internal(" c.xhtml#end "), Some(("c.xhtml", Some("end"))));
+        assert_eq!(internal("#"), Some(("", None)));
+        assert_eq!(internal("mailto:someone@example.com"), None);
+        assert_eq!(internal("a.xhtml#x:y"), Some(("a.xhtml", Some("x:y"))));
+        assert_eq!(internal(""), None);
+        assert_eq!(decode("a%20b%C3%A9%2"), "a bé%2");
+    }
+}
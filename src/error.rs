@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("xml error: {0}")]
+    Xml(#[from] quick_xml::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    /// The URL is left out, since it can carry the API key.
+    #[error("http error: {0}")]
+    Http(reqwest::Error),
+    #[error("epub error: {0}")]
+    Epub(String),
+    #[error("input error: {0}")]
+    Input(String),
+    #[error("api error: {0}")]
+    Api(String),
+    /// The safety filters of the API withheld the response; holds why.
+    #[error("blocked by the safety filters: {0}")]
+    Blocked(String),
+    /// The book was written, but some chunks could not be translated.
+    #[error(
+        "{failed} chunks could not be translated, see {}; run again with --resume to retry them",
+        report.display()
+    )]
+    Incomplete { failed: usize, report: PathBuf },
+    /// The run was stopped by Ctrl-C once written with what was translated;
+    /// holds how to go on.
+    #[error("interrupted, the output is incomplete; {0}")]
+    Interrupted(String),
+    /// The run stopped at `--max-cost` or `--max-tokens` once written with
+    /// what was translated; holds how to go on.
+    #[error("spending cap reached, the output is incomplete; {0}")]
+    OverBudget(String),
+    /// Books of a batch failed; the others were translated all the same.
+    #[error("{failed} of {books} books failed")]
+    Batch { failed: usize, books: usize },
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e.without_url())
+    }
+}
@@ -0,0 +1,252 @@
+pub mod fb2;
+pub mod html;
+pub mod markdown;
+pub mod text;
+
+use crate::epub::layout::Layout;
+use crate::epub::{chapter, spine_paragraphs, Epub};
+use crate::error::Error;
+use crate::language;
+use crate::translate::translator::{Context, Translator};
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Format of a book, told by the extension of its file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Epub,
+    Text,
+    Markdown,
+    Fb2,
+    Html,
+}
+
+/// A book in one of the single-file formats, read as the paragraphs sent for
+/// translation and written back with their translations.
+pub trait Document: Send + Sync {
+    fn paragraphs(&self) -> Vec<String>;
+
+    /// The document with each paragraph laid out with its translation, an
+    /// empty translation keeping the original.
+    fn write(&self, translated: &[String], layout: Layout) -> Result<Vec<u8>, Error>;
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "epub" => Some(Self::Epub),
+            "txt" | "text" => Some(Self::Text),
+            "md" | "markdown" => Some(Self::Markdown),
+            "fb2" => Some(Self::Fb2),
+            "html" | "htm" | "xhtml" => Some(Self::Html),
+            _ => None,
+        }
+    }
+
+    /// Read a document of a single-file format; EPUB is read as an archive
+    /// by [`Epub`].
+    pub fn read(self, content: &[u8], options: &Options) -> Result<Box<dyn Document>, Error> {
+        let text = std::str::from_utf8(content).map_err(|_| {
+            Error::Input("not UTF-8; convert it first, e.g. with iconv".to_string())
+        })?;
+        match self {
+            Self::Epub => Err(Error::Input("not a single-file format".to_string())),
+            Self::Text => Ok(Box::new(text::read(text))),
+            Self::Markdown => Ok(Box::new(markdown::read(text))),
+            Self::Fb2 => Ok(Box::new(fb2::Fb2::read(content, options)?)),
+            Self::Html => Ok(Box::new(html::Html::read(content, options)?)),
+        }
+    }
+}
+
+/// What the single-file formats read besides the paragraphs, from the
+/// options of the run; the default reads the paragraphs alone.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// keep the inline markup as markers, with `--preserve-markup`
+    pub markup: bool,
+    /// attributes translated with the paragraphs, with `--translate-attributes`
+    pub attributes: Vec<String>,
+    /// language set in the metadata, with `--translate-metadata`
+    pub language: Option<String>,
+}
+
+impl From<&Context> for Options {
+    fn from(context: &Context) -> Self {
+        Self {
+            markup: context.preserve_markup,
+            attributes: context.translate_attributes.clone(),
+            language: context
+                .translate_metadata
+                .then(|| language::code(&context.language)),
+        }
+    }
+}
+
+/// A file of the book: the whole book, or a chapter of a directory.
+struct Chapter {
+    name: String,
+    input: PathBuf,
+    output: PathBuf,
+    format: Option<Format>,
+}
+
+impl Chapter {
+    fn read(&self, format: Format, options: &Options) -> Result<Box<dyn Document>, Error> {
+        format
+            .read(&std::fs::read(&self.input)?, options)
+            .map_err(self.in_file())
+    }
+
+    /// An error of the chapter, to tell which file is broken.
+    fn in_file(&self) -> impl Fn(Error) -> Error + '_ {
+        move |e| Error::Input(format!("{}: {}", self.name, e))
+    }
+}
+
+/// Translate the book at `input` into `output` in the same format. A file
+/// with an unknown extension is read as EPUB; a directory is translated as
+/// a book of chapter files, in name order, into the `output` directory,
+/// other files being copied.
+pub async fn translate(input: &Path, output: &Path, translator: &Translator) -> Result<(), Error> {
+    if !input.is_dir() && matches!(Format::from_path(input), None | Some(Format::Epub)) {
+        let epub = Epub::new(input.to_path_buf(), output.to_path_buf());
+        return epub.translate(translator).await;
+    }
+    let result = translate_chapters(input, output, translator).await;
+    translator.context().report.conclude(output, result)
+}
+
+/// The paragraphs of the book at `input`, in reading order, as they would
+/// be sent for translation.
+pub async fn paragraphs(input: &Path, options: &Options) -> Result<Vec<String>, Error> {
+    if !input.is_dir() && matches!(Format::from_path(input), None | Some(Format::Epub)) {
+        return spine_paragraphs(File::open(input)?).await;
+    }
+    let mut paragraphs = Vec::new();
+    for chapter in chapters(input, Path::new(""))? {
+        if let Some(format) = chapter.format {
+            paragraphs.extend(chapter.read(format, options)?.paragraphs());
+        }
+    }
+    Ok(paragraphs)
+}
+
+fn chapters(input: &Path, output: &Path) -> Result<Vec<Chapter>, Error> {
+    if !input.is_dir() {
+        return Ok(vec![Chapter {
+            name: entry_name(input),
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            format: Format::from_path(input),
+        }]);
+    }
+    let mut chapters = Vec::new();
+    for entry in std::fs::read_dir(input)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            warn!("skip directory {}", path.display());
+            continue;
+        }
+        let name = entry_name(&path);
+        chapters.push(Chapter {
+            output: output.join(&name),
+            format: Format::from_path(&path).filter(|format| *format != Format::Epub),
+            input: path,
+            name,
+        });
+    }
+    chapters.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(chapters)
+}
+
+fn entry_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+async fn translate_chapters(
+    input: &Path,
+    output: &Path,
+    translator: &Translator,
+) -> Result<(), Error> {
+    let context = translator.context();
+    if input.is_dir() {
+        std::fs::create_dir_all(output)?;
+    }
+    let chapters = chapters(input, output)?;
+    let options = Options::from(context);
+    let spine: Vec<String> = chapters
+        .iter()
+        .filter(|chapter| chapter.format.is_some())
+        .map(|chapter| chapter.name.clone())
+        .collect();
+
+    // counted first and read again when translated, so only the chapters in
+    // flight are held in memory
+    let mut translated = 0;
+    let mut paragraphs = 0;
+    for chapter in &chapters {
+        if let Some(format) = translated_format(context, &spine, chapter) {
+            let document = chapter.read(format, &options)?;
+            translated += 1;
+            paragraphs += document.paragraphs().len();
+        }
+    }
+    context.progress.start(translated, paragraphs);
+
+    let size = chapters.len();
+    let mut written = stream::iter(chapters.into_iter().enumerate())
+        .map(|(i, chapter)| {
+            let (spine, options) = (&spine, &options);
+            async move {
+                info!("{}/{} {}", i + 1, size, chapter.name);
+                let Some(format) = translated_format(context, spine, &chapter) else {
+                    if chapter.format.is_some() {
+                        info!("skip {}", chapter.name);
+                    }
+                    std::fs::copy(&chapter.input, &chapter.output)?;
+                    return Ok(());
+                };
+                let document = chapter.read(format, options)?;
+                let language =
+                    chapter::language(context, spine, &chapter.name).unwrap_or(&context.language);
+                let paragraphs = document.paragraphs();
+                let mut lines = translator
+                    .translate_into(paragraphs.clone(), language)
+                    .await;
+                translator
+                    .check_scripts(&chapter.name, &paragraphs, &mut lines, language)
+                    .await;
+                if let Some(names) = &context.names {
+                    names.record(&chapter.name, &lines);
+                }
+                let content = document
+                    .write(&lines, context.layout)
+                    .map_err(chapter.in_file())?;
+                std::fs::write(&chapter.output, content)?;
+                context
+                    .progress
+                    .chapter_done(&chapter.name, &context.totals);
+                Ok::<_, Error>(())
+            }
+        })
+        .buffered(context.max_chapters_in_flight.max(1));
+    while let Some(result) = written.next().await {
+        result?;
+    }
+    drop(written);
+    translator.finish()
+}
+
+/// The format of a chapter that is translated, `None` for one that is
+/// copied as it is.
+fn translated_format(context: &Context, spine: &[String], chapter: &Chapter) -> Option<Format> {
+    chapter
+        .format
+        .filter(|_| chapter::language(context, spine, &chapter.name).is_some())
+}
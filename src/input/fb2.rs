@@ -0,0 +1,118 @@
+use crate::epub::layout::Layout;
+use crate::epub::{escaped_text, strip_xml_content, translate_lines, unescape, write_document};
+use crate::error::Error;
+use crate::input::{Document, Options};
+use log::warn;
+use quick_xml::events::{BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::io::Cursor;
+
+/// A FictionBook 2 document. Its paragraphs, verses, subtitles and text
+/// authors are translated, in sections, epigraphs and the annotation alike.
+/// With `--translate-metadata`, the book title is translated too and the
+/// language of the book set to the language of the run.
+///
+/// FB2 has neither asides nor styled blocks, so the annotated and bilingual
+/// layouts are written inline.
+pub struct Fb2 {
+    content: Vec<u8>,
+    markup: bool,
+    /// code of the target language, written to `title-info/lang`
+    language: Option<String>,
+    /// the paragraphs that are not the book title
+    lines: Vec<String>,
+}
+
+impl Fb2 {
+    pub fn read(content: &[u8], options: &Options) -> Result<Self, Error> {
+        let content = strip_xml_content(content)?;
+        let lines = translate_lines(&content, options.markup)?;
+        Ok(Self {
+            content,
+            markup: options.markup,
+            language: options.language.clone(),
+            lines,
+        })
+    }
+}
+
+impl Document for Fb2 {
+    fn paragraphs(&self) -> Vec<String> {
+        let mut paragraphs = self.lines.clone();
+        if self.language.is_some() {
+            paragraphs.extend(rewrite_title_info(&self.content, None, None).1);
+        }
+        paragraphs
+    }
+
+    fn write(&self, translated: &[String], layout: Layout) -> Result<Vec<u8>, Error> {
+        let layout = match layout {
+            Layout::Annotated | Layout::Bilingual => {
+                warn!(
+                    "fb2: no {:?} layout, writing the translations inline",
+                    layout
+                );
+                Layout::Inline
+            }
+            layout => layout,
+        };
+        let (lines, titles) = translated.split_at(self.lines.len().min(translated.len()));
+        let content = write_document(&self.content, lines.to_vec(), layout, self.markup)?;
+        Ok(match &self.language {
+            Some(language) => rewrite_title_info(&content, titles.first(), Some(language)).0,
+            None => content,
+        })
+    }
+}
+
+/// Replace the `book-title` and the `lang` of the `title-info` of the
+/// description with `title` and `language`, and return the original title.
+fn rewrite_title_info(
+    content: &[u8],
+    title: Option<&String>,
+    language: Option<&str>,
+) -> (Vec<u8>, Option<String>) {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut original = None;
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(e) => {
+                warn!("fb2: {}", e);
+                return (content.to_vec(), None);
+            }
+        };
+        match &event {
+            Event::Start(e) => path.push(e.local_name().as_ref().to_vec()),
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(e) if in_title_info(&path, b"book-title") => {
+                let text = unescape(e);
+                original = Some(text.trim().to_string()).filter(|text| !text.is_empty());
+                if let Some(title) = title.filter(|title| !title.is_empty()) {
+                    writer.write_event(escaped_text(title)).unwrap();
+                    continue;
+                }
+            }
+            Event::Text(_) if in_title_info(&path, b"lang") => {
+                if let Some(language) = language {
+                    writer
+                        .write_event(Event::Text(BytesText::new(language)))
+                        .unwrap();
+                    continue;
+                }
+            }
+            _ => (),
+        }
+        writer.write_event(event).unwrap();
+    }
+    (writer.into_inner().into_inner(), original)
+}
+
+fn in_title_info(path: &[Vec<u8>], name: &[u8]) -> bool {
+    path.len() >= 2 && path[path.len() - 2] == b"title-info" && path[path.len() - 1] == name
+}
@@ -0,0 +1,151 @@
+use crate::epub::layout::Layout;
+use crate::epub::{attributes, strip_xml_content, translate_lines, write_document};
+use crate::error::Error;
+use crate::input::{Document, Options};
+use log::warn;
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::io::Cursor;
+
+/// Elements of HTML that have no content and no end tag.
+const VOID: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Elements that close an open `p`, as in HTML parsing.
+const BLOCKS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "div",
+    "dl",
+    "fieldset",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+];
+
+/// A standalone HTML document, translated like the content documents of an
+/// EPUB; its text attributes are sent along with its paragraphs.
+pub struct Html {
+    content: Vec<u8>,
+    markup: bool,
+    attributes: Vec<String>,
+    /// the paragraphs that are not attribute values
+    lines: Vec<String>,
+}
+
+impl Html {
+    pub fn read(content: &[u8], options: &Options) -> Result<Self, Error> {
+        let content = strip_xml_content(&normalize(content))?;
+        let lines = translate_lines(&content, options.markup)?;
+        Ok(Self {
+            content,
+            markup: options.markup,
+            attributes: options.attributes.clone(),
+            lines,
+        })
+    }
+}
+
+impl Document for Html {
+    fn paragraphs(&self) -> Vec<String> {
+        let mut paragraphs = self.lines.clone();
+        paragraphs.extend(attributes::collect(&self.content, &self.attributes));
+        paragraphs
+    }
+
+    fn write(&self, translated: &[String], layout: Layout) -> Result<Vec<u8>, Error> {
+        let (lines, values) = translated.split_at(self.lines.len().min(translated.len()));
+        let content = write_document(&self.content, lines.to_vec(), layout, self.markup)?;
+        Ok(attributes::rewrite(&content, &self.attributes, values))
+    }
+}
+
+/// Rewrite HTML as well-formed XML: element names in lower case, void
+/// elements written empty, paragraphs and list items closed where HTML
+/// implies it, elements left open closed and stray end tags dropped. A
+/// document that cannot be read is kept as it is.
+pub fn normalize(content: &[u8]) -> Vec<u8> {
+    let mut reader = Reader::from_reader(content);
+    reader.config_mut().check_end_names = false;
+    reader.config_mut().allow_unmatched_ends = true;
+
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut open: Vec<String> = Vec::new();
+    loop {
+        let event = match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => event,
+            Err(e) => {
+                warn!("html: {}", e);
+                return content.to_vec();
+            }
+        };
+        match event {
+            Event::Start(e) => {
+                let e = lowercase(e);
+                let name = String::from_utf8_lossy(e.name().0).into_owned();
+                let implied = match open.last().map(String::as_str) {
+                    Some("p") => BLOCKS.contains(&name.as_str()),
+                    Some("li") => name == "li",
+                    _ => false,
+                };
+                if implied {
+                    let closed = open.pop().unwrap();
+                    writer
+                        .write_event(Event::End(BytesEnd::new(closed)))
+                        .unwrap();
+                }
+                if VOID.contains(&name.as_str()) {
+                    writer.write_event(Event::Empty(e)).unwrap();
+                } else {
+                    writer.write_event(Event::Start(e)).unwrap();
+                    open.push(name);
+                }
+            }
+            Event::Empty(e) => writer.write_event(Event::Empty(lowercase(e))).unwrap(),
+            Event::End(e) => {
+                let name = String::from_utf8_lossy(e.name().0).to_lowercase();
+                if let Some(i) = open.iter().rposition(|open| *open == name) {
+                    for name in open.drain(i..).rev() {
+                        writer.write_event(Event::End(BytesEnd::new(name))).unwrap();
+                    }
+                }
+            }
+            event => writer.write_event(event).unwrap(),
+        }
+    }
+    for name in open.drain(..).rev() {
+        writer.write_event(Event::End(BytesEnd::new(name))).unwrap();
+    }
+    writer.into_inner().into_inner()
+}
+
+fn lowercase(element: BytesStart) -> BytesStart {
+    let name = String::from_utf8_lossy(element.name().0);
+    if !name.chars().any(|c| c.is_ascii_uppercase()) {
+        return element;
+    }
+    let mut copy = BytesStart::new(name.to_lowercase());
+    copy.extend_attributes(element.attributes().flatten());
+    copy.into_owned()
+}
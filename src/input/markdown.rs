@@ -0,0 +1,103 @@
+use crate::input::text::{paragraph, Paragraph, Piece, Text};
+use regex::Regex;
+
+/// Read a Markdown document. Headings, list items, block quotes and
+/// paragraphs are translated without their markers; code blocks, tables,
+/// HTML blocks and rules are kept as they are.
+pub fn read(content: &str) -> Text {
+    let heading = Regex::new(r"^(#{1,6}\s+)(.*?)(?:\s+#+)?\s*$").unwrap();
+    let item = Regex::new(r"^(\s*(?:[-*+]|\d+[.)])\s+)(.*)$").unwrap();
+    let quote = Regex::new(r"^(\s*>\s?)(.*)$").unwrap();
+    let verbatim =
+        Regex::new(r"^(\s*[|<]|\s*((-\s*){3,}|(\*\s*){3,}|(_\s*){3,}|=+)$|\s{4}|\t)").unwrap();
+    let fence = Regex::new(r"^\s*(```|~~~)").unwrap();
+
+    let mut pieces = Vec::new();
+    let mut current: Option<Paragraph> = None;
+    let mut in_fence: Option<String> = None;
+    for line in content.lines() {
+        if let Some(marker) = &in_fence {
+            if line.trim_start().starts_with(marker.as_str()) {
+                in_fence = None;
+            }
+            pieces.push(Piece::Verbatim(format!("{}\n", line)));
+            continue;
+        }
+        if let Some(captures) = fence.captures(line) {
+            pieces.extend(current.take().map(paragraph));
+            in_fence = Some(captures[1].to_string());
+            pieces.push(Piece::Verbatim(format!("{}\n", line)));
+            continue;
+        }
+        if line.trim().is_empty() {
+            pieces.extend(current.take().map(paragraph));
+            pieces.push(Piece::Verbatim(format!("{}\n", line)));
+            continue;
+        }
+        let started = if let Some(captures) = heading.captures(line) {
+            Some((
+                captures.get(1).unwrap().as_str(),
+                captures.get(2).unwrap().as_str(),
+                "\n\n",
+                true,
+            ))
+        } else if let Some(captures) = item.captures(line) {
+            Some((
+                captures.get(1).unwrap().as_str(),
+                captures.get(2).unwrap().as_str(),
+                "\n",
+                false,
+            ))
+        } else if let Some(captures) = quote.captures(line) {
+            let prefix = captures.get(1).unwrap().as_str();
+            let text = captures.get(2).unwrap().as_str();
+            match &mut current {
+                Some(current) if current.prefix == prefix => {
+                    join(current, text);
+                    continue;
+                }
+                _ => Some((prefix, text, "\n>\n", false)),
+            }
+        } else if current.is_none() && verbatim.is_match(line) {
+            pieces.push(Piece::Verbatim(format!("{}\n", line)));
+            continue;
+        } else if verbatim.is_match(line) && !line.starts_with(char::is_whitespace) {
+            pieces.extend(current.take().map(paragraph));
+            pieces.push(Piece::Verbatim(format!("{}\n", line)));
+            continue;
+        } else {
+            None
+        };
+        match (started, &mut current) {
+            (Some((prefix, text, separator, single)), _) => {
+                pieces.extend(current.take().map(paragraph));
+                let block = Paragraph {
+                    prefix: prefix.to_string(),
+                    text: text.trim().to_string(),
+                    separator: separator.to_string(),
+                };
+                if single {
+                    pieces.push(paragraph(block));
+                } else {
+                    current = Some(block);
+                }
+            }
+            (None, Some(current)) => join(current, line),
+            (None, None) => {
+                current = Some(Paragraph {
+                    prefix: String::new(),
+                    text: line.trim().to_string(),
+                    separator: "\n\n".to_string(),
+                });
+            }
+        }
+    }
+    pieces.extend(current.map(paragraph));
+    Text::new(pieces)
+}
+
+/// Continue a paragraph with the next line, joining the wrapped lines.
+fn join(paragraph: &mut Paragraph, line: &str) {
+    paragraph.text.push(' ');
+    paragraph.text.push_str(line.trim());
+}
@@ -0,0 +1,133 @@
+use crate::epub::layout::Layout;
+use crate::error::Error;
+use crate::input::Document;
+use regex::Regex;
+
+/// Longest line of text wrapped at a fixed width.
+const WRAP_WIDTH: usize = 100;
+
+/// A plain text document, or a Markdown one read by
+/// [`markdown::read`](crate::input::markdown::read): the lines kept as they
+/// are and the paragraphs to translate, in order.
+pub struct Text {
+    pieces: Vec<Piece>,
+}
+
+pub(crate) enum Piece {
+    /// A line written back unchanged, with its line break
+    Verbatim(String),
+    Paragraph(Paragraph),
+}
+
+pub(crate) struct Paragraph {
+    /// Written before the original and before the translation: the
+    /// indentation of the paragraph, or its Markdown marker
+    pub prefix: String,
+    pub text: String,
+    /// Written between the original and its translation when both are kept
+    pub separator: String,
+}
+
+impl Text {
+    pub(crate) fn new(pieces: Vec<Piece>) -> Self {
+        Self { pieces }
+    }
+}
+
+impl Document for Text {
+    fn paragraphs(&self) -> Vec<String> {
+        self.pieces
+            .iter()
+            .filter_map(|piece| match piece {
+                Piece::Paragraph(paragraph) => Some(paragraph.text.clone()),
+                Piece::Verbatim(_) => None,
+            })
+            .collect()
+    }
+
+    /// The translated-only layout writes the translation alone; the others
+    /// write the original paragraph followed by its translation, there being
+    /// no footnotes in plain text.
+    fn write(&self, translated: &[String], layout: Layout) -> Result<Vec<u8>, Error> {
+        let mut translated = translated.iter();
+        let mut output = String::new();
+        for piece in &self.pieces {
+            let paragraph = match piece {
+                Piece::Verbatim(line) => {
+                    output.push_str(line);
+                    continue;
+                }
+                Piece::Paragraph(paragraph) => paragraph,
+            };
+            let translation = translated.next().filter(|line| !line.is_empty());
+            output.push_str(&paragraph.prefix);
+            match translation {
+                Some(translation) if layout == Layout::TranslatedOnly => {
+                    output.push_str(translation);
+                }
+                Some(translation) => {
+                    output.push_str(&paragraph.text);
+                    output.push_str(&paragraph.separator);
+                    output.push_str(&paragraph.prefix);
+                    output.push_str(translation);
+                }
+                None => output.push_str(&paragraph.text),
+            }
+            output.push('\n');
+        }
+        Ok(output.into_bytes())
+    }
+}
+
+/// Read a plain text document. In text wrapped at a fixed width, with blank
+/// lines, paragraphs are separated by blank lines and their lines joined;
+/// otherwise each line is a paragraph. Lines of digits and punctuation
+/// alone, such as scene breaks, are not translated.
+pub fn read(content: &str) -> Text {
+    let lines: Vec<&str> = content.lines().collect();
+    let blank = |line: &&str| line.trim().is_empty();
+    let wrapped =
+        lines.iter().any(blank) && lines.iter().all(|line| line.chars().count() <= WRAP_WIDTH);
+    let separator = if wrapped { "\n\n" } else { "\n" };
+
+    let mut pieces = Vec::new();
+    let mut current: Option<Paragraph> = None;
+    for line in lines {
+        if blank(&line) {
+            pieces.extend(current.take().map(paragraph));
+            pieces.push(Piece::Verbatim(format!("{}\n", line)));
+            continue;
+        }
+        match &mut current {
+            Some(current) if wrapped => {
+                current.text.push(' ');
+                current.text.push_str(line.trim());
+            }
+            _ => {
+                pieces.extend(current.take().map(paragraph));
+                let text = line.trim_start();
+                current = Some(Paragraph {
+                    prefix: line[..line.len() - text.len()].to_string(),
+                    text: text.trim_end().to_string(),
+                    separator: separator.to_string(),
+                });
+            }
+        }
+    }
+    pieces.extend(current.map(paragraph));
+    Text::new(pieces)
+}
+
+/// A paragraph to translate, or a verbatim line when there is nothing to
+/// translate in it.
+pub(crate) fn paragraph(paragraph: Paragraph) -> Piece {
+    if ignore_text().is_match(&paragraph.text) {
+        Piece::Verbatim(format!("{}{}\n", paragraph.prefix, paragraph.text))
+    } else {
+        Piece::Paragraph(paragraph)
+    }
+}
+
+fn ignore_text() -> Regex {
+    Regex::new(r"^[\s\p{Cc}\p{So}0-9[:punct:]–]*$").unwrap()
+}
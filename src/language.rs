@@ -0,0 +1,563 @@
+use std::collections::BTreeMap;
+
+pub struct Language {
+    /// English name, as passed to `--language`
+    pub name: &'static str,
+    /// ISO 639-1 code
+    pub code: &'static str,
+    /// Written in a script with letter case
+    pub cased: bool,
+    /// Written right to left
+    pub rtl: bool,
+}
+
+const LANGUAGES: [Language; 32] = [
+    Language {
+        name: "english",
+        code: "en",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "french",
+        code: "fr",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "german",
+        code: "de",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "spanish",
+        code: "es",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "portuguese",
+        code: "pt",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "italian",
+        code: "it",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "dutch",
+        code: "nl",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "vietnamese",
+        code: "vi",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "polish",
+        code: "pl",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "czech",
+        code: "cs",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "russian",
+        code: "ru",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "ukrainian",
+        code: "uk",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "greek",
+        code: "el",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "turkish",
+        code: "tr",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "indonesian",
+        code: "id",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "malay",
+        code: "ms",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "swedish",
+        code: "sv",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "norwegian",
+        code: "no",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "danish",
+        code: "da",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "finnish",
+        code: "fi",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "romanian",
+        code: "ro",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "hungarian",
+        code: "hu",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "tagalog",
+        code: "tl",
+        cased: true,
+        rtl: false,
+    },
+    Language {
+        name: "japanese",
+        code: "ja",
+        cased: false,
+        rtl: false,
+    },
+    Language {
+        name: "chinese",
+        code: "zh",
+        cased: false,
+        rtl: false,
+    },
+    Language {
+        name: "korean",
+        code: "ko",
+        cased: false,
+        rtl: false,
+    },
+    Language {
+        name: "thai",
+        code: "th",
+        cased: false,
+        rtl: false,
+    },
+    Language {
+        name: "arabic",
+        code: "ar",
+        cased: false,
+        rtl: true,
+    },
+    Language {
+        name: "hebrew",
+        code: "he",
+        cased: false,
+        rtl: true,
+    },
+    Language {
+        name: "persian",
+        code: "fa",
+        cased: false,
+        rtl: true,
+    },
+    Language {
+        name: "hindi",
+        code: "hi",
+        cased: false,
+        rtl: false,
+    },
+    Language {
+        name: "bengali",
+        code: "bn",
+        cased: false,
+        rtl: false,
+    },
+];
+
+/// Look a language up by English name or code, ignoring case and region
+/// subtags such as `pt-BR`.
+pub fn find(language: &str) -> Option<&'static Language> {
+    let language = language.trim().to_lowercase();
+    let primary = language.split(['-', '_']).next().unwrap_or_default();
+    LANGUAGES
+        .iter()
+        .find(|l| l.name == language || l.code == primary)
+}
+
+/// Whether `language` is written right to left.
+pub fn is_rtl(language: &str) -> bool {
+    find(language).is_some_and(|language| language.rtl)
+}
+
+/// The language code to write into markup, or the language as given when it
+/// is not in the table.
+pub fn code(language: &str) -> String {
+    find(language).map_or_else(|| language.trim().to_string(), |l| l.code.to_string())
+}
+
+/// Fewest letters a text needs for its language to be detected.
+const MIN_LETTERS: usize = 50;
+
+/// Frequent short words of the languages written in the Latin script, which
+/// tell them apart; the most matched words name the language.
+const COMMON_WORDS: [(&str, &[&str]); 20] = [
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "was", "he", "that", "it", "with", "is",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "est", "une", "il", "que", "pas", "dans",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "das", "ist", "nicht", "sie", "ich", "zu", "ein",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "que", "del", "una", "por", "con", "se",
+        ],
+    ),
+    (
+        "pt",
+        &["o", "os", "as", "e", "que", "não", "uma", "do", "da", "um"],
+    ),
+    (
+        "it",
+        &[
+            "il", "di", "che", "e", "non", "un", "della", "per", "gli", "è",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "en", "van", "niet", "ik", "dat", "op", "zijn",
+        ],
+    ),
+    (
+        "vi",
+        &[
+            "và", "của", "là", "không", "một", "có", "những", "được", "người", "tôi",
+        ],
+    ),
+    (
+        "pl",
+        &["i", "w", "nie", "się", "na", "że", "jest", "to", "z", "do"],
+    ),
+    (
+        "cs",
+        &["a", "je", "se", "na", "že", "to", "v", "jsem", "ale", "já"],
+    ),
+    (
+        "tr",
+        &[
+            "bir", "ve", "bu", "da", "de", "için", "ne", "çok", "ile", "gibi",
+        ],
+    ),
+    (
+        "id",
+        &[
+            "yang", "dan", "di", "itu", "dengan", "tidak", "ini", "untuk", "dari", "akan",
+        ],
+    ),
+    (
+        "ms",
+        &[
+            "yang", "dan", "di", "itu", "dengan", "tidak", "ini", "kepada", "dalam", "boleh",
+        ],
+    ),
+    (
+        "sv",
+        &[
+            "och", "att", "det", "som", "en", "är", "på", "jag", "inte", "han",
+        ],
+    ),
+    (
+        "no",
+        &[
+            "og", "det", "som", "en", "på", "er", "jeg", "ikke", "han", "hun",
+        ],
+    ),
+    (
+        "da",
+        &[
+            "og", "det", "at", "en", "på", "er", "jeg", "ikke", "han", "hun",
+        ],
+    ),
+    (
+        "fi",
+        &[
+            "ja", "on", "ei", "se", "oli", "hän", "että", "mutta", "kun", "niin",
+        ],
+    ),
+    (
+        "ro",
+        &["și", "în", "de", "nu", "că", "este", "un", "o", "pe", "cu"],
+    ),
+    (
+        "hu",
+        &[
+            "a", "az", "és", "hogy", "nem", "egy", "is", "volt", "meg", "de",
+        ],
+    ),
+    (
+        "tl",
+        &[
+            "ang", "ng", "sa", "na", "mga", "ay", "at", "hindi", "siya", "ko",
+        ],
+    ),
+];
+
+/// Share of the letters of a text, in percent, in the script of the
+/// language detected, for a confident guess.
+const CONFIDENT_SCRIPT: usize = 80;
+
+/// A language detected in a text.
+pub struct Detected {
+    pub language: &'static Language,
+    /// whether the language stands out enough to refuse a book over it:
+    /// nearly all of the letters in its script and, in the Latin script,
+    /// its frequent words well ahead of those of the next language
+    pub confident: bool,
+}
+
+/// Guess the language `text` is written in from its script, and, for the
+/// Latin and Cyrillic scripts, from its letters and frequent words. `None`
+/// when the text is too short or no language stands out.
+pub fn detect(text: &str) -> Option<Detected> {
+    // ordered, so that scripts as frequent are picked the same every time
+    let mut scripts: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut letters = 0;
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        if let Some(script) = script(c) {
+            *scripts.entry(script).or_default() += 1;
+        }
+    }
+    if letters < MIN_LETTERS {
+        return None;
+    }
+    let count = |script| scripts.get(script).copied().unwrap_or(0);
+    // Japanese mixes kana into Han characters; Chinese has none
+    let confident = |most: usize| most * 100 >= letters * CONFIDENT_SCRIPT;
+    if count("kana") * 10 > count("han") && count("kana") > 0 {
+        let cjk = count("kana") + count("han");
+        if cjk * 2 > letters {
+            return Some(Detected {
+                language: find("ja")?,
+                confident: confident(cjk),
+            });
+        }
+    }
+    // the first in the order of their names of scripts as frequent
+    let (&script, &most) = scripts.iter().rev().max_by_key(|(_, count)| **count)?;
+    if most * 2 < letters {
+        return None;
+    }
+    let has = |letters: &str| text.chars().any(|c| letters.contains(c));
+    let code = match script {
+        "latin" => {
+            let (language, distinct) = common_words(text)?;
+            return Some(Detected {
+                language,
+                confident: confident(most) && distinct,
+            });
+        }
+        "cyrillic" if has("іїєґІЇЄҐ") => "uk",
+        "cyrillic" => "ru",
+        "arabic" if has("پچژگ") => "fa",
+        "arabic" => "ar",
+        "han" => "zh",
+        "kana" => "ja",
+        script => script,
+    };
+    Some(Detected {
+        language: find(code)?,
+        confident: confident(most),
+    })
+}
+
+/// The script of a letter, as the code of the language detected from it
+/// when the script is written in one language only.
+fn script(c: char) -> Option<&'static str> {
+    Some(match c {
+        'a'..='z' | 'A'..='Z' | '\u{00c0}'..='\u{024f}' | '\u{1e00}'..='\u{1eff}' => "latin",
+        '\u{0370}'..='\u{03ff}' => "el",
+        '\u{0400}'..='\u{04ff}' => "cyrillic",
+        '\u{0590}'..='\u{05ff}' => "he",
+        '\u{0600}'..='\u{06ff}' => "arabic",
+        '\u{0900}'..='\u{097f}' => "hi",
+        '\u{0980}'..='\u{09ff}' => "bn",
+        '\u{0e00}'..='\u{0e7f}' => "th",
+        '\u{3040}'..='\u{30ff}' => "kana",
+        '\u{1100}'..='\u{11ff}' | '\u{ac00}'..='\u{d7af}' => "ko",
+        '\u{4e00}'..='\u{9fff}' | '\u{3400}'..='\u{4dbf}' => "han",
+        _ => return None,
+    })
+}
+
+/// The Latin-script language whose frequent words are the most found in
+/// `text`, and whether they are found half as often again as those of any
+/// other.
+fn common_words(text: &str) -> Option<(&'static Language, bool)> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let mut counts: Vec<(&str, usize)> = COMMON_WORDS
+        .iter()
+        .map(|(code, common)| {
+            let count = words
+                .iter()
+                .filter(|word| common.contains(&word.as_str()))
+                .count();
+            (*code, count)
+        })
+        .collect();
+    // the first listed of languages as often matched, Indonesian before
+    // Malay
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    let (code, count) = counts[0];
+    let next = counts[1].1;
+    // at least one in twenty words, so a few names or loanwords in an
+    // unlisted language do not decide
+    if count * 20 < words.len() {
+        return None;
+    }
+    Some((find(code)?, count * 2 >= next * 3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A passage of each language told apart, by its code.
+    const SAMPLES: [(&str, &str); 32] = [
+        ("en", "The old man walked to the harbour and it was late. He said that the boat was gone with the tide, and the children waited in the rain."),
+        ("fr", "Le vieil homme marchait vers le port et il était tard. Il dit que la barque est partie dans la nuit et que les enfants ne sont pas dans la maison."),
+        ("de", "Der alte Mann ging zum Hafen und es war spät. Er sagte, dass das Boot nicht mehr da ist und die Kinder im Regen auf ihn warten, sie und ich."),
+        ("es", "El viejo caminaba hacia el puerto y era tarde. Dijo que la barca se fue con la marea y los niños esperaban por él con las madres del pueblo."),
+        ("pt", "O velho caminhava para o porto e era tarde. Disse que o barco não estava lá e os meninos esperavam na chuva com as mães da aldeia, um a um."),
+        ("it", "Il vecchio camminava verso il porto ed era tardi. Disse che la barca non c'era più e che i bambini della città aspettavano per lui sotto la pioggia."),
+        ("nl", "De oude man liep naar de haven en het was laat. Hij zei dat de boot niet meer op zijn plek lag en dat de kinderen in de regen van een dak wachtten."),
+        ("vi", "Ông già đi bộ ra bến cảng và trời đã muộn. Ông nói rằng con thuyền không còn ở đó, và những đứa trẻ của làng là người đã chờ đợi tôi."),
+        ("pl", "Stary człowiek szedł do portu i było już późno. Powiedział, że łodzi nie ma na miejscu, że to jest koniec, i dzieci czekały w deszczu z matkami."),
+        ("cs", "Starý muž šel do přístavu a bylo už pozdě. Řekl, že loď je pryč a že to ví, ale děti na něj čekaly v dešti a já jsem se díval na moře."),
+        ("tr", "Yaşlı adam limana doğru yürüdü ve çok geç olmuştu. Bu teknenin gittiğini söyledi, çocuklar da yağmurun altında bir saat onu bekledi, ne gibi bir gece."),
+        ("id", "Orang tua itu berjalan ke pelabuhan dan hari sudah larut. Dia berkata bahwa perahu yang hilang itu tidak akan kembali dari laut untuk anak-anak di desa."),
+        ("sv", "Den gamle mannen gick till hamnen och det var sent. Han sa att båten inte var kvar på platsen och att barnen som väntade är kvar i regnet."),
+        ("fi", "Vanha mies käveli satamaan ja oli jo myöhä. Hän sanoi, että vene oli poissa, mutta lapset odottivat sateessa niin kauan kun se on mahdollista, ei muuta."),
+        ("ro", "Bătrânul mergea spre port și era târziu. A spus că barca nu mai este la mal și că copiii îl așteaptă în ploaie cu o lampă, pe rând, cu mamele de acasă."),
+        ("hu", "Az öreg ember a kikötő felé ment, és már késő volt. Azt mondta, hogy a csónak nem volt ott, és a gyerekek az esőben vártak egy órát is."),
+        ("tl", "Ang matandang lalaki ay naglakad papunta sa daungan at gabi na. Sinabi niya na ang bangka ay wala na, at ang mga bata ay naghintay sa ulan."),
+        ("el", "Ο γέρος περπατούσε προς το λιμάνι και ήταν αργά. Είπε ότι η βάρκα είχε φύγει με την παλίρροια και τα παιδιά περίμεναν στη βροχή."),
+        ("ru", "Старик шёл к гавани, и было уже поздно. Он сказал, что лодка ушла с приливом, а дети ждали его под дождём у старого дома."),
+        ("uk", "Старий ішов до гавані, і було вже пізно. Він сказав, що човен пішов із припливом, а діти чекали його під дощем біля її хати."),
+        ("he", "הזקן הלך אל הנמל והיה כבר מאוחר. הוא אמר שהסירה הפליגה עם הגאות והילדים חיכו לו בגשם ליד הבית הישן."),
+        ("ar", "مشى الرجل العجوز إلى الميناء وكان الوقت متأخرا. قال إن القارب قد رحل مع المد وإن الأطفال انتظروه تحت المطر قرب البيت."),
+        ("fa", "پیرمرد به سوی بندر رفت و دیروقت بود. گفت که قایق با مد رفته است و بچه‌ها زیر باران کنار خانهٔ قدیمی چشم به راه او ماندند."),
+        ("hi", "बूढ़ा आदमी बंदरगाह की ओर चला और बहुत देर हो चुकी थी। उसने कहा कि नाव ज्वार के साथ चली गई और बच्चे बारिश में उसका इंतज़ार करते रहे।"),
+        ("bn", "বৃদ্ধ লোকটি বন্দরের দিকে হেঁটে গেল এবং তখন অনেক দেরি হয়ে গেছে। সে বলল নৌকাটি জোয়ারের সাথে চলে গেছে আর শিশুরা বৃষ্টিতে অপেক্ষা করছিল।"),
+        ("th", "ชายชราเดินไปที่ท่าเรือและตอนนั้นก็ดึกแล้ว เขาบอกว่าเรือออกไปกับน้ำขึ้นแล้ว และเด็กๆ ก็รอเขาอยู่กลางสายฝนข้างบ้านหลังเก่า"),
+        ("ja", "老人は港へ歩いていったが、もう遅かった。彼は舟が潮と一緒に出ていってしまったと言い、子どもたちは古い家のそばで雨の中、ずっと彼を待っていた。"),
+        ("ko", "노인은 항구로 걸어갔고 이미 늦은 시간이었다. 그는 배가 밀물과 함께 떠났다고 말했고 아이들은 오래된 집 옆에서 빗속에 그를 기다렸다."),
+        ("zh", "老人向港口走去，天已经很晚了。他说小船随着潮水走了，孩子们在老房子旁边的雨中一直等着他回来，谁也没有离开。第二天早上，海上的风停了，村里的人都到岸边去看。"),
+        ("no", "Den gamle mannen gikk ned til havnen, og det var sent. Han sa båten ikke var der, og hun og barna som ventet er ute i regnet, sa jeg."),
+        ("da", "Den gamle mand gik ned til havnen, og det var sent. Han sagde at båden ikke var der, og at hun og børnene ventede i regnen, sagde jeg."),
+        ("ms", "Orang tua itu berjalan ke pelabuhan dan hari sudah lewat. Dia berkata kepada anak-anak yang menunggu bahawa perahu itu tidak boleh kembali dalam hujan."),
+    ];
+
+    #[test]
+    fn languages_are_detected() {
+        for (code, sample) in SAMPLES {
+            let detected = detect(sample).map(|detected| detected.language.code);
+            assert_eq!(detected, Some(code), "{}", sample);
+        }
+    }
+
+    #[test]
+    fn distinct_languages_are_confident() {
+        for (code, sample) in SAMPLES {
+            let confident = detect(sample).is_some_and(|detected| detected.confident);
+            // the frequent words of Norwegian and Danish are much the same
+            let alike = ["no", "da"].contains(&code);
+            assert_eq!(confident, !alike, "{}: {}", code, sample);
+        }
+    }
+
+    #[test]
+    fn mixed_texts_are_weak_guesses() {
+        // an English book quoting French at length
+        let mixed = format!("{} {}", SAMPLES[0].1, SAMPLES[1].1);
+        let detected = detect(&mixed).unwrap();
+        assert!(!detected.confident, "{}", detected.language.name);
+        // Russian with Latin names in most of its words
+        let names = format!(
+            "{} {}",
+            SAMPLES[18].1, "Alexander Petrovich Smirnoff Ivanovich"
+        );
+        assert!(detect(&names).is_some_and(|detected| !detected.confident));
+    }
+
+    #[test]
+    fn scripts_as_frequent_are_picked_the_same() {
+        let greek = "αβγδεζηθικλμνξοπρστυφχψωαβγδεζηθικλμνξοπρστυφχψωαβ";
+        let cyrillic = "абвгдежзийклмнопрстуфхцчшщъыьэюяабвгдежзийклмнопрс";
+        for text in [
+            format!("{}{}", greek, cyrillic),
+            format!("{}{}", cyrillic, greek),
+        ] {
+            // "cyrillic" before "el"
+            let detected = detect(&text).map(|detected| detected.language.code);
+            assert_eq!(detected, Some("ru"));
+        }
+    }
+
+    #[test]
+    fn short_or_unlisted_texts_are_not_detected() {
+        assert!(detect("The boat was gone.").is_none());
+        // numbers and punctuation are not letters
+        assert!(detect(&"1234567890, ".repeat(20)).is_none());
+        assert!(detect(&"Xyzzy plugh quux frobnicate ".repeat(5)).is_none());
+    }
+}
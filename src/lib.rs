@@ -0,0 +1,26 @@
+//! Translation of EPUB books and other documents with LLM APIs, the engine
+//! of the `trans-epub` command line. [`Pipeline`] runs it from another
+//! program; the modules below are what it is made of.
+
+pub mod batch;
+pub mod cache;
+pub mod client;
+pub mod config;
+pub mod control;
+pub mod epub;
+pub mod error;
+pub mod input;
+pub mod language;
+pub mod logging;
+pub mod memory;
+pub mod names;
+pub mod pipeline;
+pub mod serve;
+pub mod tmx;
+pub mod translate;
+pub mod watch;
+pub mod xliff;
+
+pub use crate::epub::translate_epub_bytes;
+pub use crate::error::Error;
+pub use crate::pipeline::{Pipeline, Provider};
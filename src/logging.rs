@@ -0,0 +1,248 @@
+//! The log of a run: lines of text, or JSON objects with `--log-format
+//! json`, each tagged with the trace id of the chunk it is about.
+//!
+//! A chunk gets its id when it is sent, a run-wide number, and keeps it
+//! through its request, the parsing of its response, its retries and the
+//! writing of its translations; the smaller chunks of a retry, a split or a
+//! requeue are numbered under it, as in `12.2`. The id is added to the
+//! key-values of every record logged while the chunk is worked on, as
+//! `chunk`, next to the `stage` of the work.
+
+use clap::ValueEnum;
+use env_logger::{fmt::Formatter, Env, Logger};
+use log::kv::{self, Key, Source, Value, VisitSource};
+use log::{Log, Metadata, Record};
+use serde_json::{Map, Value as Json};
+use std::future::Future;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Lines of text
+    Text,
+    /// One JSON object a line, to be analyzed with other tools
+    Json,
+}
+
+tokio::task_local! {
+    /// the trace id of the chunk a task translates
+    static TRACE: String;
+}
+
+/// The run-wide number of the last chunk sent outside of any other.
+static CHUNKS: AtomicUsize = AtomicUsize::new(0);
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Log to stderr in `format`, at the level of `RUST_LOG`, `info` by default.
+pub fn init(format: LogFormat) {
+    FORMAT.get_or_init(|| format);
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    let logger = Traced(formatted(&mut builder, format).build());
+    log::set_max_level(logger.0.filter());
+    log::set_boxed_logger(Box::new(logger)).expect("the logger is set once");
+}
+
+fn formatted(builder: &mut env_logger::Builder, format: LogFormat) -> &mut env_logger::Builder {
+    match format {
+        LogFormat::Text => builder.format(text),
+        LogFormat::Json => builder.format(json),
+    }
+}
+
+/// A logger adding the trace id of the chunk being translated to the
+/// key-values of the records.
+struct Traced(Logger);
+
+impl Log for Traced {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let Some(id) = chunk_id() else {
+            return self.0.log(record);
+        };
+        let chunk = ("chunk", id.as_str());
+        let key_values: [&dyn Source; 2] = [record.key_values(), &chunk];
+        self.0
+            .log(&record.to_builder().key_values(&key_values).build());
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Whether the log is JSON, which no progress line is drawn over.
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&LogFormat::Json)
+}
+
+/// The trace ids of `count` chunks: numbered under the chunk being
+/// translated, or the next ones of the run.
+pub fn chunk_ids(count: usize) -> Vec<String> {
+    match chunk_id() {
+        Some(parent) => (1..=count)
+            .map(|number| format!("{}.{}", parent, number))
+            .collect(),
+        None => {
+            let first = CHUNKS.fetch_add(count, Ordering::Relaxed) + 1;
+            (first..first + count).map(|id| id.to_string()).collect()
+        }
+    }
+}
+
+/// The trace id of the chunk being translated, if any.
+pub fn chunk_id() -> Option<String> {
+    TRACE.try_with(String::clone).ok()
+}
+
+/// Run `future` as the work on the chunk of trace id `id`.
+pub async fn traced<F: Future>(id: String, future: F) -> F::Output {
+    TRACE.scope(id, future).await
+}
+
+fn text(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let style = buf.default_level_style(record.level());
+    // clear the progress line first; it is drawn again on its next update
+    let clear = if io::stderr().is_terminal() {
+        "\r\x1b[K"
+    } else {
+        ""
+    };
+    let trace = record
+        .key_values()
+        .get(Key::from_str("chunk"))
+        .map(|id| format!(" #{}", id))
+        .unwrap_or_default();
+    writeln!(
+        buf,
+        "{}[{} {style}{:<5}{style:#} {}{}] {}",
+        clear,
+        buf.timestamp(),
+        record.level(),
+        record.target(),
+        trace,
+        record.args()
+    )
+}
+
+fn json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut line = Map::new();
+    line.insert("time".into(), buf.timestamp().to_string().into());
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+    line.insert("message".into(), record.args().to_string().into());
+    writeln!(buf, "{}", Json::Object(line))
+}
+
+/// The key-values of a record, such as its `stage` and `chunk`, as JSON
+/// fields.
+struct Fields<'a>(&'a mut Map<String, Json>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match value.to_u64() {
+            Some(number) => number.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::sync::{Arc, Mutex};
+
+    /// The log written by a logger of `format`, shared with the test.
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logger(format: LogFormat, written: &Written) -> Traced {
+        let mut builder = env_logger::Builder::new();
+        builder
+            .filter_level(log::LevelFilter::Info)
+            .target(env_logger::Target::Pipe(Box::new(written.clone())));
+        Traced(formatted(&mut builder, format).build())
+    }
+
+    fn log(logger: &Traced, stage: &str, message: &str) {
+        let stage = ("stage", stage);
+        logger.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("trans_epub::translate::translator")
+                .key_values(&stage)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    #[tokio::test]
+    async fn json_logs_are_an_object_a_line() {
+        let written = Written::default();
+        let logger = logger(LogFormat::Json, &written);
+        log(&logger, "request", "before any chunk");
+        traced("12".to_string(), async {
+            log(&logger, "request", "response status: 200");
+            log(&logger, "parse", "a message\nover two lines");
+            for id in chunk_ids(2) {
+                traced(id, async { log(&logger, "retry", "retried") }).await;
+            }
+        })
+        .await;
+        let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Json> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 5, "{}", written);
+        let field = |line: &Json, name: &str| line[name].as_str().map(String::from);
+        assert_eq!(field(&lines[0], "chunk"), None);
+        let chunks: Vec<_> = lines[1..].iter().map(|line| field(line, "chunk")).collect();
+        assert_eq!(
+            chunks,
+            ["12", "12", "12.1", "12.2"].map(|id| Some(id.to_string()))
+        );
+        let stages: Vec<_> = lines.iter().map(|line| field(line, "stage")).collect();
+        assert_eq!(
+            stages,
+            ["request", "request", "parse", "retry", "retry"].map(|stage| Some(stage.to_string()))
+        );
+        assert_eq!(
+            field(&lines[2], "message").as_deref(),
+            Some("a message\nover two lines")
+        );
+        assert_eq!(field(&lines[2], "level").as_deref(), Some("WARN"));
+    }
+
+    #[tokio::test]
+    async fn text_logs_carry_the_chunk() {
+        let written = Written::default();
+        let logger = logger(LogFormat::Text, &written);
+        traced("7".to_string(), async { log(&logger, "write", "recorded") }).await;
+        let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            written.contains("trans_epub::translate::translator #7] recorded"),
+            "{}",
+            written
+        );
+    }
+}
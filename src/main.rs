@@ -1,44 +1,122 @@
-mod client;
-mod epub;
-mod translate;
-
-use crate::epub::Epub;
-use crate::translate::translator::{Context, Translator};
-use clap::{Parser, Subcommand};
-use env_logger::Env;
-use log::debug;
-use std::path::PathBuf;
+use clap::{Args as _, CommandFactory, Parser, Subcommand};
+use log::{debug, error, info, warn};
+use reqwest::header::{HeaderName, HeaderValue};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{self, IsTerminal, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use trans_epub::batch;
+use trans_epub::cache::{self, Cache};
+use trans_epub::client::budget::Budget;
+use trans_epub::client::capability::JsonMode;
+use trans_epub::client::gemini::PromptCache;
+use trans_epub::client::keys::Keys;
+use trans_epub::client::limiter::Limiter;
+use trans_epub::client::preflight::Preflight;
+use trans_epub::client::pricing::{self, Price};
+use trans_epub::client::quota::{OnQuota, Quota};
+use trans_epub::client::ratelimit::{parse_duration, Throttle};
+use trans_epub::client::retry::{RetryOn, RetryPolicy};
+use trans_epub::client::safety::{OnBlocked, Threshold};
+use trans_epub::client::shutdown::Shutdown;
+use trans_epub::client::totals::Totals;
+use trans_epub::config::{self, Config, Value};
+use trans_epub::control::{self, Control};
+use trans_epub::epub::attributes;
+use trans_epub::epub::chapter::{ChapterLanguage, Chapters};
+use trans_epub::epub::diff::diff_epub_bytes;
+use trans_epub::epub::estimate;
+use trans_epub::epub::inspect::inspect_epub_bytes;
+use trans_epub::epub::layout::Layout;
+use trans_epub::epub::markup;
+use trans_epub::epub::notes::Notes;
+use trans_epub::epub::provenance::{self, Provenance, ProvenanceMode};
+use trans_epub::epub::retranslate::{retranslate_epub, Filter, Selection};
+use trans_epub::epub::toc::Headings;
+use trans_epub::epub::validate::validate_epub_bytes;
+use trans_epub::input;
+use trans_epub::language;
+use trans_epub::logging::{self, LogFormat};
+use trans_epub::memory::Memory;
+use trans_epub::names::{self, Names};
+use trans_epub::pipeline::{Config as PipelineConfig, Factory, Provider};
+use trans_epub::tmx;
+use trans_epub::translate::anthropic::Anthropic;
+use trans_epub::translate::deepl::DeepL;
+use trans_epub::translate::dump::{self, Dump, Dumps};
+use trans_epub::translate::extract;
+use trans_epub::translate::gemini::Gemini;
+use trans_epub::translate::glossary::Glossary;
+use trans_epub::translate::line::{LineNumbering, OnFailure, RetryStrategy, Whitespace};
+use trans_epub::translate::mock::{Mock, Transform};
+use trans_epub::translate::ollama::Ollama;
+use trans_epub::translate::open_ai::OpenAi;
+use trans_epub::translate::progress::Progress;
+use trans_epub::translate::prompt::{self, Honorifics, Register};
+use trans_epub::translate::quality::{Script, ScriptCheck};
+use trans_epub::translate::report::Report;
+use trans_epub::translate::review::Review;
+use trans_epub::translate::self_test;
+use trans_epub::translate::translator::{Context, Translator, MAX_RETRIES};
+use trans_epub::xliff::{self, Unlisted, Xliff};
+use trans_epub::{serve, watch};
 
 #[derive(Parser)]
-#[command(version, about)]
+#[command(
+    version,
+    about,
+    after_help = "Options of the translating commands are also read from ./trans-epub.toml, or the \
+                  file given with --config; options on the command line override it."
+)]
 struct Args {
     #[clap(subcommand)]
     subcommand: SubCommands,
+
+    /// format of the log on stderr: text, or a JSON object a line with the trace id of the chunk
+    /// and the stage of the work on it
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// configuration file of the translating commands, instead of ./trans-epub.toml; read
+    /// before the command line is parsed
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
 enum SubCommands {
     /// Use OpenAI API
     OpenAi {
-        /// input file path
-        #[arg(short, long)]
-        input: PathBuf,
+        /// input file paths: EPUBs, .txt, .md, .fb2 or .html files, or directories of chapter
+        /// files; several inputs or a pattern such as '*.epub' are translated one by one
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
 
-        /// output file path
+        /// output file path, a directory for a directory input or several inputs
         #[arg(short, long)]
         output: PathBuf,
 
-        /// translate language
-        #[arg(short, long)]
-        language: String,
+        /// translate language, or several separated by commas, each into its own output
+        #[arg(short, long, value_delimiter = ',', required = true)]
+        language: Vec<String>,
 
         /// OpenAI model ex(gpt-4o, gpt-4-turbo, gpt-3.5-turbo-1106)
         #[arg(short, long, default_value_t = String::from("gpt-4o"))]
         model: String,
 
         /// OpenAI API Key
-        #[arg(short, long, env, hide_env_values = true)]
-        api_key: String,
+        #[arg(
+            short,
+            long,
+            env,
+            hide_env_values = true,
+            required_unless_present_any = ["api_keys", "api_keys_file"]
+        )]
+        api_key: Option<String>,
 
         /// Number of lines of translation
         #[arg(long, default_value_t = 20)]
@@ -47,28 +125,38 @@ enum SubCommands {
         /// Number of concurrent requests
         #[arg(long, default_value_t = 5)]
         requests: usize,
+
+        #[command(flatten)]
+        options: Options,
     },
     /// Use Gemini API
     Gemini {
-        /// input file path
-        #[arg(short, long)]
-        input: PathBuf,
+        /// input file paths: EPUBs, .txt, .md, .fb2 or .html files, or directories of chapter
+        /// files; several inputs or a pattern such as '*.epub' are translated one by one
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
 
-        /// output file path
+        /// output file path, a directory for a directory input or several inputs
         #[arg(short, long)]
         output: PathBuf,
 
-        /// translate language
-        #[arg(short, long)]
-        language: String,
+        /// translate language, or several separated by commas, each into its own output
+        #[arg(short, long, value_delimiter = ',', required = true)]
+        language: Vec<String>,
 
         /// Gemini model ex(gemini-1.5-flash)
         #[arg(short, long, default_value_t = String::from("gemini-1.5-flash"))]
         model: String,
 
         /// Gemini API Key
-        #[arg(short, long, env, hide_env_values = true)]
-        api_key: String,
+        #[arg(
+            short,
+            long,
+            env,
+            hide_env_values = true,
+            required_unless_present_any = ["api_keys", "api_keys_file"]
+        )]
+        api_key: Option<String>,
 
         /// Number of lines of translation
         #[arg(long, default_value_t = 100)]
@@ -77,15 +165,864 @@ enum SubCommands {
         /// Number of concurrent requests
         #[arg(long, default_value_t = 1)]
         requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Use Anthropic API
+    Anthropic {
+        /// input file paths: EPUBs, .txt, .md, .fb2 or .html files, or directories of chapter
+        /// files; several inputs or a pattern such as '*.epub' are translated one by one
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
+
+        /// output file path, a directory for a directory input or several inputs
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// translate language, or several separated by commas, each into its own output
+        #[arg(short, long, value_delimiter = ',', required = true)]
+        language: Vec<String>,
+
+        /// Claude model ex(claude-3-5-sonnet-latest, claude-3-5-haiku-latest)
+        #[arg(short, long, default_value_t = String::from("claude-3-5-sonnet-latest"))]
+        model: String,
+
+        /// Anthropic API Key
+        #[arg(
+            short,
+            long,
+            env,
+            hide_env_values = true,
+            required_unless_present_any = ["api_keys", "api_keys_file"]
+        )]
+        api_key: Option<String>,
+
+        /// Number of lines of translation
+        #[arg(long, default_value_t = 50)]
+        lines: usize,
+
+        /// Number of concurrent requests
+        #[arg(long, default_value_t = 2)]
+        requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Use DeepL API
+    #[command(name = "deepl")]
+    DeepL {
+        /// input file paths: EPUBs, .txt, .md, .fb2 or .html files, or directories of chapter
+        /// files; several inputs or a pattern such as '*.epub' are translated one by one
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
+
+        /// output file path, a directory for a directory input or several inputs
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// translate language, or several separated by commas, each into its own output
+        #[arg(short, long, value_delimiter = ',', required = true)]
+        language: Vec<String>,
+
+        /// DeepL API Key; a key of DeepL API Free, ending with `:fx`, is sent to its API
+        #[arg(
+            short,
+            long,
+            env,
+            hide_env_values = true,
+            required_unless_present_any = ["api_keys", "api_keys_file"]
+        )]
+        api_key: Option<String>,
+
+        /// Number of lines of translation
+        #[arg(long, default_value_t = 50)]
+        lines: usize,
+
+        /// Number of concurrent requests
+        #[arg(long, default_value_t = 2)]
+        requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Use a local model served by Ollama
+    Ollama {
+        /// input file paths: EPUBs, .txt, .md, .fb2 or .html files, or directories of chapter
+        /// files; several inputs or a pattern such as '*.epub' are translated one by one
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
+
+        /// output file path, a directory for a directory input or several inputs
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// translate language, or several separated by commas, each into its own output
+        #[arg(short, long, value_delimiter = ',', required = true)]
+        language: Vec<String>,
+
+        /// Ollama model ex(llama3.1, qwen2.5:14b)
+        #[arg(short, long, default_value_t = String::from("llama3.1"))]
+        model: String,
+
+        /// Number of lines of translation
+        #[arg(long, default_value_t = 10)]
+        lines: usize,
+
+        /// Number of concurrent requests
+        #[arg(long, default_value_t = 1)]
+        requests: usize,
+
+        /// Context window of the model in tokens; Ollama truncates longer prompts
+        #[arg(long, default_value_t = 8192)]
+        num_ctx: usize,
+
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Translate offline by transforming each paragraph the same way every time, to test or
+    /// benchmark a whole run without an API
+    Mock {
+        /// input file paths: EPUBs, .txt, .md, .fb2 or .html files, or directories of chapter
+        /// files; several inputs or a pattern such as '*.epub' are translated one by one
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
+
+        /// output file path, a directory for a directory input or several inputs
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// translate language, or several separated by commas, each into its own output
+        #[arg(short, long, value_delimiter = ',', required = true)]
+        language: Vec<String>,
+
+        /// What becomes of each paragraph
+        #[arg(long, value_enum, default_value_t = Transform::Wrap)]
+        transform: Transform,
+
+        /// Number of lines of translation
+        #[arg(long, default_value_t = 20)]
+        lines: usize,
+
+        /// Number of concurrent requests
+        #[arg(long, default_value_t = 4)]
+        requests: usize,
+
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Serve an HTTP API to upload EPUBs, translate them as jobs, poll the jobs and download the
+    /// results
+    Serve {
+        /// Address to listen on; give 0.0.0.0 to take jobs from other machines, with --token
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: IpAddr,
+
+        /// Port to listen on
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+
+        /// Bearer token every request must carry in its Authorization header
+        #[arg(long, env = "SERVE_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+
+        /// Seconds a finished job is kept, with its output, before it is forgotten
+        #[arg(long, default_value_t = 3600)]
+        keep: u64,
+
+        /// Largest EPUB taken in an upload, in MiB
+        #[arg(long, default_value_t = 100)]
+        max_upload: usize,
+
+        /// Jobs translated at once; an upload beyond them is answered 503
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+        max_jobs: u64,
+
+        /// Seconds a client has to send a request, its upload included
+        #[arg(long, default_value_t = 300)]
+        read_timeout: u64,
+
+        /// translate language of the jobs that give none with `?language=`
+        #[arg(short, long)]
+        language: Option<String>,
+
+        #[command(flatten)]
+        engine: Engine,
+    },
+    /// Watch a directory and translate each EPUB dropped into it into another directory
+    Watch {
+        /// directory to watch
+        inbox: PathBuf,
+
+        /// directory the translated books are written to
+        outbox: PathBuf,
+
+        /// translate language
+        #[arg(short, long)]
+        language: String,
+
+        /// Seconds between two scans of the directory
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+
+        #[command(flatten)]
+        engine: Engine,
+    },
+    /// Translate a book and write its paragraphs with the machine translation as XLIFF 2.1, to be
+    /// post-edited in a CAT tool
+    ExportXliff {
+        /// input file path: an EPUB, a .txt, .md, .fb2 or .html file, or a directory of chapter files
+        input: PathBuf,
+
+        /// output XLIFF file path, the input with the .xlf extension when not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// translate language
+        #[arg(short, long)]
+        language: String,
+
+        #[command(flatten)]
+        engine: Engine,
+    },
+    /// Write a book translated with the targets of a post-edited XLIFF from export-xliff
+    ImportXliff {
+        /// input file path, the book the XLIFF was exported from
+        input: PathBuf,
+
+        /// post-edited XLIFF file path
+        xliff: PathBuf,
+
+        /// output file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// translate language, the target language of the XLIFF when not given
+        #[arg(short, long)]
+        language: Option<String>,
+
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Translate again the paragraphs of a translated EPUB chosen with --paragraphs or --filter,
+    /// from its <output>.provenance.json of --provenance sidecar, and write them in place of their
+    /// translations
+    Retranslate {
+        /// translated EPUB file path
+        input: PathBuf,
+
+        /// file listing the paragraphs, one a line: `<document> paragraph <n>` as the log names
+        /// them, `<document>:<n>` or the hash of the source
+        #[arg(long, required_unless_present = "filters")]
+        paragraphs: Option<PathBuf>,
+
+        /// Only the paragraphs matching this, `contains:TEXT` in the translation, `source:TEXT`,
+        /// `model:NAME` or `run:ID`; may be repeated
+        #[arg(long = "filter")]
+        filters: Vec<Filter>,
+
+        /// output file path, the input when not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        engine: Engine,
+    },
+    /// Export a translation memory file as TMX
+    TmxExport {
+        /// translation memory file recorded with --memory
+        #[arg(short, long)]
+        memory: PathBuf,
+
+        /// output TMX file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// source language of the translated books
+        #[arg(short, long, default_value_t = String::from("en"))]
+        source_language: String,
+    },
+    /// Check the response parser against the bundled fixtures
+    SelfTest,
+    /// Send again the request of a failure dumped with --dump-failures and print the raw response
+    Replay {
+        /// dumped failure file, under .trans-epub/failures/
+        file: PathBuf,
+
+        /// model to send it to, that of the dump when not given
+        #[arg(short, long)]
+        model: Option<String>,
+
+        /// API Key, not needed for Ollama
+        #[arg(short, long, env, hide_env_values = true)]
+        api_key: Option<String>,
+
+        /// API base URL, that of the dump when not given
+        #[arg(long)]
+        base_url: Option<String>,
+    },
+    /// Print the structure of an EPUB without translating
+    Inspect {
+        /// input file path
+        #[arg(short, long)]
+        input: PathBuf,
+    },
+    /// Check a translated EPUB: its manifest, well-formed documents and internal links, and with
+    /// --source its spine order and the paragraphs of each chapter
+    Validate {
+        /// translated EPUB file path
+        input: PathBuf,
+
+        /// EPUB it was translated from
+        #[arg(short, long)]
+        source: Option<PathBuf>,
+    },
+    /// Set the paragraphs of a translated EPUB beside those of its source, chapter by chapter,
+    /// in an HTML report of those dropped, added or left untranslated
+    Diff {
+        /// EPUB it was translated from
+        original: PathBuf,
+
+        /// translated EPUB file path
+        translated: PathBuf,
+
+        /// HTML report file path, `<translated>.diff.html` by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Send a command to a run started with --control: pause, resume, requests <N>, model <NAME>
+    /// or status
+    Control {
+        /// the --control socket of the run
+        socket: PathBuf,
+
+        /// the command and its argument
+        #[arg(required = true, num_args = 1..)]
+        command: Vec<String>,
     },
+    /// Estimate the requests, tokens and cost of a translation without sending anything
+    Estimate {
+        /// input file path: an EPUB, a .txt, .md, .fb2 or .html file, or a directory of chapter files
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// model to price the translation for
+        #[arg(short, long, default_value_t = String::from("gemini-1.5-flash"))]
+        model: String,
+
+        /// Number of lines of translation
+        #[arg(long, default_value_t = 100)]
+        lines: usize,
+
+        /// Pack chunks up to this many estimated tokens instead of by --lines
+        #[arg(long)]
+        max_chunk_tokens: Option<usize>,
+
+        /// Price of input tokens in USD per million, for models not in the price table
+        #[arg(long, requires = "output_price")]
+        input_price: Option<f64>,
+
+        /// Price of output tokens in USD per million
+        #[arg(long, requires = "input_price")]
+        output_price: Option<f64>,
+    },
+}
+
+/// The provider and options of the books translated by `serve`, `watch` and
+/// `export-xliff`.
+#[derive(clap::Args)]
+struct Engine {
+    /// API to translate with
+    #[arg(long, value_enum)]
+    provider: Provider,
+
+    /// model, the default of the provider's subcommand when not given
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// API Key, not needed for Ollama
+    #[arg(short, long, env, hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Number of lines of translation, the default of the provider's subcommand when not given
+    #[arg(long)]
+    lines: Option<usize>,
+
+    /// Number of concurrent requests of each book, the default of the provider's subcommand when
+    /// not given
+    #[arg(long)]
+    requests: Option<usize>,
+
+    /// Context window of the model in tokens, for Ollama
+    #[arg(long, default_value_t = 8192)]
+    num_ctx: usize,
+
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(clap::Args, Clone)]
+struct Options {
+    /// Pack chunks up to this many estimated tokens instead of by --lines
+    #[arg(long)]
+    max_chunk_tokens: Option<usize>,
+
+    /// Never put more than this many paragraphs in one chunk
+    #[arg(long)]
+    max_paragraphs_per_chunk: Option<usize>,
+
+    /// End a chunk at least half full after a scene break, a paragraph such as "***" with no
+    /// letters, so scenes are not cut across requests
+    #[arg(long)]
+    scene_breaks: bool,
+
+    /// Send this many paragraphs before each chunk along with it as context, with their
+    /// translations when their chunk is done
+    #[arg(long, default_value_t = 0)]
+    context_lines: usize,
+
+    /// Numbering of the `line` field returned by the model
+    #[arg(long, value_enum, default_value_t = LineNumbering::Auto)]
+    line_numbering: LineNumbering,
+
+    /// Check that the API key can use the model before translating
+    #[arg(long, value_enum, default_value_t = Preflight::Warn)]
+    preflight: Preflight,
+
+    /// How to pace requests between API calls
+    #[arg(long, value_enum, default_value_t = Throttle::Headers)]
+    throttle: Throttle,
+
+    /// Do not draw the progress line on the terminal
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Write the totals of the run (requests, retries, tokens, time, estimated cost) as JSON
+    #[arg(long)]
+    stats_out: Option<PathBuf>,
+
+    /// Stop sending once the estimated cost of the run reaches this many US dollars, or ask to
+    /// go on at a terminal, again at each multiple of it; the book is written with what is
+    /// translated
+    #[arg(long)]
+    max_cost: Option<f64>,
+
+    /// Stop sending once the requests of the run used this many tokens, or ask to go on at a
+    /// terminal, again at each multiple of it
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_tokens: Option<u64>,
+
+    /// Go on past --max-cost and --max-tokens without asking, with a warning at each multiple
+    #[arg(long)]
+    yes_continue: bool,
+
+    /// Write the prompt and the raw response of each chunk whose response cannot be parsed or has
+    /// the wrong number of lines to .trans-epub/failures/, listed in its index.jsonl, to be sent
+    /// again with `replay`
+    #[arg(long)]
+    dump_failures: bool,
+
+    /// Requests per minute allowed by the API plan, shared by all requests of the run
+    #[arg(long)]
+    rpm: Option<u32>,
+
+    /// Tokens per minute allowed by the API plan, paced from estimated and reported token counts
+    #[arg(long)]
+    tpm: Option<u32>,
+
+    /// Start with one request at a time and raise the concurrency up to --requests while the
+    /// responses come back quickly, halving it on a 429, a 5xx or a failed connection
+    #[arg(long)]
+    adaptive_requests: bool,
+
+    /// Unix socket taking commands during the run, sent with `trans-epub control`: pause,
+    /// resume, requests <N>, model <NAME> or status
+    #[arg(long)]
+    control: Option<PathBuf>,
+
+    /// API keys used in turn; a key answered with 429 is benched for --key-cooldown
+    #[arg(long, value_delimiter = ',')]
+    api_keys: Vec<String>,
+
+    /// File of API keys used in turn, one per line
+    #[arg(long)]
+    api_keys_file: Option<PathBuf>,
+
+    /// Seconds a key of the pool rests after a 429 response, or longer when the response asks
+    #[arg(long, default_value_t = 60)]
+    key_cooldown: u64,
+
+    /// Base URL of the API, for OpenAI-compatible servers, Azure OpenAI, a proxy or a remote Ollama
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// Extra HTTP header sent with every request, as `Name: value` (repeatable)
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Language the book is written in, named in the prompt; detected from the book when not given
+    #[arg(long)]
+    source_language: Option<String>,
+
+    /// Translate a book that appears to be in the target language already instead of refusing
+    #[arg(long)]
+    allow_same_language: bool,
+
+    /// System instruction (persona) sent separately from the task prompt
+    #[arg(long)]
+    system_instruction: Option<String>,
+
+    /// Cache the system instruction and the whole glossary once with the Gemini API and refer to
+    /// them in each request, billed at a quarter of the input price; for preambles over the
+    /// minimum size of the model, such as a large glossary
+    #[arg(long)]
+    cache_prompt: bool,
+
+    /// Sampling temperature of the Gemini requests, e.g. 0 for the most literal translation;
+    /// that of the model when not given
+    #[arg(long)]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling (topP) of the Gemini requests, from 0 to 1
+    #[arg(long)]
+    top_p: Option<f32>,
+
+    /// Most tokens of a Gemini response (maxOutputTokens); a chunk cut short by it is retried
+    /// in smaller parts
+    #[arg(long)]
+    max_output_tokens: Option<u32>,
+
+    /// What the safety filters of the Gemini API block in every category; the defaults of the
+    /// model when not given
+    #[arg(long, value_enum)]
+    safety_threshold: Option<Threshold>,
+
+    /// What becomes of a chunk the safety filters block
+    #[arg(long, value_enum, default_value_t = OnBlocked::Flag)]
+    on_blocked: OnBlocked,
+
+    /// Register of the translation, added to the prompt
+    #[arg(long, value_enum)]
+    register: Option<Register>,
+
+    /// Keep honorifics such as Korean -nim and -ssi, or localize them into the forms of address
+    /// of the target language, added to the prompt
+    #[arg(long, value_enum)]
+    honorifics: Option<Honorifics>,
+
+    /// Glossary file of `source = target` lines, or `source,target[,notes]` rows in a .csv file,
+    /// added to the prompt for the terms in each chunk
+    #[arg(long)]
+    glossary: Option<PathBuf>,
+
+    /// Replace glossary terms left untranslated in the translation with their glossary translation
+    #[arg(long)]
+    enforce_glossary: bool,
+
+    /// Draft a glossary of the book's names and terms to this file and pause for review before
+    /// translating; an existing file is used as reviewed. --glossary terms take precedence
+    #[arg(long)]
+    extract_glossary: Option<PathBuf>,
+
+    /// Instruction template file, with {{language}}, {{paragraph_count}} and {{glossary}} filled in
+    #[arg(long, conflicts_with = "prompt_lang")]
+    prompt_template: Option<PathBuf>,
+
+    /// Style preset of the instructions: technical, academic, romance, literary or subtitle, or a
+    /// preset of --style-preset
+    #[arg(long, conflicts_with_all = ["prompt_template", "prompt_lang"])]
+    style: Option<String>,
+
+    /// Register a style preset as `NAME=TEMPLATE_FILE` (repeatable), e.g. in the configuration
+    /// file
+    #[arg(long = "style-preset", value_parser = parse_preset)]
+    style_presets: Vec<(String, PathBuf)>,
+
+    /// Language of the instruction template read from the prompt directory as `<LANG>.txt`
+    #[arg(long)]
+    prompt_lang: Option<String>,
+
+    /// Directory of instruction templates selected with --prompt-lang
+    #[arg(long, default_value = "prompts")]
+    prompt_dir: PathBuf,
+
+    /// Polish each translated chapter in a second pass, sending the original along as a reference
+    #[arg(long)]
+    refine: bool,
+
+    /// Instruction template file of the --refine pass, with {{language}}, {{paragraph_count}} and
+    /// {{glossary}} filled in
+    #[arg(long, requires = "refine")]
+    refine_template: Option<PathBuf>,
+
+    /// Stream responses as they are generated (text output mode); echoed to stdout without a progress bar
+    #[arg(long)]
+    stream: bool,
+
+    /// Number of chapters translated at once; finished chapters are written out in order
+    #[arg(long, default_value_t = 1)]
+    max_chapters_in_flight: usize,
+
+    /// Translate the text attributes alt, title, aria-label and placeholder
+    #[arg(long)]
+    translate_attributes: bool,
+
+    /// Another attribute to translate with --translate-attributes (repeatable)
+    #[arg(long, value_parser = parse_attribute, requires = "translate_attributes")]
+    translate_attribute: Vec<String>,
+
+    /// Translate only these chapters, by spine position as `5..12`, `7` or `40..` (comma separated);
+    /// the others are written untranslated
+    #[arg(long, value_delimiter = ',')]
+    chapters: Vec<Chapters>,
+
+    /// Translate only these chapters, by path or file name as `ch005.xhtml` (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    chapter_ids: Vec<String>,
+
+    /// Never translate these chapters, by spine position, path or pattern as `*copyright*` (comma
+    /// separated, repeatable)
+    #[arg(long, value_delimiter = ',')]
+    skip: Vec<Chapters>,
+
+    /// List the chapters of the EPUB and ask which to skip before translating
+    #[arg(long)]
+    select_skipped: bool,
+
+    /// Target language of some chapters, as `3=skip`, `5-7=English` (spine positions) or `PATH=LANGUAGE` (repeatable)
+    #[arg(long)]
+    chapter_language: Vec<ChapterLanguage>,
+
+    /// Translate the title, description, subjects and series name in the package metadata, set its
+    /// language, and translate the table of contents
+    #[arg(long)]
+    translate_metadata: bool,
+
+    /// Write the output as EPUB 3, with the language and writing direction of
+    /// the translation and a checked manifest
+    #[arg(long)]
+    epub3: bool,
+
+    /// Regenerate the cover with the translated title and author, over the
+    /// cover image or on a plain page when the book has none
+    #[arg(long)]
+    retitle_cover: bool,
+
+    /// Transcribe the text of the images with Gemini vision and add it,
+    /// translated, as a caption after each image
+    #[cfg(feature = "ocr")]
+    #[arg(long)]
+    ocr: bool,
+
+    /// Translate a paragraph split across two content documents as one (heuristic)
+    #[arg(long)]
+    stitch_paragraphs: bool,
+
+    /// Keep the all-caps emphasis of shouting and system messages
+    #[arg(long)]
+    preserve_emphasis: bool,
+
+    /// Keep the inline markup of paragraphs (emphasis, links…) by
+    /// sending it as numbered tags
+    #[arg(long)]
+    preserve_markup: bool,
+
+    /// Whether to request the JSON response mode of the provider
+    #[arg(long, value_enum, default_value_t = JsonMode::Auto)]
+    json_mode: JsonMode,
+
+    /// Model name prefix without JSON mode support, in addition to the built-in ones
+    #[arg(long)]
+    no_json_mode_model: Vec<String>,
+
+    /// Record translated segment pairs to this translation memory file
+    #[arg(long)]
+    memory: Option<PathBuf>,
+
+    /// Take the translations already recorded in the --memory file and translate only the rest;
+    /// without --memory, chunks are checkpointed to .trans-epub/<OUTPUT FILE NAME>.jsonl
+    #[arg(long)]
+    resume: bool,
+
+    /// Translate into these languages, separated by commas, with DeepL instead of the model of
+    /// the subcommand, e.g. the European languages of a run into several
+    #[arg(long, value_delimiter = ',', requires = "deepl_api_key")]
+    deepl: Vec<String>,
+
+    /// DeepL API key of --deepl
+    #[arg(long, env = "DEEPL_API_KEY", hide_env_values = true)]
+    deepl_api_key: Option<String>,
+
+    /// Have a cheaper engine, such as deepl or ollama, translate the drafts, which the model of
+    /// the subcommand only post-edits with the original as a reference
+    #[arg(long, conflicts_with = "refine")]
+    draft_with: Option<Provider>,
+
+    /// Model of --draft-with, its default model when not given
+    #[arg(long, requires = "draft_with")]
+    draft_model: Option<String>,
+
+    /// API key of --draft-with, --deepl-api-key for deepl when not given
+    #[arg(long, requires = "draft_with")]
+    draft_api_key: Option<String>,
+
+    /// API base URL of --draft-with
+    #[arg(long, requires = "draft_with")]
+    draft_base_url: Option<String>,
+
+    /// Instruction template file of the post-edit of --draft-with, with {{language}},
+    /// {{paragraph_count}} and {{glossary}} filled in
+    #[arg(long, requires = "draft_with")]
+    post_edit_template: Option<PathBuf>,
+
+    /// Translation memory in TMX, as exported by CAT tools such as OmegaT: paragraphs it holds with
+    /// a translation into the target language are not requested (repeatable)
+    #[arg(long)]
+    tmx: Vec<PathBuf>,
+
+    /// Write the source and translated paragraphs of the run to this TMX file at its end
+    #[arg(long)]
+    tmx_out: Option<PathBuf>,
+
+    /// Do not take translations from the cache of earlier runs nor add to it
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Directory of the translation cache [default: $XDG_CACHE_HOME/trans-epub or ~/.cache/trans-epub]
+    #[arg(long, conflicts_with = "no_cache")]
+    cache_dir: Option<PathBuf>,
+
+    /// What to do when requests keep failing with 429 for exhausted quota
+    #[arg(long, value_enum, default_value_t = OnQuota::Wait)]
+    on_quota: OnQuota,
+
+    /// Longest 429 wait in seconds treated as a brief rate limit rather than exhausted quota
+    #[arg(long, default_value_t = 300)]
+    max_quota_wait: u64,
+
+    /// Cancel a request not answered in full within this time, like `120s` or `2m`, and send it
+    /// again; streamed responses must complete within it too
+    #[arg(long, value_parser = parse_timeout)]
+    request_timeout: Option<Duration>,
+
+    /// Attempts of a request in all when the connection fails or the server answers with a 5xx
+    /// status, the first one included
+    #[arg(long, default_value_t = 4)]
+    max_attempts: u32,
+
+    /// Wait in milliseconds before the first new attempt of a failed request, doubled after each
+    #[arg(long, default_value_t = 1000)]
+    retry_delay: u64,
+
+    /// Fraction of the wait between attempts it is spread by either way, from 0 to 1
+    #[arg(long, default_value_t = 0.5)]
+    retry_jitter: f64,
+
+    /// Failures of a request that are sent again
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "connect,timeout,transport,5xx"
+    )]
+    retry_on: Vec<RetryOn>,
+
+    /// Translate again, one by one, paragraphs whose translation is identical to the source, keeps
+    /// runs of its script or `<paragraph>` tags, or is far shorter or longer than the source
+    #[arg(long)]
+    quality_check: bool,
+
+    /// Check the translations for letters of scripts the target language is
+    /// not written in, like Han left in Vietnamese, and report or requeue them
+    #[arg(long, value_enum)]
+    script_check: Option<ScriptCheck>,
+
+    /// After the run, report the names rendered in more than one way across
+    /// the chapters, in the log and as <output>.names.json
+    #[arg(long)]
+    names_report: bool,
+
+    /// With --names-report, ask which rendering to keep for each name and
+    /// harmonize the output to it
+    #[arg(long, requires = "names_report")]
+    harmonize_names: bool,
+
+    /// Record where each translated paragraph comes from, a hash of its source, the model and the
+    /// run, to retranslate only those of an old model or a buggy run later
+    #[arg(long, value_enum)]
+    provenance: Option<ProvenanceMode>,
+
+    /// Name of the run in the --provenance records and those rewritten by retranslate; by
+    /// default the time it started and its process id
+    #[arg(long)]
+    run_id: Option<String>,
+
+    /// Scripts the translations may be written in, instead of those of the
+    /// target language (Latin, plus its own script)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    allowed_scripts: Vec<Script>,
+
+    /// Review retried, failed and partly untranslated paragraphs in the terminal before they are
+    /// written: accept, edit or translate again
+    #[arg(long)]
+    review: bool,
+
+    /// What to do with a paragraph that still fails after its retries
+    #[arg(long, value_enum, default_value_t = OnFailure::Accept)]
+    on_failure: OnFailure,
+
+    /// How a chunk that failed or came back with another number of paragraphs is sent again
+    #[arg(long, value_enum, default_value_t = RetryStrategy::Split)]
+    retry_strategy: RetryStrategy,
+
+    /// Paragraphs per chunk of a retry with the split and model strategies
+    #[arg(long, default_value_t = 1)]
+    retry_chunk_size: usize,
+
+    /// Rounds of retries a paragraph gets before it is given up; the last round is one paragraph
+    /// at a time
+    #[arg(long, default_value_t = MAX_RETRIES, value_parser = clap::value_parser!(u32).range(1..))]
+    max_retries: u32,
+
+    /// Model of the retries with --retry-strategy model
+    #[arg(long, required_if_eq("retry_strategy", "model"))]
+    retry_model: Option<String>,
+
+    /// Models to send a paragraph to in turn when it is given up on, e.g. a stronger model
+    #[arg(long, value_delimiter = ',')]
+    fallback: Vec<String>,
+
+    /// How the translation is laid out next to the original
+    #[arg(long, value_enum, default_value_t = Layout::Inline)]
+    layout: Layout,
+
+    /// Whitespace around each translated paragraph
+    #[arg(long, value_enum, default_value_t = Whitespace::None)]
+    whitespace: Whitespace,
+
+    /// Fix the typography of the translations for the target language:
+    /// quotation marks, ellipses, spacing and CJK full-width punctuation
+    #[arg(long)]
+    typography: bool,
+
+    /// Log the token usage of every chunk instead of only the total at the end
+    #[arg(long)]
+    stats_per_chunk: bool,
 }
 
 #[tokio::main]
-async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+async fn main() -> ExitCode {
+    let args = match with_config(std::env::args_os().collect()) {
+        Ok(args) => Args::parse_from(args),
+        Err(e) => {
+            logging::init(LogFormat::Text);
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    logging::init(args.log_format);
     debug!("start");
-    let args = Args::parse();
-    match args.subcommand {
+    let result = match args.subcommand {
         SubCommands::OpenAi {
             api_key,
             model,
@@ -94,16 +1031,31 @@ async fn main() {
             requests,
             input,
             output,
+            options,
         } => {
-            let translator = Translator::OpenAi(Context {
-                model,
-                api_key,
-                language,
-                lines,
-                requests,
-            });
-            let epub = Epub::new(input, output);
-            epub.translate(translator).await;
+            let api_key = api_key.unwrap_or_default();
+            translate_books(
+                input,
+                output,
+                &language,
+                options,
+                |output, language, options| {
+                    let deepl = deepl_key(&options, language);
+                    let context = context(
+                        model.clone(),
+                        api_key.clone(),
+                        language.to_string(),
+                        lines,
+                        requests,
+                        output,
+                        options,
+                    )?;
+                    Ok(with_deepl(context, deepl, |context| {
+                        Translator::new(context, OpenAi)
+                    }))
+                },
+            )
+            .await
         }
         SubCommands::Gemini {
             api_key,
@@ -113,17 +1065,1689 @@ async fn main() {
             requests,
             input,
             output,
+            options,
+        } => {
+            let api_key = api_key.unwrap_or_default();
+            translate_books(
+                input,
+                output,
+                &language,
+                options,
+                |output, language, options| {
+                    let deepl = deepl_key(&options, language);
+                    let context = context(
+                        model.clone(),
+                        api_key.clone(),
+                        language.to_string(),
+                        lines,
+                        requests,
+                        output,
+                        options,
+                    )?;
+                    Ok(with_deepl(context, deepl, |context| {
+                        Translator::new(context, Gemini)
+                    }))
+                },
+            )
+            .await
+        }
+        SubCommands::Anthropic {
+            api_key,
+            model,
+            language,
+            lines,
+            requests,
+            input,
+            output,
+            options,
+        } => {
+            let api_key = api_key.unwrap_or_default();
+            translate_books(
+                input,
+                output,
+                &language,
+                options,
+                |output, language, options| {
+                    let deepl = deepl_key(&options, language);
+                    let context = context(
+                        model.clone(),
+                        api_key.clone(),
+                        language.to_string(),
+                        lines,
+                        requests,
+                        output,
+                        options,
+                    )?;
+                    Ok(with_deepl(context, deepl, |context| {
+                        Translator::new(context, Anthropic)
+                    }))
+                },
+            )
+            .await
+        }
+        SubCommands::DeepL {
+            api_key,
+            language,
+            lines,
+            requests,
+            input,
+            output,
+            options,
+        } => {
+            let api_key = api_key.unwrap_or_default();
+            translate_books(
+                input,
+                output,
+                &language,
+                options,
+                |output, language, options| {
+                    let context = context(
+                        Provider::DeepL.default_model().to_string(),
+                        api_key.clone(),
+                        language.to_string(),
+                        lines,
+                        requests,
+                        output,
+                        options,
+                    )?;
+                    Ok(Translator::new(context, DeepL))
+                },
+            )
+            .await
+        }
+        SubCommands::Ollama {
+            model,
+            language,
+            lines,
+            requests,
+            num_ctx,
+            input,
+            output,
+            options,
+        } => {
+            translate_books(
+                input,
+                output,
+                &language,
+                options,
+                |output, language, options| {
+                    let deepl = deepl_key(&options, language);
+                    let context = context(
+                        model.clone(),
+                        String::new(),
+                        language.to_string(),
+                        lines,
+                        requests,
+                        output,
+                        options,
+                    )?;
+                    let context = Context {
+                        num_ctx: Some(num_ctx),
+                        ..context
+                    };
+                    Ok(with_deepl(context, deepl, |context| {
+                        Translator::new(context, Ollama)
+                    }))
+                },
+            )
+            .await
+        }
+        SubCommands::Mock {
+            input,
+            output,
+            language,
+            transform,
+            lines,
+            requests,
+            options,
+        } => {
+            translate_books(
+                input,
+                output,
+                &language,
+                options,
+                |output, language, mut options| {
+                    // the transforms differ, and a benchmark would only read
+                    // the cache
+                    options.no_cache = true;
+                    let context = context(
+                        Provider::Mock.default_model().to_string(),
+                        String::new(),
+                        language.to_string(),
+                        lines,
+                        requests,
+                        output,
+                        options,
+                    )?;
+                    Ok(Translator::new(context, Mock { transform }))
+                },
+            )
+            .await
+        }
+        SubCommands::Serve {
+            bind,
+            port,
+            token,
+            keep,
+            max_upload,
+            max_jobs,
+            read_timeout,
+            language,
+            engine,
+        } => {
+            let preflight = engine.options.preflight;
+            let factory = factory(engine);
+            let language = language.unwrap_or_default();
+            let server = serve::Server {
+                bind,
+                port,
+                token,
+                keep: Duration::from_secs(keep),
+                max_upload: max_upload.saturating_mul(1024 * 1024),
+                read_timeout: Duration::from_secs(read_timeout),
+            };
+            match check(&factory, &language, preflight).await {
+                Ok(()) => {
+                    serve::serve(
+                        server,
+                        serve::Jobs::new(factory, language, max_jobs as usize),
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            }
+        }
+        SubCommands::Watch {
+            inbox,
+            outbox,
+            language,
+            interval,
+            engine,
+        } => {
+            let preflight = engine.options.preflight;
+            let factory = factory(engine);
+            match check(&factory, &language, preflight).await {
+                Ok(()) => {
+                    let interval = Duration::from_secs(interval.max(1));
+                    watch::watch(&inbox, &outbox, &language, interval, &factory).await
+                }
+                Err(e) => Err(e),
+            }
+        }
+        SubCommands::ExportXliff {
+            input,
+            output,
+            language,
+            engine,
+        } => {
+            let output = output.unwrap_or_else(|| input.with_extension("xlf"));
+            export_xliff(input, output, language, engine).await
+        }
+        SubCommands::ImportXliff {
+            input,
+            xliff,
+            output,
+            language,
+            options,
+        } => import_xliff(input, xliff, output, language, options).await,
+        SubCommands::TmxExport {
+            memory,
+            output,
+            source_language,
+        } => tmx_export(memory, output, &source_language),
+        SubCommands::SelfTest => {
+            if !self_test() {
+                return ExitCode::FAILURE;
+            }
+            Ok(())
+        }
+        SubCommands::Replay {
+            file,
+            model,
+            api_key,
+            base_url,
+        } => replay(file, model, api_key, base_url).await,
+        SubCommands::Inspect { input } => inspect(input).await,
+        SubCommands::Validate { input, source } => validate(input, source),
+        SubCommands::Retranslate {
+            input,
+            paragraphs,
+            filters,
+            output,
+            engine,
+        } => retranslate(input, paragraphs, filters, output, engine).await,
+        SubCommands::Diff {
+            original,
+            translated,
+            output,
+        } => diff(original, translated, output),
+        SubCommands::Control { socket, command } => send_control(socket, command).await,
+        SubCommands::Estimate {
+            input,
+            model,
+            lines,
+            max_chunk_tokens,
+            input_price,
+            output_price,
         } => {
-            let translator = Translator::Gemini(Context {
-                model,
-                api_key,
-                language,
-                lines,
-                requests,
+            let price = match (input_price, output_price) {
+                (Some(input), Some(output)) => Some(Price { input, output }),
+                _ => pricing::price(&model),
+            };
+            estimate(input, &model, lines, max_chunk_tokens, price).await
+        }
+    };
+    debug!("end");
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// What the books of a batch share, taken from the translator of the first.
+struct Shared {
+    limiter: Arc<Limiter>,
+    cache: Option<Arc<Cache>>,
+    budget: Option<Arc<Budget>>,
+}
+
+/// Translate the book `inputs` into `output`, or each of several books into
+/// the directory `output`, going on with the next book when one fails. The
+/// books of a batch share the limiter and the cache, and the glossary drafted
+/// from the first book with `--extract-glossary` is used for all of them.
+///
+/// With several `languages` each book is translated into each of them in
+/// turn, to outputs named like `book.vi.epub`, with a glossary drafted per
+/// language.
+async fn translate_books(
+    inputs: Vec<PathBuf>,
+    output: PathBuf,
+    languages: &[String],
+    options: Options,
+    translator: impl Fn(&Path, &str, Options) -> Result<Translator, trans_epub::Error>,
+) -> Result<(), trans_epub::Error> {
+    let draft = options.extract_glossary.clone();
+    let select = options.select_skipped;
+    let shutdown = Arc::new(Shutdown::default());
+    tokio::spawn(on_interrupt(shutdown.clone()));
+    let control = Arc::new(Control::default());
+    let control_path = options.control.clone();
+    let mut socket = None;
+    let single = matches!(inputs.as_slice(), [input] if !batch::is_pattern(input));
+    let tmx_out = options.tmx_out.clone();
+    if let ([input], [language]) = (inputs.as_slice(), languages) {
+        if single {
+            let mut translator = translator(&output, language, options)?;
+            translator.set_shutdown(shutdown);
+            let _socket = listen(control_path.as_deref(), &control, &mut translator)?;
+            let result = translate(&mut translator, input, &output, draft.as_deref(), select).await;
+            let context = translator.context();
+            if let (Some(path), Some(collected)) = (&tmx_out, &context.collected) {
+                write_tmx(path, collected, context.source_language.as_deref())?;
+            }
+            return result;
+        }
+    }
+    let books = if single {
+        vec![batch::Book {
+            input: inputs[0].clone(),
+            output: output.clone(),
+        }]
+    } else {
+        let books = batch::books(&inputs, &output)?;
+        std::fs::create_dir_all(&output)?;
+        books
+    };
+    let books = batch::fan_out(books, languages);
+    let stats_out = options.stats_out.clone();
+    let mut collected: Option<Arc<tmx::Collected>> = None;
+    let mut source_language = None;
+    let mut shared: Option<Shared> = None;
+    let mut outcomes = Vec::new();
+    for (i, (book, language)) in books.iter().enumerate() {
+        if shutdown.is_requested() {
+            let not_started = match shared.as_ref().and_then(|shared| shared.budget.as_ref()) {
+                Some(budget) if budget.is_exceeded() => {
+                    "spending cap reached before it was started"
+                }
+                _ => "interrupted before it was started",
+            };
+            outcomes.push(batch::Outcome {
+                input: book.input.clone(),
+                output: book.output.clone(),
+                language: language.clone(),
+                error: Some(not_started.to_string()),
+                stats: None,
             });
-            let epub = Epub::new(input, output);
-            epub.translate(translator).await;
+            continue;
         }
+        info!(
+            "batch: {}/{} {} into {}",
+            i + 1,
+            books.len(),
+            book.input.display(),
+            language
+        );
+        let mut options = options.clone();
+        options.stats_out = None;
+        // the cache of the first book is kept for the others
+        options.no_cache |= shared.is_some();
+        let draft = match &draft {
+            Some(path) if languages.len() > 1 => Some(batch::language_path(path, language)),
+            draft => draft.clone(),
+        };
+        let (result, stats) = match translator(&book.output, language, options) {
+            Ok(mut translator) => {
+                translator.set_shutdown(shutdown.clone());
+                // the books share the limiter of the first, which the
+                // socket changes
+                match socket {
+                    Some(_) => translator.set_control(control.clone()),
+                    None => socket = listen(control_path.as_deref(), &control, &mut translator)?,
+                }
+                let context = translator.context_mut();
+                match &shared {
+                    Some(shared) => {
+                        context.limiter = shared.limiter.clone();
+                        context.cache = shared.cache.clone();
+                        context.budget = shared.budget.clone();
+                    }
+                    None => {
+                        shared = Some(Shared {
+                            limiter: context.limiter.clone(),
+                            cache: context.cache.clone(),
+                            budget: context.budget.clone(),
+                        })
+                    }
+                }
+                // the pairs of all the books go to one --tmx-out file
+                match &collected {
+                    Some(collected) => context.collected = Some(collected.clone()),
+                    None => collected = context.collected.clone(),
+                }
+                let result = translate(
+                    &mut translator,
+                    &book.input,
+                    &book.output,
+                    draft.as_deref(),
+                    select,
+                )
+                .await;
+                let context = translator.context();
+                if source_language.is_none() {
+                    source_language = context.source_language.clone();
+                }
+                (result, Some(context.totals.summary(&context.model)))
+            }
+            Err(e) => (Err(e), None),
+        };
+        if let Err(e) = &result {
+            error!("{}: {}", book.input.display(), e);
+        }
+        outcomes.push(batch::Outcome {
+            input: book.input.clone(),
+            output: book.output.clone(),
+            language: language.clone(),
+            error: result.err().map(|e| e.to_string()),
+            stats,
+        });
+    }
+    if let (Some(path), Some(collected)) = (&tmx_out, &collected) {
+        write_tmx(path, collected, source_language.as_deref())?;
+    }
+    batch::conclude(&outcomes, stats_out.as_deref())
+}
+
+/// Take the commands of the `--control` socket at `path`, if any, for the
+/// run of `translator`.
+fn listen(
+    path: Option<&Path>,
+    control: &Arc<Control>,
+    translator: &mut Translator,
+) -> Result<Option<control::Socket>, trans_epub::Error> {
+    translator.set_control(control.clone());
+    let context = translator.context();
+    path.map(|path| {
+        control::Socket::open(
+            path,
+            control.clone(),
+            context.limiter.clone(),
+            context.model.clone(),
+        )
+    })
+    .transpose()
+}
+
+/// Write the segment pairs collected with `--tmx-out`.
+fn write_tmx(
+    path: &Path,
+    collected: &tmx::Collected,
+    source_language: Option<&str>,
+) -> Result<(), trans_epub::Error> {
+    let segments = collected.take();
+    info!(
+        "tmx: {} segments written to {}",
+        segments.len(),
+        path.display()
+    );
+    let file = std::fs::File::create(path)?;
+    // "und" for a source language that was neither given nor detected
+    let source_language = source_language.unwrap_or("und");
+    tmx::write(std::io::BufWriter::new(file), source_language, &segments)?;
+    Ok(())
+}
+
+/// On Ctrl-C or SIGTERM, stop requesting so the book is written with what is
+/// translated; on a second one, quit at once.
+async fn on_interrupt(shutdown: Arc<Shutdown>) {
+    interrupted().await;
+    warn!(
+        "interrupted, writing the output with what is translated; interrupt again to quit at once"
+    );
+    shutdown.request();
+    interrupted().await;
+    error!("interrupted again, the output is left unfinished");
+    std::process::exit(130);
+}
+
+async fn interrupted() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            return std::future::pending().await;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn translate(
+    translator: &mut Translator,
+    input: &Path,
+    output: &Path,
+    glossary_draft: Option<&Path>,
+    select_skipped: bool,
+) -> Result<(), trans_epub::Error> {
+    if select_skipped {
+        let skipped = select_skipped_chapters(input).await?;
+        translator.context_mut().skip.extend(skipped);
+    }
+    let preflight = translator.context().preflight;
+    if preflight != Preflight::Off {
+        if let Err(message) = translator.preflight().await {
+            if preflight == Preflight::Abort {
+                return Err(trans_epub::Error::Api(message));
+            }
+            warn!("preflight: {}", message);
+        }
+    }
+    if translator.context().source_language.is_none() {
+        let detected = detect_language(translator.context(), input).await?;
+        translator.context_mut().source_language = detected;
+    }
+    if let Some(path) = glossary_draft {
+        let extracted = extract_glossary(translator, input, path).await?;
+        translator.context_mut().glossary.merge(extracted);
+    }
+    let result = input::translate(input, output, translator).await;
+    let context = translator.context();
+    if let Some(names) = &context.names {
+        // an incomplete book is still written
+        if matches!(result, Ok(()) | Err(trans_epub::Error::Incomplete { .. })) {
+            names::conclude(names, output, context.harmonize_names).await?;
+        }
+    }
+    if let Some(provenance) = &context.provenance {
+        if matches!(result, Ok(()) | Err(trans_epub::Error::Incomplete { .. })) {
+            provenance.conclude(output)?;
+        }
+    }
+    result
+}
+
+/// Make the translators of `serve` and `watch`, one per book, from the
+/// options they were started with. The books share the limits of
+/// `--requests`, `--rpm` and `--tpm`; each has a checkpoint and
+/// `--stats-out` of its own, named after it.
+fn factory(engine: Engine) -> Factory {
+    let Engine {
+        provider,
+        model,
+        api_key,
+        lines,
+        requests,
+        num_ctx,
+        options,
+    } = engine;
+    let defaults = PipelineConfig::new(provider, "", "").context;
+    let model = model.unwrap_or_else(|| provider.default_model().to_string());
+    let (lines, requests) = (
+        lines.unwrap_or(defaults.lines),
+        requests.unwrap_or(defaults.requests),
+    );
+    let limiter = Arc::new(
+        Limiter::new(requests)
+            .with_rates(options.rpm, options.tpm)
+            .adaptive(options.adaptive_requests),
+    );
+    Box::new(move |language, output| {
+        let deepl = deepl_key(&options, language);
+        let context = context(
+            model.clone(),
+            api_key.clone().unwrap_or_default(),
+            language.to_string(),
+            lines,
+            requests,
+            output,
+            options.clone(),
+        )?;
+        // books may be translated side by side, so none draws a progress
+        // line, and nobody is at the terminal to review them
+        let context = Context {
+            num_ctx: (provider == Provider::Ollama).then_some(num_ctx),
+            progress: Progress::new(false),
+            review: None,
+            limiter: limiter.clone(),
+            stats_out: options
+                .stats_out
+                .as_deref()
+                .map(|path| batch::book_path(path, output)),
+            ..context
+        };
+        Ok(with_deepl(context, deepl, |context| {
+            provider.translator(context)
+        }))
+    })
+}
+
+/// The key of `--deepl-api-key` when `language` is one of `--deepl`.
+fn deepl_key(options: &Options, language: &str) -> Option<String> {
+    let code = language::code(language);
+    let listed = options
+        .deepl
+        .iter()
+        .any(|deepl| language::code(deepl) == code);
+    listed.then(|| options.deepl_api_key.clone().unwrap_or_default())
+}
+
+/// The translator `make` makes from `context`, or one translating with
+/// DeepL with its `deepl` key for a language of `--deepl`.
+fn with_deepl(
+    context: Context,
+    deepl: Option<String>,
+    make: impl FnOnce(Context) -> Translator,
+) -> Translator {
+    let Some(api_key) = deepl else {
+        return make(context);
+    };
+    info!("{}: translating with DeepL", context.language);
+    let context = Context {
+        model: Provider::DeepL.default_model().to_string(),
+        api_key,
+        keys: Keys::default(),
+        drafter: None,
+        ..context
+    };
+    Translator::new(context, DeepL)
+}
+
+/// The context of the drafter of `--draft-with` into `language`, with the
+/// chunk size and concurrency of its provider and no progress line of its
+/// own.
+fn draft_context(provider: Provider, language: &str, options: &Options) -> Context {
+    let model = options
+        .draft_model
+        .clone()
+        .unwrap_or_else(|| provider.default_model().to_string());
+    let api_key = match (&options.draft_api_key, provider) {
+        (Some(key), _) => key.clone(),
+        (None, Provider::DeepL) => options.deepl_api_key.clone().unwrap_or_default(),
+        (None, _) => String::new(),
+    };
+    info!("{}: drafting with {}", language, model);
+    let config = PipelineConfig::new(provider, &model, language);
+    Context {
+        api_key,
+        base_url: options.draft_base_url.clone(),
+        source_language: options.source_language.clone(),
+        preserve_emphasis: options.preserve_emphasis,
+        preserve_markup: options.preserve_markup,
+        json_mode: options.json_mode,
+        whitespace: options.whitespace,
+        request_timeout: options.request_timeout,
+        progress: Progress::new(false),
+        ..config.context
+    }
+}
+
+/// Make a translator of `serve` or `watch` to check the options, and
+/// preflight it.
+async fn check(
+    factory: &Factory,
+    language: &str,
+    preflight: Preflight,
+) -> Result<(), trans_epub::Error> {
+    let translator = factory(language, Path::new("preflight.epub"))?;
+    if preflight != Preflight::Off {
+        if let Err(message) = translator.preflight().await {
+            if preflight == Preflight::Abort {
+                return Err(trans_epub::Error::Api(message));
+            }
+            warn!("preflight: {}", message);
+        }
+    }
+    Ok(())
+}
+
+/// Paragraphs sampled across a book to detect its language.
+const LANGUAGE_SAMPLE: usize = 200;
+
+/// Detect the language of the book at `input` from a sample of its
+/// paragraphs; fails when the book clearly appears to be in the target
+/// language already, unless `--allow-same-language`, and only warns when the
+/// guess is weak.
+async fn detect_language(
+    context: &Context,
+    input: &Path,
+) -> Result<Option<String>, trans_epub::Error> {
+    let options = input::Options::from(context);
+    let paragraphs = input::paragraphs(input, &options).await?;
+    let step = (paragraphs.len() / LANGUAGE_SAMPLE).max(1);
+    let sample: Vec<&str> = paragraphs
+        .iter()
+        .step_by(step)
+        .map(String::as_str)
+        .collect();
+    let Some(detected) = language::detect(&sample.join("\n")) else {
+        info!("source language: not detected");
+        return Ok(None);
+    };
+    let language = detected.language;
+    match detected.confident {
+        true => info!("source language: {} (detected)", language.name),
+        false => info!("source language: {} (guessed)", language.name),
+    }
+    if language::find(&context.language).is_some_and(|target| target.code == language.code) {
+        let message = format!(
+            "{} appears to be in {} already",
+            input.display(),
+            language.name
+        );
+        if detected.confident && !context.allow_same_language {
+            return Err(trans_epub::Error::Input(format!(
+                "{}; pass --allow-same-language to translate it anyway",
+                message
+            )));
+        }
+        warn!("{}", message);
+    }
+    Ok(Some(language.name.to_string()))
+}
+
+/// Read the glossary drafted by an earlier run from `path`, or draft one from
+/// the book and let the user review it before the translation starts.
+async fn extract_glossary(
+    translator: &Translator,
+    input: &Path,
+    path: &Path,
+) -> Result<Glossary, trans_epub::Error> {
+    if path.exists() {
+        info!("glossary: using the reviewed draft {}", path.display());
+        return Ok(Glossary::load(path)?);
+    }
+    let options = input::Options::from(translator.context());
+    let paragraphs = input::paragraphs(input, &options).await?;
+    let draft = extract::extract(translator, &paragraphs).await;
+    draft.save(path)?;
+    info!(
+        "glossary: {} terms drafted to {}",
+        draft.len(),
+        path.display()
+    );
+    if !io::stdin().is_terminal() {
+        return Ok(draft);
+    }
+    eprint!("Review {} and press Enter to translate: ", path.display());
+    io::stdin().read_line(&mut String::new())?;
+    Ok(Glossary::load(path)?)
+}
+
+fn context(
+    model: String,
+    api_key: String,
+    language: String,
+    lines: usize,
+    requests: usize,
+    output: &Path,
+    options: Options,
+) -> Result<Context, trans_epub::Error> {
+    if options.names_report && !language::find(&language).is_some_and(|l| l.cased) {
+        warn!(
+            "names: {} is not written with letter case, its names are not recognized",
+            language
+        );
+    }
+    let instructions = match (&options.prompt_template, &options.prompt_lang) {
+        (Some(path), _) => Some(prompt::load_file(path)?),
+        (None, Some(lang)) => Some(prompt::load(&options.prompt_dir, lang)?),
+        (None, None) => match &options.style {
+            Some(style) => Some(prompt::style(style, &options.style_presets)?),
+            None => None,
+        },
+    };
+    let refine_instructions = match options
+        .refine_template
+        .as_ref()
+        .or(options.post_edit_template.as_ref())
+    {
+        Some(path) => Some(prompt::load_file(path)?),
+        None => None,
+    };
+    let glossary = match &options.glossary {
+        Some(path) => Glossary::load(path)?,
+        None => Glossary::default(),
+    };
+    let cache = match options.cache_dir.clone().or_else(cache::default_dir) {
+        Some(dir) if !options.no_cache => Some(Cache::shared(&dir)?),
+        _ => None,
+    };
+    let drafter = options.draft_with.map(|provider| {
+        let context = Context {
+            instructions: instructions.clone(),
+            glossary: glossary.clone(),
+            cache: cache.clone(),
+            ..draft_context(provider, &language, &options)
+        };
+        Box::new(provider.translator(context))
+    });
+    let memory_path = match options.memory {
+        Some(path) => Some(path),
+        None if options.resume => Some(checkpoint_path(output)),
+        None => None,
+    };
+    let mut recalled = HashMap::new();
+    if let Some(path) = memory_path
+        .as_ref()
+        .filter(|path| options.resume && path.exists())
+    {
+        for segment in Memory::new(path.clone()).load()? {
+            recalled.insert((segment.language, segment.source), segment.target);
+        }
+        info!(
+            "resume: {} translations recalled from {}",
+            recalled.len(),
+            path.display()
+        );
+    }
+    for path in &options.tmx {
+        let pairs = tmx::read(&std::fs::read(path)?, &language)?;
+        let before = recalled.len();
+        for (source, target) in pairs {
+            recalled.entry((language.clone(), source)).or_insert(target);
+        }
+        info!(
+            "tmx: {} translations into {} from {}",
+            recalled.len() - before,
+            language,
+            path.display()
+        );
+    }
+    let memory = memory_path.map(Memory::new);
+    let mut keys: Vec<String> = Some(api_key)
+        .filter(|key| !key.is_empty())
+        .into_iter()
+        .chain(options.api_keys)
+        .collect();
+    if let Some(path) = &options.api_keys_file {
+        let file = std::fs::read_to_string(path)
+            .map_err(|e| trans_epub::Error::Input(format!("{}: {}", path.display(), e)))?;
+        keys.extend(
+            file.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+    let mut seen = HashSet::new();
+    keys.retain(|key| seen.insert(key.clone()));
+    if keys.len() > 1 {
+        info!("api keys: {}", keys.len());
+    }
+    let api_key = keys.first().cloned().unwrap_or_default();
+    let translate_attributes = if options.translate_attributes {
+        attributes::SAFE
+            .iter()
+            .map(|name| name.to_string())
+            .chain(options.translate_attribute)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let provenance = options
+        .provenance
+        .map(|mode| Provenance::new(mode, options.run_id));
+    if options.max_cost.is_some_and(|max_cost| max_cost <= 0.0) {
+        return Err(trans_epub::Error::Input(
+            "--max-cost must be more than 0".to_string(),
+        ));
+    }
+    // a model given through --control later is checked before its requests
+    let priced = std::iter::once(&model)
+        .chain(&options.fallback)
+        .chain(&options.retry_model);
+    if let Some(unpriced) = priced
+        .filter(|_| options.max_cost.is_some())
+        .find(|model| pricing::price(model).is_none())
+    {
+        return Err(trans_epub::Error::Input(format!(
+            "--max-cost needs the price of {}, which is not known; cap the tokens with --max-tokens",
+            unpriced
+        )));
+    }
+    let budget = (options.max_cost.is_some() || options.max_tokens.is_some()).then(|| {
+        Arc::new(Budget::new(
+            options.max_cost,
+            options.max_tokens,
+            options.yes_continue,
+        ))
+    });
+    Ok(Context {
+        model,
+        api_key,
+        keys: Keys::new(keys, Duration::from_secs(options.key_cooldown)),
+        base_url: options.base_url,
+        language,
+        chapters: options
+            .chapters
+            .into_iter()
+            .chain(options.chapter_ids.into_iter().map(Chapters::Path))
+            .collect(),
+        skip: options.skip,
+        chapter_languages: options.chapter_language,
+        lines,
+        max_chunk_tokens: options.max_chunk_tokens,
+        max_paragraphs_per_chunk: options.max_paragraphs_per_chunk,
+        scene_breaks: options.scene_breaks,
+        context_lines: options.context_lines,
+        requests,
+        num_ctx: None,
+        line_numbering: options.line_numbering,
+        learned_base: Mutex::default(),
+        throttle: options.throttle,
+        preflight: options.preflight,
+        headers: options.headers,
+        system_instruction: options.system_instruction,
+        instructions,
+        prompt_cache: options.cache_prompt.then(PromptCache::default),
+        temperature: options.temperature,
+        top_p: options.top_p,
+        max_output_tokens: options.max_output_tokens,
+        safety_threshold: options.safety_threshold,
+        on_blocked: options.on_blocked,
+        glossary,
+        enforce_glossary: options.enforce_glossary,
+        stream: options.stream,
+        max_chapters_in_flight: options.max_chapters_in_flight,
+        stitch_paragraphs: options.stitch_paragraphs,
+        quality_check: options.quality_check,
+        script_check: options.script_check,
+        allowed_scripts: options.allowed_scripts,
+        translate_attributes,
+        translate_metadata: options.translate_metadata,
+        epub3: options.epub3,
+        headings: Headings::default(),
+        notes: Notes::default(),
+        names: options.names_report.then(Names::default),
+        harmonize_names: options.harmonize_names,
+        provenance,
+        retitle_cover: options.retitle_cover,
+        #[cfg(feature = "ocr")]
+        ocr: options.ocr,
+        preserve_emphasis: options.preserve_emphasis,
+        preserve_markup: options.preserve_markup,
+        json_mode: options.json_mode,
+        models_without_json_mode: options.no_json_mode_model,
+        memory,
+        cache,
+        recalled,
+        collected: options.tmx_out.as_ref().map(|_| Arc::default()),
+        on_failure: options.on_failure,
+        retry_strategy: options.retry_strategy,
+        retry_chunk_size: options.retry_chunk_size,
+        max_retries: options.max_retries,
+        retry_model: options.retry_model,
+        fallback: options.fallback,
+        source_language: options.source_language,
+        register: options.register,
+        honorifics: options.honorifics,
+        allow_same_language: options.allow_same_language,
+        whitespace: options.whitespace,
+        typography: options.typography,
+        layout: options.layout,
+        limiter: Arc::new(
+            Limiter::new(requests)
+                .with_rates(options.rpm, options.tpm)
+                .adaptive(options.adaptive_requests),
+        ),
+        request_timeout: options.request_timeout,
+        retry: RetryPolicy {
+            attempts: options.max_attempts,
+            delay: Duration::from_millis(options.retry_delay),
+            jitter: options.retry_jitter,
+            on: options.retry_on.clone(),
+        },
+        quota: Quota::new(
+            options.on_quota,
+            Duration::from_secs(options.max_quota_wait),
+        ),
+        shutdown: Arc::default(),
+        control: Arc::default(),
+        budget,
+        stats_per_chunk: options.stats_per_chunk,
+        totals: Totals::default(),
+        refine: options.refine,
+        refine_instructions,
+        refine_totals: Totals::default(),
+        drafter,
+        stats_out: options.stats_out,
+        report: Report::default(),
+        dumps: options
+            .dump_failures
+            .then(|| Dumps::new(PathBuf::from(dump::DIR))),
+        review: options.review.then(Review::default),
+        progress: Progress::new(
+            !options.no_progress && !logging::is_json() && io::stderr().is_terminal(),
+        ),
+    })
+}
+
+/// List the spine of the EPUB `input` with the opening of each chapter and
+/// read the chapters to skip from stdin.
+async fn select_skipped_chapters(input: &Path) -> Result<Vec<Chapters>, trans_epub::Error> {
+    if input.is_dir()
+        || !matches!(
+            input::Format::from_path(input),
+            None | Some(input::Format::Epub)
+        )
+    {
+        warn!(
+            "--select-skipped: {} is not an EPUB, nothing to select",
+            input.display()
+        );
+        return Ok(Vec::new());
+    }
+    let inspection = inspect_epub_bytes(&std::fs::read(input)?).await?;
+    let mut stderr = io::stderr();
+    for (index, chapter) in inspection.chapters.iter().enumerate() {
+        let opening: String = chapter.opening.chars().take(60).collect();
+        writeln!(
+            stderr,
+            "{:>4} {} ({} paragraphs) {}",
+            index + 1,
+            chapter.name,
+            chapter.paragraphs,
+            opening
+        )?;
+    }
+    write!(
+        stderr,
+        "chapters to skip (e.g. 1-3,copyright.xhtml; empty for none): "
+    )?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let skipped = answer
+        .split(',')
+        .filter(|chapters| !chapters.trim().is_empty())
+        .map(|chapters| chapters.parse())
+        .collect::<Result<Vec<Chapters>, _>>()
+        .map_err(trans_epub::Error::Input)?;
+    Ok(skipped)
+}
+
+/// Checkpoint of a `--resume` run without `--memory`, kept per output file.
+fn checkpoint_path(output: &Path) -> PathBuf {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    PathBuf::from(".trans-epub").join(format!("{}.jsonl", name))
+}
+
+/// Whether a subcommand takes its options from the configuration file: it
+/// does if it has every option of [`Options`], which it flattens.
+fn is_configured(command: &clap::Command) -> bool {
+    let options = Options::augment_args(clap::Command::new("options"));
+    let configured = options.get_arguments().all(|option| {
+        command
+            .get_arguments()
+            .any(|arg| arg.get_id() == option.get_id())
+    });
+    configured
+}
+
+/// Whether `arg` of the command line is the short option `short`: alone,
+/// or with its value attached, as `-xVALUE` or `-x=VALUE`, if it takes one.
+fn is_short(arg: &str, short: char, takes_values: bool) -> bool {
+    match arg
+        .strip_prefix('-')
+        .and_then(|arg| arg.strip_prefix(short))
+    {
+        Some(value) => value.is_empty() || takes_values,
+        None => false,
+    }
+}
+
+/// Insert the options of the configuration file, `--config` or
+/// `trans-epub.toml` in the current directory, after the subcommand of the
+/// command line. Options on the command line are kept as they are and the
+/// file's value of them is left out.
+fn with_config(mut args: Vec<OsString>) -> Result<Vec<OsString>, trans_epub::Error> {
+    let all = Args::command();
+    let given: Vec<String> = args
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let Some(at) = subcommand_position(&all, &given) else {
+        return Ok(args);
+    };
+    let Some(command) = all
+        .find_subcommand(&given[at])
+        .filter(|command| is_configured(command))
+    else {
+        return Ok(args);
+    };
+    let subcommand = given[at].clone();
+    // the options given anywhere on the command line but the subcommand
+    let given: Vec<String> = given
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 0 && *i != at)
+        .map(|(_, arg)| arg)
+        .collect();
+    let path = match given.iter().position(|arg| arg == "--config") {
+        Some(i) => PathBuf::from(given.get(i + 1).ok_or_else(|| {
+            trans_epub::Error::Input(
+                "--config: expected the path of a configuration file".to_string(),
+            )
+        })?),
+        None => match given.iter().find_map(|arg| arg.strip_prefix("--config=")) {
+            Some(path) => PathBuf::from(path),
+            None if Path::new(config::FILE_NAME).exists() => PathBuf::from(config::FILE_NAME),
+            None => return Ok(args),
+        },
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| trans_epub::Error::Input(format!("{}: {}", path.display(), e)))?;
+    let config = Config::parse(&text)
+        .map_err(|e| trans_epub::Error::Input(format!("{}: {}", path.display(), e)))?;
+    debug!("config: {}", path.display());
+
+    let mut options = Vec::new();
+    for (key, value) in config.options(&subcommand) {
+        let error =
+            |message: String| trans_epub::Error::Input(format!("{}: {}", path.display(), message));
+        // the API key is given by the name of the variable holding it
+        let long = match key.as_str() {
+            "api-key-env" => "api-key",
+            key => key,
+        };
+        let takes = |command: &clap::Command| {
+            command
+                .get_arguments()
+                .any(|arg| arg.get_long() == Some(long))
+        };
+        // options of other subcommands, such as an API key for Ollama, are
+        // left out
+        if !takes(command) {
+            if !all
+                .get_subcommands()
+                .any(|command| is_configured(command) && takes(command))
+            {
+                return Err(error(format!("unknown option `{}`", key)));
+            }
+            continue;
+        }
+        let (key, value) = match key.as_str() {
+            "api-key-env" => {
+                let Value::String(name) = value else {
+                    return Err(error(
+                        "api-key-env: expected the name of a variable".to_string(),
+                    ));
+                };
+                let key = std::env::var(&name)
+                    .map_err(|_| error(format!("api-key-env: {} is not set", name)))?;
+                ("api-key".to_string(), Value::String(key))
+            }
+            _ => (key, value),
+        };
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .unwrap();
+        let takes_values = arg.get_action().takes_values();
+        let overridden = given.iter().any(|given| {
+            *given == format!("--{}", key)
+                || given.starts_with(&format!("--{}=", key))
+                || arg
+                    .get_short()
+                    .is_some_and(|short| is_short(given, short, takes_values))
+        });
+        if overridden {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(value) | Value::Number(value) => value,
+                Value::Bool(flag) if !arg.get_action().takes_values() => {
+                    if flag {
+                        options.push(OsString::from(format!("--{}", key)));
+                    }
+                    continue;
+                }
+                Value::Bool(flag) => flag.to_string(),
+                Value::Array(_) => return Err(error(format!("{}: nested array", key))),
+            };
+            options.push(OsString::from(format!("--{}={}", key, value)));
+        }
+    }
+    args.splice(at + 1..at + 1, options);
+    Ok(args)
+}
+
+/// The position of the subcommand in `args`, after the global options of
+/// `all` and their values, if there is one.
+fn subcommand_position(all: &clap::Command, args: &[String]) -> Option<usize> {
+    let mut i = 1;
+    while let Some(arg) = args.get(i) {
+        let Some(name) = arg.strip_prefix("--") else {
+            return (!arg.starts_with('-')).then_some(i);
+        };
+        let takes_value = all
+            .get_arguments()
+            .any(|option| option.get_long() == Some(name) && option.get_action().takes_values());
+        // the value of `--name VALUE`, not of `--name=VALUE`
+        i += 1 + takes_value as usize;
+    }
+    None
+}
+
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = header.split_once(':') else {
+        return Err(format!("expected `Name: value`, got `{}`", header));
+    };
+    let (name, value) = (name.trim(), value.trim());
+    HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("{}: {}", name, e))?;
+    HeaderValue::from_str(value).map_err(|e| format!("{}: {}", name, e))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn parse_preset(preset: &str) -> Result<(String, PathBuf), String> {
+    match preset.split_once('=') {
+        Some((name, path)) if !name.trim().is_empty() && !path.trim().is_empty() => {
+            Ok((name.trim().to_string(), PathBuf::from(path.trim())))
+        }
+        _ => Err(format!("expected `NAME=TEMPLATE_FILE`, got `{}`", preset)),
+    }
+}
+
+fn parse_timeout(timeout: &str) -> Result<Duration, String> {
+    parse_duration(timeout)
+        .filter(|timeout| !timeout.is_zero())
+        .ok_or_else(|| format!("expected a duration like `120s` or `2m`, got `{}`", timeout))
+}
+
+fn parse_attribute(name: &str) -> Result<String, String> {
+    if attributes::is_structural(name) {
+        return Err(format!(
+            "`{}` is a structural attribute and is never translated",
+            name
+        ));
+    }
+    Ok(name.to_string())
+}
+
+fn self_test() -> bool {
+    let reports = self_test::run();
+    for report in &reports {
+        println!("{}", report);
+    }
+    let failed = reports.iter().filter(|report| !report.passed()).count();
+    println!("{} passed, {} failed", reports.len() - failed, failed);
+    failed == 0
+}
+
+/// Send the request of the failure dumped to `file` again, to the model
+/// and base URL of the dump unless others are given, and print the raw text
+/// of the response.
+async fn replay(
+    file: PathBuf,
+    model: Option<String>,
+    api_key: Option<String>,
+    base_url: Option<String>,
+) -> Result<(), trans_epub::Error> {
+    let dump = Dump::read(&file)?;
+    let provider = dump.provider()?;
+    let model = model.unwrap_or(dump.model);
+    info!(
+        "replay: chunk {} to {}, dumped for: {}",
+        dump.chunk.as_deref().unwrap_or("?"),
+        model,
+        dump.reason
+    );
+    let mut config = PipelineConfig::new(provider, &model, &dump.language);
+    config.context.api_key = api_key.unwrap_or_default();
+    config.context.base_url = base_url.or(dump.base_url);
+    let translator = provider.translator(config.context);
+    let response = translator
+        .replay(&dump.exchange)
+        .await
+        .map_err(trans_epub::Error::Api)?;
+    println!("{}", response);
+    Ok(())
+}
+
+async fn inspect(input: PathBuf) -> Result<(), trans_epub::Error> {
+    let input = std::fs::read(input)?;
+    let inspection = inspect_epub_bytes(&input).await?;
+    print!("{}", inspection);
+    Ok(())
+}
+
+fn validate(input: PathBuf, source: Option<PathBuf>) -> Result<(), trans_epub::Error> {
+    let output = std::fs::read(input)?;
+    let source = source.map(std::fs::read).transpose()?;
+    let validation = validate_epub_bytes(&output, source.as_deref())?;
+    print!("{}", validation);
+    if !validation.is_valid() {
+        return Err(trans_epub::Error::Epub(format!(
+            "{} problems found",
+            validation.problems.len()
+        )));
+    }
+    Ok(())
+}
+
+fn diff(
+    original: PathBuf,
+    translated: PathBuf,
+    output: Option<PathBuf>,
+) -> Result<(), trans_epub::Error> {
+    let diff = diff_epub_bytes(&std::fs::read(&original)?, &std::fs::read(&translated)?)?;
+    let output = output.unwrap_or_else(|| {
+        let mut path = translated.as_os_str().to_owned();
+        path.push(".diff.html");
+        PathBuf::from(path)
+    });
+    let name = |path: &PathBuf| {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    std::fs::write(&output, diff.html(&name(&original), &name(&translated)))?;
+    print!("{}", diff);
+    println!("report written to {}", output.display());
+    Ok(())
+}
+
+/// Send a command to the `--control` socket of a run and print its answer.
+async fn send_control(socket: PathBuf, command: Vec<String>) -> Result<(), trans_epub::Error> {
+    let reply = control::send(&socket, &command.join(" ")).await?;
+    if let Some(error) = reply.strip_prefix("error: ") {
+        return Err(trans_epub::Error::Input(error.to_string()));
+    }
+    println!("{}", reply);
+    Ok(())
+}
+
+async fn estimate(
+    input: PathBuf,
+    model: &str,
+    lines: usize,
+    max_chunk_tokens: Option<usize>,
+    price: Option<Price>,
+) -> Result<(), trans_epub::Error> {
+    let paragraphs = input::paragraphs(&input, &input::Options::default()).await?;
+    let estimate = estimate::estimate(&paragraphs, model, lines, max_chunk_tokens, price);
+    print!("{}", estimate);
+    Ok(())
+}
+
+/// Translate the book at `input` into `language` and write its paragraphs
+/// with their translations to the XLIFF file `output`; the translated book
+/// itself is not kept. Paragraphs that failed are written without a target.
+async fn export_xliff(
+    input: PathBuf,
+    output: PathBuf,
+    language: String,
+    engine: Engine,
+) -> Result<(), trans_epub::Error> {
+    let Engine {
+        provider,
+        model,
+        api_key,
+        lines,
+        requests,
+        num_ctx,
+        options,
+    } = engine;
+    let defaults = PipelineConfig::new(provider, "", "").context;
+    let deepl = deepl_key(&options, &language);
+    let extension = input.extension().unwrap_or_default().to_string_lossy();
+    let book = std::env::temp_dir().join(format!(
+        "trans-epub-{}.{}",
+        std::process::id(),
+        if input.is_dir() { "d" } else { &extension }
+    ));
+    let context = context(
+        model.unwrap_or_else(|| provider.default_model().to_string()),
+        api_key.unwrap_or_default(),
+        language.clone(),
+        lines.unwrap_or(defaults.lines),
+        requests.unwrap_or(defaults.requests),
+        &book,
+        options,
+    )?;
+    let collected = Arc::new(tmx::Collected::default());
+    let context = Context {
+        num_ctx: (provider == Provider::Ollama).then_some(num_ctx),
+        collected: Some(collected.clone()),
+        ..context
+    };
+    let mut translator = with_deepl(context, deepl, |context| provider.translator(context));
+    let shutdown = Arc::new(Shutdown::default());
+    tokio::spawn(on_interrupt(shutdown.clone()));
+    translator.set_shutdown(shutdown);
+    let result = translate(&mut translator, &input, &book, None, false).await;
+    if book.is_dir() {
+        std::fs::remove_dir_all(&book)?;
+    } else if book.exists() {
+        std::fs::remove_file(&book)?;
+    }
+    // an incomplete translation is still exported
+    if !matches!(result, Ok(()) | Err(trans_epub::Error::Incomplete { .. })) {
+        return result;
+    }
+    let segments = collected.take();
+    info!(
+        "xliff: {} units written to {}",
+        segments.len(),
+        output.display()
+    );
+    let original = input.file_name().unwrap_or_default().to_string_lossy();
+    let source_language = translator.context().source_language.as_deref();
+    xliff::write(
+        std::io::BufWriter::new(std::fs::File::create(&output)?),
+        &original,
+        source_language.unwrap_or("und"),
+        &language,
+        &segments,
+    )?;
+    result
+}
+
+/// Write the book at `input` into `output` with the translations of the
+/// post-edited XLIFF file `xliff` and nothing requested, leaving the
+/// paragraphs it does not hold in the original.
+async fn import_xliff(
+    input: PathBuf,
+    xliff: PathBuf,
+    output: PathBuf,
+    language: Option<String>,
+    mut options: Options,
+) -> Result<(), trans_epub::Error> {
+    let Xliff {
+        language: target_language,
+        pairs,
+    } = xliff::read(&std::fs::read(&xliff)?)?;
+    let language = language.or(target_language).ok_or_else(|| {
+        trans_epub::Error::Input(format!(
+            "{}: no trgLang; give the language with --language",
+            xliff.display()
+        ))
+    })?;
+    info!(
+        "xliff: {} translations into {} from {}",
+        pairs.len(),
+        language,
+        xliff.display()
+    );
+    // the translations are those of the post-editor, not of a model
+    options.no_cache = true;
+    let mut context = context(
+        String::from("xliff"),
+        String::new(),
+        language.clone(),
+        100,
+        1,
+        &output,
+        options,
+    )?;
+    for (source, target) in pairs {
+        context.recalled.insert((language.clone(), source), target);
+    }
+    let translator = Translator::new(context, Unlisted);
+    input::translate(&input, &output, &translator).await
+}
+
+/// Translate again the paragraphs of `input` chosen by `paragraphs` and
+/// `filters`, writing the book and its sidecar to `output`.
+async fn retranslate(
+    input: PathBuf,
+    paragraphs: Option<PathBuf>,
+    filters: Vec<Filter>,
+    output: Option<PathBuf>,
+    engine: Engine,
+) -> Result<(), trans_epub::Error> {
+    let Engine {
+        provider,
+        model,
+        api_key,
+        lines,
+        requests,
+        num_ctx,
+        mut options,
+    } = engine;
+    let sidecar = provenance::sidecar(&input);
+    if !sidecar.exists() {
+        return Err(trans_epub::Error::Input(format!(
+            "{}: not found; translate the book with --provenance sidecar first",
+            sidecar.display()
+        )));
+    }
+    let mut entries = provenance::read(&sidecar)?;
+    let mut selection = Selection::new(filters);
+    if let Some(path) = paragraphs {
+        selection.list(&std::fs::read_to_string(path)?)?;
+    }
+    let language = entries
+        .first()
+        .map(|entry| entry.language.clone())
+        .unwrap_or_default();
+    let run_id = options
+        .run_id
+        .clone()
+        .unwrap_or_else(provenance::new_run_id);
+    let output = output.unwrap_or_else(|| input.clone());
+    // the paragraphs are sent again however they were translated before
+    options.no_cache = true;
+    // only to tell where each new translation comes from; the sidecar is
+    // written from the entries
+    options.provenance = Some(ProvenanceMode::Sidecar);
+    let defaults = PipelineConfig::new(provider, "", "").context;
+    let deepl = deepl_key(&options, &language);
+    let context = context(
+        model.unwrap_or_else(|| provider.default_model().to_string()),
+        api_key.unwrap_or_default(),
+        language,
+        lines.unwrap_or(defaults.lines),
+        requests.unwrap_or(defaults.requests),
+        &output,
+        options,
+    )?;
+    let context = Context {
+        num_ctx: (provider == Provider::Ollama).then_some(num_ctx),
+        preserve_markup: entries.iter().any(|entry| markup::is_marked(&entry.source)),
+        ..context
+    };
+    let translator = with_deepl(context, deepl, |context| provider.translator(context));
+    let epub = std::fs::read(&input)?;
+    let (written, replaced) =
+        retranslate_epub(&epub, &mut entries, &selection, &translator, &run_id).await?;
+    std::fs::write(&output, written)?;
+    provenance::write(&provenance::sidecar(&output), &entries)?;
+    info!(
+        "retranslate: {} paragraphs replaced in {}",
+        replaced,
+        output.display()
+    );
+    translator.finish()
+}
+
+fn tmx_export(
+    memory: PathBuf,
+    output: PathBuf,
+    source_language: &str,
+) -> Result<(), trans_epub::Error> {
+    let segments = Memory::new(memory).load()?;
+    let file = std::fs::File::create(output)?;
+    tmx::write(std::io::BufWriter::new(file), source_language, &segments)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommands_with_the_options_take_the_configuration() {
+        let all = Args::command();
+        let configured: Vec<&str> = all
+            .get_subcommands()
+            .filter(|command| is_configured(command))
+            .map(|command| command.get_name())
+            .collect();
+        for name in [
+            "gemini",
+            "deepl",
+            "mock",
+            "serve",
+            "retranslate",
+            "import-xliff",
+        ] {
+            assert!(configured.contains(&name), "{}: {:?}", name, configured);
+        }
+        assert!(!configured.contains(&"inspect"), "{:?}", configured);
+    }
+
+    #[test]
+    fn the_subcommand_follows_the_global_options() {
+        let all = Args::command();
+        let position = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            subcommand_position(&all, &args)
+        };
+        assert_eq!(position(&["trans-epub", "mock", "-i", "a"]), Some(1));
+        assert_eq!(
+            position(&["trans-epub", "--log-format", "json", "mock"]),
+            Some(3)
+        );
+        assert_eq!(
+            position(&[
+                "trans-epub",
+                "--log-format=json",
+                "--config",
+                "a.toml",
+                "mock"
+            ]),
+            Some(4)
+        );
+        assert_eq!(position(&["trans-epub", "--log-format", "json"]), None);
+        assert_eq!(position(&["trans-epub", "-h"]), None);
+    }
+
+    #[test]
+    fn configured_options_go_after_the_subcommand() {
+        let path = std::env::temp_dir().join(format!("trans-epub-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "language = \"French\"\n[mock]\ntransform = \"reverse\"\n",
+        )
+        .unwrap();
+        let config = format!("--config={}", path.display());
+        let args = with_config(
+            [
+                "trans-epub",
+                "--log-format",
+                "json",
+                &config,
+                "mock",
+                "-lGerman",
+                "-i",
+                "a.epub",
+                "-o",
+                "b.epub",
+            ]
+            .iter()
+            .map(OsString::from)
+            .collect(),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let args: Vec<_> = args.iter().map(|arg| arg.to_string_lossy()).collect();
+        assert_eq!(
+            args,
+            [
+                "trans-epub",
+                "--log-format",
+                "json",
+                &config,
+                "mock",
+                "--transform=reverse",
+                "-lGerman",
+                "-i",
+                "a.epub",
+                "-o",
+                "b.epub"
+            ]
+        );
+        Args::parse_from(args.iter().map(|arg| arg.as_ref()));
+    }
+
+    #[test]
+    fn paragraphs_get_five_retries_by_default() {
+        let all = Args::command();
+        let max_retries = all
+            .find_subcommand("gemini")
+            .and_then(|command| {
+                command
+                    .get_arguments()
+                    .find(|arg| arg.get_id() == "max_retries")
+            })
+            .map(|arg| arg.get_default_values().to_vec());
+        assert_eq!(max_retries, Some(vec!["5".into()]));
+    }
+
+    #[test]
+    fn short_options_are_matched_whole() {
+        assert!(is_short("-m", 'm', true));
+        assert!(is_short("-mgpt-4o", 'm', true));
+        assert!(is_short("-m=gpt-4o", 'm', true));
+        assert!(!is_short("-mx", 'm', false));
+        assert!(!is_short("--model", 'm', true));
+        assert!(!is_short("-l", 'm', true));
+        assert!(!is_short("m", 'm', true));
     }
-    debug!("end");
 }
@@ -0,0 +1,89 @@
+use crate::error::Error;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize)]
+pub struct Segment {
+    pub source: String,
+    pub target: String,
+    pub language: String,
+    pub model: String,
+}
+
+/// Translation memory kept as a JSON Lines file of segment pairs.
+pub struct Memory {
+    path: PathBuf,
+}
+
+impl Memory {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn append(&self, segments: &[Segment]) -> Result<(), Error> {
+        if segments.is_empty() {
+            return Ok(());
+        }
+        let mut buffer = Vec::new();
+        for segment in segments {
+            serde_json::to_writer(&mut buffer, segment)?;
+            buffer.push(b'\n');
+        }
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&self.path)?;
+        // start on a new line after a line cut short by an interrupted run
+        if file.seek(SeekFrom::End(-1)).is_ok() {
+            let mut last = [0];
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                buffer.insert(0, b'\n');
+            }
+        }
+        file.write_all(&buffer)?;
+        Ok(())
+    }
+
+    /// Write the file anew with only `segments`, to a temporary file renamed
+    /// over it, so a run killed meanwhile leaves the file as it was.
+    pub fn rewrite<'a>(
+        &self,
+        segments: impl IntoIterator<Item = &'a Segment>,
+    ) -> Result<(), Error> {
+        let mut buffer = Vec::new();
+        for segment in segments {
+            serde_json::to_writer(&mut buffer, segment)?;
+            buffer.push(b'\n');
+        }
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, buffer)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<Vec<Segment>, Error> {
+        let file = File::open(&self.path)?;
+        let mut segments = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(segment) => segments.push(segment),
+                // a line cut short when a run was killed mid-write
+                Err(e) => warn!("{}: ignoring a broken line: {}", self.path.display(), e),
+            }
+        }
+        Ok(segments)
+    }
+}
@@ -0,0 +1,488 @@
+use crate::epub::markup;
+use crate::error::Error;
+use log::{info, warn};
+use quick_xml::events::{BytesText, Event};
+use quick_xml::{Reader, Writer};
+use regex::{NoExpand, Regex};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Characters that end a sentence, after which a capitalized word is no
+/// sign of a name.
+const SENTENCE_ENDS: &[char] = &['.', '!', '?', '…', ':'];
+
+/// Characters that open a quotation, whose first word is capitalized too.
+const OPENINGS: &[char] = &['"', '“', '„', '«', '‘', '\'', '(', '—'];
+
+/// The proper nouns of the translations of a run, recorded with
+/// `--names-report` as the documents are translated, to report the names
+/// rendered in more than one way. A name is a run of capitalized words
+/// within a sentence, so only languages written with letter case have them.
+#[derive(Default)]
+pub struct Names {
+    recorded: Mutex<Recorded>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    /// runs of capitalized words within a sentence
+    renderings: HashMap<String, Rendering>,
+    /// runs starting a sentence, with their chapter; whether their first
+    /// word is part of the name is told from the other runs at the end
+    openings: Vec<(String, String)>,
+}
+
+#[derive(Default, Clone)]
+struct Rendering {
+    count: usize,
+    chapters: Vec<String>,
+}
+
+impl Rendering {
+    fn add(&mut self, chapter: &str) {
+        self.count += 1;
+        if !self.chapters.iter().any(|seen| seen == chapter) {
+            self.chapters.push(chapter.to_string());
+        }
+    }
+}
+
+/// The renderings of one name, the most frequent first.
+#[derive(Serialize)]
+pub struct Group {
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Serialize)]
+pub struct Variant {
+    pub text: String,
+    pub count: usize,
+    pub chapters: Vec<String>,
+}
+
+impl Names {
+    /// Record the names of the `translations` of the document `chapter`.
+    pub fn record(&self, chapter: &str, translations: &[String]) {
+        let mut recorded = self.recorded.lock().unwrap();
+        for translation in translations {
+            for (name, at_start) in candidates(&markup::strip(translation)) {
+                if at_start {
+                    recorded.openings.push((name, chapter.to_string()));
+                } else {
+                    recorded.renderings.entry(name).or_default().add(chapter);
+                }
+            }
+        }
+    }
+
+    /// The names rendered in more than one way, the most frequent first,
+    /// renderings being grouped when they differ only in spacing, hyphens
+    /// and case, or in a letter in six.
+    pub fn inconsistent(&self) -> Vec<Group> {
+        let recorded = self.recorded.lock().unwrap();
+        let mut renderings = recorded.renderings.clone();
+        for (opening, chapter) in &recorded.openings {
+            // "Then Yoo Joonghyuk" is "Yoo Joonghyuk" when it is met within
+            // a sentence, and taken whole when it has several words
+            let known = opening
+                .match_indices(' ')
+                .map(|(i, _)| &opening[i + 1..])
+                .find(|name| recorded.renderings.contains_key(*name));
+            match known {
+                Some(name) => renderings.get_mut(name).unwrap().add(chapter),
+                None if recorded.renderings.contains_key(opening) || opening.contains(' ') => {
+                    renderings.entry(opening.clone()).or_default().add(chapter);
+                }
+                None => (),
+            }
+        }
+        let mut variants: Vec<Variant> = renderings
+            .iter()
+            .map(|(text, rendering)| Variant {
+                text: text.clone(),
+                count: rendering.count,
+                chapters: rendering.chapters.clone(),
+            })
+            .collect();
+        variants.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.text.cmp(&b.text)));
+        let mut groups: Vec<(String, Group)> = Vec::new();
+        for variant in variants {
+            let variant_key = key(&variant.text);
+            let group = groups
+                .iter_mut()
+                .find(|(head, _)| similar(head, &variant_key));
+            match group {
+                Some((_, group)) => group.variants.push(variant),
+                None => groups.push((
+                    variant_key,
+                    Group {
+                        variants: vec![variant],
+                    },
+                )),
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(_, group)| group)
+            .filter(|group| group.variants.len() > 1)
+            .collect()
+    }
+}
+
+/// Report the names rendered in more than one way in the run that wrote
+/// `output`, in the log and as `<output>.names.json`, and with `harmonize`
+/// offer to replace the other renderings of each with one, in `output`.
+pub async fn conclude(names: &Names, output: &Path, harmonize: bool) -> Result<(), Error> {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".names.json");
+    let path = PathBuf::from(path);
+    let groups = names.inconsistent();
+    if groups.is_empty() {
+        info!("names: every name is rendered one way");
+        // the report of an earlier run is out of date
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        return Ok(());
+    }
+    for group in &groups {
+        let variants: Vec<String> = group
+            .variants
+            .iter()
+            .map(|variant| {
+                format!(
+                    "{} ({}x in {})",
+                    variant.text,
+                    variant.count,
+                    variant.chapters.join(", ")
+                )
+            })
+            .collect();
+        warn!("names: {}", variants.join(" / "));
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(&groups)? + "\n")?;
+    info!(
+        "names: {} names rendered in more than one way, see {}",
+        groups.len(),
+        path.display()
+    );
+    if !harmonize {
+        return Ok(());
+    }
+    let mut replacements = Vec::new();
+    for (number, group) in groups.iter().enumerate() {
+        let Some(chosen) = ask((number + 1, groups.len()), group).await? else {
+            continue;
+        };
+        for variant in &group.variants {
+            if variant.text != chosen {
+                replacements.push((variant.text.clone(), chosen.clone()));
+            }
+        }
+    }
+    if replacements.is_empty() {
+        return Ok(());
+    }
+    // longer renderings first, so one holding another is replaced whole
+    replacements.sort_by_key(|(variant, _)| std::cmp::Reverse(variant.len()));
+    info!(
+        "names: harmonizing {} renderings in {}",
+        replacements.len(),
+        output.display()
+    );
+    rewrite(output, &replacements)
+}
+
+/// Show the renderings of a name on stderr and read which to keep from
+/// stdin: an empty answer or the end of the input keeps the most frequent,
+/// `n` none, and another answer is the spelling to use.
+async fn ask(number: (usize, usize), group: &Group) -> io::Result<Option<String>> {
+    let mut stderr = io::stderr();
+    writeln!(stderr, "\nnames {}/{}:", number.0, number.1)?;
+    for variant in &group.variants {
+        writeln!(stderr, "  {} ({}x)", variant.text, variant.count)?;
+    }
+    write!(
+        stderr,
+        "harmonize to \"{}\"? [Y]es, [n]o, or type the spelling: ",
+        group.variants[0].text
+    )?;
+    stderr.flush()?;
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).map(|_| line)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(match answer.trim() {
+        "" | "y" | "yes" => Some(group.variants[0].text.clone()),
+        "n" | "no" => None,
+        spelling => Some(spelling.to_string()),
+    })
+}
+
+/// Replace the `replacements` as whole words in the text of `output`: of
+/// its content documents for an EPUB, of the file otherwise.
+fn rewrite(output: &Path, replacements: &[(String, String)]) -> Result<(), Error> {
+    let replacements: Vec<(Regex, &str)> = replacements
+        .iter()
+        .map(|(variant, chosen)| {
+            let pattern = format!(r"\b{}\b", regex::escape(variant));
+            (Regex::new(&pattern).expect("escaped name"), chosen.as_str())
+        })
+        .collect();
+    let replace = |text: &str| {
+        replacements
+            .iter()
+            .fold(text.to_string(), |text, (variant, chosen)| {
+                variant.replace_all(&text, NoExpand(chosen)).into_owned()
+            })
+    };
+    if output.is_dir() {
+        warn!("names: {} is a directory, not harmonized", output.display());
+        return Ok(());
+    }
+    let is_epub = output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("epub"));
+    if !is_epub {
+        let text = std::fs::read_to_string(output)?;
+        std::fs::write(output, replace(&text))?;
+        return Ok(());
+    }
+    let mut archive = ZipArchive::new(File::open(output)?)?;
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let content = if [".xhtml", ".html", ".htm"]
+            .iter()
+            .any(|extension| name.ends_with(extension))
+        {
+            rewrite_text(&content, &replace)
+        } else {
+            content
+        };
+        let options = match name.as_str() {
+            "mimetype" => {
+                SimpleFileOptions::default().compression_method(CompressionMethod::Stored)
+            }
+            _ => SimpleFileOptions::default(),
+        };
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(&content)?;
+    }
+    let written = zip.finish()?.into_inner();
+    std::fs::write(output, written)?;
+    Ok(())
+}
+
+/// `content` with each of its text nodes passed through `replace`.
+fn rewrite_text(content: &[u8], replace: &impl Fn(&str) -> String) -> Vec<u8> {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(Event::Text(e)) => {
+                let text = crate::epub::unescape(&e);
+                let replaced = replace(&text);
+                if replaced == text {
+                    writer.write_event(Event::Text(e)).unwrap();
+                } else {
+                    writer
+                        .write_event(Event::Text(BytesText::new(&replaced)))
+                        .unwrap();
+                }
+            }
+            Ok(event) => writer.write_event(event).unwrap(),
+            Err(_) => return content.to_vec(),
+        }
+    }
+    writer.into_inner().into_inner()
+}
+
+/// The runs of capitalized words of `text`, with whether they start a
+/// sentence, when a capitalized word may be no name.
+fn candidates(text: &str) -> Vec<(String, bool)> {
+    let words = Regex::new(r"[\p{L}\p{N}][\p{L}\p{N}'’-]*").unwrap();
+    let mut names = Vec::new();
+    let mut run: Vec<&str> = Vec::new();
+    let mut run_at_start = false;
+    let mut at_start = true;
+    let mut end = 0;
+    for word in words.find_iter(text) {
+        let gap = &text[end..word.start()];
+        end = word.end();
+        // punctuation between words, like a comma or a quotation mark, ends
+        // a run
+        if !gap.trim().is_empty() {
+            flush(&mut names, &mut run, run_at_start);
+        }
+        at_start |= gap.contains(SENTENCE_ENDS) || gap.contains(OPENINGS);
+        let name = word.as_str();
+        let name = name
+            .strip_suffix("'s")
+            .or_else(|| name.strip_suffix("’s"))
+            .unwrap_or(name);
+        let capitalized = name.chars().next().is_some_and(char::is_uppercase)
+            && name.chars().any(char::is_lowercase);
+        if capitalized {
+            if run.is_empty() {
+                run_at_start = at_start;
+            }
+            run.push(name);
+        } else {
+            flush(&mut names, &mut run, run_at_start);
+        }
+        at_start = false;
+    }
+    flush(&mut names, &mut run, run_at_start);
+    names
+}
+
+fn flush(names: &mut Vec<(String, bool)>, run: &mut Vec<&str>, at_start: bool) {
+    if !run.is_empty() {
+        names.push((run.join(" "), at_start));
+    }
+    run.clear();
+}
+
+/// A rendering lower cased, without spaces and hyphens.
+fn key(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Whether two keys are renderings of one name: starting alike and apart
+/// by at most one edit in six letters.
+fn similar(a: &str, b: &str) -> bool {
+    if a.chars().next() != b.chars().next() {
+        return false;
+    }
+    let length = a.chars().count().min(b.chars().count());
+    distance(a, b) <= length / 6
+}
+
+/// The Levenshtein distance of `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    fn variants(group: &Group) -> Vec<(&str, usize)> {
+        group
+            .variants
+            .iter()
+            .map(|variant| (variant.text.as_str(), variant.count))
+            .collect()
+    }
+
+    #[test]
+    fn names_are_runs_of_capitalized_words() {
+        assert_eq!(
+            candidates("Then Yoo Joonghyuk met Han Sooyoung's sister, Mia."),
+            vec![
+                ("Then Yoo Joonghyuk".to_string(), true),
+                ("Han Sooyoung".to_string(), false),
+                ("Mia".to_string(), false),
+            ]
+        );
+        // acronyms and the first word of a quotation are no sure names
+        assert_eq!(
+            candidates("the NASA file said “Never” to Kim"),
+            vec![("Never".to_string(), true), ("Kim".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn renderings_of_one_name_are_grouped() {
+        let names = Names::default();
+        names.record(
+            "ch1.xhtml",
+            &texts(&[
+                "He saw Yoo Joonghyuk and Yoo Joonghyuk saw him.",
+                "Later yoo-Joonghyuk left with Kim.",
+            ]),
+        );
+        names.record("ch2.xhtml", &texts(&["Kim met Yoo Jonghyuk again."]));
+        let groups = names.inconsistent();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            variants(&groups[0]),
+            [("Yoo Joonghyuk", 2), ("Yoo Jonghyuk", 1)]
+        );
+        assert_eq!(groups[0].variants[1].chapters, ["ch2.xhtml"]);
+    }
+
+    #[test]
+    fn sentence_openings_count_as_the_name_they_end_with() {
+        let names = Names::default();
+        names.record(
+            "ch1.xhtml",
+            &texts(&[
+                "Han Sooyoung laughed. Then Han Soyoung spoke to Han Sooyoung and Han Soyoung.",
+            ]),
+        );
+        let groups = names.inconsistent();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            variants(&groups[0]),
+            [("Han Sooyoung", 2), ("Han Soyoung", 2)]
+        );
+    }
+
+    #[test]
+    fn distinct_names_stay_apart() {
+        assert!(similar(&key("Joonghyuk"), &key("Jonghyuk")));
+        assert!(!similar(&key("Mia"), &key("Mio")));
+        assert!(!similar(&key("Kim"), &key("Jim")));
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn harmonizing_replaces_whole_words_of_the_text() {
+        let replace = |text: &str| text.replace("Jonghyuk", "Joonghyuk");
+        assert_eq!(
+            rewrite_text(b"<p title=\"Jonghyuk\">Jonghyuk &amp; Kim</p>", &replace),
+            b"<p title=\"Jonghyuk\">Joonghyuk &amp; Kim</p>"
+        );
+        let path =
+            std::env::temp_dir().join(format!("trans-epub-names-{}.txt", std::process::id()));
+        std::fs::write(&path, "Jonghyuk and Jonghyukson\n").unwrap();
+        rewrite(&path, &[("Jonghyuk".to_string(), "Joonghyuk".to_string())]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "Joonghyuk and Jonghyukson\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use crate::client::limiter::Limiter;
+use crate::epub::translate_epub_bytes;
+use crate::error::Error;
+use crate::input;
+use crate::translate::anthropic::Anthropic;
+use crate::translate::deepl::DeepL;
+use crate::translate::gemini::Gemini;
+use crate::translate::mock::Mock;
+use crate::translate::ollama::Ollama;
+use crate::translate::open_ai::OpenAi;
+use crate::translate::translator::{Context, Translator, MAX_RETRIES};
+use clap::ValueEnum;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The API a [`Pipeline`] translates with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Provider {
+    /// The OpenAI API or an OpenAI-compatible server
+    OpenAi,
+    /// The Gemini API
+    Gemini,
+    /// A local model served by Ollama
+    Ollama,
+    /// The Anthropic API
+    Anthropic,
+    /// The DeepL API, for the languages it translates
+    #[value(name = "deepl")]
+    DeepL,
+    /// No API: paragraphs are transformed the same way every time, to test
+    /// a run offline
+    Mock,
+}
+
+impl Provider {
+    /// The model of the command line when none is given.
+    pub fn default_model(self) -> &'static str {
+        match self {
+            Self::OpenAi => "gpt-4o",
+            Self::Gemini => "gemini-1.5-flash",
+            Self::Ollama => "llama3.1",
+            Self::Anthropic => "claude-3-5-sonnet-latest",
+            Self::DeepL => "deepl",
+            Self::Mock => "mock",
+        }
+    }
+
+    pub fn translator(self, context: Context) -> Translator {
+        match self {
+            Self::OpenAi => Translator::new(context, OpenAi),
+            Self::Gemini => Translator::new(context, Gemini),
+            Self::Ollama => Translator::new(context, Ollama),
+            Self::Anthropic => Translator::new(context, Anthropic),
+            Self::DeepL => Translator::new(context, DeepL),
+            Self::Mock => Translator::new(context, Mock::default()),
+        }
+    }
+}
+
+/// Makes a translator into the given language for the book written to the
+/// given path, such as one per job of a server, each with progress and
+/// totals of its own.
+pub type Factory = Box<dyn Fn(&str, &Path) -> Result<Translator, Error> + Send + Sync>;
+
+/// What a [`Pipeline`] is made from: the provider and the [`Context`] of the
+/// run, which holds the model, the API key, the target language and every
+/// other option of the command line.
+pub struct Config {
+    pub provider: Provider,
+    pub context: Context,
+}
+
+impl Config {
+    /// The defaults of the command line for `provider`: its chunk size and
+    /// number of concurrent requests, and for Ollama its context window.
+    /// Set the other fields of `context`, such as `api_key`, before making
+    /// the pipeline.
+    pub fn new(provider: Provider, model: &str, language: &str) -> Self {
+        let (lines, requests) = match provider {
+            Provider::OpenAi => (20, 5),
+            Provider::Gemini => (100, 1),
+            Provider::Ollama => (10, 1),
+            Provider::Anthropic => (50, 2),
+            Provider::DeepL => (50, 2),
+            Provider::Mock => (20, 4),
+        };
+        Self {
+            provider,
+            context: Context {
+                model: model.to_string(),
+                language: language.to_string(),
+                lines,
+                requests,
+                num_ctx: (provider == Provider::Ollama).then_some(8192),
+                max_chapters_in_flight: 1,
+                retry_chunk_size: 1,
+                max_retries: MAX_RETRIES,
+                limiter: Arc::new(Limiter::new(requests)),
+                ..Context::default()
+            },
+        }
+    }
+}
+
+/// The translation engine for use from other programs, such as a GUI front
+/// end or a web service, without going through the command line.
+///
+/// ```no_run
+/// use trans_epub::pipeline::{Config, Pipeline, Provider};
+///
+/// # async fn run() -> Result<(), trans_epub::Error> {
+/// let mut config = Config::new(Provider::Gemini, "gemini-1.5-flash", "Japanese");
+/// config.context.api_key = std::env::var("API_KEY").unwrap_or_default();
+/// let translated = Pipeline::new(config).translate_epub("origin.epub").await?;
+/// std::fs::write("translated.epub", translated)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The totals of a pipeline, such as its requests and tokens, add up over
+/// every book it translates.
+pub struct Pipeline {
+    translator: Translator,
+}
+
+impl Pipeline {
+    pub fn new(config: Config) -> Self {
+        Self {
+            translator: config.provider.translator(config.context),
+        }
+    }
+
+    pub fn translator(&self) -> &Translator {
+        &self.translator
+    }
+
+    /// Check that the API key can use the model, with a request of a few
+    /// tokens.
+    pub async fn preflight(&self) -> Result<(), String> {
+        self.translator.preflight().await
+    }
+
+    /// Translate the EPUB at `path` and return the translated EPUB.
+    pub async fn translate_epub(&self, path: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+        let input = std::fs::read(path)?;
+        self.translate_epub_bytes(&input).await
+    }
+
+    /// Translate an EPUB held in memory and return the translated EPUB.
+    pub async fn translate_epub_bytes(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        translate_epub_bytes(input, &self.translator).await
+    }
+
+    /// Translate paragraphs of text into the language of the run, in order.
+    pub async fn translate_paragraphs(&self, paragraphs: Vec<String>) -> Vec<String> {
+        self.translator.translate(paragraphs).await
+    }
+
+    /// Translate a book of any input format, or a directory of chapter
+    /// files, from `input` into `output`, as the command line does; failed
+    /// chunks are reported next to the output.
+    pub async fn translate_file(&self, input: &Path, output: &Path) -> Result<(), Error> {
+        input::translate(input, output, &self.translator).await
+    }
+}
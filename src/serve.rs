@@ -0,0 +1,616 @@
+use crate::epub::translate_epub_bytes;
+use crate::error::Error;
+use crate::pipeline::Factory;
+use crate::translate::translator::Translator;
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
+use tokio::sync::{Notify, Semaphore};
+
+/// Longest request head read, the request line and headers.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// How a server listens, and how long it holds what it translated.
+pub struct Server {
+    /// address to listen on; the loopback one keeps the API, and the API key
+    /// behind it, to this machine
+    pub bind: IpAddr,
+    pub port: u16,
+    /// the bearer token every request must carry, when set
+    pub token: Option<String>,
+    /// how long a finished job is kept, its output with it
+    pub keep: Duration,
+    /// largest EPUB taken in an upload, in bytes; each is held in memory
+    /// while it is translated
+    pub max_upload: usize,
+    /// how long a client has to send its request, upload included
+    pub read_timeout: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+/// A translation job: one uploaded EPUB, translated by a translator of its
+/// own so its progress and totals are apart from the other jobs.
+struct Job {
+    language: String,
+    /// made on the thread of the job, once it runs
+    translator: OnceLock<Translator>,
+    state: Mutex<(State, Option<String>)>,
+    /// when the job stopped running
+    ended: Mutex<Option<Instant>>,
+    /// the translated EPUB, until it is downloaded
+    output: Mutex<Option<Vec<u8>>>,
+    downloaded: AtomicBool,
+    cancel: Notify,
+}
+
+impl Job {
+    fn status(&self, id: u64) -> serde_json::Value {
+        let (state, error) = self.state.lock().unwrap().clone();
+        let progress = self
+            .translator
+            .get()
+            .map(|translator| translator.context().progress.counts())
+            .unwrap_or_default();
+        json!({
+            "id": id,
+            "language": self.language,
+            "state": state,
+            "error": error,
+            "progress": progress,
+            "downloaded": self.downloaded.load(Ordering::Relaxed),
+        })
+    }
+
+    /// End a running job; returns whether it was still running, since a
+    /// cancelled job stays cancelled.
+    fn end(&self, state: State, error: Option<String>) -> bool {
+        let mut current = self.state.lock().unwrap();
+        let running = current.0 == State::Running;
+        if running {
+            *current = (state, error);
+            *self.ended.lock().unwrap() = Some(Instant::now());
+        }
+        running
+    }
+
+    /// Whether the job ended longer than `keep` ago.
+    fn is_stale(&self, keep: Duration) -> bool {
+        self.ended
+            .lock()
+            .unwrap()
+            .is_some_and(|ended| ended.elapsed() >= keep)
+    }
+}
+
+/// Why a job was not started.
+#[derive(Debug)]
+enum Refused {
+    /// the request is wrong
+    Invalid(String),
+    /// `max_jobs` jobs are running
+    Busy(usize),
+}
+
+/// The jobs of a server, by id.
+pub struct Jobs {
+    factory: Arc<Factory>,
+    /// language of a job that does not ask for one
+    language: String,
+    max_jobs: usize,
+    /// a permit for each job that may run at once
+    slots: Arc<Semaphore>,
+    next: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+}
+
+impl Jobs {
+    pub fn new(factory: Factory, language: String, max_jobs: usize) -> Self {
+        Self {
+            factory: Arc::new(factory),
+            language,
+            max_jobs,
+            slots: Arc::new(Semaphore::new(max_jobs)),
+            next: AtomicU64::new(1),
+            jobs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn start(&self, input: Vec<u8>, language: Option<String>) -> Result<u64, Refused> {
+        let language = language.unwrap_or_else(|| self.language.clone());
+        if language.is_empty() {
+            return Err(Refused::Invalid("no language, give ?language=".to_string()));
+        }
+        let slot = self
+            .slots
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Refused::Busy(self.max_jobs))?;
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let job = Arc::new(Job {
+            language,
+            translator: OnceLock::new(),
+            state: Mutex::new((State::Running, None)),
+            ended: Mutex::new(None),
+            output: Mutex::new(None),
+            downloaded: AtomicBool::new(false),
+            cancel: Notify::new(),
+        });
+        let running = job.clone();
+        let factory = self.factory.clone();
+        // the translation borrows across its chunks in a way that cannot
+        // be spawned as a task, so each job runs on a blocking thread, which
+        // also makes its translator and opens the files of its options
+        let handle = Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            // a name of its own, for the checkpoint and the stats of the job
+            let output = format!("job-{}.epub", id);
+            let translator = match factory(&running.language, Path::new(&output)) {
+                Ok(translator) => running.translator.get_or_init(|| translator),
+                Err(e) => {
+                    error!("job {}: {}", id, e);
+                    running.end(State::Failed, Some(e.to_string()));
+                    return;
+                }
+            };
+            handle.block_on(async {
+                let translated = tokio::select! {
+                    translated = translate_epub_bytes(&input, translator) => translated,
+                    _ = running.cancel.notified() => return,
+                };
+                match translated {
+                    Ok(output) => {
+                        info!("job {}: done", id);
+                        *running.output.lock().unwrap() = Some(output);
+                        running.end(State::Done, None);
+                    }
+                    Err(e) => {
+                        error!("job {}: {}", id, e);
+                        running.end(State::Failed, Some(e.to_string()));
+                    }
+                }
+            })
+        });
+        self.jobs.lock().unwrap().insert(id, job);
+        info!("job {}: started", id);
+        Ok(id)
+    }
+
+    fn get(&self, id: u64) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Stop a job if it is running, its requests in flight dropped, and
+    /// forget it with its output.
+    fn remove(&self, id: u64) -> Option<Arc<Job>> {
+        let job = self.jobs.lock().unwrap().remove(&id)?;
+        // the permit is kept when the job is not waiting yet
+        job.cancel.notify_one();
+        if job.end(State::Cancelled, None) {
+            info!("job {}: cancelled", id);
+        }
+        info!("job {}: removed", id);
+        Some(job)
+    }
+
+    /// Forget the jobs that ended longer than `keep` ago.
+    fn evict(&self, keep: Duration) {
+        self.jobs.lock().unwrap().retain(|id, job| {
+            let stale = job.is_stale(keep);
+            if stale {
+                info!("job {}: expired", id);
+            }
+            !stale
+        });
+    }
+}
+
+/// Serve the HTTP API of the jobs as `server` says until the process ends:
+///
+/// - `POST /jobs?language=<LANG>` with an EPUB as the body starts a job
+/// - `GET /jobs` lists the jobs and `GET /jobs/<ID>` polls one
+/// - `GET /jobs/<ID>/output` downloads the translated EPUB once done, which
+///   is then let go
+/// - `DELETE /jobs/<ID>` cancels a job if it runs and forgets it
+///
+/// Finished jobs are forgotten once they are older than `server.keep`.
+pub async fn serve(server: Server, jobs: Jobs) -> Result<(), Error> {
+    let listener = TcpListener::bind((server.bind, server.port)).await?;
+    info!("serve: listening on {}", listener.local_addr()?);
+    if server.token.is_none() && !server.bind.is_loopback() {
+        warn!(
+            "serve: listening on {} without --token, anyone who reaches it translates with the API key",
+            server.bind
+        );
+    }
+    let jobs = Arc::new(jobs);
+    let server = Arc::new(server);
+    let expiring = jobs.clone();
+    let keep = server.keep;
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(keep.clamp(Duration::from_secs(1), Duration::from_secs(60)));
+        loop {
+            interval.tick().await;
+            expiring.evict(keep);
+        }
+    });
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let (jobs, server) = (jobs.clone(), server.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &jobs, &server).await {
+                warn!("serve: {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+async fn handle(mut stream: TcpStream, jobs: &Jobs, server: &Server) -> std::io::Result<()> {
+    let read = tokio::time::timeout(
+        server.read_timeout,
+        read_request(&mut stream, server.max_upload),
+    );
+    let request = match read.await {
+        Ok(read) => read?,
+        Err(_) => Err((408, "request not received in time".to_string())),
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err((status, message)) => {
+            return respond_json(&mut stream, status, json!({"error": message})).await
+        }
+    };
+    if let Some(token) = &server.token {
+        let expected = format!("Bearer {}", token);
+        if !request
+            .authorization
+            .as_deref()
+            .is_some_and(|authorization| same(authorization.as_bytes(), expected.as_bytes()))
+        {
+            return respond_json(
+                &mut stream,
+                401,
+                json!({"error": "no or wrong bearer token"}),
+            )
+            .await;
+        }
+    }
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let id = segments.get(1).and_then(|id| id.parse::<u64>().ok());
+    let not_found = json!({"error": "no such job"});
+    match (request.method.as_str(), segments.as_slice(), id) {
+        ("POST", ["jobs"], _) => {
+            let language = request
+                .query
+                .iter()
+                .find(|(name, _)| name == "language")
+                .map(|(_, value)| value.clone());
+            match jobs.start(request.body, language) {
+                Ok(id) => respond_json(&mut stream, 201, jobs.get(id).unwrap().status(id)).await,
+                Err(Refused::Invalid(message)) => {
+                    respond_json(&mut stream, 400, json!({"error": message})).await
+                }
+                Err(Refused::Busy(max_jobs)) => {
+                    let message = format!("at most {} jobs run at once, try again later", max_jobs);
+                    respond_json(&mut stream, 503, json!({"error": message})).await
+                }
+            }
+        }
+        ("GET", ["jobs"], _) => {
+            let list: Vec<_> = jobs
+                .jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, job)| job.status(*id))
+                .collect();
+            respond_json(&mut stream, 200, json!(list)).await
+        }
+        ("GET", ["jobs", _], Some(id)) => match jobs.get(id) {
+            Some(job) => respond_json(&mut stream, 200, job.status(id)).await,
+            None => respond_json(&mut stream, 404, not_found).await,
+        },
+        ("GET", ["jobs", _, "output"], Some(id)) => {
+            let Some(job) = jobs.get(id) else {
+                return respond_json(&mut stream, 404, not_found).await;
+            };
+            let output = job.output.lock().unwrap().take();
+            let Some(output) = output else {
+                let status = match job.downloaded.load(Ordering::Relaxed) {
+                    true => 410,
+                    false => 409,
+                };
+                return respond_json(&mut stream, status, job.status(id)).await;
+            };
+            match respond(&mut stream, 200, "application/epub+zip", &output).await {
+                Ok(()) => {
+                    info!("job {}: downloaded", id);
+                    job.downloaded.store(true, Ordering::Relaxed);
+                    Ok(())
+                }
+                Err(e) => {
+                    // kept for another try
+                    *job.output.lock().unwrap() = Some(output);
+                    Err(e)
+                }
+            }
+        }
+        ("DELETE", ["jobs", _], Some(id)) => match jobs.remove(id) {
+            Some(job) => respond_json(&mut stream, 200, job.status(id)).await,
+            None => respond_json(&mut stream, 404, not_found).await,
+        },
+        _ => respond_json(&mut stream, 404, json!({"error": "no such endpoint"})).await,
+    }
+}
+
+/// Read an HTTP/1.1 request with its body of `Content-Length` bytes, up to
+/// `max_body`; the inner error is the status and the reason a request is
+/// refused.
+async fn read_request(
+    stream: &mut TcpStream,
+    max_body: usize,
+) -> std::io::Result<Result<Request, (u16, String)>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > MAX_HEAD {
+            return Ok(Err((400, "request head too long".to_string())));
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(Err((400, "connection closed".to_string())));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+    let head = String::from_utf8_lossy(&buffer[..end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Ok(Err((400, "malformed request line".to_string())));
+    };
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    let header = |wanted: &str| {
+        headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| *value)
+    };
+    let length = header("content-length")
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    if length > max_body {
+        return Ok(Err((
+            413,
+            format!("body too large, at most {} bytes", max_body),
+        )));
+    }
+    let authorization = header("authorization").map(String::from);
+    let mut body = buffer.split_off(end + 4);
+    while body.len() < length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(Err((400, "body cut short".to_string())));
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(length);
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (decode(name), decode(value)))
+        .collect();
+    Ok(Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        authorization,
+        body,
+    }))
+}
+
+/// Whether `a` and `b` are the same, in a time that does not tell how much
+/// of a guessed token is right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Percent-decode a query string component, `+` as a space.
+fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+async fn respond_json(
+    stream: &mut TcpStream,
+    status: u16,
+    body: serde_json::Value,
+) -> std::io::Result<()> {
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    respond(stream, status, "application/json", &body).await
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{Config, Provider};
+
+    fn jobs() -> Jobs {
+        let factory: Factory = Box::new(|language, _| {
+            let config = Config::new(Provider::Mock, "mock", language);
+            Ok(config.provider.translator(config.context))
+        });
+        Jobs::new(factory, "German".to_string(), 2)
+    }
+
+    fn server(read_timeout: Duration) -> Server {
+        Server {
+            bind: IpAddr::from([127, 0, 0, 1]),
+            port: 0,
+            token: None,
+            keep: Duration::from_secs(60),
+            max_upload: 1024,
+            read_timeout,
+        }
+    }
+
+    async fn ended(jobs: &Jobs, id: u64) {
+        while jobs.get(id).unwrap().state.lock().unwrap().0 == State::Running {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn finished_jobs_expire() {
+        let jobs = jobs();
+        let id = jobs.start(b"not an epub".to_vec(), None).unwrap();
+        ended(&jobs, id).await;
+        assert_eq!(jobs.get(id).unwrap().state.lock().unwrap().0, State::Failed);
+        jobs.evict(Duration::from_secs(60));
+        assert!(jobs.get(id).is_some());
+        jobs.evict(Duration::ZERO);
+        assert!(jobs.get(id).is_none());
+    }
+
+    #[tokio::test]
+    async fn removed_jobs_are_forgotten() {
+        let jobs = jobs();
+        let id = jobs.start(b"not an epub".to_vec(), None).unwrap();
+        ended(&jobs, id).await;
+        let job = jobs.remove(id).unwrap();
+        // it had ended, so it is not marked cancelled
+        assert_eq!(job.state.lock().unwrap().0, State::Failed);
+        assert!(jobs.get(id).is_none());
+        assert!(jobs.remove(id).is_none());
+    }
+
+    #[tokio::test]
+    async fn jobs_beyond_the_limit_are_refused() {
+        let factory: Factory = Box::new(|_, _| {
+            std::thread::sleep(Duration::from_millis(200));
+            Err(Error::Input("no provider".to_string()))
+        });
+        let jobs = Jobs::new(factory, "German".to_string(), 2);
+        let first = jobs.start(Vec::new(), None).unwrap();
+        jobs.start(Vec::new(), None).unwrap();
+        assert!(matches!(
+            jobs.start(Vec::new(), None),
+            Err(Refused::Busy(2))
+        ));
+        // a job that ended gives its place back
+        ended(&jobs, first).await;
+        let job = jobs.get(first).unwrap();
+        let (state, error) = job.state.lock().unwrap().clone();
+        assert_eq!(state, State::Failed);
+        assert_eq!(error.as_deref(), Some("input error: no provider"));
+        ended(&jobs, first + 1).await;
+        assert!(jobs.start(Vec::new(), None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // the head is never finished
+        client.write_all(b"GET /jobs HTTP/1.1\r\n").await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let jobs = jobs();
+        handle(stream, &jobs, &server(Duration::from_millis(50)))
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+    }
+
+    #[test]
+    fn tokens_are_compared_whole() {
+        assert!(same(b"Bearer secret", b"Bearer secret"));
+        assert!(!same(b"Bearer secre", b"Bearer secret"));
+        assert!(!same(b"Bearer secrew", b"Bearer secret"));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::error::Error;
+use crate::language;
+use crate::memory::Segment;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Inline elements of a segment holding native codes rather than text.
+const CODES: &[&[u8]] = &[b"bpt", b"ept", b"ph", b"it"];
+
+/// The segment pairs a run translated, collected with `--tmx-out` or
+/// `export-xliff` to be written at its end.
+#[derive(Default)]
+pub struct Collected {
+    segments: Mutex<Vec<Segment>>,
+}
+
+impl Collected {
+    pub fn record(&self, language: &str, model: &str, sources: &[String], targets: &[String]) {
+        let mut segments = self.segments.lock().unwrap();
+        for (source, target) in sources.iter().zip(targets) {
+            if source.trim().is_empty() {
+                continue;
+            }
+            segments.push(Segment {
+                source: source.clone(),
+                target: target.clone(),
+                language: language.to_string(),
+                model: model.to_string(),
+            });
+        }
+    }
+
+    /// The collected pairs, once each: a heading repeated in the table of
+    /// contents is one unit.
+    pub fn take(&self) -> Vec<Segment> {
+        let mut segments = std::mem::take(&mut *self.segments.lock().unwrap());
+        let mut seen = HashSet::new();
+        segments.retain(|segment| seen.insert((segment.language.clone(), segment.source.clone())));
+        segments
+    }
+}
+
+/// Write segments as a TMX 1.4 document, leaving out those not translated.
+pub fn write<W: Write>(
+    mut writer: W,
+    source_language: &str,
+    segments: &[Segment],
+) -> std::io::Result<()> {
+    let source_language = language::code(source_language);
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(writer, r#"<tmx version="1.4">"#)?;
+    writeln!(
+        writer,
+        r#"  <header creationtool="{}" creationtoolversion="{}" segtype="paragraph" o-tmf="trans-epub" adminlang="en" srclang="{}" datatype="plaintext"/>"#,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        escape(source_language.as_str())
+    )?;
+    writeln!(writer, "  <body>")?;
+    for segment in segments.iter().filter(|segment| !segment.target.is_empty()) {
+        writeln!(writer, "    <tu>")?;
+        write_tuv(&mut writer, &source_language, &segment.source)?;
+        write_tuv(
+            &mut writer,
+            &language::code(&segment.language),
+            &segment.target,
+        )?;
+        writeln!(writer, "    </tu>")?;
+    }
+    writeln!(writer, "  </body>")?;
+    writeln!(writer, "</tmx>")
+}
+
+fn write_tuv<W: Write>(writer: &mut W, lang: &str, text: &str) -> std::io::Result<()> {
+    writeln!(
+        writer,
+        r#"      <tuv xml:lang="{}"><seg>{}</seg></tuv>"#,
+        escape(lang),
+        escape(text)
+    )
+}
+
+/// The source and target segments of the translation units of a TMX
+/// document that have a variant in `language`. The source is the variant in
+/// the `srclang` of the unit or of the header, or the first variant in
+/// another language when it is `*all*`. Inline codes are left out.
+pub fn read(tmx: &[u8], language: &str) -> Result<Vec<(String, String)>, Error> {
+    let target = language::code(language);
+    let primary = |lang: &str| {
+        lang.split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase()
+    };
+    let mut reader = Reader::from_reader(tmx);
+    let mut pairs = Vec::new();
+    let mut header_language = None;
+    let mut unit_language = None;
+    // the language and text of each variant of the current unit
+    let mut variants: Vec<(String, String)> = Vec::new();
+    let (mut in_segment, mut in_code) = (false, 0);
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"header" => {
+                header_language = attribute(&e, "srclang");
+            }
+            Event::Start(e) => match e.name().as_ref() {
+                b"tu" => {
+                    unit_language = attribute(&e, "srclang");
+                    variants.clear();
+                }
+                b"tuv" => {
+                    let lang = attribute(&e, "xml:lang").or_else(|| attribute(&e, "lang"));
+                    variants.push((lang.unwrap_or_default(), String::new()));
+                }
+                b"seg" => in_segment = true,
+                name if in_segment && CODES.contains(&name) => in_code += 1,
+                _ => (),
+            },
+            Event::Text(e) if in_segment && in_code == 0 => {
+                if let Some((_, text)) = variants.last_mut() {
+                    text.push_str(&e.unescape()?);
+                }
+            }
+            Event::End(e) => match e.name().as_ref() {
+                b"seg" => in_segment = false,
+                name if in_segment && CODES.contains(&name) => in_code -= 1,
+                b"tu" => {
+                    let source_language = unit_language.as_ref().or(header_language.as_ref());
+                    let source_language = source_language
+                        .filter(|lang| *lang != "*all*")
+                        .map(|lang| primary(lang));
+                    let is_target = |lang: &str| primary(lang) == target.to_lowercase();
+                    let translation = variants.iter().find(|(lang, _)| is_target(lang));
+                    let source = variants.iter().find(|(lang, _)| match &source_language {
+                        Some(source) => primary(lang) == *source && !is_target(lang),
+                        None => !is_target(lang),
+                    });
+                    if let (Some((_, source)), Some((_, translation))) = (source, translation) {
+                        if !source.trim().is_empty() && !translation.trim().is_empty() {
+                            pairs.push((source.clone(), translation.clone()));
+                        }
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    Ok(pairs)
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Option<String> {
+    let value = e.try_get_attribute(name).ok().flatten()?;
+    Some(value.unescape_value().ok()?.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(source, target)| (source.to_string(), target.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn collected_pairs_are_written_and_read_back() {
+        let collected = Collected::default();
+        collected.record(
+            "German",
+            "mock",
+            &texts(&["Chapter 1", " ", "Fish & chips", "Untranslated"]),
+            &texts(&["Kapitel 1", " ", "Fisch & Pommes", ""]),
+        );
+        // a heading repeated in the table of contents
+        collected.record(
+            "German",
+            "mock",
+            &texts(&["Chapter 1"]),
+            &texts(&["Kapitel 1"]),
+        );
+        let segments = collected.take();
+        assert_eq!(segments.len(), 3);
+        assert!(collected.take().is_empty());
+        let mut written = Vec::new();
+        write(&mut written, "English", &segments).unwrap();
+        let text = String::from_utf8(written.clone()).unwrap();
+        assert!(text.contains(r#"srclang="en""#));
+        assert!(text.contains(r#"<tuv xml:lang="de"><seg>Fisch &amp; Pommes</seg></tuv>"#));
+        assert_eq!(
+            read(&written, "German").unwrap(),
+            pairs(&[
+                ("Chapter 1", "Kapitel 1"),
+                ("Fish & chips", "Fisch & Pommes")
+            ])
+        );
+        assert!(read(&written, "French").unwrap().is_empty());
+    }
+
+    #[test]
+    fn sources_follow_the_srclang_of_the_unit_or_header() {
+        let document = r#"<tmx version="1.4">
+  <header srclang="en-US"/>
+  <body>
+    <tu>
+      <tuv xml:lang="fr-FR"><seg>Bonjour</seg></tuv>
+      <tuv xml:lang="en-GB"><seg>Hello <bpt i="1">&lt;b&gt;</bpt>you<ept i="1">&lt;/b&gt;</ept></seg></tuv>
+      <tuv xml:lang="de"><seg>Hallo</seg></tuv>
+    </tu>
+    <tu srclang="fr">
+      <tuv lang="fr"><seg>Merci</seg></tuv>
+      <tuv lang="de"><seg>Danke</seg></tuv>
+    </tu>
+    <tu srclang="*all*">
+      <tuv xml:lang="it"><seg>Ciao</seg></tuv>
+      <tuv xml:lang="de"><seg>Tschüss</seg></tuv>
+    </tu>
+    <tu>
+      <tuv xml:lang="en"><seg>Empty</seg></tuv>
+      <tuv xml:lang="de"><seg> </seg></tuv>
+    </tu>
+  </body>
+</tmx>"#;
+        assert_eq!(
+            read(document.as_bytes(), "German").unwrap(),
+            pairs(&[
+                ("Hello you", "Hallo"),
+                ("Merci", "Danke"),
+                ("Ciao", "Tschüss")
+            ])
+        );
+    }
+}
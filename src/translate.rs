@@ -1,3 +1,23 @@
-mod gemini;
-mod open_ai;
+pub mod anthropic;
+pub(crate) mod chunk;
+pub mod deepl;
+pub mod dump;
+mod emphasis;
+pub mod extract;
+pub mod gemini;
+pub mod glossary;
+pub(crate) mod json;
+pub mod line;
+pub mod mock;
+pub mod ollama;
+pub mod open_ai;
+pub mod progress;
+pub mod prompt;
+pub mod quality;
+pub mod refine;
+pub mod report;
+pub mod review;
+pub mod self_test;
+mod text;
 pub mod translator;
+pub mod typography;
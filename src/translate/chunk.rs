@@ -0,0 +1,109 @@
+/// Split `lines` into chunks of at most `max_paragraphs` paragraphs and, with
+/// a token budget, at most `max_tokens` estimated tokens. A paragraph over
+/// the budget gets a chunk of its own.
+///
+/// With `scene_breaks`, a chunk at least half full also ends after a [scene
+/// break](is_scene_break), so that a scene is rather sent whole than cut
+/// across two requests.
+pub fn split(
+    lines: &[String],
+    max_paragraphs: usize,
+    max_tokens: Option<usize>,
+    scene_breaks: bool,
+) -> Vec<&[String]> {
+    let max_paragraphs = max_paragraphs.max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut tokens = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let line_tokens = estimate_tokens(line);
+        let full = i - start >= max_paragraphs
+            || max_tokens.is_some_and(|max_tokens| i > start && tokens + line_tokens > max_tokens);
+        let half_full = (i - start) * 2 >= max_paragraphs
+            || max_tokens.is_some_and(|max_tokens| tokens * 2 >= max_tokens);
+        let scene_ends = scene_breaks && i > start && is_scene_break(&lines[i - 1]) && half_full;
+        if full || scene_ends {
+            chunks.push(&lines[start..i]);
+            start = i;
+            tokens = 0;
+        }
+        tokens += line_tokens;
+    }
+    if start < lines.len() {
+        chunks.push(&lines[start..]);
+    }
+    chunks
+}
+
+/// Whether a paragraph is a scene break such as `***`, `* * *` or `§`: a
+/// few characters, none a letter or a digit. A `<hr>` has no text and is not
+/// a paragraph, so the paragraph after it starts the scene unmarked.
+pub fn is_scene_break(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && line.chars().count() <= 20 && !line.chars().any(char::is_alphanumeric)
+}
+
+/// Rough token count: about four characters per token for ASCII text and
+/// one token per character for other scripts.
+pub fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(char::is_ascii).count();
+    let other = text.chars().count() - ascii;
+    ascii.div_ceil(4) + other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paragraphs(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    fn lengths(chunks: &[&[String]]) -> Vec<usize> {
+        chunks.iter().map(|chunk| chunk.len()).collect()
+    }
+
+    #[test]
+    fn chunks_hold_at_most_max_paragraphs() {
+        let lines = paragraphs(&["a"; 7]);
+        assert_eq!(lengths(&split(&lines, 3, None, false)), [3, 3, 1]);
+        assert_eq!(lengths(&split(&lines, 0, None, false)), [1; 7]);
+        assert!(split(&[], 3, None, false).is_empty());
+    }
+
+    #[test]
+    fn chunks_hold_at_most_max_tokens() {
+        // 8 tokens each, then one paragraph over the budget
+        let long = "x".repeat(32);
+        let over = "y".repeat(100);
+        let lines = paragraphs(&[&long, &long, &long, &over, &long]);
+        assert_eq!(lengths(&split(&lines, 10, Some(20), false)), [2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn chunks_at_least_half_full_end_at_scene_breaks() {
+        let lines = paragraphs(&["a", "* * *", "b", "c", "d", "§", "e", "f"]);
+        assert_eq!(lengths(&split(&lines, 4, None, true)), [2, 4, 2]);
+        assert_eq!(lengths(&split(&lines, 4, None, false)), [4, 4]);
+        let lines = paragraphs(&["a", "b", "***", "c", "d", "e"]);
+        assert_eq!(lengths(&split(&lines, 4, None, true)), [3, 3]);
+    }
+
+    #[test]
+    fn scene_breaks_have_no_letters() {
+        for line in ["***", " * * * ", "§", "~~~", "#"] {
+            assert!(is_scene_break(line), "{:?}", line);
+        }
+        for line in ["", "   ", "Chapter 2", "***II***", &"-".repeat(21)] {
+            assert!(!is_scene_break(line), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn tokens_are_estimated_by_script() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello"), 2);
+        assert_eq!(estimate_tokens("你好世界"), 4);
+        assert_eq!(estimate_tokens("ab 你好"), 3);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::client;
+use crate::client::deepl::{request, Stats};
+use crate::error::Error;
+use crate::pipeline::Provider;
+use crate::translate::translator::{self, Backend, BulkTranslated, Context, Preceding};
+use futures::future::BoxFuture;
+
+/// Translation with the DeepL API, one text per paragraph. DeepL takes no
+/// instructions, so the prompt, the glossary and the style options do not
+/// apply, and it answers no free-form prompts.
+pub struct DeepL;
+
+impl Backend for DeepL {
+    fn translate_bulk<'a>(
+        &'a self,
+        context: &'a Context,
+        language: &'a str,
+        lines: &'a [String],
+        preceding: &'a [Preceding],
+    ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+        Box::pin(translate_bulk(context, language, lines, preceding))
+    }
+
+    fn provider(&self) -> Option<Provider> {
+        Some(Provider::DeepL)
+    }
+
+    fn probe<'a>(&'a self, context: &'a Context) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(client::deepl::probe(context))
+    }
+}
+
+/// DeepL bills characters, not tokens: the billed characters are counted as
+/// prompt tokens, which the price of the `deepl` model is per million of.
+impl From<Stats> for translator::Stats {
+    fn from(stats: Stats) -> Self {
+        Self {
+            prompt_tokens: stats.billed_characters,
+            output_tokens: 0,
+            total_tokens: stats.billed_characters,
+            cached_tokens: 0,
+        }
+    }
+}
+
+async fn translate_bulk(
+    context: &Context,
+    language: &str,
+    original_lines: &[String],
+    preceding: &[Preceding],
+) -> Result<BulkTranslated, Error> {
+    let preceding: Vec<&str> = preceding
+        .iter()
+        .map(|paragraph| paragraph.original.as_str())
+        .collect();
+    let response = request(context, language, original_lines, &preceding.join("\n")).await?;
+    Ok(BulkTranslated {
+        translated_lines: response.texts,
+        stats: response.stats.into(),
+        ..BulkTranslated::default()
+    })
+}
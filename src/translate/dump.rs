@@ -0,0 +1,174 @@
+use crate::error::Error;
+use crate::logging;
+use crate::pipeline::Provider;
+use crate::translate::translator::Context;
+use clap::ValueEnum;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The directory of `--dump-failures`.
+pub const DIR: &str = ".trans-epub/failures";
+
+/// A request to translate a chunk as it was sent, with the raw text of its
+/// response.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Exchange {
+    pub system_instruction: String,
+    pub prompt: String,
+    /// the paragraphs sent as contents apart from the prompt
+    pub contents: Vec<String>,
+    /// the response was asked for as JSON of the schema of the backend,
+    /// rather than as the text of a streamed response
+    pub structured: bool,
+    pub response: String,
+}
+
+impl Exchange {
+    /// The exchange of a request, kept only when failures are dumped.
+    pub fn keep(
+        context: &Context,
+        system_instruction: &str,
+        prompt: &str,
+        contents: &[String],
+        structured: bool,
+        response: &str,
+    ) -> Option<Self> {
+        context.dumps.as_ref()?;
+        Some(Self {
+            system_instruction: system_instruction.to_string(),
+            prompt: prompt.to_string(),
+            contents: contents.to_vec(),
+            structured,
+            response: response.to_string(),
+        })
+    }
+}
+
+/// A failed chunk as written to its file, to be inspected or sent again
+/// with `trans-epub replay`.
+#[derive(Serialize, Deserialize)]
+pub struct Dump {
+    /// the provider, as its name on the command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    pub language: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<String>,
+    pub reason: String,
+    pub paragraphs: Vec<String>,
+    pub exchange: Exchange,
+}
+
+impl Dump {
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn provider(&self) -> Result<Provider, Error> {
+        let Some(provider) = &self.provider else {
+            return Err(Error::Input(
+                "the dump names no provider to replay it with".to_string(),
+            ));
+        };
+        Provider::from_str(provider, true)
+            .map_err(|_| Error::Input(format!("unknown provider {}", provider)))
+    }
+}
+
+/// A line of `index.jsonl`, which lists the files of the dumped failures.
+#[derive(Serialize)]
+struct Entry<'a> {
+    file: &'a str,
+    chunk: Option<&'a str>,
+    model: &'a str,
+    language: &'a str,
+    reason: &'a str,
+}
+
+/// The chunks whose responses could not be used, with `--dump-failures`:
+/// each is written to a file of its own in `dir` with its prompt and raw
+/// response, and listed in `index.jsonl` there.
+pub struct Dumps {
+    dir: PathBuf,
+    /// the start of the run in seconds, which the files are named after
+    run: u64,
+    count: AtomicUsize,
+}
+
+impl Dumps {
+    pub fn new(dir: PathBuf) -> Self {
+        let run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        Self {
+            dir,
+            run,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Dump the chunk of `paragraphs` that failed for `reason` with
+    /// `provider`, logging rather than failing the run when it cannot be
+    /// written.
+    pub fn dump(
+        &self,
+        context: &Context,
+        provider: Option<Provider>,
+        language: &str,
+        reason: &str,
+        paragraphs: &[String],
+        exchange: Exchange,
+    ) {
+        let chunk = logging::chunk_id();
+        let number = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        let file = match &chunk {
+            Some(chunk) => format!("{}-{}.json", self.run, chunk),
+            None => format!("{}-{}.json", self.run, number),
+        };
+        let dump = Dump {
+            provider: provider
+                .and_then(|provider| provider.to_possible_value())
+                .map(|value| value.get_name().to_string()),
+            model: context.request_model(),
+            base_url: context.base_url.clone(),
+            language: language.to_string(),
+            chunk: chunk.clone(),
+            reason: reason.to_string(),
+            paragraphs: paragraphs.to_vec(),
+            exchange,
+        };
+        let entry = Entry {
+            file: &file,
+            chunk: chunk.as_deref(),
+            model: &dump.model,
+            language,
+            reason,
+        };
+        match self.write(&file, &dump, &entry) {
+            Ok(()) => info!(stage = "dump"; "failure dumped to {}", self.dir.join(&file).display()),
+            Err(e) => error!(stage = "dump"; "failure dump error: {}", e),
+        }
+    }
+
+    fn write(&self, file: &str, dump: &Dump, entry: &Entry) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(
+            self.dir.join(file),
+            serde_json::to_string_pretty(dump)? + "\n",
+        )?;
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("index.jsonl"))?;
+        writeln!(index, "{}", serde_json::to_string(entry)?)
+    }
+}
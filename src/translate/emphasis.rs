@@ -0,0 +1,41 @@
+use crate::language;
+
+/// Instruction added to the prompt when a chunk contains all-caps paragraphs.
+pub const INSTRUCTION: &str = "A paragraph in `<paragraph emphasis=\"caps\">` tag is written in all caps for shouting or system messages; keep that emphasis in the translation, in all caps if the target language has letter case and with an equivalent emphasis otherwise.\n";
+
+/// A paragraph with at least a few letters, all of them upper case.
+pub fn is_all_caps(text: &str) -> bool {
+    let mut letters = 0;
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        if c.is_lowercase() || !c.is_uppercase() {
+            return false;
+        }
+        letters += 1;
+    }
+    letters >= 4
+}
+
+pub fn has_letter_case(language: &str) -> bool {
+    language::find(language).is_some_and(|language| language.cased)
+}
+
+pub fn tag(line: &str, emphasized: bool) -> String {
+    if emphasized {
+        format!("<paragraph emphasis=\"caps\">{}</paragraph>", line)
+    } else {
+        format!("<paragraph>{}</paragraph>", line)
+    }
+}
+
+/// Upper-case the translation of all-caps paragraphs when the target
+/// language has letter case, in case the model normalized them anyway.
+pub fn restore(language: &str, original_lines: &[String], translated_lines: &mut [String]) {
+    if !has_letter_case(language) || original_lines.len() != translated_lines.len() {
+        return;
+    }
+    for (original, translated) in original_lines.iter().zip(translated_lines) {
+        if is_all_caps(original) {
+            *translated = translated.to_uppercase();
+        }
+    }
+}
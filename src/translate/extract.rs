@@ -0,0 +1,82 @@
+use crate::translate::chunk;
+use crate::translate::glossary::Glossary;
+use crate::translate::json;
+use crate::translate::translator::Translator;
+use futures::{stream, StreamExt};
+use log::{error, info};
+use serde::Deserialize;
+
+/// Estimated tokens of book text sent per extraction request.
+const CHUNK_TOKENS: usize = 4000;
+
+#[derive(Deserialize)]
+struct Terms {
+    terms: Vec<Term>,
+}
+
+#[derive(Deserialize)]
+struct Term {
+    source: String,
+    target: String,
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+/// Ask the model for the names and recurring terms of the whole book with
+/// their translation, as a draft glossary to review before translating.
+///
+/// Chunks are read in book order and the first translation given for a term
+/// is kept.
+pub async fn extract(translator: &Translator, paragraphs: &[String]) -> Glossary {
+    let context = translator.context();
+    let chunks = chunk::split(
+        paragraphs,
+        usize::MAX,
+        Some(context.max_chunk_tokens.unwrap_or(CHUNK_TOKENS)),
+        false,
+    );
+    let count = chunks.len();
+    let mut answers: Vec<_> = stream::iter(chunks.into_iter().enumerate())
+        .map(|(number, chunked)| async move {
+            info!("extract glossary {}/{}", number + 1, count);
+            let answer = translator
+                .complete(&prompt(&context.language, chunked))
+                .await;
+            (number, answer)
+        })
+        .buffer_unordered(context.requests)
+        .collect()
+        .await;
+    answers.sort_by_key(|(number, _)| *number);
+
+    let mut glossary = Glossary::default();
+    for (number, answer) in answers {
+        let terms = answer.and_then(|text| json::parse::<Terms>(&text).map_err(|e| e.to_string()));
+        match terms {
+            Ok(terms) => {
+                for term in terms.terms {
+                    let (source, target) = (term.source.trim(), term.target.trim());
+                    if !source.is_empty() && !target.is_empty() && !glossary.contains(source) {
+                        glossary.insert_with_notes(source, target, term.notes.as_deref());
+                    }
+                }
+            }
+            Err(e) => error!("glossary extraction {}/{} error: {}", number + 1, count, e),
+        }
+    }
+    glossary
+}
+
+fn prompt(language: &str, paragraphs: &[String]) -> String {
+    format!(
+        "List the names of characters, places and organizations, the names of skills and titles, \
+        and the other recurring terms in the following text that need one consistent translation into {}.\n\
+        Leave out common words.\n\
+        Please output the following JSON.\n\
+        {{\"terms\": [{{\"source\": the term as written in the text, \"target\": its translation into {}, \"notes\": what the term is}}]}}\n\
+        Here is the text:\n{}",
+        language,
+        language,
+        paragraphs.join("\n")
+    )
+}
@@ -1,134 +1,195 @@
-use crate::client::gemini::{request, Stats};
-use crate::translate::translator::Context;
-use futures::{stream, StreamExt};
-use log::{debug, error, trace};
+use crate::client;
+use crate::client::gemini::{request, stream_request, Stats};
+use crate::error::Error;
+use crate::pipeline::Provider;
+use crate::translate::dump::Exchange;
+use crate::translate::emphasis;
+use crate::translate::json;
+use crate::translate::line::{reorder, LineNumbering};
+use crate::translate::prompt;
+use crate::translate::text;
+use crate::translate::translator::{self, Backend, BulkTranslated, Completion, Context, Preceding};
+use futures::future::BoxFuture;
+use log::error;
 use serde::Deserialize;
+use serde_json::{json, Value};
+
+const DEFAULT_SYSTEM_INSTRUCTION: &str = "You are an excellent translator.";
 
 #[derive(Deserialize)]
 struct Translated {
+    line: Option<i64>,
     text: Vec<String>,
 }
 
-pub struct BulkTranslated {
-    pub number: i32,
-    pub original_lines: Vec<String>,
-    pub translated_lines: Vec<String>,
-    pub stats: Stats,
-}
+/// Translation with the Gemini API.
+pub struct Gemini;
 
-pub async fn translate(context: &Context, lines: Vec<String>) -> Vec<String> {
-    debug!("line_length:{}", lines.len());
-    if lines.is_empty() {
-        return lines;
+impl Backend for Gemini {
+    fn translate_bulk<'a>(
+        &'a self,
+        context: &'a Context,
+        language: &'a str,
+        lines: &'a [String],
+        preceding: &'a [Preceding],
+    ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+        Box::pin(translate_bulk(context, language, lines, preceding))
+    }
+
+    fn provider(&self) -> Option<Provider> {
+        Some(Provider::Gemini)
+    }
+
+    fn probe<'a>(&'a self, context: &'a Context) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(client::gemini::probe(context))
+    }
+
+    fn complete<'a>(
+        &'a self,
+        context: &'a Context,
+        prompt: &'a str,
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async move {
+            let response = request(
+                context,
+                system_instruction(context),
+                None,
+                prompt,
+                &vec![],
+                None,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            Ok(Completion {
+                text: response.text,
+                stats: response.stats.into(),
+            })
+        })
     }
-    translate_parallel(
-        &context.language,
-        &context.model,
-        &context.api_key,
-        lines,
-        context.lines,
-        context.requests,
-        0,
-    )
-    .await
-}
 
-async fn translate_parallel(
-    language: &String,
-    model: &String,
-    api_key: &String,
-    lines: Vec<String>,
-    chunk_lines: usize,
-    requests: usize,
-    retry_count: i32,
-) -> Vec<String> {
-    let mut number = 0;
-    let bodies = stream::iter(lines.chunks(chunk_lines))
-        .map(|chunked| {
-            let language = language.clone();
-            let model = model.clone();
-            let api_key = api_key.clone();
-            number += 1;
-            let order_number = number;
-            async move {
-                translate_bulk(order_number, &language, &model, &api_key, chunked.to_vec()).await
-            }
+    fn replay<'a>(
+        &'a self,
+        context: &'a Context,
+        exchange: &'a Exchange,
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async move {
+            let response = request(
+                context,
+                &exchange.system_instruction,
+                None,
+                &exchange.prompt,
+                &exchange.contents,
+                exchange.structured.then(schema),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            Ok(Completion {
+                text: response.text,
+                stats: response.stats.into(),
+            })
         })
-        .buffer_unordered(requests);
+    }
 
-    let mut responses = vec![];
-    let mut bodies_stream = bodies;
-    while let Some(response) = bodies_stream.next().await {
-        responses.push(response);
+    #[cfg(feature = "ocr")]
+    fn transcribe<'a>(
+        &'a self,
+        context: &'a Context,
+        prompt: &'a str,
+        mime: &'a str,
+        image: &'a [u8],
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async move {
+            let response = client::gemini::transcribe(context, prompt, mime, image)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Completion {
+                text: response.text,
+                stats: response.stats.into(),
+            })
+        })
     }
+}
 
-    responses.sort_by(|a, b| a.number.cmp(&b.number));
-    let mut translated = vec![];
-    for response in responses {
-        let mut translated_lines = response.translated_lines;
-        let original_lines = response.original_lines;
-        response.stats.log();
-        if translated_lines.len() != original_lines.len() {
-            if retry_count > 4 {
-                panic!("retry max error");
-            }
-            for l in &original_lines {
-                trace!("{}", l);
-            }
-            for l in &translated_lines {
-                trace!("{}", l);
-            }
-            error!("retry count: {}", retry_count);
-            error!(
-                "translated line length error {}/{}",
-                translated_lines.len(),
-                original_lines.len()
-            );
-            translated_lines = Box::pin(translate_parallel(
-                language,
-                model,
-                api_key,
-                original_lines,
-                1,
-                requests,
-                retry_count + 1,
-            ))
-            .await;
+impl From<Stats> for translator::Stats {
+    fn from(stats: Stats) -> Self {
+        Self {
+            prompt_tokens: stats.prompt_token_count,
+            output_tokens: stats.candidates_token_count,
+            total_tokens: stats.total_token_count,
+            cached_tokens: stats.cached_content_token_count,
         }
-        translated.append(&mut translated_lines);
     }
-    translated
 }
 
 async fn translate_bulk(
-    number: i32,
-    language: &String,
-    model: &str,
-    api_key: &String,
-    original_lines: Vec<String>,
-) -> BulkTranslated {
+    context: &Context,
+    language: &str,
+    original_lines: &[String],
+    preceding: &[Preceding],
+) -> Result<BulkTranslated, Error> {
     let mut user_contents: Vec<String> = vec![];
-    for line in &original_lines {
-        user_contents.push(format!("<paragraph>{}</paragraph>", line));
+    let mut emphasized = false;
+    for line in original_lines {
+        let caps = context.preserve_emphasis && emphasis::is_all_caps(line);
+        emphasized |= caps;
+        user_contents.push(emphasis::tag(line, caps));
+    }
+    let emphasis = if emphasized {
+        emphasis::INSTRUCTION
+    } else {
+        ""
+    };
+
+    let cached = match &context.prompt_cache {
+        // the preamble is cached for the model of the run only
+        Some(cache) if context.request_model() == context.model => {
+            cache.name(context, &preamble(context)).await
+        }
+        _ => None,
+    };
+    let instructions = match cached {
+        Some(_) => {
+            prompt::instructions_without_glossary(context, language, original_lines, preceding)
+        }
+        None => prompt::instructions(context, language, original_lines, preceding),
+    };
+    if context.stream {
+        let prompt = format!(
+            "{}{}{}",
+            instructions,
+            emphasis,
+            text::prompt(language, original_lines.len())
+        );
+        let mut watch = text::Watch::new(context, original_lines.len());
+        let response = stream_request(
+            context,
+            system_instruction(context),
+            cached,
+            &prompt,
+            &user_contents,
+            |delta| watch.push(delta),
+        )
+        .await;
+        drop(watch);
+        let response = response?;
+        return Ok(BulkTranslated {
+            translated_lines: text::parse(&response.text),
+            exchange: Exchange::keep(
+                context,
+                system_instruction(context),
+                &prompt,
+                &user_contents,
+                false,
+                &response.text,
+            ),
+            stats: response.stats.into(),
+            truncated: response.truncated,
+            ..BulkTranslated::default()
+        });
     }
 
     let prompt = format!(
-        "You are an expert translator of fantasy literature, proficient in multiple languages including Vietnamese and Han-Viet (Sino-Vietnamese), with a deep understanding of East Asian storytelling styles. I am providing you with a text segment from the novel 'Omniscient Reader’s Viewpoint' (Vietnamese title: 'Toàn trí độc giả'), a renowned Korean fantasy work translated into English. Your task is to translate this text into {} with the highest quality, adhering to the following requirements and rules:\n\
-        1. Preserve the original storytelling style—vivid, humorous, and tense—as it appears in the source text.\n\
-        2. If the target language is Vietnamese, use Han-Viet vocabulary for skill names, Constellation titles, and key concepts to create a formal, captivating tone that resonates with East Asian fantasy aesthetics. Specifically for Vietnamese:\n\
-        - Translate 'Secretive Plotter' as 'Kẻ Mưu Phản Bí Mật'.\n\
-        - Translate 'Black Flame Dragon' as 'Hắc Hỏa Vực Long'.\n\
-        - Translate 'Prisoner of the Golden Headband' as 'Chủ Nhân của Vòng Kim Cô', and apply a similar style to other Constellation titles.\n\
-        - Translate general terms as follows: 'Streamer' to 'Kẻ Phát Thanh', 'Scenario' to 'Kịch Bản', 'Incarnation' to 'Hóa Thân'.\n\
-        3. Ensure no English or Chinese words remain in the translation—convert everything into the target language (except proper names like 'Kim Dokja' or 'Yoo Joonghyuk').\n\
-        4. Produce a natural, fluent, and engaging translation that appeals to readers of fantasy literature in the target language.\n\
-        Additional Translation Rules:\n\
-        - Maintain semantic accuracy: Do not alter the meaning or intent of the original text.\n\
-        - Avoid unnecessary repetition: Use varied vocabulary where appropriate to enhance readability, but keep key terms consistent.\n\
-        - Prioritize consistency: Apply the same translation for recurring names, skills, or concepts throughout the text.\n\
-        - Adapt idioms or cultural references: Localize them into equivalents that fit the fantasy context of the target language.\n\
-        - Enhance tone where needed: Amplify the dramatic or emotional impact using expressive phrasing suited to the target language (e.g., Han-Viet for Vietnamese).\n\
-        Translate it into {}. Please output the following JSON.\n\
+        "{}{}Translate it into {}. Please output the following JSON.\n\
         A string in `<paragraph>` tag to `</paragraph>` tag is one paragraph.\n\
         If a paragraph of input is translated and consists of multiple sentences, output an array consisting of multiple Strings.\n\
         There are {} paragraphs of input, please output {} lines.\n\
@@ -136,36 +197,100 @@ async fn translate_bulk(
         Paragraph = {{\"line\": number, \"text\": list[string]}}\n\
         Return a `list[Paragraph]`.\n\
         Please remove `<paragraph>` and `</paragraph>` tags from the translation result.\n\
-        Here is the text to translate:\n{}", 
-        language, 
-        language, 
-        &original_lines.len(), 
-        &original_lines.len(), 
+        Here is the text to translate:\n{}",
+        instructions,
+        emphasis,
+        language,
+        &original_lines.len(),
+        &original_lines.len(),
         &original_lines.join("\n")
     );
 
-    let response = request(model, api_key, &prompt, &user_contents)
-        .await
-        .expect("Gemini API Request Error");
-    let translated_vec = serde_json::from_str::<Vec<Translated>>(response.text.trim());
-    if translated_vec.is_err() {
-        error!("JSON Parse error choice:{}", &response.text.trim());
-        return BulkTranslated {
-            number,
-            original_lines,
-            translated_lines: vec![],
-            stats: response.stats,
-        };
-    }
-    let mut translated_lines = vec![];
-    for result in translated_vec.unwrap() {
-        translated_lines.push(result.text.join("\n"));
-    }
+    let response = request(
+        context,
+        system_instruction(context),
+        cached,
+        &prompt,
+        &user_contents,
+        Some(schema()),
+    )
+    .await?;
+    let Ok(paragraphs) = parse_numbered(&response.text) else {
+        error!(stage = "parse"; "JSON Parse error choice:{}", &response.text.trim());
+        return Ok(BulkTranslated {
+            exchange: Exchange::keep(
+                context,
+                system_instruction(context),
+                &prompt,
+                &user_contents,
+                true,
+                &response.text,
+            ),
+            stats: response.stats.into(),
+            truncated: response.truncated,
+            ..BulkTranslated::default()
+        });
+    };
 
-    BulkTranslated {
-        number,
-        original_lines,
-        translated_lines,
-        stats: response.stats,
-    }
+    let exchange = Exchange::keep(
+        context,
+        system_instruction(context),
+        &prompt,
+        &user_contents,
+        true,
+        &response.text,
+    );
+    Ok(BulkTranslated {
+        truncated: response.truncated,
+        exchange,
+        ..BulkTranslated::numbered(context, paragraphs, original_lines, response.stats.into())
+    })
+}
+
+/// Parse a JSON mode response into paragraphs ordered by their `line`.
+pub(crate) fn parse(numbering: LineNumbering, text: &str) -> serde_json::Result<Vec<String>> {
+    Ok(reorder(numbering, parse_numbered(text)?))
+}
+
+/// Parse a JSON mode response into paragraphs with their `line`, in the
+/// order of the response.
+fn parse_numbered(text: &str) -> serde_json::Result<Vec<(Option<i64>, String)>> {
+    let translated = json::parse::<Vec<Translated>>(text)?;
+    Ok(translated
+        .into_iter()
+        .map(|result| (result.line, result.text.join("\n")))
+        .collect())
+}
+
+/// Schema of a `list[Paragraph]` response, in the OpenAPI subset of
+/// `responseSchema`.
+fn schema() -> Value {
+    json!({
+        "type": "ARRAY",
+        "items": {
+            "type": "OBJECT",
+            "properties": {
+                "line": {"type": "INTEGER"},
+                "text": {"type": "ARRAY", "items": {"type": "STRING"}}
+            },
+            "required": ["line", "text"]
+        }
+    })
+}
+
+/// What `--cache-prompt` caches: the system instruction, and the whole
+/// glossary instead of the terms of each chunk.
+fn preamble(context: &Context) -> String {
+    format!(
+        "{}\n\n{}",
+        system_instruction(context),
+        context.glossary.render_all()
+    )
+}
+
+fn system_instruction(context: &Context) -> &str {
+    context
+        .system_instruction
+        .as_deref()
+        .unwrap_or(DEFAULT_SYSTEM_INSTRUCTION)
 }
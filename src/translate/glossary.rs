@@ -0,0 +1,306 @@
+use regex::{NoExpand, RegexBuilder};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// Fixed translations of recurring terms, kept sorted so the same glossary
+/// always renders the same prompt text.
+#[derive(Clone, Debug, Default)]
+pub struct Glossary {
+    terms: BTreeMap<String, Term>,
+}
+
+#[derive(Clone, Debug)]
+struct Term {
+    target: String,
+    notes: Option<String>,
+}
+
+impl Glossary {
+    /// Read a glossary file: `source,target[,notes]` rows when the file ends
+    /// in `.csv`, `source = target` lines otherwise. Blank lines and `#`
+    /// comments are skipped, and so is a `source,target` header row.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let csv = is_csv(path);
+        let mut glossary = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = if csv {
+                csv_fields(line)
+            } else {
+                line.splitn(2, '=').map(str::to_string).collect()
+            };
+            let (source, target) = match fields.as_slice() {
+                [source, target, ..] => (source.trim(), target.trim()),
+                _ => {
+                    let expected = if csv {
+                        "source,target[,notes]"
+                    } else {
+                        "source = target"
+                    };
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}:{}: expected `{}`", path.display(), number + 1, expected),
+                    ));
+                }
+            };
+            if csv && number == 0 && source.eq_ignore_ascii_case("source") {
+                continue;
+            }
+            let notes = fields.get(2).map(|notes| notes.trim());
+            glossary.insert_with_notes(source, target, notes.filter(|notes| !notes.is_empty()));
+        }
+        Ok(glossary)
+    }
+
+    pub fn insert(&mut self, source: &str, target: &str) {
+        self.insert_with_notes(source, target, None);
+    }
+
+    pub fn insert_with_notes(&mut self, source: &str, target: &str, notes: Option<&str>) {
+        self.terms.insert(
+            source.to_string(),
+            Term {
+                target: target.to_string(),
+                notes: notes.map(str::to_string),
+            },
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    pub fn contains(&self, source: &str) -> bool {
+        self.terms.contains_key(source)
+    }
+
+    /// Add the terms of `other` not defined here.
+    pub fn merge(&mut self, other: Glossary) {
+        for (source, term) in other.terms {
+            self.terms.entry(source).or_insert(term);
+        }
+    }
+
+    /// Write the glossary in the format [`Glossary::load`] reads from `path`;
+    /// notes become a comment above the term outside of CSV.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let csv = is_csv(path);
+        let mut text = String::new();
+        if csv {
+            text.push_str("source,target,notes\n");
+        }
+        for (source, term) in &self.terms {
+            let notes = term.notes.as_deref().unwrap_or_default();
+            if csv {
+                let fields = [source.as_str(), term.target.as_str(), notes];
+                let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                text.push_str(&fields.join(","));
+            } else {
+                if !notes.is_empty() {
+                    text.push_str(&format!("# {}\n", notes));
+                }
+                text.push_str(&format!("{} = {}", source, term.target));
+            }
+            text.push('\n');
+        }
+        std::fs::write(path, text)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Prompt lines for the terms occurring in `lines`, in sorted order.
+    pub fn render(&self, lines: &[String]) -> String {
+        let text = lines.join("\n").to_lowercase();
+        self.render_terms(|source| text.contains(&source.to_lowercase()))
+    }
+
+    /// Prompt lines for every term, in sorted order, as sent once for all
+    /// the requests of a run.
+    pub fn render_all(&self) -> String {
+        self.render_terms(|_| true)
+    }
+
+    fn render_terms(&self, occurs: impl Fn(&str) -> bool) -> String {
+        let terms: Vec<String> = self
+            .terms
+            .iter()
+            .filter(|(source, _)| occurs(source))
+            .map(|(source, term)| match &term.notes {
+                Some(notes) => format!(
+                    "- Translate '{}' as '{}' ({}).\n",
+                    source, term.target, notes
+                ),
+                None => format!("- Translate '{}' as '{}'.\n", source, term.target),
+            })
+            .collect();
+        if terms.is_empty() {
+            return String::new();
+        }
+        format!(
+            "Use these translations for recurring terms:\n{}",
+            terms.concat()
+        )
+    }
+
+    /// Replace the terms of each source paragraph that the model left
+    /// untranslated in its translation with their glossary translation.
+    pub fn enforce(&self, sources: &[String], translated: &mut [String]) {
+        for (source, translation) in sources.iter().zip(translated) {
+            let source = source.to_lowercase();
+            for (term, entry) in &self.terms {
+                if !source.contains(&term.to_lowercase()) {
+                    continue;
+                }
+                let pattern = format!(r"\b{}\b", regex::escape(term));
+                let Ok(regex) = RegexBuilder::new(&pattern).case_insensitive(true).build() else {
+                    continue;
+                };
+                if regex.is_match(translation) {
+                    *translation = regex
+                        .replace_all(translation, NoExpand(&entry.target))
+                        .into_owned();
+                }
+            }
+        }
+    }
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"))
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Split a CSV row into fields, with `"` quoting and `""` escapes.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    fn temp(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "trans-epub-glossary-{}-{}",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn glossary() -> Glossary {
+        let mut glossary = Glossary::default();
+        glossary.insert("Mana", "Mana");
+        glossary.insert_with_notes("Tower", "Turm", Some("the tower of trials, \"the\" one"));
+        glossary
+    }
+
+    #[test]
+    fn glossaries_are_saved_as_they_are_loaded() {
+        let path = temp("terms.csv");
+        glossary().save(&path).unwrap();
+        let loaded = Glossary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.render_all(), glossary().render_all());
+        // outside of CSV the notes are a comment, which is not read back
+        let path = temp("terms.txt");
+        glossary().save(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "Mana = Mana\n# the tower of trials, \"the\" one\nTower = Turm\n"
+        );
+        let loaded = Glossary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded
+            .render_all()
+            .ends_with("- Translate 'Tower' as 'Turm'.\n"));
+    }
+
+    #[test]
+    fn glossary_files_skip_comments_and_headers() {
+        let path = temp("read.csv");
+        std::fs::write(
+            &path,
+            "source,target\n# a comment\n\n\"Seoul, Korea\",Seoul\nblade , Klinge ,\n",
+        )
+        .unwrap();
+        let loaded = Glossary::load(&path).unwrap();
+        assert_eq!(
+            loaded.render_all(),
+            "Use these translations for recurring terms:\n\
+             - Translate 'Seoul, Korea' as 'Seoul'.\n\
+             - Translate 'blade' as 'Klinge'.\n"
+        );
+        std::fs::write(&path, "source,target\nonly a source\n").unwrap();
+        let error = Glossary::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error
+            .to_string()
+            .ends_with(":2: expected `source,target[,notes]`"));
+    }
+
+    #[test]
+    fn prompts_list_the_terms_occurring() {
+        assert_eq!(
+            glossary().render(&texts(&["The TOWER stood."])),
+            "Use these translations for recurring terms:\n\
+             - Translate 'Tower' as 'Turm' (the tower of trials, \"the\" one).\n"
+        );
+        assert_eq!(glossary().render(&texts(&["Nothing here."])), "");
+    }
+
+    #[test]
+    fn merging_keeps_the_terms_defined() {
+        let mut merged = glossary();
+        let mut other = Glossary::default();
+        other.insert("Tower", "Burg");
+        other.insert("Guild", "Gilde");
+        merged.merge(other);
+        assert_eq!(merged.len(), 3);
+        assert!(merged.contains("Guild"));
+        assert!(merged.render_all().contains("'Tower' as 'Turm'"));
+    }
+
+    #[test]
+    fn untranslated_terms_are_replaced() {
+        let sources = texts(&["The Tower of mana.", "A towering wave."]);
+        let mut translated = texts(&["Der tower des Mana.", "Eine towering Welle."]);
+        glossary().enforce(&sources, &mut translated);
+        assert_eq!(translated, ["Der Turm des Mana.", "Eine towering Welle."]);
+    }
+}
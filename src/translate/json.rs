@@ -0,0 +1,63 @@
+use log::debug;
+use serde::de::DeserializeOwned;
+
+/// Parse a model response as JSON, retrying on a repaired copy when it is
+/// wrapped in a markdown fence, surrounded by prose or has trailing commas.
+pub fn parse<T: DeserializeOwned>(text: &str) -> serde_json::Result<T> {
+    let text = text.trim();
+    serde_json::from_str(text).or_else(|e| {
+        let repaired = repair(text);
+        if repaired == text {
+            return Err(e);
+        }
+        debug!("JSON repaired: {}", repaired);
+        serde_json::from_str(&repaired)
+    })
+}
+
+pub fn repair(text: &str) -> String {
+    remove_trailing_commas(outermost(strip_fence(text.trim())))
+}
+
+fn strip_fence(text: &str) -> &str {
+    let Some(rest) = text.strip_prefix("```") else {
+        return text;
+    };
+    let rest = rest.split_once('\n').map_or("", |(_, body)| body);
+    rest.trim_end().strip_suffix("```").unwrap_or(rest).trim()
+}
+
+/// The span from the first opening bracket to the last closing one.
+fn outermost(text: &str) -> &str {
+    let start = text.find(['[', '{']);
+    let end = text.rfind([']', '}']);
+    match (start, end) {
+        (Some(start), Some(end)) if start < end => &text[start..=end],
+        _ => text,
+    }
+}
+
+fn remove_trailing_commas(text: &str) -> String {
+    let mut repaired = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let next = text[index + 1..].trim_start().chars().next();
+            if matches!(next, Some(']' | '}')) {
+                continue;
+            }
+        }
+        repaired.push(c);
+    }
+    repaired
+}
@@ -0,0 +1,574 @@
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LineNumbering {
+    /// Detect 0- or 1-based numbering from the returned values
+    #[default]
+    Auto,
+    /// `line` starts at 0
+    Zero,
+    /// `line` starts at 1
+    One,
+    /// Ignore `line` and keep the array order
+    Ignore,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnFailure {
+    /// Use the original paragraph as its translation
+    Passthrough,
+    /// Leave the paragraph untranslated
+    Skip,
+    /// Accept whatever the model returned, joined into one paragraph
+    #[default]
+    Accept,
+}
+
+/// How a chunk that failed, or came back with another number of paragraphs,
+/// is sent again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RetryStrategy {
+    /// In chunks of --retry-chunk-size paragraphs
+    #[default]
+    Split,
+    /// In two halves, halved again while they fail
+    Halve,
+    /// In chunks of --retry-chunk-size paragraphs, with --retry-model
+    Model,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Whitespace {
+    /// Trim leading and trailing whitespace of every translation
+    Trim,
+    /// Keep the translation as the model returned it
+    #[default]
+    None,
+    /// Give each translation the leading and trailing whitespace of its source
+    Preserve,
+}
+
+/// Normalize the whitespace around each translated paragraph; the number of
+/// paragraphs never changes and untranslated (empty) paragraphs stay empty.
+pub fn whitespace(policy: Whitespace, original_lines: &[String], translated_lines: &mut [String]) {
+    for (original, translated) in original_lines.iter().zip(translated_lines) {
+        if translated.is_empty() {
+            continue;
+        }
+        match policy {
+            Whitespace::None => (),
+            Whitespace::Trim => *translated = translated.trim().to_string(),
+            Whitespace::Preserve => {
+                let body = original.trim_start();
+                let leading = &original[..original.len() - body.len()];
+                let trailing = &body[body.trim_end().len()..];
+                *translated = format!("{}{}{}", leading, translated.trim(), trailing);
+            }
+        }
+    }
+}
+
+/// Resolve a single paragraph whose translation still does not come back as
+/// exactly one line after retrying it on its own.
+pub fn on_failure(
+    policy: OnFailure,
+    mut original_lines: Vec<String>,
+    translated_lines: Vec<String>,
+) -> Vec<String> {
+    match policy {
+        OnFailure::Passthrough => original_lines.truncate(1),
+        OnFailure::Skip => original_lines = vec![String::new()],
+        OnFailure::Accept => original_lines = vec![translated_lines.join("\n")],
+    }
+    original_lines
+}
+
+/// Reorder translated paragraphs by the `line` number returned by the model.
+///
+/// Falls back to the array order when a number is missing, duplicated or out
+/// of range, so a bad numbering never makes things worse than ignoring it.
+pub fn reorder(numbering: LineNumbering, paragraphs: Vec<(Option<i64>, String)>) -> Vec<String> {
+    let Some(indexes) = normalized_indexes(numbering, &paragraphs) else {
+        return paragraphs.into_iter().map(|(_, text)| text).collect();
+    };
+    let mut ordered = vec![String::new(); paragraphs.len()];
+    for (index, (_, text)) in indexes.into_iter().zip(paragraphs) {
+        ordered[index] = text;
+    }
+    ordered
+}
+
+/// Place the translations of a response that left out some of the `sources`
+/// by their `line` number, so they can be kept and only the missing ones sent
+/// again; `None` for a missing one.
+///
+/// Nothing is placed unless every translation has a number of its own
+/// within the chunk and is about as long, for its source, as the others: a
+/// model that merged two paragraphs and numbered the rest in sequence would
+/// otherwise put translations against the wrong paragraphs. A response with
+/// as many or more translations, some paragraph split in two, is never
+/// aligned. Nor is one whose numbering may start at 0 or at 1, unless an
+/// earlier complete response of the run told which.
+pub fn align(
+    numbering: LineNumbering,
+    learned: Option<i64>,
+    paragraphs: &[(Option<i64>, String)],
+    sources: &[String],
+) -> Option<Vec<Option<String>>> {
+    if paragraphs.is_empty() || paragraphs.len() >= sources.len() {
+        return None;
+    }
+    let lines = paragraphs
+        .iter()
+        .map(|(line, _)| *line)
+        .collect::<Option<Vec<i64>>>()?;
+    let base = partial_base(numbering, &lines, sources.len(), learned)?;
+    let mut aligned = vec![None; sources.len()];
+    for (line, (_, text)) in lines.into_iter().zip(paragraphs) {
+        let index = usize::try_from(line - base).ok()?;
+        if aligned.get(index)?.is_some() || text.trim().is_empty() {
+            return None;
+        }
+        aligned[index] = Some(text.clone());
+    }
+    // the length of a translation against its source, for sources long
+    // enough to tell
+    let mut ratios: Vec<f64> = sources
+        .iter()
+        .zip(&aligned)
+        .filter_map(|(source, text)| {
+            let source = source.chars().count();
+            let text = text.as_ref()?.chars().count();
+            (source >= MIN_RATIO_CHARS).then_some(text as f64 / source as f64)
+        })
+        .collect();
+    ratios.sort_by(f64::total_cmp);
+    if let Some(median) = ratios.get(ratios.len() / 2) {
+        let range = median / MAX_RATIO_SPREAD..=median * MAX_RATIO_SPREAD;
+        if !ratios.iter().all(|ratio| range.contains(ratio)) {
+            return None;
+        }
+    }
+    Some(aligned)
+}
+
+/// Shortest source whose translation length is compared in [`align`].
+const MIN_RATIO_CHARS: usize = 20;
+
+/// How far the length of a translation for its source may be from the
+/// median of the chunk in [`align`], as a factor.
+const MAX_RATIO_SPREAD: f64 = 2.5;
+
+fn normalized_indexes(
+    numbering: LineNumbering,
+    paragraphs: &[(Option<i64>, String)],
+) -> Option<Vec<usize>> {
+    let lines = paragraphs
+        .iter()
+        .map(|(line, _)| *line)
+        .collect::<Option<Vec<i64>>>()?;
+    let base = base(numbering, &lines)?;
+
+    let mut seen = vec![false; lines.len()];
+    let mut indexes = Vec::with_capacity(lines.len());
+    for line in lines {
+        let index = usize::try_from(line - base).ok()?;
+        if *seen.get(index)? {
+            return None;
+        }
+        seen[index] = true;
+        indexes.push(index);
+    }
+    Some(indexes)
+}
+
+/// The number of the first line, `None` when the numbering is ignored or is
+/// neither 0- nor 1-based.
+fn base(numbering: LineNumbering, lines: &[i64]) -> Option<i64> {
+    match numbering {
+        LineNumbering::Ignore => None,
+        LineNumbering::Zero => Some(0),
+        LineNumbering::One => Some(1),
+        LineNumbering::Auto => match lines.iter().min() {
+            Some(min @ (0 | 1)) => Some(*min),
+            _ => None,
+        },
+    }
+}
+
+/// The number of the first line of a response that left some out, when it
+/// can be told: with `Auto`, its smallest number is that of the first line
+/// only when that line is there, so the numbering is 0-based when a line is
+/// numbered 0 and 1-based when one is numbered as the last of `expected`,
+/// and is otherwise that `learned` from the complete responses of the run.
+fn partial_base(
+    numbering: LineNumbering,
+    lines: &[i64],
+    expected: usize,
+    learned: Option<i64>,
+) -> Option<i64> {
+    if numbering != LineNumbering::Auto {
+        return base(numbering, lines);
+    }
+    if lines.contains(&0) {
+        Some(0)
+    } else if lines.contains(&(expected as i64)) {
+        Some(1)
+    } else {
+        learned
+    }
+}
+
+/// The number of the first line of a complete response, its lines each
+/// numbered once, to be `learned` for the [`align`]ment of the next ones.
+pub fn complete_base(
+    numbering: LineNumbering,
+    paragraphs: &[(Option<i64>, String)],
+) -> Option<i64> {
+    let lines = paragraphs
+        .iter()
+        .map(|(line, _)| *line)
+        .collect::<Option<Vec<i64>>>()?;
+    normalized_indexes(numbering, paragraphs)?;
+    base(numbering, &lines)
+}
+
+/// What is wrong with the `line` numbers of a response to `expected`
+/// paragraphs, such as `missing lines 3, 7; repeated line 5`, or `None` when
+/// each line is there exactly once or the response is not numbered.
+pub fn problems(
+    numbering: LineNumbering,
+    paragraphs: &[(Option<i64>, String)],
+    expected: usize,
+) -> Option<String> {
+    if numbering == LineNumbering::Ignore {
+        return None;
+    }
+    let lines: Vec<i64> = paragraphs.iter().filter_map(|(line, _)| *line).collect();
+    if lines.is_empty() {
+        return None;
+    }
+    let unnumbered = paragraphs.len() - lines.len();
+    let Some(base) = base(numbering, &lines) else {
+        return Some(format!(
+            "numbered neither from 0 nor from 1: {}",
+            list(&lines)
+        ));
+    };
+    let mut found = vec![0; expected];
+    let mut outside = Vec::new();
+    for line in lines {
+        match usize::try_from(line - base)
+            .ok()
+            .filter(|index| *index < expected)
+        {
+            Some(index) => found[index] += 1,
+            None => outside.push(line),
+        }
+    }
+    let numbers = |count: fn(usize) -> bool| -> Vec<i64> {
+        (0..expected)
+            .filter(|index| count(found[*index]))
+            .map(|index| index as i64 + base)
+            .collect()
+    };
+    let mut problems = Vec::new();
+    for (what, lines) in [
+        ("missing", numbers(|found| found == 0)),
+        ("repeated", numbers(|found| found > 1)),
+        ("out of range", outside),
+    ] {
+        match lines.len() {
+            0 => {}
+            1 => problems.push(format!("{} line {}", what, lines[0])),
+            _ => problems.push(format!("{} lines {}", what, list(&lines))),
+        }
+    }
+    if unnumbered > 0 {
+        problems.push(format!("{} without a line", unnumbered));
+    }
+    (!problems.is_empty()).then(|| problems.join("; "))
+}
+
+fn list(lines: &[i64]) -> String {
+    let lines: Vec<String> = lines.iter().map(i64::to_string).collect();
+    lines.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(lines: &[Option<i64>]) -> Vec<(Option<i64>, String)> {
+        lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| (*line, format!("p{}", i)))
+            .collect()
+    }
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    fn sources(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("source {}", i)).collect()
+    }
+
+    #[test]
+    fn reorder_zero_based() {
+        let paragraphs = numbered(&[Some(2), Some(0), Some(1)]);
+        for numbering in [LineNumbering::Auto, LineNumbering::Zero] {
+            assert_eq!(
+                reorder(numbering, paragraphs.clone()),
+                texts(&["p1", "p2", "p0"])
+            );
+        }
+    }
+
+    #[test]
+    fn reorder_one_based() {
+        let paragraphs = numbered(&[Some(3), Some(1), Some(2)]);
+        for numbering in [LineNumbering::Auto, LineNumbering::One] {
+            assert_eq!(
+                reorder(numbering, paragraphs.clone()),
+                texts(&["p1", "p2", "p0"])
+            );
+        }
+    }
+
+    #[test]
+    fn reorder_ignored() {
+        let paragraphs = numbered(&[Some(2), Some(0), Some(1)]);
+        assert_eq!(
+            reorder(LineNumbering::Ignore, paragraphs),
+            texts(&["p0", "p1", "p2"])
+        );
+    }
+
+    #[test]
+    fn reorder_falls_back_to_the_array_order() {
+        let in_order = texts(&["p0", "p1", "p2"]);
+        for (what, lines) in [
+            ("missing", [Some(1), None, Some(0)]),
+            ("duplicated", [Some(0), Some(1), Some(1)]),
+            ("out of range", [Some(0), Some(1), Some(5)]),
+            ("negative", [Some(-1), Some(0), Some(1)]),
+            ("from 2", [Some(4), Some(2), Some(3)]),
+        ] {
+            for numbering in [LineNumbering::Auto, LineNumbering::Zero] {
+                assert_eq!(
+                    reorder(numbering, numbered(&lines)),
+                    in_order,
+                    "{} with {:?}",
+                    what,
+                    numbering
+                );
+            }
+        }
+        // 1-based numbers read as 0-based are out of range
+        assert_eq!(
+            reorder(LineNumbering::Zero, numbered(&[Some(3), Some(1), Some(2)])),
+            in_order
+        );
+        // 0-based numbers read as 1-based go below the first line
+        assert_eq!(
+            reorder(LineNumbering::One, numbered(&[Some(2), Some(0), Some(1)])),
+            in_order
+        );
+    }
+
+    #[test]
+    fn align_places_a_zero_based_response_missing_a_line() {
+        let aligned = align(
+            LineNumbering::Auto,
+            None,
+            &numbered(&[Some(0), Some(2)]),
+            &sources(3),
+        );
+        assert_eq!(
+            aligned,
+            Some(vec![Some("p0".to_string()), None, Some("p1".to_string())])
+        );
+    }
+
+    #[test]
+    fn align_places_a_one_based_response_missing_its_first_line() {
+        let aligned = align(
+            LineNumbering::Auto,
+            None,
+            &numbered(&[Some(2), Some(3)]),
+            &sources(3),
+        );
+        assert_eq!(
+            aligned,
+            Some(vec![None, Some("p0".to_string()), Some("p1".to_string())])
+        );
+    }
+
+    #[test]
+    fn align_leaves_an_ambiguous_numbering() {
+        // a 0-based response without line 0 starts at 1, as would a 1-based
+        // one without its last line
+        let paragraphs = numbered(&[Some(1), Some(2)]);
+        assert_eq!(
+            align(LineNumbering::Auto, None, &paragraphs, &sources(4)),
+            None
+        );
+        assert_eq!(
+            align(LineNumbering::Auto, Some(0), &paragraphs, &sources(4)),
+            Some(vec![
+                None,
+                Some("p0".to_string()),
+                Some("p1".to_string()),
+                None
+            ])
+        );
+        assert_eq!(
+            align(LineNumbering::Auto, Some(1), &paragraphs, &sources(4)),
+            Some(vec![
+                Some("p0".to_string()),
+                Some("p1".to_string()),
+                None,
+                None
+            ])
+        );
+        assert_eq!(
+            align(LineNumbering::Zero, None, &paragraphs, &sources(4)),
+            Some(vec![
+                None,
+                Some("p0".to_string()),
+                Some("p1".to_string()),
+                None
+            ])
+        );
+    }
+
+    #[test]
+    fn align_leaves_a_bad_numbering() {
+        for (what, lines) in [
+            ("unnumbered", vec![Some(0), None]),
+            ("duplicated", vec![Some(0), Some(0)]),
+            ("out of range", vec![Some(0), Some(7)]),
+        ] {
+            assert_eq!(
+                align(LineNumbering::Auto, None, &numbered(&lines), &sources(3)),
+                None,
+                "{}",
+                what
+            );
+        }
+        // as many translations as paragraphs is not a response missing any
+        assert_eq!(
+            align(
+                LineNumbering::Auto,
+                None,
+                &numbered(&[Some(0), Some(1), Some(2)]),
+                &sources(3)
+            ),
+            None
+        );
+        assert_eq!(
+            align(
+                LineNumbering::Ignore,
+                None,
+                &numbered(&[Some(0)]),
+                &sources(3)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn align_leaves_translations_of_unlikely_lengths() {
+        let sources = texts(&[
+            "A first paragraph, long enough to compare.",
+            "A second paragraph, long enough to compare.",
+            "A third paragraph, long enough to compare.",
+            "A fourth paragraph, long enough to compare.",
+        ]);
+        let paragraphs = vec![
+            (Some(0), "Ein erster Absatz, lang genug.".to_string()),
+            (Some(1), "Ein zweiter Absatz, lang genug.".to_string()),
+            (
+                Some(2),
+                "Ein dritter Absatz, lang genug, und dazu der ganze vierte Absatz, \
+                 der mit ihm zusammengelegt wurde, samt allem, was er sagt."
+                    .to_string(),
+            ),
+        ];
+        assert_eq!(
+            align(LineNumbering::Zero, None, &paragraphs, &sources),
+            None
+        );
+    }
+
+    #[test]
+    fn complete_base_of_a_response() {
+        let zero = numbered(&[Some(1), Some(0)]);
+        let one = numbered(&[Some(1), Some(2)]);
+        assert_eq!(complete_base(LineNumbering::Auto, &zero), Some(0));
+        assert_eq!(complete_base(LineNumbering::Auto, &one), Some(1));
+        assert_eq!(
+            complete_base(LineNumbering::Auto, &numbered(&[Some(0), Some(0)])),
+            None
+        );
+        assert_eq!(complete_base(LineNumbering::Ignore, &zero), None);
+    }
+
+    #[test]
+    fn problems_of_the_numbering() {
+        assert_eq!(
+            problems(LineNumbering::Auto, &numbered(&[Some(0), Some(1)]), 2),
+            None
+        );
+        assert_eq!(
+            problems(
+                LineNumbering::Auto,
+                &numbered(&[Some(0), Some(2), Some(2), Some(9), None]),
+                4
+            ),
+            Some(
+                "missing lines 1, 3; repeated line 2; out of range line 9; 1 without a line"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            problems(LineNumbering::Auto, &numbered(&[Some(3), Some(4)]), 2),
+            Some("numbered neither from 0 nor from 1: 3, 4".to_string())
+        );
+        assert_eq!(
+            problems(LineNumbering::Ignore, &numbered(&[Some(3)]), 1),
+            None
+        );
+    }
+
+    #[test]
+    fn on_failure_policies() {
+        let original = texts(&["source"]);
+        let translated = texts(&["one", "two"]);
+        assert_eq!(
+            on_failure(OnFailure::Passthrough, original.clone(), translated.clone()),
+            original
+        );
+        assert_eq!(
+            on_failure(OnFailure::Skip, original.clone(), translated.clone()),
+            texts(&[""])
+        );
+        assert_eq!(
+            on_failure(OnFailure::Accept, original, translated),
+            texts(&["one\ntwo"])
+        );
+    }
+
+    #[test]
+    fn whitespace_policies() {
+        let original = texts(&["  source\n", "other"]);
+        let mut trimmed = texts(&[" text ", ""]);
+        whitespace(Whitespace::Trim, &original, &mut trimmed);
+        assert_eq!(trimmed, texts(&["text", ""]));
+        let mut preserved = texts(&[" text ", ""]);
+        whitespace(Whitespace::Preserve, &original, &mut preserved);
+        assert_eq!(preserved, texts(&["  text\n", ""]));
+    }
+}
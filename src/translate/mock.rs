@@ -0,0 +1,87 @@
+use crate::error::Error;
+use crate::pipeline::Provider;
+use crate::translate::chunk;
+use crate::translate::translator::{Backend, BulkTranslated, Context, Preceding, Stats};
+use clap::ValueEnum;
+use futures::future::BoxFuture;
+use regex::Regex;
+
+/// What the mock backend makes of a paragraph, with `--transform`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Transform {
+    /// Wrap it in markers naming the target language, as `[German] text`
+    #[default]
+    Wrap,
+    /// Reverse its text, leaving the markup tags where they are
+    Reverse,
+}
+
+/// A translator that transforms each paragraph the same way every time,
+/// without sending anything, to test and benchmark a whole run offline.
+/// The tokens are estimated as for `estimate`.
+#[derive(Default)]
+pub struct Mock {
+    pub transform: Transform,
+}
+
+impl Backend for Mock {
+    fn translate_bulk<'a>(
+        &'a self,
+        _context: &'a Context,
+        language: &'a str,
+        lines: &'a [String],
+        _preceding: &'a [Preceding],
+    ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+        Box::pin(async move {
+            let translated_lines: Vec<String> = lines
+                .iter()
+                .map(|line| self.transform.apply(language, line))
+                .collect();
+            let prompt_tokens = lines.iter().map(|line| chunk::estimate_tokens(line)).sum();
+            let output_tokens = translated_lines
+                .iter()
+                .map(|line| chunk::estimate_tokens(line))
+                .sum();
+            Ok(BulkTranslated {
+                translated_lines,
+                stats: stats(prompt_tokens, output_tokens),
+                ..BulkTranslated::default()
+            })
+        })
+    }
+
+    fn provider(&self) -> Option<Provider> {
+        Some(Provider::Mock)
+    }
+}
+
+impl Transform {
+    pub fn apply(self, language: &str, text: &str) -> String {
+        match self {
+            Transform::Wrap => format!("[{}] {}", language, text),
+            Transform::Reverse => {
+                // the numbered tags of --preserve-markup
+                let markup = Regex::new(r"⟦/?\d+/?⟧").unwrap();
+                let mut reversed = String::new();
+                let mut last = 0;
+                for tag in markup.find_iter(text) {
+                    reversed.extend(text[last..tag.start()].chars().rev());
+                    reversed.push_str(tag.as_str());
+                    last = tag.end();
+                }
+                reversed.extend(text[last..].chars().rev());
+                reversed
+            }
+        }
+    }
+}
+
+fn stats(prompt_tokens: usize, output_tokens: usize) -> Stats {
+    let (prompt_tokens, output_tokens) = (prompt_tokens as i32, output_tokens as i32);
+    Stats {
+        prompt_tokens,
+        output_tokens,
+        total_tokens: prompt_tokens + output_tokens,
+        cached_tokens: 0,
+    }
+}
@@ -0,0 +1,202 @@
+use crate::client;
+use crate::client::ollama::{request, stream_request, Stats};
+use crate::error::Error;
+use crate::pipeline::Provider;
+use crate::translate::dump::Exchange;
+use crate::translate::emphasis;
+use crate::translate::open_ai::{parse_numbered, schema};
+use crate::translate::prompt;
+use crate::translate::text;
+use crate::translate::translator::{self, Backend, BulkTranslated, Completion, Context, Preceding};
+use futures::future::BoxFuture;
+use log::error;
+
+const DEFAULT_SYSTEM_INSTRUCTION: &str = "You are an excellent translator.";
+
+/// Translation with a local model served by Ollama.
+pub struct Ollama;
+
+impl Backend for Ollama {
+    fn translate_bulk<'a>(
+        &'a self,
+        context: &'a Context,
+        language: &'a str,
+        lines: &'a [String],
+        preceding: &'a [Preceding],
+    ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+        Box::pin(translate_bulk(context, language, lines, preceding))
+    }
+
+    fn provider(&self) -> Option<Provider> {
+        Some(Provider::Ollama)
+    }
+
+    fn probe<'a>(&'a self, context: &'a Context) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(client::ollama::probe(context))
+    }
+
+    fn complete<'a>(
+        &'a self,
+        context: &'a Context,
+        prompt: &'a str,
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async move {
+            let response = request(context, system_instruction(context), prompt, &[], None)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Completion {
+                text: response.text,
+                stats: response.stats.into(),
+            })
+        })
+    }
+
+    fn replay<'a>(
+        &'a self,
+        context: &'a Context,
+        exchange: &'a Exchange,
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async move {
+            let response = request(
+                context,
+                &exchange.system_instruction,
+                &exchange.prompt,
+                &exchange.contents,
+                exchange.structured.then(schema),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            Ok(Completion {
+                text: response.text,
+                stats: response.stats.into(),
+            })
+        })
+    }
+}
+
+impl From<Stats> for translator::Stats {
+    fn from(stats: Stats) -> Self {
+        Self {
+            prompt_tokens: stats.prompt_eval_count,
+            output_tokens: stats.eval_count,
+            total_tokens: stats.total(),
+            cached_tokens: 0,
+        }
+    }
+}
+
+async fn translate_bulk(
+    context: &Context,
+    language: &str,
+    original_lines: &[String],
+    preceding: &[Preceding],
+) -> Result<BulkTranslated, Error> {
+    let mut user_contents: Vec<String> = vec![];
+    let mut emphasized = false;
+    for line in original_lines {
+        let caps = context.preserve_emphasis && emphasis::is_all_caps(line);
+        emphasized |= caps;
+        user_contents.push(emphasis::tag(line, caps));
+    }
+    let emphasis = if emphasized {
+        emphasis::INSTRUCTION
+    } else {
+        ""
+    };
+
+    let instructions = prompt::instructions(context, language, original_lines, preceding);
+    if context.stream {
+        let prompt = format!(
+            "{}{}{}",
+            instructions,
+            emphasis,
+            text::prompt(language, original_lines.len())
+        );
+        let mut watch = text::Watch::new(context, original_lines.len());
+        let response = stream_request(
+            context,
+            system_instruction(context),
+            &prompt,
+            &user_contents,
+            |delta| watch.push(delta),
+        )
+        .await;
+        drop(watch);
+        let response = response?;
+        return Ok(BulkTranslated {
+            translated_lines: text::parse(&response.text),
+            exchange: Exchange::keep(
+                context,
+                system_instruction(context),
+                &prompt,
+                &user_contents,
+                false,
+                &response.text,
+            ),
+            stats: response.stats.into(),
+            ..BulkTranslated::default()
+        });
+    }
+
+    let prompt = format!(
+        "{}{}Translate it into {}. Please output the following JSON.\n\
+        A string in `<paragraph>` tag to `</paragraph>` tag is one paragraph.\n\
+        The value of the `results` Key is an array type.\n\
+        Please output one line for each paragraph entered.\n\
+        There are {} paragraphs of input, please output {} lines.\n\
+        The value of `line` Key is a number type.\n\
+        Please output the number of the input paragraph.\n\
+        The value of `translated` Key is an array of String type.\n\
+        If a paragraph of input is translated and a paragraph consists of multiple sentences, output an array consisting of multiple String.\n\
+        Please remove `<paragraph>` and `</paragraph>` tags from the translation result.",
+        instructions,
+        emphasis,
+        language,
+        &original_lines.len(),
+        &original_lines.len()
+    );
+
+    let response = request(
+        context,
+        system_instruction(context),
+        &prompt,
+        &user_contents,
+        Some(schema()),
+    )
+    .await?;
+    let Ok(paragraphs) = parse_numbered(&response.text) else {
+        error!(stage = "parse"; "JSON Parse error choice:{}", &response.text.trim());
+        return Ok(BulkTranslated {
+            exchange: Exchange::keep(
+                context,
+                system_instruction(context),
+                &prompt,
+                &user_contents,
+                true,
+                &response.text,
+            ),
+            stats: response.stats.into(),
+            ..BulkTranslated::default()
+        });
+    };
+
+    let exchange = Exchange::keep(
+        context,
+        system_instruction(context),
+        &prompt,
+        &user_contents,
+        true,
+        &response.text,
+    );
+    Ok(BulkTranslated {
+        exchange,
+        ..BulkTranslated::numbered(context, paragraphs, original_lines, response.stats.into())
+    })
+}
+
+fn system_instruction(context: &Context) -> &str {
+    context
+        .system_instruction
+        .as_deref()
+        .unwrap_or(DEFAULT_SYSTEM_INSTRUCTION)
+}
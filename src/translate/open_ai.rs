@@ -1,8 +1,20 @@
-use crate::client::open_ai::{request, Ratelimit, Stats};
-use crate::translate::translator::Context;
-use futures::{stream, StreamExt};
-use log::{debug, error, trace};
+use crate::client;
+use crate::client::open_ai::{request, stream_request, Stats};
+use crate::error::Error;
+use crate::pipeline::Provider;
+use crate::translate::dump::Exchange;
+use crate::translate::emphasis;
+use crate::translate::json;
+use crate::translate::line::{reorder, LineNumbering};
+use crate::translate::prompt;
+use crate::translate::text;
+use crate::translate::translator::{self, Backend, BulkTranslated, Completion, Context, Preceding};
+use futures::future::BoxFuture;
+use log::error;
 use serde::Deserialize;
+use serde_json::{json, Value};
+
+const DEFAULT_SYSTEM_INSTRUCTION: &str = "You are an excellent translator.";
 
 #[derive(Deserialize)]
 struct ChoiceContent {
@@ -11,116 +23,138 @@ struct ChoiceContent {
 
 #[derive(Deserialize)]
 struct ChoiceContentResult {
+    line: Option<i64>,
     translated: Vec<String>,
 }
 
-pub struct BulkTranslated {
-    pub number: i32,
-    pub original_lines: Vec<String>,
-    pub translated_lines: Vec<String>,
-    pub stats: Stats,
-    pub ratelimit: Ratelimit,
-}
+/// Translation with the OpenAI API or an OpenAI-compatible server.
+pub struct OpenAi;
 
-pub async fn translate(context: &Context, lines: Vec<String>) -> Vec<String> {
-    debug!("line_length:{}", lines.len());
-    if lines.is_empty() {
-        return lines;
+impl Backend for OpenAi {
+    fn translate_bulk<'a>(
+        &'a self,
+        context: &'a Context,
+        language: &'a str,
+        lines: &'a [String],
+        preceding: &'a [Preceding],
+    ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+        Box::pin(translate_bulk(context, language, lines, preceding))
     }
-    translate_parallel(
-        &context.language,
-        &context.model,
-        &context.api_key,
-        lines,
-        context.lines,
-        context.requests,
-        0,
-    )
-    .await
-}
 
-async fn translate_parallel(
-    language: &String,
-    model: &String,
-    api_key: &String,
-    lines: Vec<String>,
-    chunk_lines: usize,
-    requests: usize,
-    retry_count: i32,
-) -> Vec<String> {
-    let mut number = 0;
-    let bodies = stream::iter(lines.chunks(chunk_lines))
-        .map(|chunked| {
-            let language = language.clone();
-            let model = model.clone();
-            let api_key = api_key.clone();
-            number += 1;
-            let order_number = number;
-            async move {
-                translate_bulk(order_number, &language, &model, &api_key, chunked.to_vec()).await
-            }
+    fn provider(&self) -> Option<Provider> {
+        Some(Provider::OpenAi)
+    }
+
+    fn probe<'a>(&'a self, context: &'a Context) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(client::open_ai::probe(context))
+    }
+
+    fn complete<'a>(
+        &'a self,
+        context: &'a Context,
+        prompt: &'a str,
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async move {
+            let response = request(context, system_instruction(context), prompt, &vec![], None)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Completion {
+                text: response.choice,
+                stats: response.stats.into(),
+            })
         })
-        .buffer_unordered(requests);
+    }
 
-    let mut responses = vec![];
-    let mut bodies_stream = bodies;
-    while let Some(response) = bodies_stream.next().await {
-        responses.push(response);
+    fn replay<'a>(
+        &'a self,
+        context: &'a Context,
+        exchange: &'a Exchange,
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async move {
+            let response = request(
+                context,
+                &exchange.system_instruction,
+                &exchange.prompt,
+                &exchange.contents,
+                exchange.structured.then(schema),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            Ok(Completion {
+                text: response.choice,
+                stats: response.stats.into(),
+            })
+        })
     }
+}
 
-    responses.sort_by(|a, b| a.number.cmp(&b.number));
-    let mut translated = vec![];
-    for response in responses {
-        let mut translated_lines = response.translated_lines;
-        let original_lines = response.original_lines;
-        response.stats.log();
-        response.ratelimit.log();
-        if translated_lines.len() != original_lines.len() {
-            if retry_count > 4 {
-                panic!("retry max error");
-            }
-            for l in &original_lines {
-                trace!("{}", l);
-            }
-            for l in &translated_lines {
-                trace!("{}", l);
-            }
-            error!("retry count: {}", retry_count);
-            error!(
-                "translated line length error {}/{}",
-                translated_lines.len(),
-                original_lines.len()
-            );
-            translated_lines = Box::pin(translate_parallel(
-                language,
-                model,
-                api_key,
-                original_lines,
-                1,
-                requests,
-                retry_count + 1,
-            ))
-            .await;
+impl From<Stats> for translator::Stats {
+    fn from(stats: Stats) -> Self {
+        Self {
+            prompt_tokens: stats.prompt_tokens,
+            output_tokens: stats.completion_tokens,
+            total_tokens: stats.total_tokens,
+            cached_tokens: 0,
         }
-        translated.append(&mut translated_lines);
     }
-    translated
 }
 
 async fn translate_bulk(
-    number: i32,
-    language: &String,
-    model: &str,
-    api_key: &String,
-    original_lines: Vec<String>,
-) -> BulkTranslated {
+    context: &Context,
+    language: &str,
+    original_lines: &[String],
+    preceding: &[Preceding],
+) -> Result<BulkTranslated, Error> {
     let mut user_contents: Vec<String> = vec![];
-    for line in &original_lines {
-        user_contents.push(format!("<paragraph>{}</paragraph>", line));
+    let mut emphasized = false;
+    for line in original_lines {
+        let caps = context.preserve_emphasis && emphasis::is_all_caps(line);
+        emphasized |= caps;
+        user_contents.push(emphasis::tag(line, caps));
     }
+    let emphasis = if emphasized {
+        emphasis::INSTRUCTION
+    } else {
+        ""
+    };
 
-    let prompt = format!("You are an excellent translator.\
-        Translate it into {}. Please output the following JSON.\
+    let instructions = prompt::instructions(context, language, original_lines, preceding);
+    if context.stream {
+        let prompt = format!(
+            "{}{}{}",
+            instructions,
+            emphasis,
+            text::prompt(language, original_lines.len())
+        );
+        let mut watch = text::Watch::new(context, original_lines.len());
+        let response = stream_request(
+            context,
+            system_instruction(context),
+            &prompt,
+            &user_contents,
+            |delta| watch.push(delta),
+        )
+        .await;
+        drop(watch);
+        let response = response?;
+        response.ratelimit.log();
+        return Ok(BulkTranslated {
+            translated_lines: text::parse(&response.choice),
+            exchange: Exchange::keep(
+                context,
+                system_instruction(context),
+                &prompt,
+                &user_contents,
+                false,
+                &response.choice,
+            ),
+            stats: response.stats.into(),
+            truncated: response.truncated,
+            ..BulkTranslated::default()
+        });
+    }
+
+    let prompt = format!("{}{}Translate it into {}. Please output the following JSON.\
         A string in `<paragraph>` tag to `</paragraph>` tag is one paragraph.\
         The value of the `results` Key is an array type.\
         Please output one line for each paragraph entered.\
@@ -129,32 +163,91 @@ async fn translate_bulk(
         Please output the number of the input paragraph.\
         The value of `translated` Key is an array of String type.\
         If a paragraph of input is translated and a paragraph consists of multiple sentences, output an array consisting of multiple String.\
-        Please remove `<paragraph>` and `</paragraph>` tags from the translation result.", language, &original_lines.len(), &original_lines.len());
-
-    let response = request(model, api_key, &prompt, &user_contents)
-        .await
-        .expect("OpenAI API Request Error");
-    let choice_content = serde_json::from_str::<ChoiceContent>(response.choice.trim());
-    if choice_content.is_err() {
-        error!("JSON Parse error choice:{}", &response.choice.trim());
-        return BulkTranslated {
-            number,
-            original_lines,
-            translated_lines: vec![],
-            stats: response.stats,
-            ratelimit: response.ratelimit,
-        };
-    }
-    let mut translated_lines = vec![];
-    for result in choice_content.unwrap().results {
-        translated_lines.push(result.translated.join("\n"));
-    }
+        Please remove `<paragraph>` and `</paragraph>` tags from the translation result.", instructions, emphasis, language, &original_lines.len(), &original_lines.len());
 
-    BulkTranslated {
-        number,
-        original_lines,
-        translated_lines,
-        stats: response.stats,
-        ratelimit: response.ratelimit,
-    }
+    let response = request(
+        context,
+        system_instruction(context),
+        &prompt,
+        &user_contents,
+        Some(schema()),
+    )
+    .await?;
+    response.ratelimit.log();
+    let Ok(paragraphs) = parse_numbered(&response.choice) else {
+        error!(stage = "parse"; "JSON Parse error choice:{}", &response.choice.trim());
+        return Ok(BulkTranslated {
+            exchange: Exchange::keep(
+                context,
+                system_instruction(context),
+                &prompt,
+                &user_contents,
+                true,
+                &response.choice,
+            ),
+            stats: response.stats.into(),
+            truncated: response.truncated,
+            ..BulkTranslated::default()
+        });
+    };
+
+    let exchange = Exchange::keep(
+        context,
+        system_instruction(context),
+        &prompt,
+        &user_contents,
+        true,
+        &response.choice,
+    );
+    Ok(BulkTranslated {
+        truncated: response.truncated,
+        exchange,
+        ..BulkTranslated::numbered(context, paragraphs, original_lines, response.stats.into())
+    })
+}
+
+/// Parse a JSON mode response into paragraphs ordered by their `line`.
+pub(crate) fn parse(numbering: LineNumbering, text: &str) -> serde_json::Result<Vec<String>> {
+    Ok(reorder(numbering, parse_numbered(text)?))
+}
+
+/// Parse a JSON mode response into paragraphs with their `line`, in the
+/// order of the response.
+pub(crate) fn parse_numbered(text: &str) -> serde_json::Result<Vec<(Option<i64>, String)>> {
+    let choice_content = json::parse::<ChoiceContent>(text)?;
+    Ok(choice_content
+        .results
+        .into_iter()
+        .map(|result| (result.line, result.translated.join("\n")))
+        .collect())
+}
+
+/// JSON Schema of a `results` response, strict as structured outputs want it.
+pub(crate) fn schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "results": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "line": {"type": "integer"},
+                        "translated": {"type": "array", "items": {"type": "string"}}
+                    },
+                    "required": ["line", "translated"],
+                    "additionalProperties": false
+                }
+            }
+        },
+        "required": ["results"],
+        "additionalProperties": false
+    })
+}
+
+fn system_instruction(context: &Context) -> &str {
+    context
+        .system_instruction
+        .as_deref()
+        .unwrap_or(DEFAULT_SYSTEM_INSTRUCTION)
 }
@@ -0,0 +1,282 @@
+use crate::client::totals::Totals;
+use log::info;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Shortest interval between two redraws of the progress line.
+const REDRAW: Duration = Duration::from_millis(200);
+
+const BAR_WIDTH: usize = 30;
+
+/// The counts of a [`Progress`] at one moment, as polled over HTTP in
+/// `serve` mode.
+#[derive(Default, Serialize)]
+pub struct Counts {
+    pub chapters_done: usize,
+    pub chapters: usize,
+    pub paragraphs_done: usize,
+    pub paragraphs: usize,
+}
+
+/// Progress of a run over the paragraphs and chapters of the book, with a
+/// throughput and an ETA from the rate so far.
+///
+/// Drawn as one line on stderr when it is a terminal; otherwise a log line is
+/// written as each chapter completes. The line is written here rather than
+/// through a progress bar crate, so that the log lines, which clear it
+/// before they are printed, and the `(+N streaming)` count share one writer.
+#[derive(Default)]
+pub struct Progress {
+    bar: bool,
+    started: Mutex<Option<Instant>>,
+    drawn: Mutex<Option<Instant>>,
+    chapters: AtomicUsize,
+    chapters_done: AtomicUsize,
+    /// chapters whose paragraphs are counted in `paragraphs`
+    chapters_read: AtomicUsize,
+    paragraphs: AtomicUsize,
+    paragraphs_done: AtomicUsize,
+    /// paragraphs completed in responses still streaming
+    streaming: AtomicUsize,
+    finished: AtomicBool,
+}
+
+impl Progress {
+    pub fn new(bar: bool) -> Self {
+        Self {
+            bar,
+            ..Self::default()
+        }
+    }
+
+    /// Start counting towards `chapters` chapters holding `paragraphs`
+    /// paragraphs.
+    pub fn start(&self, chapters: usize, paragraphs: usize) {
+        self.chapters_read.store(chapters, Ordering::Relaxed);
+        self.paragraphs.store(paragraphs, Ordering::Relaxed);
+        self.start_reading(chapters);
+    }
+
+    /// Start counting towards `chapters` chapters whose paragraphs are
+    /// counted with [`read`](Self::read) as each is read; until then the
+    /// total is estimated from the chapters read so far.
+    pub fn start_reading(&self, chapters: usize) {
+        self.chapters.store(chapters, Ordering::Relaxed);
+        *self.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Count the `paragraphs` of a chapter read after
+    /// [`start_reading`](Self::start_reading).
+    pub fn read(&self, paragraphs: usize) {
+        self.paragraphs.fetch_add(paragraphs, Ordering::Relaxed);
+        self.chapters_read.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count finished paragraphs; the throughput and retries are read from
+    /// the `totals` of the run.
+    pub fn advance(&self, paragraphs: usize, totals: &Totals) {
+        self.paragraphs_done
+            .fetch_add(paragraphs, Ordering::Relaxed);
+        self.draw(totals, false);
+    }
+
+    /// Count paragraphs completed in a response that is still streaming,
+    /// until [`streamed`](Self::streamed) takes them off when it ends.
+    pub fn stream(&self, paragraphs: usize, totals: &Totals) {
+        self.streaming.fetch_add(paragraphs, Ordering::Relaxed);
+        self.draw(totals, false);
+    }
+
+    pub fn streamed(&self, paragraphs: usize) {
+        self.streaming.fetch_sub(paragraphs, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            chapters_done: self.chapters_done.load(Ordering::Relaxed),
+            chapters: self.chapters.load(Ordering::Relaxed),
+            paragraphs_done: self.paragraphs_done.load(Ordering::Relaxed),
+            paragraphs: self.paragraphs.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the progress line is drawn on the terminal.
+    pub fn is_drawn(&self) -> bool {
+        self.bar
+    }
+
+    pub fn chapter_done(&self, name: &str, totals: &Totals) {
+        let done = self.chapters_done.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.bar {
+            self.draw(totals, true);
+        } else if self.started.lock().unwrap().is_some() {
+            info!("progress: {}", self.logged(name, done, totals));
+        }
+    }
+
+    /// The log line of chapter `name`, the `done`th, without a progress line.
+    fn logged(&self, name: &str, done: usize, totals: &Totals) -> String {
+        format!(
+            "{} done, chapters {}/{} {}",
+            name,
+            done,
+            self.chapters.load(Ordering::Relaxed),
+            self.line(totals)
+        )
+    }
+
+    /// The paragraphs of the book: those counted, or estimated from the
+    /// chapters read so far while some are not, `None` before any is.
+    fn total(&self) -> Option<usize> {
+        let paragraphs = self.paragraphs.load(Ordering::Relaxed);
+        let chapters = self.chapters.load(Ordering::Relaxed);
+        match self.chapters_read.load(Ordering::Relaxed) {
+            0 if chapters > 0 => None,
+            read if read < chapters => Some(paragraphs * chapters / read),
+            _ => Some(paragraphs),
+        }
+    }
+
+    /// Leave the progress line on the terminal once the book is written.
+    pub fn finish(&self, totals: &Totals) {
+        if self.bar && !self.finished.load(Ordering::Relaxed) {
+            self.draw(totals, true);
+            self.finished.store(true, Ordering::Relaxed);
+            eprintln!();
+        }
+    }
+
+    fn draw(&self, totals: &Totals, force: bool) {
+        if !self.bar || self.finished.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.started.lock().unwrap().is_none() {
+            return;
+        }
+        let mut drawn = self.drawn.lock().unwrap();
+        if !force && drawn.is_some_and(|drawn| drawn.elapsed() < REDRAW) {
+            return;
+        }
+        *drawn = Some(Instant::now());
+        let done = self.paragraphs_done.load(Ordering::Relaxed);
+        let total = self.total().unwrap_or(0).max(1);
+        let filled = BAR_WIDTH * done.min(total) / total;
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r\x1b[K[{}{}] chapters {}/{} {}",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            self.chapters_done.load(Ordering::Relaxed),
+            self.chapters.load(Ordering::Relaxed),
+            self.line(totals)
+        );
+        let _ = stderr.flush();
+    }
+
+    /// `done/total (+streaming) paragraphs, tokens/s, retries, ETA`
+    fn line(&self, totals: &Totals) -> String {
+        let done = self.paragraphs_done.load(Ordering::Relaxed);
+        let total = self.total().map(|total| total.max(done));
+        let elapsed = self
+            .started
+            .lock()
+            .unwrap()
+            .map_or(Duration::ZERO, |started| started.elapsed());
+        let seconds = elapsed.as_secs_f64().max(0.001);
+        let throughput = totals.tokens() as f64 / seconds;
+        let eta = match total {
+            Some(total) if done > 0 => clock(seconds * (total - done) as f64 / done as f64),
+            _ => "--".to_string(),
+        };
+        // an estimate while some chapters are not read
+        let estimated =
+            self.chapters_read.load(Ordering::Relaxed) < self.chapters.load(Ordering::Relaxed);
+        let total = match total {
+            Some(total) if estimated => format!("~{}", total),
+            Some(total) => total.to_string(),
+            None => "?".to_string(),
+        };
+        let streaming = match self.streaming.load(Ordering::Relaxed) {
+            0 => String::new(),
+            streaming => format!(" (+{} streaming)", streaming),
+        };
+        format!(
+            "{}/{}{} paragraphs {:.0} tokens/s retries {} ETA {}",
+            done,
+            total,
+            streaming,
+            throughput,
+            totals.retries(),
+            eta
+        )
+    }
+}
+
+/// `1h02m03s`, `2m03s` or `3s`
+fn clock(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chapters_are_logged_without_a_progress_line() {
+        let totals = Totals::default();
+        let progress = Progress::new(false);
+        progress.start(2, 4);
+        progress.advance(2, &totals);
+        progress.chapter_done("one.xhtml", &totals);
+        assert!(!progress.is_drawn());
+        let logged = progress.logged("one.xhtml", 1, &totals);
+        assert!(
+            logged.starts_with(
+                "one.xhtml done, chapters 1/2 2/4 paragraphs 0 tokens/s retries 0 ETA "
+            ),
+            "{}",
+            logged
+        );
+        // nothing was drawn, so nothing is left on the terminal
+        progress.finish(&totals);
+        assert!(!progress.finished.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn totals_are_estimated_until_every_chapter_is_read() {
+        let totals = Totals::default();
+        let progress = Progress::new(false);
+        progress.start_reading(4);
+        assert_eq!(progress.total(), None);
+        assert!(progress.line(&totals).starts_with("0/? paragraphs"));
+        progress.read(10);
+        assert_eq!(progress.total(), Some(40));
+        progress.advance(5, &totals);
+        assert!(progress.line(&totals).starts_with("5/~40 paragraphs"));
+        for paragraphs in [20, 0, 30] {
+            progress.read(paragraphs);
+        }
+        assert_eq!(progress.total(), Some(60));
+        assert!(progress.line(&totals).starts_with("5/60 paragraphs"));
+        assert_eq!(progress.counts().paragraphs, 60);
+    }
+
+    #[test]
+    fn clocks_are_short() {
+        assert_eq!(clock(3.4), "3s");
+        assert_eq!(clock(123.0), "2m03s");
+        assert_eq!(clock(3723.0), "1h02m03s");
+    }
+}
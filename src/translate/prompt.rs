@@ -0,0 +1,187 @@
+use crate::epub::markup;
+use crate::translate::translator::{Context, Preceding};
+use clap::ValueEnum;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Built-in instructions, for any book.
+pub const DEFAULT_TEMPLATE: &str = include_str!("../../prompts/en.txt");
+
+/// Built-in instructions of the `--refine` pass.
+pub const REFINE_TEMPLATE: &str = include_str!("../../prompts/refine.txt");
+
+/// Built-in instructions of the post-edit of the drafts of `--draft-with`.
+pub const POST_EDIT_TEMPLATE: &str = include_str!("../../prompts/post-edit.txt");
+
+/// Built-in `--style` presets, by name.
+pub const STYLES: [(&str, &str); 5] = [
+    ("technical", include_str!("../../prompts/technical.txt")),
+    ("academic", include_str!("../../prompts/academic.txt")),
+    ("romance", include_str!("../../prompts/romance.txt")),
+    ("literary", include_str!("../../prompts/literary.txt")),
+    ("subtitle", include_str!("../../prompts/subtitle.txt")),
+];
+
+/// Placeholder replaced with the target language.
+const LANGUAGE: &str = "{{language}}";
+/// Placeholder replaced with the number of paragraphs in the chunk.
+const PARAGRAPH_COUNT: &str = "{{paragraph_count}}";
+/// Placeholder replaced with the glossary terms of the chunk; without it
+/// they are added after the instructions.
+const GLOSSARY: &str = "{{glossary}}";
+
+/// The register of the translation, with `--register`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Register {
+    /// Polite and formal, as in a published translation
+    Formal,
+    /// Casual and everyday, as people really speak
+    Casual,
+}
+
+impl Register {
+    fn instruction(self, language: &str) -> String {
+        match self {
+            Register::Formal => format!(
+                "Use a formal, polite register in {}, as in a published translation.\n",
+                language
+            ),
+            Register::Casual => format!(
+                "Use a casual, everyday register in {}, the way people really speak.\n",
+                language
+            ),
+        }
+    }
+}
+
+/// What becomes of honorifics such as Korean -nim and -ssi, with
+/// `--honorifics`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Honorifics {
+    /// Keep them romanized, as in `Hyung-nim`
+    Keep,
+    /// Replace them with the forms of address of the target language
+    Localize,
+}
+
+/// The honorifics meant, in the instructions of `--honorifics`.
+const HONORIFICS: &str =
+    "the honorifics of the original, such as Korean -nim and -ssi or Japanese -san and -sama";
+
+impl Honorifics {
+    fn instruction(self, language: &str) -> String {
+        match self {
+            Honorifics::Keep => {
+                format!("Keep {}, romanized and attached to the name.\n", HONORIFICS)
+            }
+            Honorifics::Localize => format!(
+                "Replace {} with the forms of address natural in {}.\n",
+                HONORIFICS, language
+            ),
+        }
+    }
+}
+
+/// Read the instruction template written in `lang` from `<dir>/<lang>.txt`.
+pub fn load(dir: &Path, lang: &str) -> io::Result<String> {
+    load_file(&dir.join(format!("{}.txt", lang)))
+}
+
+/// Read an instruction template.
+pub fn load_file(path: &Path) -> io::Result<String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// The instruction template of the style `name`: a preset of `presets`,
+/// registered by name with its template file, or else a built-in one.
+pub fn style(name: &str, presets: &[(String, PathBuf)]) -> io::Result<String> {
+    if let Some((_, path)) = presets.iter().find(|(preset, _)| preset == name) {
+        return load_file(path);
+    }
+    match STYLES.iter().find(|(style, _)| *style == name) {
+        Some((_, template)) => Ok(template.to_string()),
+        None => {
+            let mut names: Vec<&str> = STYLES.iter().map(|(style, _)| *style).collect();
+            names.extend(presets.iter().map(|(preset, _)| preset.as_str()));
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "unknown style `{}`, expected one of {}",
+                    name,
+                    names.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+/// The instructions of a chunk, from the template of the run or the default
+/// one, followed by the paragraphs `preceding` it when there are any.
+pub fn instructions(
+    context: &Context,
+    language: &str,
+    lines: &[String],
+    preceding: &[Preceding],
+) -> String {
+    let glossary = context.glossary.render(lines);
+    compose(context, language, lines, preceding, &glossary)
+}
+
+/// [`instructions`] without the glossary, for a request whose glossary was
+/// sent once in a cached preamble.
+pub fn instructions_without_glossary(
+    context: &Context,
+    language: &str,
+    lines: &[String],
+    preceding: &[Preceding],
+) -> String {
+    compose(context, language, lines, preceding, "")
+}
+
+fn compose(
+    context: &Context,
+    language: &str,
+    lines: &[String],
+    preceding: &[Preceding],
+    glossary: &str,
+) -> String {
+    let template = context.instructions.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+    let mut instructions = render(template, language, lines.len(), glossary);
+    if let Some(source) = &context.source_language {
+        instructions.push_str(&format!("The text is written in {}.\n", source));
+    }
+    instructions.push_str(&context.notes.render(lines));
+    if let Some(register) = context.register {
+        instructions.push_str(&register.instruction(language));
+    }
+    if let Some(honorifics) = context.honorifics {
+        instructions.push_str(&honorifics.instruction(language));
+    }
+    if context.preserve_markup && lines.iter().any(|line| markup::is_marked(line)) {
+        instructions.push_str(markup::INSTRUCTION);
+    }
+    if !preceding.is_empty() {
+        instructions.push_str(&format!(
+            "For context only, here are the paragraphs just before the text, with their translations when done; do not translate or output them:\n{}\n",
+            serde_json::to_string(preceding).unwrap_or_default()
+        ));
+    }
+    instructions
+}
+
+/// Fill in the instruction template; the paragraphs are not part of it and
+/// are sent unchanged.
+pub fn render(template: &str, language: &str, paragraph_count: usize, glossary: &str) -> String {
+    let mut instructions = template
+        .replace(LANGUAGE, language)
+        .replace(PARAGRAPH_COUNT, &paragraph_count.to_string())
+        .replace(GLOSSARY, glossary.trim_end())
+        .trim_end()
+        .to_string();
+    instructions.push('\n');
+    if !template.contains(GLOSSARY) {
+        instructions.push_str(glossary);
+    }
+    instructions
+}
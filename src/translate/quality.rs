@@ -0,0 +1,272 @@
+use crate::language;
+use crate::translate::chunk::estimate_tokens;
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Shortest run of letters of a script of the source, in a translation
+/// written in another script, taken as text left untranslated.
+const RUN: usize = 4;
+
+/// Fewest letters in a source paragraph for a translation identical to it to
+/// be taken as untranslated; names and short exclamations are kept as they
+/// are.
+const IDENTICAL_LETTERS: usize = 20;
+
+/// Fewest estimated tokens in a source paragraph for the length of its
+/// translation to be checked.
+const LENGTH_TOKENS: usize = 20;
+
+/// Widest ratio of the estimated tokens of a translation to those of its
+/// source, either way.
+const LENGTH_RATIO: usize = 5;
+
+/// What is wrong with a translation that looks untranslated or garbled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The translation is the source paragraph
+    Identical,
+    /// A run of a script of the source is left in a translation written in
+    /// another script, e.g. kana in English
+    SourceScript(Script),
+    /// `<paragraph>` tags of the prompt format are left in the translation
+    Tags,
+    /// The translation is far shorter or longer than the source
+    Length { source: usize, translation: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Identical => write!(f, "identical to the source"),
+            Problem::SourceScript(script) => {
+                write!(f, "{:?} characters of the source left", script)
+            }
+            Problem::Tags => write!(f, "<paragraph> tags left"),
+            Problem::Length {
+                source,
+                translation,
+            } => write!(
+                f,
+                "about {} tokens for {} in the source",
+                translation, source
+            ),
+        }
+    }
+}
+
+/// Check a translation for signs that it is untranslated or garbled. An
+/// empty translation, left so on purpose or for a failure, is not checked.
+pub fn check(source: &str, translation: &str) -> Option<Problem> {
+    let translation = translation.trim();
+    if translation.is_empty() {
+        return None;
+    }
+    if translation.contains("<paragraph") || translation.contains("</paragraph>") {
+        return Some(Problem::Tags);
+    }
+    if translation == source.trim()
+        && source.chars().filter(|c| c.is_alphabetic()).count() >= IDENTICAL_LETTERS
+    {
+        return Some(Problem::Identical);
+    }
+    if let Some(script) = residual(source, translation) {
+        return Some(Problem::SourceScript(script));
+    }
+    let (source, translation) = (estimate_tokens(source), estimate_tokens(translation));
+    if source >= LENGTH_TOKENS
+        && (translation * LENGTH_RATIO < source || translation > source * LENGTH_RATIO)
+    {
+        return Some(Problem::Length {
+            source,
+            translation,
+        });
+    }
+    None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Script {
+    Latin,
+    Han,
+    Kana,
+    Hangul,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Thai,
+    Devanagari,
+    /// Every other script
+    #[value(skip)]
+    Other,
+}
+
+/// What `--script-check` does with a translation holding letters of a
+/// script its language is not written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ScriptCheck {
+    /// Log the paragraph with its document and number
+    Report,
+    /// Log the paragraph and translate it again
+    Requeue,
+}
+
+/// Scripts each target language is written in, by language code; Latin is
+/// expected in all of them, for names and acronyms.
+const EXPECTED: &[(&str, &[Script])] = &[
+    ("ja", &[Script::Latin, Script::Han, Script::Kana]),
+    ("zh", &[Script::Latin, Script::Han]),
+    ("ko", &[Script::Latin, Script::Hangul, Script::Han]),
+    ("ru", &[Script::Latin, Script::Cyrillic]),
+    ("uk", &[Script::Latin, Script::Cyrillic]),
+    ("el", &[Script::Latin, Script::Greek]),
+    ("ar", &[Script::Latin, Script::Arabic]),
+    ("fa", &[Script::Latin, Script::Arabic]),
+    ("he", &[Script::Latin, Script::Hebrew]),
+    ("th", &[Script::Latin, Script::Thai]),
+    ("hi", &[Script::Latin, Script::Devanagari]),
+];
+
+/// The scripts `language` is written in: those of the table, Latin alone
+/// for the other languages of [`language`], and `None` for a language it
+/// does not know.
+pub fn expected(language: &str) -> Option<&'static [Script]> {
+    let language = language::find(language)?;
+    match EXPECTED.iter().find(|(code, _)| *code == language.code) {
+        Some((_, scripts)) => Some(scripts),
+        None if language.cased && language.code != "el" => Some(&[Script::Latin]),
+        None => None,
+    }
+}
+
+/// The scripts with letters in `translation` that are not `allowed`, in the
+/// order they first appear.
+pub fn unexpected(translation: &str, allowed: &[Script]) -> Vec<Script> {
+    let mut scripts = Vec::new();
+    for script in translation.chars().filter_map(script) {
+        if !allowed.contains(&script) && !scripts.contains(&script) {
+            scripts.push(script);
+        }
+    }
+    scripts
+}
+
+fn script(c: char) -> Option<Script> {
+    if !c.is_alphabetic() {
+        return None;
+    }
+    Some(match c as u32 {
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF => Script::Han,
+        0x3040..=0x30FF | 0x31F0..=0x31FF => Script::Kana,
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+        0x0400..=0x04FF => Script::Cyrillic,
+        0x0370..=0x03FF => Script::Greek,
+        0x0600..=0x06FF => Script::Arabic,
+        0x0590..=0x05FF => Script::Hebrew,
+        0x0E00..=0x0E7F => Script::Thai,
+        0x0900..=0x097F => Script::Devanagari,
+        0x0041..=0x024F | 0x1E00..=0x1EFF => Script::Latin,
+        _ => Script::Other,
+    })
+}
+
+/// A script of `source` with a run of [`RUN`] letters in `translation` that
+/// is not the script the translation is mostly written in. Latin letters are
+/// never taken as residue, names and acronyms being kept in them, and Han is
+/// part of Japanese and Korean writing, so it is no residue in a translation
+/// mostly in kana or hangul.
+fn residual(source: &str, translation: &str) -> Option<Script> {
+    let mut counts: HashMap<Script, usize> = HashMap::new();
+    let mut runs: HashMap<Script, usize> = HashMap::new();
+    let mut run = (None, 0);
+    for c in translation.chars() {
+        let script = script(c);
+        if let Some(script) = script {
+            *counts.entry(script).or_default() += 1;
+        }
+        run = match run {
+            (previous, length) if previous == script => (script, length + 1),
+            _ => (script, 1),
+        };
+        if let (Some(script), length) = run {
+            let longest = runs.entry(script).or_default();
+            *longest = (*longest).max(length);
+        }
+    }
+    let (dominant, _) = counts.iter().max_by_key(|(_, count)| **count)?;
+    let mut scripts: Vec<Script> = source.chars().filter_map(script).collect();
+    scripts.dedup();
+    scripts.into_iter().find(|script| {
+        !matches!(script, Script::Latin | Script::Other)
+            && script != dominant
+            && runs.get(script).is_some_and(|run| *run >= RUN)
+            && !(*script == Script::Han && matches!(dominant, Script::Kana | Script::Hangul))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "The old man walked down to the harbour as the tide went out.";
+
+    #[test]
+    fn garbled_translations_are_flagged() {
+        assert_eq!(
+            check(
+                SOURCE,
+                "<paragraph>Der alte Mann ging zum Hafen.</paragraph>"
+            ),
+            Some(Problem::Tags)
+        );
+        assert_eq!(check(SOURCE, SOURCE), Some(Problem::Identical));
+        let long = SOURCE.repeat(4);
+        assert!(matches!(
+            check(&long, "Der alte Mann."),
+            Some(Problem::Length { .. })
+        ));
+        // a short paragraph is not measured
+        assert_eq!(check(SOURCE, "Der Mann."), None);
+        assert_eq!(
+            check(
+                SOURCE,
+                "Der alte Mann ging zum Hafen hinunter, als die Ebbe einsetzte."
+            ),
+            None
+        );
+        // left empty on purpose or for a failure
+        assert_eq!(check(SOURCE, "  "), None);
+        // a name is kept as it is
+        assert_eq!(check("Sherlock Holmes", "Sherlock Holmes"), None);
+    }
+
+    #[test]
+    fn source_scripts_left_in_are_flagged() {
+        let source = "老人は港へ歩いていった。";
+        assert_eq!(
+            check(source, "The old man walked to いっていった the harbour."),
+            Some(Problem::SourceScript(Script::Kana))
+        );
+        assert_eq!(check(source, "The old man walked to the harbour."), None);
+        // Han is written in Japanese, and a short run is a quoted word
+        assert_eq!(check("老人港", "老人は港へ歩いていった。"), None);
+        assert_eq!(check(source, "He said いい and left."), None);
+    }
+
+    #[test]
+    fn scripts_are_expected_by_language() {
+        assert_eq!(
+            expected("Japanese"),
+            Some(&[Script::Latin, Script::Han, Script::Kana][..])
+        );
+        assert_eq!(expected("de"), Some(&[Script::Latin][..]));
+        assert_eq!(expected("Greek"), Some(&[Script::Latin, Script::Greek][..]));
+        assert_eq!(expected("Klingon"), None);
+        assert_eq!(
+            unexpected("Привет, 世界 and 世界 again", &[Script::Latin]),
+            [Script::Cyrillic, Script::Han]
+        );
+        assert!(unexpected("Hallo, Welt! 123", &[Script::Latin]).is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+use crate::epub::markup;
+use crate::translate::chunk;
+use crate::translate::json;
+use crate::translate::prompt;
+use crate::translate::translator::Translator;
+use futures::{stream, StreamExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+struct Pair<'a> {
+    original: &'a str,
+    draft: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Revised {
+    paragraphs: Vec<String>,
+}
+
+/// Polish the `drafts`, translations of `sources` into `language`, in a
+/// second pass that sends each original along as a reference, or post-edit
+/// the drafts of the drafter of the run. The requests of a refine pass are
+/// counted in the refine totals of the run. A chunk that fails, or comes
+/// back with another number of paragraphs, keeps its drafts; untranslated
+/// paragraphs are not sent.
+pub async fn refine(
+    translator: &Translator,
+    sources: &[String],
+    mut drafts: Vec<String>,
+    language: &str,
+) -> Vec<String> {
+    let context = translator.context();
+    let sent: Vec<usize> = (0..sources.len().min(drafts.len()))
+        .filter(|i| !drafts[*i].is_empty())
+        .collect();
+    let originals: Vec<String> = sent.iter().map(|i| sources[*i].clone()).collect();
+    let chunk_lines = match context.max_chunk_tokens {
+        Some(_) => usize::MAX,
+        None => context.lines,
+    };
+    let chunks = chunk::split(
+        &originals,
+        chunk_lines.min(context.max_paragraphs_per_chunk.unwrap_or(usize::MAX)),
+        context.max_chunk_tokens,
+        context.scene_breaks,
+    );
+    let mut start = 0;
+    let chunks: Vec<&[usize]> = chunks
+        .iter()
+        .map(|chunk| {
+            let indices = &sent[start..start + chunk.len()];
+            start += chunk.len();
+            indices
+        })
+        .collect();
+    let revised: Vec<_> = stream::iter(chunks)
+        .map(|indices| {
+            let drafts = &drafts;
+            async move {
+                let originals: Vec<String> = indices.iter().map(|i| sources[*i].clone()).collect();
+                let pairs: Vec<Pair> = indices
+                    .iter()
+                    .map(|i| Pair {
+                        original: &sources[*i],
+                        draft: &drafts[*i],
+                    })
+                    .collect();
+                let revised = translator
+                    .refine(&prompt(translator, language, &originals, &pairs))
+                    .await
+                    .and_then(|text| json::parse::<Revised>(&text).map_err(|e| e.to_string()));
+                (indices, revised)
+            }
+        })
+        .buffer_unordered(context.requests)
+        .collect()
+        .await;
+    for (indices, revised) in revised {
+        match revised {
+            Ok(revised) if revised.paragraphs.len() == indices.len() => {
+                for (i, paragraph) in indices.iter().zip(revised.paragraphs) {
+                    if !paragraph.trim().is_empty() {
+                        drafts[*i] = paragraph;
+                    }
+                }
+            }
+            Ok(revised) => warn!(
+                "refine: {} paragraphs for {}, keeping the drafts",
+                revised.paragraphs.len(),
+                indices.len()
+            ),
+            Err(e) => warn!("refine: {}, keeping the drafts", e),
+        }
+    }
+    debug!("refine: {} paragraphs", sent.len());
+    drafts
+}
+
+fn prompt(translator: &Translator, language: &str, originals: &[String], pairs: &[Pair]) -> String {
+    let context = translator.context();
+    let template = match (&context.refine_instructions, &context.drafter) {
+        (Some(instructions), _) => instructions,
+        (None, Some(_)) => prompt::POST_EDIT_TEMPLATE,
+        (None, None) => prompt::REFINE_TEMPLATE,
+    };
+    let mut instructions = prompt::render(
+        template,
+        language,
+        pairs.len(),
+        &context.glossary.render(originals),
+    );
+    if context.preserve_markup && originals.iter().any(|line| markup::is_marked(line)) {
+        instructions.push_str(markup::INSTRUCTION);
+    }
+    format!(
+        "{}Please output the following JSON with one revised translation for each paragraph given, in the same order.\n\
+        {{\"paragraphs\": [string]}}\n\
+        Here are the paragraphs:\n{}",
+        instructions,
+        serde_json::to_string(pairs).unwrap_or_default()
+    )
+}
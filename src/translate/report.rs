@@ -0,0 +1,68 @@
+use crate::error::Error;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Chunks whose requests kept failing, collected over a run so the book can
+/// still be written and the failures reported at the end.
+#[derive(Default)]
+pub struct Report {
+    failures: Mutex<Vec<Failure>>,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    language: String,
+    error: String,
+    paragraphs: Vec<String>,
+}
+
+impl Report {
+    pub fn record(&self, language: &str, error: &str, paragraphs: &[String]) {
+        self.failures.lock().unwrap().push(Failure {
+            language: language.to_string(),
+            error: error.to_string(),
+            paragraphs: paragraphs.to_vec(),
+        });
+    }
+
+    /// Number of failed chunks.
+    pub fn len(&self) -> usize {
+        self.failures.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the failed chunks as a JSON array of `language`, `error` and
+    /// source `paragraphs`.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let failures = self.failures.lock().unwrap();
+        let text = serde_json::to_string_pretty(&*failures)?;
+        std::fs::write(path, text + "\n")
+    }
+
+    /// Finish a run that wrote `output`: write the report next to it as
+    /// `<output>.failures.json` and turn the `result` into an error naming
+    /// it when chunks failed, or remove the report of an earlier run.
+    pub fn conclude(&self, output: &Path, result: Result<(), Error>) -> Result<(), Error> {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".failures.json");
+        let path = PathBuf::from(path);
+        if self.is_empty() {
+            // the report of an earlier run is out of date
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            return result;
+        }
+        self.write(&path)?;
+        result?;
+        Err(Error::Incomplete {
+            failed: self.len(),
+            report: path,
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use crate::translate::quality;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// Translations of low confidence, shown in the terminal with `--review` to
+/// be accepted, edited or translated again before they are written.
+///
+/// A paragraph is flagged when its chunk was retried or failed, and when its
+/// translation fails a [quality check](quality::check).
+#[derive(Default)]
+pub struct Review {
+    /// reason of each flagged paragraph, by language and source
+    flagged: Mutex<HashMap<(String, String), String>>,
+    /// held while a document is reviewed, so the questions of chapters in
+    /// flight do not interleave
+    pub(crate) terminal: tokio::sync::Mutex<()>,
+}
+
+pub enum Decision {
+    Accept,
+    Edit(String),
+    Retranslate,
+}
+
+impl Review {
+    /// Flag `sources` for review; a later reason, such as the failure of
+    /// the retry, replaces an earlier one.
+    pub fn flag(&self, language: &str, sources: &[String], reason: &str) {
+        let mut flagged = self.flagged.lock().unwrap();
+        for source in sources {
+            flagged.insert((language.to_string(), source.clone()), reason.to_string());
+        }
+    }
+
+    /// Why the translation of `source` needs a review, if it does; the
+    /// flag is cleared.
+    pub fn take(&self, language: &str, source: &str, translation: &str) -> Option<String> {
+        let flagged = self
+            .flagged
+            .lock()
+            .unwrap()
+            .remove(&(language.to_string(), source.to_string()));
+        flagged.or_else(|| quality::check(source, translation).map(|problem| problem.to_string()))
+    }
+
+    /// Show a paragraph and its translation on stderr and read what to do
+    /// with it from stdin. An empty answer or the end of the input accepts.
+    pub async fn ask(
+        &self,
+        number: (usize, usize),
+        reason: &str,
+        source: &str,
+        translation: &str,
+    ) -> io::Result<Decision> {
+        let mut stderr = io::stderr();
+        writeln!(stderr, "\nreview {}/{}: {}", number.0, number.1, reason)?;
+        writeln!(stderr, "  source:      {}", source)?;
+        writeln!(stderr, "  translation: {}", translation)?;
+        loop {
+            write!(stderr, "[a]ccept, [e]dit, [r]etranslate? ")?;
+            stderr.flush()?;
+            let answer = read_line().await?;
+            match answer.trim() {
+                "" | "a" | "accept" => return Ok(Decision::Accept),
+                "r" | "retranslate" => return Ok(Decision::Retranslate),
+                "e" | "edit" => {
+                    write!(stderr, "translation: ")?;
+                    stderr.flush()?;
+                    let edited = read_line().await?;
+                    return Ok(match edited.trim() {
+                        "" => Decision::Accept,
+                        edited => Decision::Edit(edited.to_string()),
+                    });
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+async fn read_line() -> io::Result<String> {
+    tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).map(|_| line)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
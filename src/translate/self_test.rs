@@ -0,0 +1,169 @@
+use crate::translate::line::LineNumbering;
+use crate::translate::{gemini, open_ai};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Valid JSON as returned
+    Parsed,
+    /// Parsed after repairing the fence, surrounding prose or trailing commas
+    Repaired,
+    /// Not recoverable; the chunk is retried
+    Error,
+}
+
+#[derive(Clone, Copy)]
+enum Provider {
+    Gemini,
+    OpenAi,
+}
+
+/// A captured model response and what the parser is expected to make of it.
+struct Fixture {
+    name: &'static str,
+    provider: Provider,
+    response: &'static str,
+    outcome: Outcome,
+    lines: &'static [&'static str],
+}
+
+const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "gemini_good",
+        provider: Provider::Gemini,
+        response: include_str!("../../fixtures/responses/gemini_good.json"),
+        outcome: Outcome::Parsed,
+        lines: &["Xin chào.", "Tạm biệt.\nHẹn gặp lại."],
+    },
+    Fixture {
+        name: "gemini_fenced",
+        provider: Provider::Gemini,
+        response: include_str!("../../fixtures/responses/gemini_fenced.txt"),
+        outcome: Outcome::Repaired,
+        lines: &["Xin chào.", "Tạm biệt."],
+    },
+    Fixture {
+        name: "gemini_prose",
+        provider: Provider::Gemini,
+        response: include_str!("../../fixtures/responses/gemini_prose.txt"),
+        outcome: Outcome::Repaired,
+        lines: &["Xin chào.", "Tạm biệt."],
+    },
+    Fixture {
+        name: "gemini_truncated",
+        provider: Provider::Gemini,
+        response: include_str!("../../fixtures/responses/gemini_truncated.json"),
+        outcome: Outcome::Error,
+        lines: &[],
+    },
+    Fixture {
+        name: "gemini_shuffled",
+        provider: Provider::Gemini,
+        response: include_str!("../../fixtures/responses/gemini_shuffled.json"),
+        outcome: Outcome::Parsed,
+        lines: &["Một.", "Hai.", "Ba."],
+    },
+    Fixture {
+        name: "gemini_zero_based",
+        provider: Provider::Gemini,
+        response: include_str!("../../fixtures/responses/gemini_zero_based.json"),
+        outcome: Outcome::Parsed,
+        lines: &["Một.", "Hai."],
+    },
+    Fixture {
+        name: "gemini_extra_line",
+        provider: Provider::Gemini,
+        response: include_str!("../../fixtures/responses/gemini_extra_line.json"),
+        outcome: Outcome::Parsed,
+        lines: &["Một.", "Hai.", "Ba."],
+    },
+    Fixture {
+        name: "gemini_trailing_comma_in_string",
+        provider: Provider::Gemini,
+        response: include_str!("../../fixtures/responses/gemini_trailing_comma_in_string.json"),
+        outcome: Outcome::Repaired,
+        lines: &["Một, ]", "Hai."],
+    },
+    Fixture {
+        name: "open_ai_good",
+        provider: Provider::OpenAi,
+        response: include_str!("../../fixtures/responses/open_ai_good.json"),
+        outcome: Outcome::Parsed,
+        lines: &["こんにちは。", "さようなら。"],
+    },
+    Fixture {
+        name: "open_ai_fenced",
+        provider: Provider::OpenAi,
+        response: include_str!("../../fixtures/responses/open_ai_fenced.txt"),
+        outcome: Outcome::Repaired,
+        lines: &["こんにちは。", "さようなら。"],
+    },
+    Fixture {
+        name: "open_ai_not_json",
+        provider: Provider::OpenAi,
+        response: include_str!("../../fixtures/responses/open_ai_not_json.txt"),
+        outcome: Outcome::Error,
+        lines: &[],
+    },
+    Fixture {
+        name: "open_ai_missing_line",
+        provider: Provider::OpenAi,
+        response: include_str!("../../fixtures/responses/open_ai_missing_line.json"),
+        outcome: Outcome::Parsed,
+        lines: &["こんにちは。", "さようなら。"],
+    },
+];
+
+pub struct Report {
+    pub name: &'static str,
+    pub expected: (Outcome, Vec<String>),
+    pub actual: (Outcome, Vec<String>),
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.passed() {
+            return write!(f, "ok     {} ({:?})", self.name, self.actual.0);
+        }
+        write!(
+            f,
+            "FAILED {}: expected {:?} {:?}, got {:?} {:?}",
+            self.name, self.expected.0, self.expected.1, self.actual.0, self.actual.1
+        )
+    }
+}
+
+/// Replay the captured responses through the parse and repair pipeline.
+pub fn run() -> Vec<Report> {
+    FIXTURES.iter().map(check).collect()
+}
+
+fn check(fixture: &Fixture) -> Report {
+    let parsed = match fixture.provider {
+        Provider::Gemini => gemini::parse(LineNumbering::Auto, fixture.response),
+        Provider::OpenAi => open_ai::parse(LineNumbering::Auto, fixture.response),
+    };
+    let actual = match parsed {
+        Ok(lines) if is_json(fixture.response) => (Outcome::Parsed, lines),
+        Ok(lines) => (Outcome::Repaired, lines),
+        Err(_) => (Outcome::Error, vec![]),
+    };
+    Report {
+        name: fixture.name,
+        expected: (
+            fixture.outcome,
+            fixture.lines.iter().map(|line| line.to_string()).collect(),
+        ),
+        actual,
+    }
+}
+
+fn is_json(text: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(text.trim()).is_ok()
+}
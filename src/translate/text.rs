@@ -0,0 +1,101 @@
+use crate::translate::translator::Context;
+use log::warn;
+use regex::Regex;
+use std::io::Write;
+use std::ops::ControlFlow;
+
+/// Characters a response may stream, leading blanks aside, before its first
+/// `<paragraph>` tag.
+const PREAMBLE: usize = 200;
+
+/// Output instructions for the text mode, where paragraphs come back wrapped
+/// in `<paragraph>` tags instead of JSON so they can be streamed as generated.
+pub fn prompt(language: &str, paragraphs: usize) -> String {
+    format!(
+        "Translate it into {}.\n\
+        A string in `<paragraph>` tag to `</paragraph>` tag is one paragraph.\n\
+        Wrap each translated paragraph in `<paragraph>` and `</paragraph>` tags, in the order of the input.\n\
+        There are {} paragraphs of input, please output {} paragraphs.\n\
+        Output nothing but the translated paragraphs.",
+        language, paragraphs, paragraphs
+    )
+}
+
+pub fn parse(text: &str) -> Vec<String> {
+    let paragraph = Regex::new(r"(?s)<paragraph>(.*?)</paragraph>").unwrap();
+    paragraph
+        .captures_iter(text)
+        .map(|captures| captures[1].trim().to_string())
+        .collect()
+}
+
+/// A response streaming in text mode, assembled from its deltas as they
+/// arrive. The completed paragraphs show in the progress line, and the text
+/// is echoed to stdout when there is no progress line to draw.
+///
+/// A response that starts with anything but a paragraph, or closes more
+/// paragraphs than were sent, is cut short: what came so far parses into
+/// another number of paragraphs, so the chunk is retried like any mismatch.
+pub struct Watch<'a> {
+    context: &'a Context,
+    expected: usize,
+    text: String,
+    closed: usize,
+    /// why the response was cut short, logged once the echo is ended
+    stopped: Option<String>,
+}
+
+impl<'a> Watch<'a> {
+    pub fn new(context: &'a Context, expected: usize) -> Self {
+        Self {
+            context,
+            expected,
+            text: String::new(),
+            closed: 0,
+            stopped: None,
+        }
+    }
+
+    pub fn push(&mut self, delta: &str) -> ControlFlow<()> {
+        if !self.context.progress.is_drawn() {
+            print(delta);
+        }
+        self.text.push_str(delta);
+        let text = self.text.trim_start();
+        if !text.contains("<paragraph>") && text.chars().count() >= PREAMBLE {
+            self.stopped = Some(format!("no paragraph in {} characters", PREAMBLE));
+            return ControlFlow::Break(());
+        }
+        let closed = text.matches("</paragraph>").count();
+        if closed > self.expected {
+            self.stopped = Some(format!("more than {} paragraphs", self.expected));
+            return ControlFlow::Break(());
+        }
+        if closed > self.closed {
+            self.context
+                .progress
+                .stream(closed - self.closed, &self.context.totals);
+            self.closed = closed;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        self.context.progress.streamed(self.closed);
+        if !self.context.progress.is_drawn() {
+            print("\n");
+        }
+        if let Some(reason) = &self.stopped {
+            warn!("stream: {}, stopped", reason);
+        }
+    }
+}
+
+/// Echo streamed text to the terminal as it arrives.
+fn print(text: &str) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
@@ -1,23 +1,1685 @@
-use crate::translate::gemini::translate as gemini;
-use crate::translate::open_ai::translate as open_ai;
+use crate::cache::Cache;
+use crate::client::budget::Budget;
+use crate::client::capability::JsonMode;
+use crate::client::gemini::PromptCache;
+use crate::client::keys::Keys;
+use crate::client::limiter::Limiter;
+use crate::client::preflight::Preflight;
+use crate::client::quota::{self, Quota};
+use crate::client::ratelimit::Throttle;
+use crate::client::retry::RetryPolicy;
+use crate::client::safety::{OnBlocked, Threshold};
+use crate::client::shutdown::Shutdown;
+use crate::client::totals::Totals;
+use crate::control::Control;
+use crate::epub::chapter::{ChapterLanguage, Chapters};
+use crate::epub::layout::Layout;
+use crate::epub::markup;
+use crate::epub::notes::Notes;
+use crate::epub::provenance::{Origin, Provenance};
+use crate::epub::toc::Headings;
+use crate::error::Error;
+use crate::logging;
+use crate::memory::{Memory, Segment};
+use crate::names::Names;
+use crate::pipeline::Provider;
+use crate::tmx;
+use crate::translate::chunk;
+use crate::translate::dump::{Dumps, Exchange};
+use crate::translate::emphasis;
+use crate::translate::glossary::Glossary;
+use crate::translate::line::{
+    self, on_failure, whitespace, LineNumbering, OnFailure, RetryStrategy, Whitespace,
+};
+use crate::translate::progress::Progress;
+use crate::translate::prompt::{Honorifics, Register};
+use crate::translate::quality::{self, Script, ScriptCheck};
+use crate::translate::refine;
+use crate::translate::report::Report;
+use crate::translate::review::{Decision, Review};
+use crate::translate::typography;
+use futures::future::BoxFuture;
+use futures::{stream, StreamExt};
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
 pub struct Context {
     pub model: String,
     pub api_key: String,
+    /// keys of `--api-keys`, used in turn instead of `api_key`
+    pub keys: Keys,
+    /// API base URL from `--base-url`, the public endpoint of the provider when `None`
+    pub base_url: Option<String>,
     pub language: String,
+    /// language the book is written in, given or detected, named in the
+    /// prompt
+    pub source_language: Option<String>,
+    /// translate a book that appears to be in the target language already
+    pub allow_same_language: bool,
+    /// chapters to translate, all of them when empty; the others are passed
+    /// through
+    pub chapters: Vec<Chapters>,
+    /// chapters never translated, from `--skip` and `--select-skipped`
+    pub skip: Vec<Chapters>,
+    pub chapter_languages: Vec<ChapterLanguage>,
     pub lines: usize,
+    pub max_chunk_tokens: Option<usize>,
+    pub max_paragraphs_per_chunk: Option<usize>,
+    /// end chunks at scene breaks with `--scene-breaks`
+    pub scene_breaks: bool,
+    /// paragraphs before each chunk sent with it as context
+    pub context_lines: usize,
     pub requests: usize,
+    /// context window requested from a local model
+    pub num_ctx: Option<usize>,
+    pub line_numbering: LineNumbering,
+    /// the number of the first line of the complete responses so far, which
+    /// tells the numbering of those that left lines out
+    pub learned_base: Mutex<Option<i64>>,
+    pub throttle: Throttle,
+    pub preflight: Preflight,
+    pub headers: Vec<(String, String)>,
+    pub system_instruction: Option<String>,
+    pub instructions: Option<String>,
+    /// the system instruction and the glossary of the Gemini requests,
+    /// cached once with `--cache-prompt`
+    pub prompt_cache: Option<PromptCache>,
+    /// sampling temperature of the Gemini requests, that of the model when
+    /// `None`
+    pub temperature: Option<f32>,
+    /// nucleus sampling of the Gemini requests, `topP`
+    pub top_p: Option<f32>,
+    /// most tokens of a Gemini response, `maxOutputTokens`
+    pub max_output_tokens: Option<u32>,
+    /// what the safety filters of the Gemini API block, the defaults of the
+    /// model when `None`
+    pub safety_threshold: Option<Threshold>,
+    pub on_blocked: OnBlocked,
+    /// register asked for in the prompt, with `--register`
+    pub register: Option<Register>,
+    /// how honorifics are rendered, with `--honorifics`
+    pub honorifics: Option<Honorifics>,
+    pub glossary: Glossary,
+    /// footnotes of the book and the sentences referring to them
+    pub notes: Notes,
+    /// names of the translations by chapter, with `--names-report`
+    pub names: Option<Names>,
+    /// offer to harmonize the names rendered in more than one way
+    pub harmonize_names: bool,
+    /// the source, model and run of each translated paragraph, with
+    /// `--provenance`
+    pub provenance: Option<Provenance>,
+    /// regenerate the cover with the translated title and author
+    pub retitle_cover: bool,
+    /// transcribe the text of the images and add it translated, with `--ocr`
+    #[cfg(feature = "ocr")]
+    pub ocr: bool,
+    pub enforce_glossary: bool,
+    pub stream: bool,
+    pub max_chapters_in_flight: usize,
+    pub stitch_paragraphs: bool,
+    /// translate again the paragraphs that look untranslated or garbled
+    pub quality_check: bool,
+    /// what to do with translations holding letters of a script their
+    /// language is not written in, with `--script-check`
+    pub script_check: Option<ScriptCheck>,
+    /// scripts the translations may be written in, those of the target
+    /// language when empty
+    pub allowed_scripts: Vec<Script>,
+    pub translate_attributes: Vec<String>,
+    pub translate_metadata: bool,
+    /// write the output as EPUB 3 with the language of the translation
+    pub epub3: bool,
+    /// chapter headings translated so far, for the table of contents
+    pub headings: Headings,
+    pub preserve_emphasis: bool,
+    /// send the inline elements of paragraphs as numbered markers
+    pub preserve_markup: bool,
+    pub json_mode: JsonMode,
+    pub models_without_json_mode: Vec<String>,
+    pub memory: Option<Memory>,
+    /// shared by the books of a batch, like the limiter
+    pub cache: Option<Arc<Cache>>,
+    /// translations recalled from the memory with `--resume` or taken from
+    /// `--tmx` files, by language and source
+    pub recalled: HashMap<(String, String), String>,
+    /// the segment pairs of the run, collected for `--tmx-out` and
+    /// `export-xliff`
+    pub collected: Option<Arc<tmx::Collected>>,
+    pub on_failure: OnFailure,
+    pub retry_strategy: RetryStrategy,
+    /// paragraphs per chunk of a retry with the split and model strategies
+    pub retry_chunk_size: usize,
+    /// rounds of retries a paragraph gets before it is given up; the last
+    /// round is one paragraph at a time
+    pub max_retries: u32,
+    /// model of the retries with `--retry-strategy model`
+    pub retry_model: Option<String>,
+    /// models a paragraph given up on is sent to in turn, with `--fallback`
+    pub fallback: Vec<String>,
+    pub whitespace: Whitespace,
+    /// fix the quotation marks, ellipses and punctuation of the translations
+    /// for the target language
+    pub typography: bool,
+    pub layout: Layout,
+    pub limiter: Arc<Limiter>,
+    pub quota: Quota,
+    /// set on Ctrl-C, shared by the books of a batch
+    pub shutdown: Arc<Shutdown>,
+    pub control: Arc<Control>,
+    /// `--max-cost` and `--max-tokens`, shared by the books of a batch
+    pub budget: Option<Arc<Budget>>,
+    pub retry: RetryPolicy,
+    /// longest a request may take before it is cancelled and sent again
+    pub request_timeout: Option<Duration>,
+    pub stats_per_chunk: bool,
+    pub totals: Totals,
+    /// polish each translated document in a second pass with `--refine`
+    pub refine: bool,
+    /// instructions of the refine pass, the default template when `None`
+    pub refine_instructions: Option<String>,
+    /// token usage of the refine pass, apart from the translation
+    pub refine_totals: Totals,
+    /// translator of `--draft-with` writing the drafts that the model of
+    /// the run only post-edits, instead of translating them itself
+    pub drafter: Option<Box<Translator>>,
+    /// file the totals of the run are written to as JSON with `--stats-out`
+    pub stats_out: Option<PathBuf>,
+    pub report: Report,
+    /// chunks whose responses cannot be used, dumped with their prompts with
+    /// `--dump-failures`
+    pub dumps: Option<Dumps>,
+    /// translations of low confidence to review in the terminal, with
+    /// `--review`
+    pub review: Option<Review>,
+    pub progress: Progress,
+}
+
+/// Rounds of retries a paragraph gets by default, as many as before
+/// `--max-retries`.
+pub const MAX_RETRIES: u32 = 5;
+
+tokio::task_local! {
+    /// the model of the retries of a chunk with `--retry-strategy model`,
+    /// while they run
+    static RETRY_MODEL: String;
+}
+
+impl Context {
+    /// The model to send a request to: that of the run, the one given
+    /// through `--control`, or `--retry-model` while a chunk is retried with
+    /// it.
+    pub fn request_model(&self) -> String {
+        RETRY_MODEL
+            .try_with(String::clone)
+            .ok()
+            .or_else(|| self.control.model())
+            .unwrap_or_else(|| self.model.clone())
+    }
+}
+
+/// A translation service that translates one chunk of paragraphs at a time.
+///
+/// Chunking, concurrency, retries, quota and usage totals are handled by
+/// [`Translator`], so another service (a corporate MT system, DeepL, a dummy
+/// for testing) only has to implement this to be used without forking.
+pub trait Backend: Send + Sync {
+    /// Translate `lines` into `language`, one translated line per paragraph.
+    /// A chunk translated to another number of lines, or whose request
+    /// failed, is retried paragraph by paragraph. `preceding` are the
+    /// paragraphs before the chunk, to send as context that is not
+    /// translated; it is empty unless `--context-lines` is given.
+    fn translate_bulk<'a>(
+        &'a self,
+        context: &'a Context,
+        language: &'a str,
+        lines: &'a [String],
+        preceding: &'a [Preceding],
+    ) -> BoxFuture<'a, Result<BulkTranslated, Error>>;
+
+    /// The provider of the service, named in the dumped failures so that
+    /// they can be replayed; none by default.
+    fn provider(&self) -> Option<Provider> {
+        None
+    }
+
+    /// Check that the service can translate before starting; succeeds by
+    /// default.
+    fn probe<'a>(&'a self, _context: &'a Context) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Answer a free-form prompt, for the passes that are not translations
+    /// such as glossary extraction; unsupported by default.
+    fn complete<'a>(
+        &'a self,
+        _context: &'a Context,
+        _prompt: &'a str,
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async { Err("this backend does not answer free-form prompts".to_string()) })
+    }
+
+    /// Send the request of a dumped `exchange` again as it was, and give the
+    /// raw text of the response; unsupported by default.
+    fn replay<'a>(
+        &'a self,
+        _context: &'a Context,
+        _exchange: &'a Exchange,
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async { Err("this backend cannot replay a request".to_string()) })
+    }
+
+    /// Give the text of an image of type `mime`, following `prompt`;
+    /// unsupported by default.
+    #[cfg(feature = "ocr")]
+    fn transcribe<'a>(
+        &'a self,
+        _context: &'a Context,
+        _prompt: &'a str,
+        _mime: &'a str,
+        _image: &'a [u8],
+    ) -> BoxFuture<'a, Result<Completion, String>> {
+        Box::pin(async { Err("only the Gemini API reads images".to_string()) })
+    }
+}
+
+/// A paragraph before a chunk, sent with it as context, and its
+/// translation when its chunk is already done.
+#[derive(Serialize)]
+pub struct Preceding {
+    pub original: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translation: Option<String>,
+}
+
+pub struct Completion {
+    pub text: String,
+    pub stats: Stats,
+}
+
+#[derive(Default)]
+pub struct BulkTranslated {
+    pub translated_lines: Vec<String>,
+    /// when `translated_lines` are not one per paragraph, the translations
+    /// that could still be placed by their `line` number, one per paragraph;
+    /// empty when there are none
+    pub salvaged: Vec<Option<String>>,
+    pub stats: Stats,
+    /// the response was cut short at the output token limit of the model,
+    /// so the chunk is translated again in halves
+    pub truncated: bool,
+    /// the request and the raw response, to dump if the response cannot be
+    /// used; kept only with `--dump-failures`
+    pub exchange: Option<Exchange>,
+    /// the model the request was sent to, set once it is answered
+    pub model: String,
+}
+
+impl BulkTranslated {
+    /// The translations of `sources` in a JSON response, ordered by their
+    /// `line` number, and [aligned](line::align) by it when some are missing.
+    pub fn numbered(
+        context: &Context,
+        paragraphs: Vec<(Option<i64>, String)>,
+        sources: &[String],
+        stats: Stats,
+    ) -> Self {
+        let numbering = context.line_numbering;
+        if let Some(problems) = line::problems(numbering, &paragraphs, sources.len()) {
+            warn!(stage = "parse"; "line numbers of the response: {}", problems);
+        }
+        let mut learned = context.learned_base.lock().unwrap();
+        if paragraphs.len() == sources.len() {
+            if let Some(base) = line::complete_base(numbering, &paragraphs) {
+                *learned = Some(base);
+            }
+        }
+        let salvaged = line::align(numbering, *learned, &paragraphs, sources).unwrap_or_default();
+        drop(learned);
+        Self {
+            translated_lines: line::reorder(numbering, paragraphs),
+            salvaged,
+            stats,
+            truncated: false,
+            exchange: None,
+            model: String::new(),
+        }
+    }
+}
+
+/// Token usage of one request.
+#[derive(Default)]
+pub struct Stats {
+    pub prompt_tokens: i32,
+    pub output_tokens: i32,
+    pub total_tokens: i32,
+    /// prompt tokens read from a cached preamble, part of `prompt_tokens`
+    pub cached_tokens: i32,
 }
 
-pub enum Translator {
-    OpenAi(Context),
-    Gemini(Context),
+impl Stats {
+    pub fn log(&self) {
+        let cached = match self.cached_tokens {
+            0 => String::new(),
+            cached => format!(" cached tokens: {}", cached),
+        };
+        info!(
+            "prompt tokens: {}{} output tokens: {} total tokens: {}",
+            self.prompt_tokens, cached, self.output_tokens, self.total_tokens
+        );
+    }
+}
+
+pub struct Translator {
+    context: Context,
+    backend: Box<dyn Backend>,
 }
 
 impl Translator {
+    pub fn new(context: Context, backend: impl Backend + 'static) -> Self {
+        Self {
+            context,
+            backend: Box::new(backend),
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Check that the API key can use the model before translating, and
+    /// that of the drafter.
+    pub async fn preflight(&self) -> Result<(), String> {
+        if let Some(drafter) = &self.context.drafter {
+            Box::pin(drafter.preflight())
+                .await
+                .map_err(|e| format!("--draft-with: {}", e))?;
+        }
+        self.backend.probe(&self.context).await
+    }
+
+    /// Stop on `shutdown`, with the drafter.
+    pub fn set_shutdown(&mut self, shutdown: Arc<Shutdown>) {
+        if let Some(drafter) = &mut self.context.drafter {
+            drafter.set_shutdown(shutdown.clone());
+        }
+        self.context.shutdown = shutdown;
+    }
+
+    /// Take the commands of `--control`, with the drafter.
+    pub fn set_control(&mut self, control: Arc<Control>) {
+        if let Some(drafter) = &mut self.context.drafter {
+            drafter.set_control(control.clone());
+        }
+        self.context.control = control;
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+
+    /// Answer a free-form prompt within the concurrency limit.
+    pub async fn complete(&self, prompt: &str) -> Result<String, String> {
+        self.complete_counted(prompt, &self.context().totals).await
+    }
+
+    /// Answer a prompt of the refine pass, counted in its own totals; the
+    /// post-edit of drafts is the translation of the run, counted in its
+    /// totals.
+    pub async fn refine(&self, prompt: &str) -> Result<String, String> {
+        let context = self.context();
+        let totals = match context.drafter {
+            Some(_) => &context.totals,
+            None => &context.refine_totals,
+        };
+        self.complete_counted(prompt, totals).await
+    }
+
+    async fn complete_counted(&self, prompt: &str, totals: &Totals) -> Result<String, String> {
+        let context = self.context();
+        let model = context.request_model();
+        if let Some(budget) = &context.budget {
+            if !budget.allows(&model).await {
+                context.shutdown.request();
+                return Err("spending cap reached".to_string());
+            }
+        }
+        let _permit = context.limiter.acquire().await;
+        let tokens = 2 * chunk::estimate_tokens(prompt);
+        context.limiter.reserve(tokens).await;
+        let sent = Instant::now();
+        let completion = tokio::select! {
+            completion = self.backend.complete(context, prompt) => completion?,
+            _ = context.shutdown.requested() => return Err("interrupted".to_string()),
+        };
+        context.limiter.observe(sent.elapsed(), tokens);
+        let stats = &completion.stats;
+        context.limiter.settle(tokens, stats.total_tokens);
+        if let Some(budget) = &context.budget {
+            budget.charge(&model, stats);
+        }
+        totals.add(&model, stats);
+        Ok(completion.text)
+    }
+
+    /// Send the request of a dumped `exchange` again and give the raw text
+    /// of the response.
+    pub async fn replay(&self, exchange: &Exchange) -> Result<String, String> {
+        let completion = self.backend.replay(&self.context, exchange).await?;
+        completion.stats.log();
+        Ok(completion.text)
+    }
+
+    /// Transcribe the text of an image within the concurrency limit.
+    #[cfg(feature = "ocr")]
+    pub async fn transcribe(
+        &self,
+        prompt: &str,
+        mime: &str,
+        image: &[u8],
+    ) -> Result<String, String> {
+        let context = self.context();
+        let _permit = context.limiter.acquire().await;
+        let completion = tokio::select! {
+            completion = self.backend.transcribe(context, prompt, mime, image) => completion?,
+            _ = context.shutdown.requested() => return Err("interrupted".to_string()),
+        };
+        context
+            .totals
+            .add(&context.request_model(), &completion.stats);
+        Ok(completion.text)
+    }
+
+    /// End a run once its output is written: leave the progress line, log
+    /// the totals and write them to `--stats-out`, then fail if the quota or
+    /// the budget ran out before the end.
+    pub fn finish(&self) -> Result<(), Error> {
+        let context = self.context();
+        context.progress.finish(&context.totals);
+        let mut summary = context.totals.summary(&context.model);
+        summary.log();
+        if context.refine {
+            let refine = context.refine_totals.summary(&context.model);
+            info!("refine pass:");
+            refine.log();
+            summary.refine = Some(Box::new(refine));
+        }
+        if let Some(drafter) = &context.drafter {
+            let drafter = drafter.context();
+            let draft = drafter.totals.summary(&drafter.model);
+            info!("draft pass:");
+            draft.log();
+            summary.draft = Some(Box::new(draft));
+        }
+        if let Some(path) = &context.stats_out {
+            std::fs::write(path, serde_json::to_string_pretty(&summary)? + "\n")?;
+        }
+        if let Some(resume_at) = context.quota.resume_at() {
+            return Err(Error::Api(format!(
+                "quota exhausted, the output is incomplete; run again with the same options and --resume after {}",
+                quota::utc(resume_at)
+            )));
+        }
+        if context.shutdown.is_requested() {
+            let rest = match (&context.memory, &context.cache) {
+                (Some(_), _) => "run again with the same options and --resume to translate the rest",
+                (None, Some(_)) => "run again with the same options to request only the rest, the translated paragraphs are cached",
+                (None, None) => "nothing was recorded, run with --resume to keep what gets translated",
+            };
+            if context
+                .budget
+                .as_ref()
+                .is_some_and(|budget| budget.is_exceeded())
+            {
+                return Err(Error::OverBudget(rest.to_string()));
+            }
+            return Err(Error::Interrupted(rest.to_string()));
+        }
+        Ok(())
+    }
+
     pub async fn translate(&self, lines: Vec<String>) -> Vec<String> {
-        match self {
-            Self::OpenAi(context) => open_ai(context, lines).await,
-            Self::Gemini(context) => gemini(context, lines).await,
+        self.translate_into(lines, &self.context().language).await
+    }
+
+    /// Translate into `language` instead of the language of the run.
+    pub async fn translate_into(&self, lines: Vec<String>, language: &str) -> Vec<String> {
+        let context = self.context();
+        let post_edit = context.refine || context.drafter.is_some();
+        if !post_edit && context.review.is_none() && context.collected.is_none() {
+            return self.translate_drafts(lines, language).await;
+        }
+        let sources = lines.clone();
+        let mut translated = self.translate_drafts(lines, language).await;
+        if post_edit {
+            translated = refine::refine(self, &sources, translated, language).await;
+            record(
+                context,
+                language,
+                &context.request_model(),
+                &sources,
+                &translated,
+            );
+        }
+        if let Some(review) = &context.review {
+            translated = self.review(review, &sources, translated, language).await;
+        }
+        if let Some(collected) = &context.collected {
+            collected.record(language, &context.request_model(), &sources, &translated);
+        }
+        translated
+    }
+
+    /// Translate, from the memory and the cache first when there are any.
+    async fn translate_drafts(&self, lines: Vec<String>, language: &str) -> Vec<String> {
+        let context = self.context();
+        if context.recalled.is_empty() && context.cache.is_none() {
+            self.draft(lines, language).await
+        } else {
+            self.resume(lines, language).await
+        }
+    }
+
+    /// Ask in the terminal about the translations of `sources` flagged for
+    /// review, one document at a time.
+    async fn review(
+        &self,
+        review: &Review,
+        sources: &[String],
+        mut translated: Vec<String>,
+        language: &str,
+    ) -> Vec<String> {
+        let flagged: Vec<(usize, String)> = sources
+            .iter()
+            .zip(&translated)
+            .enumerate()
+            .filter_map(|(i, (source, translation))| {
+                review
+                    .take(language, source, translation)
+                    .map(|reason| (i, reason))
+            })
+            .collect();
+        if flagged.is_empty() {
+            return translated;
+        }
+        let _terminal = review.terminal.lock().await;
+        let total = flagged.len();
+        for (number, (i, mut reason)) in flagged.into_iter().enumerate() {
+            loop {
+                let decision = review
+                    .ask((number + 1, total), &reason, &sources[i], &translated[i])
+                    .await;
+                match decision {
+                    Ok(Decision::Accept) => break,
+                    Ok(Decision::Edit(edited)) => {
+                        record(
+                            self.context(),
+                            language,
+                            &self.context().request_model(),
+                            &sources[i..=i],
+                            std::slice::from_ref(&edited),
+                        );
+                        translated[i] = edited;
+                        break;
+                    }
+                    Ok(Decision::Retranslate) => {
+                        let again = self
+                            .translate_requested(vec![sources[i].clone()], language)
+                            .await;
+                        translated[i] = again.into_iter().next().unwrap_or_default();
+                        reason = review
+                            .take(language, &sources[i], &translated[i])
+                            .unwrap_or_else(|| "translated again".to_string());
+                    }
+                    Err(e) => {
+                        error!("review: {}", e);
+                        return translated;
+                    }
+                }
+            }
+        }
+        translated
+    }
+
+    /// Check the translations of the paragraphs of the document `name` for
+    /// letters of scripts their language is not written in: each paragraph
+    /// is logged with its number, and translated again with
+    /// `--script-check requeue`.
+    pub async fn check_scripts(
+        &self,
+        name: &str,
+        sources: &[String],
+        translated: &mut [String],
+        language: &str,
+    ) {
+        let context = self.context();
+        let Some(check) = context.script_check else {
+            return;
+        };
+        let allowed = match quality::expected(language) {
+            _ if !context.allowed_scripts.is_empty() => &context.allowed_scripts[..],
+            Some(allowed) => allowed,
+            None => return,
+        };
+        let unexpected =
+            |translation: &str| quality::unexpected(&markup::strip(translation), allowed);
+        let offending: Vec<usize> = translated
+            .iter()
+            .enumerate()
+            .filter_map(|(i, translation)| {
+                let scripts = unexpected(translation);
+                if scripts.is_empty() {
+                    return None;
+                }
+                warn!(
+                    "script check: {} paragraph {}: {:?} letters in the {} translation: {}",
+                    name,
+                    i + 1,
+                    scripts,
+                    language,
+                    translation
+                );
+                Some(i)
+            })
+            .collect();
+        if check == ScriptCheck::Report || offending.is_empty() {
+            return;
+        }
+        context.totals.retry_each(offending.len());
+        let again = offending.iter().map(|i| sources[*i].clone()).collect();
+        let again = self.translate_requested(again, language).await;
+        for (i, translation) in offending.into_iter().zip(again) {
+            if translation.is_empty() {
+                continue;
+            }
+            if !unexpected(&translation).is_empty() {
+                warn!(
+                    "script check: {} paragraph {}: still {:?} letters after translating again",
+                    name,
+                    i + 1,
+                    unexpected(&translation)
+                );
+            }
+            translated[i] = translation;
+        }
+    }
+
+    /// Translate with the backend, or have the drafter write the drafts to
+    /// post-edit.
+    async fn draft(&self, lines: Vec<String>, language: &str) -> Vec<String> {
+        let context = self.context();
+        let Some(drafter) = &context.drafter else {
+            return self.translate_requested(lines, language).await;
+        };
+        let count = lines.len();
+        let drafts = Box::pin(drafter.translate_into(lines, language)).await;
+        context.progress.advance(count, &context.totals);
+        drafts
+    }
+
+    async fn translate_requested(&self, lines: Vec<String>, language: &str) -> Vec<String> {
+        translate(self.backend.as_ref(), self.context(), language, lines).await
+    }
+
+    /// Take the translations recalled from the memory or found in the cache
+    /// and translate only the remaining paragraphs.
+    async fn resume(&self, lines: Vec<String>, language: &str) -> Vec<String> {
+        if lines.is_empty() {
+            return lines;
+        }
+        let mut translated: Vec<Option<String>> = lines
+            .iter()
+            .map(|line| self.recall(language, line))
+            .collect();
+        let missing: Vec<String> = lines
+            .iter()
+            .zip(&translated)
+            .filter(|(_, recalled)| recalled.is_none())
+            .map(|(line, _)| line.clone())
+            .collect();
+        info!(
+            "resume: {}/{} paragraphs recalled",
+            lines.len() - missing.len(),
+            lines.len()
+        );
+        let context = self.context();
+        context
+            .progress
+            .advance(lines.len() - missing.len(), &context.totals);
+        let mut remaining = if missing.is_empty() {
+            Vec::new()
+        } else {
+            self.draft(missing, language).await
+        }
+        .into_iter();
+        let mut translated: Vec<String> = translated
+            .iter_mut()
+            .map(|line| line.take().or_else(|| remaining.next()).unwrap_or_default())
+            .collect();
+        // the cache matches paragraphs differing in whitespace
+        whitespace(self.context().whitespace, &lines, &mut translated);
+        if self.context().typography {
+            typography::typeset(language, &mut translated);
+        }
+        translated
+    }
+
+    fn recall(&self, language: &str, line: &str) -> Option<String> {
+        let context = self.context();
+        let recalled = context
+            .recalled
+            .get(&(language.to_string(), line.to_string()));
+        // the memory, `--tmx` and XLIFF files do not tell the model
+        let (translation, model) = match (recalled, &context.cache) {
+            (Some(recalled), _) => (recalled.clone(), None),
+            (None, Some(cache)) => {
+                let model = context.request_model();
+                (cache.get(&model, language, line)?, Some(model))
+            }
+            (None, None) => return None,
+        };
+        if let Some(provenance) = &context.provenance {
+            let origin = Origin {
+                model,
+                recalled: true,
+            };
+            provenance.credit(language, &[line.to_string()], &origin);
+        }
+        Some(translation)
+    }
+}
+
+async fn translate(
+    backend: &dyn Backend,
+    context: &Context,
+    language: &str,
+    lines: Vec<String>,
+) -> Vec<String> {
+    debug!("line_length:{}", lines.len());
+    if lines.is_empty() {
+        return lines;
+    }
+    // with a token budget the chunks are packed by tokens instead of `--lines`
+    let chunk_lines = match context.max_chunk_tokens {
+        Some(_) => usize::MAX,
+        None => context.lines,
+    };
+    translate_parallel(backend, context, language, lines, chunk_lines, 0).await
+}
+
+/// Translate `lines` in chunks, `requests` at a time. Each chunk carries its
+/// number from `enumerate` through its request, and the responses are put
+/// back in that order, so the translations follow `lines` whichever request
+/// finishes first.
+async fn translate_parallel(
+    backend: &dyn Backend,
+    context: &Context,
+    language: &str,
+    lines: Vec<String>,
+    chunk_lines: usize,
+    retry_count: i32,
+) -> Vec<String> {
+    let chunks = chunk::split(
+        &lines,
+        chunk_lines.min(context.max_paragraphs_per_chunk.unwrap_or(usize::MAX)),
+        context.max_chunk_tokens,
+        context.scene_breaks,
+    );
+    let starts: Vec<usize> = chunks
+        .iter()
+        .scan(0, |start, chunk| {
+            *start += chunk.len();
+            Some(*start - chunk.len())
+        })
+        .collect();
+    let ids = logging::chunk_ids(chunks.len());
+    // translations of the finished chunks, sent as context of the next ones
+    let done: Mutex<Vec<Option<String>>> = Mutex::new(vec![None; lines.len()]);
+    let mut responses: Vec<_> = stream::iter(chunks.into_iter().enumerate())
+        .map(|(number, chunked)| {
+            let (lines, done, start) = (&lines, &done, starts[number]);
+            logging::traced(ids[number].clone(), async move {
+                let preceding = preceding(context, lines, start, done);
+                let mut response =
+                    translate_bulk(backend, context, language, chunked, &preceding).await;
+                if let Ok(response) = &mut response {
+                    // a model may translate the context along with the chunk
+                    if !preceding.is_empty()
+                        && response.translated_lines.len() == preceding.len() + chunked.len()
+                    {
+                        response.translated_lines.drain(..preceding.len());
+                    }
+                    if response.translated_lines.len() == chunked.len() {
+                        if context.preserve_emphasis {
+                            emphasis::restore(language, chunked, &mut response.translated_lines);
+                        }
+                        finish(
+                            context,
+                            language,
+                            &response.model,
+                            chunked,
+                            &mut response.translated_lines,
+                        );
+                        if retry_count == 0 {
+                            context.progress.advance(chunked.len(), &context.totals);
+                        }
+                        let mut done = done.lock().unwrap();
+                        for (i, translation) in response.translated_lines.iter().enumerate() {
+                            done[start + i] = Some(translation.clone());
+                        }
+                    }
+                }
+                (number, chunked, response)
+            })
+        })
+        .buffer_unordered(context.requests)
+        .collect()
+        .await;
+
+    responses.sort_by_key(|(number, _, _)| *number);
+    let mut translated = vec![];
+    for (number, original_lines, response) in responses {
+        let settled = settle(
+            backend,
+            context,
+            language,
+            original_lines,
+            response,
+            retry_count,
+        );
+        translated.append(&mut logging::traced(ids[number].clone(), settled).await);
+    }
+    translated
+}
+
+/// What becomes of the `response` to the chunk of `original_lines` sent in
+/// round `retry_count`: its translations, or those of its retries, halves or
+/// escalation, or what `--on-failure` leaves.
+async fn settle(
+    backend: &dyn Backend,
+    context: &Context,
+    language: &str,
+    original_lines: &[String],
+    response: Result<BulkTranslated, Error>,
+    retry_count: i32,
+) -> Vec<String> {
+    let truncated = matches!(&response, Ok(response) if response.truncated);
+    let (mut translated_lines, salvaged, model, failure) = match response {
+        Ok(mut response) => {
+            if let (Some(dumps), Some(exchange)) = (&context.dumps, response.exchange.take()) {
+                if let Some(reason) =
+                    dump_reason(truncated, &response.translated_lines, original_lines)
+                {
+                    dumps.dump(
+                        context,
+                        backend.provider(),
+                        language,
+                        &reason,
+                        original_lines,
+                        exchange,
+                    );
+                }
+            }
+            let stats = &response.stats;
+            context.totals.add(&response.model, stats);
+            if context.stats_per_chunk {
+                stats.log();
+            }
+            (
+                response.translated_lines,
+                response.salvaged,
+                response.model,
+                None,
+            )
+        }
+        Err(e) => {
+            error!(stage = "request"; "request error: {}", e);
+            (vec![], vec![], String::new(), Some(e.to_string()))
+        }
+    };
+    // a chunk too long for the output token limit is split in halves,
+    // which is not a retry round
+    if truncated && original_lines.len() > 1 && translated_lines.len() != original_lines.len() {
+        warn!(
+            stage = "split";
+            "the response was cut short at the output token limit, translating the {} paragraphs in halves; lower --lines",
+            original_lines.len()
+        );
+        context.totals.split();
+        let halves = Box::pin(translate_parallel(
+            backend,
+            context,
+            language,
+            original_lines.to_vec(),
+            original_lines.len().div_ceil(2),
+            retry_count,
+        ));
+        return halves.await;
+    }
+    let given_up = original_lines.len() == 1 && retry_count >= context.max_retries as i32;
+    let failed = failure.is_some() || translated_lines.len() != original_lines.len();
+    if let Some(model) = fallback(context).filter(|_| given_up && failed) {
+        warn!(stage = "escalate"; "escalating a paragraph to {}", model);
+        context.totals.escalate();
+        let escalated = Box::pin(translate_parallel(
+            backend,
+            context,
+            language,
+            original_lines.to_vec(),
+            1,
+            retry_count,
+        ));
+        return RETRY_MODEL.scope(model, escalated).await;
+    }
+    if let Some(review) = &context.review {
+        if let (Some(failure), true) = (&failure, given_up) {
+            review.flag(language, original_lines, &format!("failed: {}", failure));
+        } else if translated_lines.len() != original_lines.len() && given_up {
+            review.flag(language, original_lines, "failed: line count mismatch");
+        } else if let Some(failure) = &failure {
+            review.flag(language, original_lines, &format!("retried: {}", failure));
+        } else if translated_lines.len() != original_lines.len() {
+            review.flag(language, original_lines, "retried: line count mismatch");
+        }
+    }
+    if let (Some(failure), true) = (&failure, given_up) {
+        // nothing was translated, so nothing is recorded and --resume
+        // requests the paragraph again
+        error!(stage = "failed"; "request error after retry, {:?}", context.on_failure);
+        context.report.record(language, failure, original_lines);
+        translated_lines = on_failure(
+            context.on_failure,
+            original_lines.to_vec(),
+            translated_lines,
+        );
+    } else if translated_lines.len() != original_lines.len() && given_up {
+        error!(
+            stage = "failed";
+            "translated line length error {}/1 after retry, {:?}",
+            translated_lines.len(),
+            context.on_failure
+        );
+        translated_lines = on_failure(
+            context.on_failure,
+            original_lines.to_vec(),
+            translated_lines,
+        );
+        // what --on-failure leaves is not a translation: as for a request
+        // error, nothing is recorded and --resume requests it again
+        polish(context, original_lines, &mut translated_lines);
+    } else if translated_lines.len() != original_lines.len() {
+        for l in original_lines {
+            trace!("{}", l);
+        }
+        for l in &translated_lines {
+            trace!("{}", l);
+        }
+        error!(stage = "retry"; "retry count: {}", retry_count);
+        context.totals.retry();
+        error!(
+            stage = "retry";
+            "translated line length error {}/{}",
+            translated_lines.len(),
+            original_lines.len()
+        );
+        translated_lines = if salvaged.is_empty() {
+            retry(
+                backend,
+                context,
+                language,
+                original_lines.to_vec(),
+                retry_count,
+            )
+            .await
+        } else {
+            Box::pin(salvage(
+                backend,
+                context,
+                language,
+                original_lines,
+                salvaged,
+                &model,
+                retry_count,
+            ))
+            .await
+        };
+        if retry_count == 0 {
+            context
+                .progress
+                .advance(original_lines.len(), &context.totals);
+        }
+    }
+    if context.quality_check && retry_count == 0 && translated_lines.len() == original_lines.len() {
+        requeue(
+            backend,
+            context,
+            language,
+            original_lines,
+            &mut translated_lines,
+        )
+        .await;
+    }
+    translated_lines
+}
+
+/// Why the `translated_lines` of a response to `sources` are dumped, if
+/// they cannot be used.
+fn dump_reason(truncated: bool, translated_lines: &[String], sources: &[String]) -> Option<String> {
+    if translated_lines.len() == sources.len() {
+        None
+    } else if truncated {
+        Some("cut short at the output token limit".to_string())
+    } else if translated_lines.is_empty() {
+        Some("the response could not be parsed".to_string())
+    } else {
+        Some(format!(
+            "line count mismatch {}/{}",
+            translated_lines.len(),
+            sources.len()
+        ))
+    }
+}
+
+/// The `--fallback` model after the one requests are sent to now, if any.
+fn fallback(context: &Context) -> Option<String> {
+    let model = context.request_model();
+    let next = match context
+        .fallback
+        .iter()
+        .position(|fallback| *fallback == model)
+    {
+        Some(position) => position + 1,
+        None => 0,
+    };
+    context.fallback.get(next).cloned()
+}
+
+/// Translate again the `lines` of a chunk that failed in round
+/// `retry_count`, in the smaller chunks of the retry strategy, or one by one
+/// in the last round.
+async fn retry(
+    backend: &dyn Backend,
+    context: &Context,
+    language: &str,
+    lines: Vec<String>,
+    retry_count: i32,
+) -> Vec<String> {
+    let round = retry_count + 1;
+    let chunk_lines = if round >= context.max_retries as i32 {
+        1
+    } else {
+        match context.retry_strategy {
+            RetryStrategy::Halve => lines.len().div_ceil(2),
+            RetryStrategy::Split | RetryStrategy::Model => context.retry_chunk_size,
+        }
+    };
+    let retried = Box::pin(translate_parallel(
+        backend,
+        context,
+        language,
+        lines,
+        chunk_lines.max(1),
+        round,
+    ));
+    match (context.retry_strategy, &context.retry_model) {
+        (RetryStrategy::Model, Some(model)) => RETRY_MODEL.scope(model.clone(), retried).await,
+        _ => retried.await,
+    }
+}
+
+/// Keep the translations of a chunk that were placed by their `line` number
+/// although some paragraphs were left out, recorded as those of the `model`
+/// that answered, and translate only those again.
+async fn salvage(
+    backend: &dyn Backend,
+    context: &Context,
+    language: &str,
+    sources: &[String],
+    salvaged: Vec<Option<String>>,
+    model: &str,
+    retry_count: i32,
+) -> Vec<String> {
+    let (kept, missing): (Vec<_>, Vec<_>) =
+        (0..sources.len()).partition(|i| salvaged[*i].is_some());
+    info!(
+        "kept {}/{} translations by their line number, translating the others again",
+        kept.len(),
+        sources.len()
+    );
+    let kept_sources: Vec<String> = kept.iter().map(|i| sources[*i].clone()).collect();
+    let mut kept_lines: Vec<String> = salvaged.into_iter().flatten().collect();
+    if context.preserve_emphasis {
+        emphasis::restore(language, &kept_sources, &mut kept_lines);
+    }
+    finish(context, language, model, &kept_sources, &mut kept_lines);
+    let again = missing.iter().map(|i| sources[*i].clone()).collect();
+    let again = retry(backend, context, language, again, retry_count).await;
+    let mut translated = vec![String::new(); sources.len()];
+    for (i, translation) in kept.into_iter().zip(kept_lines) {
+        translated[i] = translation;
+    }
+    for (i, translation) in missing.into_iter().zip(again) {
+        translated[i] = translation;
+    }
+    translated
+}
+
+/// The `--context-lines` paragraphs before the chunk at `start` of `lines`,
+/// with the translations that are `done`.
+fn preceding(
+    context: &Context,
+    lines: &[String],
+    start: usize,
+    done: &Mutex<Vec<Option<String>>>,
+) -> Vec<Preceding> {
+    let first = start.saturating_sub(context.context_lines);
+    let done = done.lock().unwrap();
+    (first..start)
+        .map(|i| Preceding {
+            original: lines[i].clone(),
+            translation: done[i]
+                .clone()
+                .filter(|translation| !translation.is_empty()),
+        })
+        .collect()
+}
+
+/// Translate again, one by one, the paragraphs of a chunk whose translations
+/// fail the [quality check](quality::check).
+async fn requeue(
+    backend: &dyn Backend,
+    context: &Context,
+    language: &str,
+    sources: &[String],
+    translated: &mut [String],
+) {
+    let failed: Vec<usize> = sources
+        .iter()
+        .zip(translated.iter())
+        .enumerate()
+        .filter_map(|(i, (source, translation))| {
+            let problem = quality::check(source, translation)?;
+            warn!(stage = "quality"; "quality check: {}, translating again: {}", problem, source);
+            Some(i)
+        })
+        .collect();
+    if failed.is_empty() {
+        return;
+    }
+    // each is sent alone
+    context.totals.retry_each(failed.len());
+    let again = failed.iter().map(|i| sources[*i].clone()).collect();
+    let again = Box::pin(translate_parallel(backend, context, language, again, 1, 1)).await;
+    for (i, translation) in failed.into_iter().zip(again) {
+        translated[i] = translation;
+    }
+}
+
+/// Polish a chunk finished by `model`, then record it to the memory and the
+/// cache right away, so an interrupted run loses no more than the chunks in
+/// flight.
+fn finish(
+    context: &Context,
+    language: &str,
+    model: &str,
+    sources: &[String],
+    translated: &mut [String],
+) {
+    polish(context, sources, translated);
+    record(context, language, model, sources, translated);
+}
+
+/// Enforce the glossary and normalize the whitespace of translated lines.
+fn polish(context: &Context, sources: &[String], translated: &mut [String]) {
+    if context.enforce_glossary {
+        context.glossary.enforce(sources, translated);
+    }
+    whitespace(context.whitespace, sources, translated);
+}
+
+/// Record translations by `model` to the memory and the cache, under that
+/// model rather than the one of the run when they come from `--fallback`,
+/// `--retry-model` or a model given through `--control`.
+fn record(
+    context: &Context,
+    language: &str,
+    model: &str,
+    sources: &[String],
+    translated: &[String],
+) {
+    if let Some(provenance) = &context.provenance {
+        let origin = Origin {
+            model: Some(model.to_string()),
+            recalled: false,
+        };
+        provenance.credit(language, sources, &origin);
+    }
+    if context.memory.is_none() && context.cache.is_none() {
+        return;
+    }
+    let segments: Vec<Segment> = sources
+        .iter()
+        .zip(translated.iter())
+        .filter(|(_, target)| !target.is_empty())
+        .map(|(source, target)| Segment {
+            source: source.clone(),
+            target: target.clone(),
+            language: language.to_string(),
+            model: model.to_string(),
+        })
+        .collect();
+    debug!(stage = "write"; "recording {} translations", segments.len());
+    if let Some(memory) = &context.memory {
+        if let Err(e) = memory.append(&segments) {
+            error!(stage = "write"; "translation memory write error: {}", e);
         }
     }
+    if let Some(cache) = &context.cache {
+        cache.insert(&segments);
+    }
+}
+
+/// Translate one chunk within the concurrency and rate limits, or leave it blank,
+/// keeping the line count, once the run stopped for exhausted quota or on
+/// Ctrl-C; a request in flight then is dropped.
+async fn translate_bulk(
+    backend: &dyn Backend,
+    context: &Context,
+    language: &str,
+    lines: &[String],
+    preceding: &[Preceding],
+) -> Result<BulkTranslated, Error> {
+    let stopped = || BulkTranslated {
+        translated_lines: vec![String::new(); lines.len()],
+        ..BulkTranslated::default()
+    };
+    let is_stopped = || context.quota.is_exhausted() || context.shutdown.is_requested();
+    tokio::select! {
+        _ = context.control.resumed() => (),
+        _ = context.shutdown.requested() => return Ok(stopped()),
+    }
+    let model = context.request_model();
+    if let Some(budget) = &context.budget {
+        if !budget.allows(&model).await {
+            // the run stops as on Ctrl-C, the book written with what is
+            // translated
+            context.shutdown.request();
+            return Ok(stopped());
+        }
+    }
+    let _permit = context.limiter.acquire().await;
+    if is_stopped() {
+        return Ok(stopped());
+    }
+    // the translation is about as long as the paragraphs it is sent with
+    let tokens = 2 * lines
+        .iter()
+        .map(|line| chunk::estimate_tokens(line))
+        .sum::<usize>();
+    let translated = async {
+        context.limiter.reserve(tokens).await;
+        let sent = Instant::now();
+        let response = backend
+            .translate_bulk(context, language, lines, preceding)
+            .await;
+        if response.is_ok() {
+            context.limiter.observe(sent.elapsed(), tokens);
+        }
+        response
+    };
+    let mut response = tokio::select! {
+        response = translated => response?,
+        _ = context.shutdown.requested() => return Ok(stopped()),
+    };
+    context.limiter.settle(tokens, response.stats.total_tokens);
+    if let Some(budget) = &context.budget {
+        budget.charge(&model, &response.stats);
+    }
+    response.model = model;
+    if is_stopped() {
+        return Ok(stopped());
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epub::provenance::ProvenanceMode;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// A backend that answers each chunk after a pseudo-random delay, so the
+    /// requests in flight finish in another order than they were sent.
+    struct Delayed {
+        state: AtomicU64,
+    }
+
+    impl Delayed {
+        fn new(seed: u64) -> Self {
+            Self {
+                state: AtomicU64::new(seed | 1),
+            }
+        }
+
+        /// The next number of a xorshift generator.
+        fn next(&self) -> u64 {
+            let mut x = self.state.load(Ordering::Relaxed);
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.state.store(x, Ordering::Relaxed);
+            x
+        }
+    }
+
+    impl Backend for Delayed {
+        fn translate_bulk<'a>(
+            &'a self,
+            _context: &'a Context,
+            _language: &'a str,
+            lines: &'a [String],
+            _preceding: &'a [Preceding],
+        ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+            let delay = Duration::from_micros(self.next() % 3000);
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                Ok(BulkTranslated {
+                    translated_lines: lines.iter().map(|line| format!("T:{}", line)).collect(),
+                    ..BulkTranslated::default()
+                })
+            })
+        }
+    }
+
+    /// A backend that always answers with one line too many.
+    struct Mismatched;
+
+    impl Backend for Mismatched {
+        fn translate_bulk<'a>(
+            &'a self,
+            _context: &'a Context,
+            _language: &'a str,
+            lines: &'a [String],
+            _preceding: &'a [Preceding],
+        ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+            Box::pin(async move {
+                let mut translated_lines: Vec<String> =
+                    lines.iter().map(|line| format!("T:{}", line)).collect();
+                translated_lines.push("extra".to_string());
+                Ok(BulkTranslated {
+                    translated_lines,
+                    ..BulkTranslated::default()
+                })
+            })
+        }
+    }
+
+    /// A backend that answers only the requests sent to the model `backup`.
+    struct Overloaded;
+
+    impl Backend for Overloaded {
+        fn translate_bulk<'a>(
+            &'a self,
+            context: &'a Context,
+            _language: &'a str,
+            lines: &'a [String],
+            _preceding: &'a [Preceding],
+        ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+            Box::pin(async move {
+                if context.request_model() != "backup" {
+                    return Err(Error::Api("overloaded".to_string()));
+                }
+                Ok(BulkTranslated {
+                    translated_lines: lines.iter().map(|line| format!("B:{}", line)).collect(),
+                    ..BulkTranslated::default()
+                })
+            })
+        }
+    }
+
+    /// A backend that fails every request and counts them.
+    #[derive(Default)]
+    struct Failing {
+        requests: AtomicU64,
+    }
+
+    impl Backend for Failing {
+        fn translate_bulk<'a>(
+            &'a self,
+            _context: &'a Context,
+            _language: &'a str,
+            _lines: &'a [String],
+            _preceding: &'a [Preceding],
+        ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Err(Error::Api("unavailable".to_string())) })
+        }
+    }
+
+    /// A backend that answers a paragraph starting with `bad` first with its
+    /// `flaw`, and then translated, counting the requests.
+    struct Flawed {
+        flaw: fn(&str) -> String,
+        sent: Mutex<HashMap<String, usize>>,
+        requests: AtomicU64,
+    }
+
+    impl Flawed {
+        fn new(flaw: fn(&str) -> String) -> Self {
+            Self {
+                flaw,
+                sent: Mutex::default(),
+                requests: AtomicU64::default(),
+            }
+        }
+    }
+
+    impl Backend for Flawed {
+        fn translate_bulk<'a>(
+            &'a self,
+            _context: &'a Context,
+            _language: &'a str,
+            lines: &'a [String],
+            _preceding: &'a [Preceding],
+        ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            let mut sent = self.sent.lock().unwrap();
+            let translated_lines = lines
+                .iter()
+                .map(|line| {
+                    let count = sent.entry(line.clone()).or_default();
+                    *count += 1;
+                    match (*count, line.starts_with("bad")) {
+                        (1, true) => (self.flaw)(line),
+                        _ => format!("T:{}", line),
+                    }
+                })
+                .collect();
+            Box::pin(async move {
+                Ok(BulkTranslated {
+                    translated_lines,
+                    ..BulkTranslated::default()
+                })
+            })
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trans-epub-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn context(requests: usize) -> Context {
+        Context {
+            language: "German".to_string(),
+            requests,
+            limiter: Arc::new(Limiter::new(requests)),
+            max_retries: 1,
+            ..Context::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn parallel_translations_keep_the_order_of_the_lines() {
+        let mut seed = 0x2545f4914f6cdd1d;
+        for count in [0, 1, 2, 3, 7, 16, 41] {
+            for chunk_lines in 1..=6 {
+                for requests in [1, 2, 4, 9] {
+                    seed += 1;
+                    let backend = Delayed::new(seed);
+                    let context = context(requests);
+                    let lines: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+                    let translated =
+                        translate_parallel(&backend, &context, "German", lines, chunk_lines, 0)
+                            .await;
+                    let expected: Vec<String> = (0..count).map(|i| format!("T:{}", i)).collect();
+                    assert_eq!(
+                        translated, expected,
+                        "{} lines in chunks of {}, {} requests at a time",
+                        count, chunk_lines, requests
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn paragraphs_get_five_retries_by_default() {
+        let backend = Failing::default();
+        let context = Context {
+            max_retries: MAX_RETRIES,
+            on_failure: OnFailure::Passthrough,
+            ..context(1)
+        };
+        let translated =
+            translate_parallel(&backend, &context, "German", vec!["one".to_string()], 1, 0).await;
+        assert_eq!(translated, ["one"]);
+        // the first request and five rounds of retries
+        assert_eq!(backend.requests.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn paragraphs_failing_the_quality_check_are_sent_again() {
+        // the tags of the prompt left in
+        let backend = Flawed::new(|line| format!("<paragraph>{}</paragraph>", line));
+        let context = Context {
+            quality_check: true,
+            ..context(1)
+        };
+        let lines: Vec<String> = ["bad one", "good", "bad two"]
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        let translated = translate_parallel(&backend, &context, "German", lines, 3, 0).await;
+        assert_eq!(translated, ["T:bad one", "T:good", "T:bad two"]);
+        // the chunk, then each failing paragraph alone
+        assert_eq!(backend.requests.load(Ordering::Relaxed), 3);
+        assert_eq!(context.totals.retries(), 2);
+    }
+
+    #[tokio::test]
+    async fn paragraphs_in_other_scripts_are_sent_again() {
+        let backend = Flawed::new(|line| format!("{} Привет", line));
+        let translator = Translator::new(
+            Context {
+                script_check: Some(ScriptCheck::Requeue),
+                lines: 10,
+                ..context(1)
+            },
+            backend,
+        );
+        let sources: Vec<String> = ["bad one", "good", "bad two"]
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        let mut translated = translator.translate_into(sources.clone(), "German").await;
+        assert_eq!(translated, ["bad one Привет", "T:good", "bad two Привет"]);
+        translator
+            .check_scripts("ch1.xhtml", &sources, &mut translated, "German")
+            .await;
+        // sent again in one chunk, and answered in the Latin script
+        assert_eq!(translated, ["T:bad one", "T:good", "T:bad two"]);
+        assert_eq!(translator.context().totals.retries(), 2);
+
+        // only reported
+        let translator = Translator::new(
+            Context {
+                script_check: Some(ScriptCheck::Report),
+                ..context(1)
+            },
+            Flawed::new(|line| line.to_string()),
+        );
+        let mut reported = vec!["Привет".to_string()];
+        translator
+            .check_scripts("ch1.xhtml", &sources[..1], &mut reported, "German")
+            .await;
+        assert_eq!(reported, ["Привет"]);
+        assert_eq!(translator.context().totals.retries(), 0);
+    }
+
+    #[tokio::test]
+    async fn lines_given_up_are_not_recorded() {
+        for on_failure in [OnFailure::Passthrough, OnFailure::Accept, OnFailure::Skip] {
+            let dir = temp_dir(&format!("given-up-{:?}", on_failure));
+            let context = Context {
+                model: "model".to_string(),
+                on_failure,
+                cache: Some(Arc::new(Cache::open(&dir).unwrap())),
+                memory: Some(Memory::new(dir.join("memory.jsonl"))),
+                ..context(1)
+            };
+            let lines = vec!["one".to_string(), "two".to_string()];
+            let translated =
+                translate_parallel(&Mismatched, &context, "German", lines.clone(), 2, 0).await;
+            assert_eq!(translated.len(), 2, "{:?}", on_failure);
+            for line in &lines {
+                let cached = context.cache.as_ref().unwrap().get("model", "German", line);
+                assert_eq!(cached, None, "{:?}", on_failure);
+            }
+            assert!(!dir.join("memory.jsonl").exists(), "{:?}", on_failure);
+            std::fs::remove_dir_all(&dir).ok();
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback_translations_are_recorded_under_their_model() {
+        let dir = temp_dir("fallback");
+        let context = Context {
+            model: "model".to_string(),
+            fallback: vec!["backup".to_string()],
+            max_retries: 0,
+            cache: Some(Arc::new(Cache::open(&dir).unwrap())),
+            memory: Some(Memory::new(dir.join("memory.jsonl"))),
+            provenance: Some(Provenance::new(ProvenanceMode::Sidecar, None)),
+            ..context(1)
+        };
+        let lines = vec!["one".to_string()];
+        let translated =
+            translate_parallel(&Overloaded, &context, "German", lines.clone(), 1, 0).await;
+        assert_eq!(translated, ["B:one"]);
+        let cache = context.cache.as_ref().unwrap();
+        assert_eq!(cache.get("model", "German", "one"), None);
+        assert_eq!(
+            cache.get("backup", "German", "one").as_deref(),
+            Some("B:one")
+        );
+        let recorded = context.memory.as_ref().unwrap().load().unwrap();
+        let models: Vec<&str> = recorded
+            .iter()
+            .map(|segment| segment.model.as_str())
+            .collect();
+        assert_eq!(models, ["backup"]);
+        let origin = context.provenance.as_ref().unwrap().origin("German", "one");
+        assert_eq!(
+            origin.and_then(|origin| origin.model).as_deref(),
+            Some("backup")
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -0,0 +1,223 @@
+use crate::language;
+use regex::Regex;
+
+/// Quotation marks of a language: opening and closing double, then single.
+type Quotes = [char; 4];
+
+/// Quotation marks by language code; the others get the English ones.
+const QUOTES: &[(&str, Quotes)] = &[
+    ("fr", ['«', '»', '‹', '›']),
+    ("de", ['„', '“', '‚', '‘']),
+    ("cs", ['„', '“', '‚', '‘']),
+    ("pl", ['„', '”', '‚', '’']),
+    ("ru", ['«', '»', '„', '“']),
+    ("uk", ['«', '»', '„', '“']),
+    ("es", ['«', '»', '“', '”']),
+    ("ja", ['「', '」', '『', '』']),
+];
+
+/// English quotation marks, also used in Vietnamese, Chinese and Korean.
+const ENGLISH_QUOTES: Quotes = ['“', '”', '‘', '’'];
+
+/// Punctuation of CJK text by its ASCII form, for Chinese and Japanese.
+const FULL_WIDTH: &[(char, char, char)] = &[
+    // (ASCII, Chinese, Japanese)
+    (',', '，', '、'),
+    ('.', '。', '。'),
+    ('!', '！', '！'),
+    ('?', '？', '？'),
+    (':', '：', '：'),
+    (';', '；', '；'),
+    ('(', '（', '（'),
+    (')', '）', '）'),
+];
+
+/// Fix the typography of translated `lines` for their target `language`,
+/// leaving inline markup alone: curly quotation marks in the style of the
+/// language, ellipses, full-width punctuation for Chinese and Japanese, no
+/// space before a comma or a full stop but the no-break spaces of French,
+/// and full-width digits in their ASCII width.
+pub fn typeset(language: &str, lines: &mut [String]) {
+    let typography = Typography::new(language);
+    let tags = Regex::new(r"<[^>]*>").unwrap();
+    for line in lines.iter_mut().filter(|line| !line.is_empty()) {
+        let mut typeset = String::with_capacity(line.len());
+        let mut written = 0;
+        let mut position = Position::default();
+        for tag in tags.find_iter(line) {
+            typeset.push_str(&typography.text(&line[written..tag.start()], &mut position));
+            typeset.push_str(tag.as_str());
+            written = tag.end();
+        }
+        typeset.push_str(&typography.text(&line[written..], &mut position));
+        *line = typeset;
+    }
+}
+
+/// Where the typesetting of a paragraph is, across its markup.
+#[derive(Default)]
+struct Position {
+    /// the character written last
+    last: Option<char>,
+    /// whether a double quotation mark is open
+    double: bool,
+    /// whether a single quotation mark is open
+    single: bool,
+}
+
+/// The rules of a target language.
+struct Typography {
+    code: String,
+    quotes: Quotes,
+    cjk: bool,
+    /// spaces before a comma or a full stop
+    unspaced: Regex,
+    /// spaces after full-width punctuation, which carries its own
+    spaced: Regex,
+    /// where French puts a no-break space: before the punctuation ending a
+    /// word, before closing and after opening quotation marks
+    french: [Regex; 3],
+}
+
+impl Typography {
+    fn new(language: &str) -> Self {
+        let code = language::code(language);
+        let quotes = QUOTES
+            .iter()
+            .find(|(language, _)| *language == code)
+            .map_or(ENGLISH_QUOTES, |(_, quotes)| *quotes);
+        Self {
+            cjk: matches!(code.as_str(), "zh" | "ja"),
+            code,
+            quotes,
+            unspaced: Regex::new(r"(\S)[ \t]+([,.])(\s|$)").unwrap(),
+            spaced: Regex::new(r"([，、。！？：；）」』]) +").unwrap(),
+            french: [
+                Regex::new(r"([^\s\x{a0};:!?«‹])[ \t]*([;:!?])(\s|$|[»›])").unwrap(),
+                Regex::new(r"([^\s\x{a0}])[ \t]*([»›])").unwrap(),
+                Regex::new(r"([«‹])[ \t]*").unwrap(),
+            ],
+        }
+    }
+
+    /// Typeset a text outside markup, from `position`; a double quotation
+    /// mark opens when none is open, a single one at the start or after a
+    /// space, and is an apostrophe otherwise.
+    fn text(&self, text: &str, position: &mut Position) -> String {
+        let text = text.replace("...", if self.cjk { "……" } else { "…" });
+        let chars: Vec<char> = text.chars().collect();
+        let mut typeset = String::with_capacity(text.len());
+        for (i, c) in chars.iter().copied().enumerate() {
+            let last = position.last;
+            let next = chars.get(i + 1).copied();
+            let opening = last.is_none_or(|last| last.is_whitespace() || "([{—–-".contains(last));
+            let c = match c {
+                '０'..='９' => char::from_digit(c as u32 - '０' as u32, 10).unwrap_or(c),
+                '"' => {
+                    position.double = !position.double;
+                    self.quotes[if position.double { 0 } else { 1 }]
+                }
+                '\'' if opening && next.is_some_and(|next| !next.is_whitespace()) => {
+                    position.single = true;
+                    self.quotes[2]
+                }
+                '\'' if position.single && !next.is_some_and(char::is_alphanumeric) => {
+                    position.single = false;
+                    self.quotes[3]
+                }
+                // an apostrophe
+                '\'' => '’',
+                '(' if self.cjk && next.is_some_and(is_cjk) => self.full_width(c, None),
+                c if self.cjk && last.is_some_and(|last| is_cjk(last) || "”’".contains(last)) => {
+                    self.full_width(c, next)
+                }
+                c => c,
+            };
+            typeset.push(c);
+            position.last = Some(c);
+        }
+        if self.cjk {
+            return self.spaced.replace_all(&typeset, "$1").into_owned();
+        }
+        let typeset = self.unspaced.replace_all(&typeset, "$1$2$3");
+        if self.code != "fr" {
+            return typeset.into_owned();
+        }
+        let typeset = self.french[0].replace_all(&typeset, "$1\u{a0}$2$3");
+        let typeset = self.french[1].replace_all(&typeset, "$1\u{a0}$2");
+        self.french[2]
+            .replace_all(&typeset, "$1\u{a0}")
+            .into_owned()
+    }
+
+    /// The full-width form of the ASCII punctuation `c` next to CJK text,
+    /// unless a letter or digit follows it, as in a number.
+    fn full_width(&self, c: char, next: Option<char>) -> char {
+        let Some((_, chinese, japanese)) = FULL_WIDTH.iter().find(|(ascii, _, _)| *ascii == c)
+        else {
+            return c;
+        };
+        if c != '(' && next.is_some_and(|next| next.is_ascii_alphanumeric()) {
+            return c;
+        }
+        if self.code == "ja" {
+            *japanese
+        } else {
+            *chinese
+        }
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{30ff}' | '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}' | '\u{ff00}'..='\u{ffef}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typeset_one(language: &str, line: &str) -> String {
+        let mut lines = vec![line.to_string()];
+        typeset(language, &mut lines);
+        lines.remove(0)
+    }
+
+    #[test]
+    fn quotation_marks_follow_the_language() {
+        let line = r#"He said "it's 'fine'" ..."#;
+        assert_eq!(typeset_one("English", line), "He said “it’s ‘fine’” …");
+        assert_eq!(typeset_one("German", line), "He said „it’s ‚fine‘“ …");
+        assert_eq!(typeset_one("Japanese", r#""はい""#), "「はい」");
+    }
+
+    #[test]
+    fn markup_is_left_alone() {
+        assert_eq!(
+            typeset_one("English", r#"<a href="x.xhtml">"Go" , she said</a>."#),
+            r#"<a href="x.xhtml">“Go”, she said</a>."#
+        );
+    }
+
+    #[test]
+    fn french_gets_no_break_spaces() {
+        assert_eq!(
+            typeset_one("French", r#""Vraiment?" dit-il: "oui !""#),
+            "«\u{a0}Vraiment\u{a0}?\u{a0}» dit-il\u{a0}: «\u{a0}oui\u{a0}!\u{a0}»"
+        );
+    }
+
+    #[test]
+    fn cjk_punctuation_is_full_width() {
+        assert_eq!(
+            typeset_one("Chinese", "你好, 世界! 版本2.5(测试)"),
+            "你好，世界！版本2.5（测试）"
+        );
+        assert_eq!(
+            typeset_one("Japanese", "はい, そうです."),
+            "はい、そうです。"
+        );
+        assert_eq!(typeset_one("English", "Room １２"), "Room 12");
+    }
+}
@@ -0,0 +1,195 @@
+use crate::error::Error;
+use crate::input::{self, Format};
+use crate::pipeline::Factory;
+use log::{error, info};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Translate each book that appears in `inbox` into `outbox`, scanning the
+/// directory every `interval` until the process ends. Books of any input
+/// format are taken, one at a time, each with a translator of its own.
+///
+/// A book is taken once its size holds between two scans, so one still
+/// being copied in is left for later. Translated books are moved into
+/// `inbox/done`; books that failed are moved into `inbox/failed` with the
+/// error next to them as `<name>.error.txt`, and a book with untranslated
+/// chunks is written to `outbox` all the same. Errors of the files are
+/// logged and the watch goes on; a book that could not be moved is not
+/// taken again while it is in `inbox`.
+pub async fn watch(
+    inbox: &Path,
+    outbox: &Path,
+    language: &str,
+    interval: Duration,
+    factory: &Factory,
+) -> Result<(), Error> {
+    let done = inbox.join("done");
+    let failed = inbox.join("failed");
+    for dir in [outbox, &done, &failed] {
+        std::fs::create_dir_all(dir)?;
+    }
+    info!(
+        "watch: {} into {}, every {}sec",
+        inbox.display(),
+        outbox.display(),
+        interval.as_secs()
+    );
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+    // books translated but not moved out of the inbox, not taken again
+    // while they are there
+    let mut stuck: HashSet<PathBuf> = HashSet::new();
+    loop {
+        let books = match books(inbox) {
+            Ok(books) => books,
+            Err(e) => {
+                error!("watch: {}: {}", inbox.display(), e);
+                Vec::new()
+            }
+        };
+        sizes.retain(|path, _| books.contains(path));
+        stuck.retain(|path| books.contains(path));
+        for path in books {
+            if stuck.contains(&path) {
+                continue;
+            }
+            let size = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                // removed since the scan
+                Err(e) => {
+                    error!("watch: {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if sizes.insert(path.clone(), size) != Some(size) {
+                continue;
+            }
+            sizes.remove(&path);
+            if let Err(e) = take(&path, outbox, &done, &failed, language, factory).await {
+                error!(
+                    "watch: {}: {}; it is not taken again until it is moved",
+                    path.display(),
+                    e
+                );
+                stuck.insert(path);
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Translate the book at `path` into `outbox` and move it into `done`, or
+/// into `failed` with its error; an error is of moving the book or of
+/// writing the report.
+async fn take(
+    path: &Path,
+    outbox: &Path,
+    done: &Path,
+    failed: &Path,
+    language: &str,
+    factory: &Factory,
+) -> Result<(), Error> {
+    let name = path.file_name().unwrap_or_default().to_owned();
+    info!("watch: translating {}", path.display());
+    let output = outbox.join(&name);
+    let result = match factory(language, &output) {
+        Ok(translator) => input::translate(path, &output, &translator).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => {
+            info!("watch: {} done", path.display());
+            std::fs::rename(path, done.join(&name))?;
+        }
+        Err(e) => {
+            error!("watch: {}: {}", path.display(), e);
+            // an incomplete book is written, with a report of its
+            // failed chunks; any other output is cut short
+            if !matches!(e, Error::Incomplete { .. }) && output.is_file() {
+                if let Err(e) = std::fs::remove_file(&output) {
+                    error!("watch: {}: {}", output.display(), e);
+                }
+            }
+            std::fs::rename(path, failed.join(&name))?;
+            let mut report = name.clone();
+            report.push(".error.txt");
+            std::fs::write(failed.join(report), format!("{}\n", e))?;
+        }
+    }
+    Ok(())
+}
+
+/// The files of `inbox` in a format that can be translated, in name order.
+fn books(inbox: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut books = Vec::new();
+    for entry in std::fs::read_dir(inbox)? {
+        let path = entry?.path();
+        if path.is_file() && Format::from_path(&path).is_some() {
+            books.push(path);
+        }
+    }
+    books.sort();
+    Ok(books)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{Config, Provider};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn books_that_cannot_be_moved_are_taken_once() {
+        let dir = std::env::temp_dir().join(format!("trans-epub-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (inbox, outbox) = (dir.join("inbox"), dir.join("outbox"));
+        std::fs::create_dir_all(&inbox).unwrap();
+        for name in ["a.txt", "b.txt"] {
+            std::fs::write(inbox.join(name), "The old man walked to the harbour.\n").unwrap();
+        }
+        // a directory in the way of moving a.txt into done
+        std::fs::create_dir_all(inbox.join("done/a.txt/kept")).unwrap();
+        let taken = Arc::new(AtomicUsize::new(0));
+        let counted = taken.clone();
+        let factory: Factory = Box::new(move |language, _| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            let config = Config::new(Provider::Mock, "mock", language);
+            Ok(config.provider.translator(config.context))
+        });
+        let watching = watch(
+            &inbox,
+            &outbox,
+            "German",
+            Duration::from_millis(10),
+            &factory,
+        );
+        // it runs until the process ends
+        assert!(tokio::time::timeout(Duration::from_millis(500), watching)
+            .await
+            .is_err());
+        assert_eq!(taken.load(Ordering::Relaxed), 2);
+        assert!(inbox.join("a.txt").is_file());
+        assert!(inbox.join("done/b.txt").is_file());
+        assert!(outbox.join("a.txt").is_file());
+        assert!(outbox.join("b.txt").is_file());
+
+        // a failed book is moved aside with its error
+        std::fs::write(inbox.join("c.html"), "<p>unclosed").unwrap();
+        let failing: Factory = Box::new(|_, _| Err(Error::Input("no provider".to_string())));
+        let watching = watch(
+            &inbox,
+            &outbox,
+            "German",
+            Duration::from_millis(10),
+            &failing,
+        );
+        assert!(tokio::time::timeout(Duration::from_millis(300), watching)
+            .await
+            .is_err());
+        assert!(inbox.join("failed/c.html").is_file());
+        let report = std::fs::read_to_string(inbox.join("failed/c.html.error.txt")).unwrap();
+        assert_eq!(report, "input error: no provider\n");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,248 @@
+use crate::error::Error;
+use crate::language;
+use crate::memory::Segment;
+use crate::translate::translator::{Backend, BulkTranslated, Context, Preceding};
+use futures::future::BoxFuture;
+use log::warn;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::io::Write;
+
+/// Inline elements of a segment that stand for markup and hold no text.
+const CODES: &[&[u8]] = &[b"ph", b"sc", b"ec", b"cp"];
+
+/// A post-edited XLIFF document: its target language and the source and
+/// target of each translated unit.
+pub struct Xliff {
+    pub language: Option<String>,
+    pub pairs: Vec<(String, String)>,
+}
+
+/// Write segments as an XLIFF 2.1 document of one file, `original` naming
+/// the book. A segment without a translation is written without a target,
+/// in the `initial` state.
+pub fn write<W: Write>(
+    mut writer: W,
+    original: &str,
+    source_language: &str,
+    target_language: &str,
+    segments: &[Segment],
+) -> std::io::Result<()> {
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        writer,
+        r#"<xliff xmlns="urn:oasis:names:tc:xliff:document:2.0" version="2.1" srcLang="{}" trgLang="{}">"#,
+        escape(language::code(source_language).as_str()),
+        escape(language::code(target_language).as_str())
+    )?;
+    writeln!(
+        writer,
+        r#"  <file id="f1" original="{}">"#,
+        escape(original)
+    )?;
+    for (i, segment) in segments.iter().enumerate() {
+        writeln!(writer, r#"    <unit id="u{}">"#, i + 1)?;
+        if segment.target.is_empty() {
+            writeln!(writer, r#"      <segment state="initial">"#)?;
+        } else {
+            writeln!(writer, r#"      <segment state="translated">"#)?;
+        }
+        writeln!(
+            writer,
+            "        <source>{}</source>",
+            escape(&segment.source)
+        )?;
+        if !segment.target.is_empty() {
+            writeln!(
+                writer,
+                "        <target>{}</target>",
+                escape(&segment.target)
+            )?;
+        }
+        writeln!(writer, "      </segment>")?;
+        writeln!(writer, "    </unit>")?;
+    }
+    writeln!(writer, "  </file>")?;
+    writeln!(writer, "</xliff>")
+}
+
+/// The units of an XLIFF 2 document that are translated. The source and
+/// the target of a unit are those of its segments put together, a segment
+/// split or joined in a CAT tool still giving the paragraph, and an
+/// ignorable without a target keeping its source. Inline codes are left
+/// out; a unit with a segment lacking a target is not translated.
+pub fn read(xliff: &[u8]) -> Result<Xliff, Error> {
+    let mut reader = Reader::from_reader(xliff);
+    let mut language = None;
+    let mut pairs = Vec::new();
+    // the source and target of the current unit, and of its current part
+    let (mut source, mut target, mut translated) = (String::new(), String::new(), true);
+    let (mut part_source, mut part_target) = (String::new(), None::<String>);
+    let (mut in_source, mut in_target, mut in_code) = (false, false, 0);
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"xliff" => language = attribute(&e, "trgLang"),
+                b"unit" => {
+                    (source, target, translated) = (String::new(), String::new(), true);
+                }
+                b"segment" | b"ignorable" => (part_source, part_target) = (String::new(), None),
+                b"source" => in_source = true,
+                b"target" => {
+                    in_target = true;
+                    part_target = Some(String::new());
+                }
+                name if CODES.contains(&name) => in_code += 1,
+                _ => (),
+            },
+            Event::Text(e) if in_code == 0 => {
+                let text = e.unescape()?;
+                if in_source {
+                    part_source.push_str(&text);
+                } else if let (true, Some(part_target)) = (in_target, &mut part_target) {
+                    part_target.push_str(&text);
+                }
+            }
+            Event::CData(e) if in_code == 0 => {
+                let text = String::from_utf8_lossy(&e);
+                if in_source {
+                    part_source.push_str(&text);
+                } else if let (true, Some(part_target)) = (in_target, &mut part_target) {
+                    part_target.push_str(&text);
+                }
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"source" => in_source = false,
+                b"target" => in_target = false,
+                name if CODES.contains(&name) => in_code -= 1,
+                b"segment" => {
+                    source.push_str(&part_source);
+                    match &part_target {
+                        Some(part_target) => target.push_str(part_target),
+                        None => translated = false,
+                    }
+                }
+                b"ignorable" => {
+                    source.push_str(&part_source);
+                    target.push_str(part_target.as_ref().unwrap_or(&part_source));
+                }
+                b"unit" if translated && !source.trim().is_empty() && !target.trim().is_empty() => {
+                    pairs.push((source.clone(), target.clone()));
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    Ok(Xliff { language, pairs })
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Option<String> {
+    let value = e.try_get_attribute(name).ok().flatten()?;
+    Some(value.unescape_value().ok()?.into_owned())
+}
+
+/// The backend of `import-xliff`, which has every translation from the
+/// XLIFF already: the paragraphs not in it are left in the original.
+pub struct Unlisted;
+
+impl Backend for Unlisted {
+    fn translate_bulk<'a>(
+        &'a self,
+        _context: &'a Context,
+        _language: &'a str,
+        lines: &'a [String],
+        _preceding: &'a [Preceding],
+    ) -> BoxFuture<'a, Result<BulkTranslated, Error>> {
+        Box::pin(async move {
+            warn!(
+                "xliff: {} paragraphs not in the XLIFF are left in the original",
+                lines.len()
+            );
+            Ok(BulkTranslated {
+                translated_lines: vec![String::new(); lines.len()],
+                ..BulkTranslated::default()
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(source: &str, target: &str) -> Segment {
+        Segment {
+            source: source.to_string(),
+            target: target.to_string(),
+            language: "German".to_string(),
+            model: "mock".to_string(),
+        }
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(source, target)| (source.to_string(), target.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn written_documents_are_read_back() {
+        let segments = [
+            segment("Fish & <chips>", "Fisch & <Pommes>"),
+            segment("Not yet", ""),
+            segment("The end", "Das Ende"),
+        ];
+        let mut written = Vec::new();
+        write(&mut written, "book.epub", "English", "German", &segments).unwrap();
+        let text = String::from_utf8(written.clone()).unwrap();
+        assert!(text.contains(r#"srcLang="en" trgLang="de""#));
+        assert!(text.contains(r#"<segment state="initial">"#));
+        assert!(text.contains("<source>Fish &amp; &lt;chips&gt;</source>"));
+        let xliff = read(&written).unwrap();
+        assert_eq!(xliff.language.as_deref(), Some("de"));
+        assert_eq!(
+            xliff.pairs,
+            pairs(&[
+                ("Fish & <chips>", "Fisch & <Pommes>"),
+                ("The end", "Das Ende")
+            ])
+        );
+    }
+
+    #[test]
+    fn units_join_their_segments_without_codes() {
+        let document = br#"<xliff xmlns="urn:oasis:names:tc:xliff:document:2.0" version="2.1" srcLang="en" trgLang="fr">
+  <file id="f1">
+    <unit id="u1">
+      <segment><source>One. </source><target>Un. </target></segment>
+      <ignorable><source> </source></ignorable>
+      <segment><source>Two <ph id="1"/>and <sc id="2">x</sc>three.</source><target><![CDATA[Deux et trois.]]></target></segment>
+    </unit>
+    <unit id="u2">
+      <segment><source>Left</source><target>Gauche</target></segment>
+      <segment><source>Right</source></segment>
+    </unit>
+  </file>
+</xliff>"#;
+        let xliff = read(document).unwrap();
+        assert_eq!(xliff.language.as_deref(), Some("fr"));
+        assert_eq!(
+            xliff.pairs,
+            pairs(&[("One.  Two and three.", "Un.  Deux et trois.")])
+        );
+    }
+
+    #[tokio::test]
+    async fn paragraphs_not_in_the_document_are_left_untranslated() {
+        let lines = vec!["one".to_string(), "two".to_string()];
+        let translated = Unlisted
+            .translate_bulk(&Context::default(), "German", &lines, &[])
+            .await
+            .unwrap();
+        assert_eq!(translated.translated_lines, ["", ""]);
+    }
+}
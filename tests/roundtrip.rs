@@ -0,0 +1,252 @@
+//! The whole round trip of an EPUB, read, chunked, translated with the mock
+//! provider and written back, over the book in `fixtures/book`.
+
+use regex::Regex;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use trans_epub::epub::layout::Layout;
+use trans_epub::epub::provenance::{self, Provenance, ProvenanceMode};
+use trans_epub::epub::retranslate::{retranslate_epub, Selection};
+use trans_epub::epub::validate::validate_epub_bytes;
+use trans_epub::epub::{spine_paragraphs, Epub};
+use trans_epub::pipeline::{Config, Pipeline, Provider};
+use trans_epub::translate::mock::{Mock, Transform};
+use trans_epub::translate::translator::Translator;
+use trans_epub::translate_epub_bytes;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// The entries of the book, in the order of its archive.
+const ENTRIES: &[&str] = &[
+    "mimetype",
+    "META-INF/container.xml",
+    "OEBPS/content.opf",
+    "OEBPS/text/ch1.xhtml",
+    "OEBPS/text/ch2.xhtml",
+    "OEBPS/nav.xhtml",
+    "OEBPS/style.css",
+];
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = format!("{}/fixtures/book/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+}
+
+/// The book packed as an EPUB, its `mimetype` first and stored.
+fn book() -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for name in ENTRIES {
+        let method = match *name {
+            "mimetype" => CompressionMethod::Stored,
+            _ => CompressionMethod::Deflated,
+        };
+        zip.start_file(
+            *name,
+            SimpleFileOptions::default().compression_method(method),
+        )
+        .unwrap();
+        zip.write_all(&fixture(name)).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+fn config() -> Config {
+    Config::new(Provider::Mock, "mock", "German")
+}
+
+async fn paragraphs(epub: &[u8]) -> Vec<String> {
+    spine_paragraphs(Cursor::new(epub)).await.unwrap()
+}
+
+/// The names and contents of the entries of an EPUB, in archive order.
+fn entries(epub: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut archive = ZipArchive::new(Cursor::new(epub)).unwrap();
+    (0..archive.len())
+        .map(|i| {
+            let mut file = archive.by_index(i).unwrap();
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).unwrap();
+            (file.name().to_string(), content)
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn every_paragraph_is_translated_inline() {
+    let input = book();
+    let output = Pipeline::new(config())
+        .translate_epub_bytes(&input)
+        .await
+        .unwrap();
+
+    let sources = paragraphs(&input).await;
+    // the headings, the paragraphs, the footnote and the list items, but not
+    // the line of asterisks
+    assert_eq!(sources.len(), 10);
+    let expected: Vec<String> = sources
+        .iter()
+        .map(|source| format!("{}<<[German] {}>>", source, source))
+        .collect();
+    assert_eq!(paragraphs(&output).await, expected);
+}
+
+#[tokio::test]
+async fn books_on_disk_are_translated_as_in_memory() {
+    let dir = std::env::temp_dir().join(format!("trans-epub-disk-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (input, output) = (dir.join("book.epub"), dir.join("translated.epub"));
+    std::fs::write(&input, book()).unwrap();
+    let translator = || {
+        let config = config();
+        config.provider.translator(config.context)
+    };
+    Epub::new(input, output.clone())
+        .translate(&translator())
+        .await
+        .unwrap();
+    let in_memory = translate_epub_bytes(&book(), &translator()).await.unwrap();
+    // the archives differ only in the modification times of their entries
+    let on_disk = std::fs::read(&output).unwrap();
+    assert_eq!(entries(&on_disk), entries(&in_memory));
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn translating_back_gives_the_source() {
+    let input = book();
+    let translator = || {
+        let mut config = config();
+        config.context.layout = Layout::TranslatedOnly;
+        config.context.lines = 3;
+        Translator::new(
+            config.context,
+            Mock {
+                transform: Transform::Reverse,
+            },
+        )
+    };
+    let reversed = translate_epub_bytes(&input, &translator()).await.unwrap();
+    let sources = paragraphs(&input).await;
+    let translated = paragraphs(&reversed).await;
+    assert_ne!(translated, sources);
+    assert_eq!(translated[0], sources[0].chars().rev().collect::<String>());
+
+    let back = translate_epub_bytes(&reversed, &translator())
+        .await
+        .unwrap();
+    assert_eq!(paragraphs(&back).await, sources);
+}
+
+/// The entries of the book compared with `fixtures/snapshots`; run with
+/// `UPDATE_SNAPSHOTS=1` to write them again after a deliberate change.
+const SNAPSHOTS: &[&str] = &[
+    "OEBPS/content.opf",
+    "OEBPS/text/ch1.xhtml",
+    "OEBPS/text/ch2.xhtml",
+    "OEBPS/nav.xhtml",
+];
+
+#[tokio::test]
+async fn the_output_matches_the_snapshots() {
+    let input = book();
+    let mut config = config();
+    config.context.epub3 = true;
+    let output = Pipeline::new(config)
+        .translate_epub_bytes(&input)
+        .await
+        .unwrap();
+    let validation = validate_epub_bytes(&output, Some(&input)).unwrap();
+    assert!(validation.is_valid(), "{}", validation);
+    let mut archive = ZipArchive::new(Cursor::new(output)).unwrap();
+
+    let names: Vec<&str> = archive.file_names().collect();
+    assert_eq!(names, ENTRIES);
+    let mimetype = archive.by_name("mimetype").unwrap();
+    assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+    drop(mimetype);
+
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+    let modified = Regex::new(r#"(<meta property="dcterms:modified">)[^<]*(</meta>)"#).unwrap();
+    for name in SNAPSHOTS {
+        let mut content = String::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        // the time of the run
+        let content = modified
+            .replace(&content, "${1}2024-01-01T00:00:00Z${2}")
+            .into_owned();
+        let path = format!("{}/fixtures/snapshots/{}", env!("CARGO_MANIFEST_DIR"), name);
+        if update {
+            std::fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            std::fs::write(&path, &content).unwrap();
+            continue;
+        }
+        let snapshot = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        assert_eq!(content, snapshot, "{} differs from its snapshot", name);
+    }
+}
+
+#[tokio::test]
+async fn unchanged_entries_are_copied() {
+    let output = Pipeline::new(config())
+        .translate_epub_bytes(&book())
+        .await
+        .unwrap();
+    let mut archive = ZipArchive::new(Cursor::new(output)).unwrap();
+    for name in ["mimetype", "OEBPS/style.css"] {
+        let mut content = Vec::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, fixture(name), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn retranslated_paragraphs_are_credited_to_their_model() {
+    let translator = |run_id: &str| {
+        let mut config = config();
+        config.context.provenance = Some(Provenance::new(
+            ProvenanceMode::Sidecar,
+            Some(run_id.to_string()),
+        ));
+        Translator::new(config.context, Mock::default())
+    };
+    let first = translator("first");
+    let output = translate_epub_bytes(&book(), &first).await.unwrap();
+    let dir = std::env::temp_dir().join(format!("trans-epub-retranslate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("book.epub");
+    let provenance = first.context().provenance.as_ref().unwrap();
+    provenance.conclude(&path).unwrap();
+    let mut entries = provenance::read(&provenance::sidecar(&path)).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(!entries.is_empty());
+    assert!(entries
+        .iter()
+        .all(|entry| entry.model.as_deref() == Some("mock") && !entry.recalled));
+
+    let second = translator("second");
+    second
+        .context()
+        .control
+        .set_model(Some("other".to_string()));
+    let (_, replaced) = retranslate_epub(
+        &output,
+        &mut entries,
+        &Selection::new(Vec::new()),
+        &second,
+        "second",
+    )
+    .await
+    .unwrap();
+    assert_eq!(replaced, entries.len());
+    assert!(entries
+        .iter()
+        .all(|entry| entry.model.as_deref() == Some("other") && entry.run_id == "second"));
+}